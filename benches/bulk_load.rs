@@ -0,0 +1,70 @@
+// bulk load of a B+tree with BTreeBuilder compared with inserting the same sorted keys one by one
+//
+// cargo bench --bench bulk_load
+use std::fs;
+use std::time::Instant;
+
+use mintkv::{BTree, BTreeBuilder};
+
+const KEY_COUNT: u64 = 50_000;
+
+// keys of blocks are LE128 encoded
+fn varint_encode(mut value: u64) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if value == 0 {
+            return buffer;
+        }
+    }
+}
+
+fn run(name: &str, load: impl FnOnce(&mut BTree)) {
+    let path = std::env::temp_dir().join(format!("mintkv-bench-bulk-load-{}", name));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let start = Instant::now();
+    let mut tree = BTree::new(path);
+    load(&mut tree);
+    tree.flush();
+    drop(tree);
+    let elapsed = start.elapsed();
+
+    let size = fs::metadata(path).unwrap().len();
+    println!(
+        "{:<12} keys: {:>8} file: {:>10}B ({:.2}B/key) time: {:?}",
+        name,
+        KEY_COUNT,
+        size,
+        size as f64 / KEY_COUNT as f64,
+        elapsed,
+    );
+    let _ = fs::remove_file(path);
+}
+
+fn main() {
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..KEY_COUNT)
+        .map(|i| (varint_encode(i), format!("value-{:0>24}", i).into_bytes()))
+        .collect();
+
+    run("insert", |tree| {
+        for (key, value) in entries.iter() {
+            tree.insert(key, value);
+        }
+    });
+    for fill_factor in [0.5, 0.7, 0.9] {
+        run(&format!("bulk-{}", fill_factor), |tree| {
+            let mut builder = BTreeBuilder::new(tree).with_fill_factor(fill_factor);
+            for (key, value) in entries.iter() {
+                builder.add(key, value);
+            }
+            builder.finish();
+        });
+    }
+}
@@ -0,0 +1,71 @@
+// compression ratio of gorilla encoded chunks compared with the raw chunk layout
+//
+// cargo bench --bench gorilla
+use std::fs;
+use std::time::Instant;
+
+use mintkv::db::{DBOptions, MintKv, ValueEncoding};
+
+const SAMPLE_COUNT: u64 = 50_000;
+
+// simple lcg, so that every run uses the same series
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn run(name: &str, encoding: ValueEncoding, series: &[(u64, f64)]) {
+    let data_dir = std::env::temp_dir().join(format!("mintkv-bench-gorilla-{}", name));
+    let data_dir = data_dir.to_str().unwrap();
+    let _ = fs::remove_dir_all(data_dir);
+
+    let options = DBOptions {
+        value_encoding: encoding,
+        block_size: 1024 * 1024,
+        ..DBOptions::default()
+    };
+    let mut db = MintKv::open(data_dir, options);
+    let start = Instant::now();
+    for &(timestamp, value) in series {
+        db.insert_f64(timestamp, value).unwrap();
+    }
+    db.commit();
+    let elapsed = start.elapsed();
+
+    let stats = db.stats();
+    println!(
+        "{:<10} raw: {:>10}B encoded: {:>10}B ratio: {:.3} ({:.2}B/sample) insert: {:?}",
+        name,
+        stats.chunk_raw_bytes,
+        stats.chunk_encoded_bytes,
+        stats.chunk_compression_ratio(),
+        stats.chunk_encoded_bytes as f64 / SAMPLE_COUNT as f64,
+        elapsed,
+    );
+    drop(db);
+    let _ = fs::remove_dir_all(data_dir);
+}
+
+fn main() {
+    let mut rng = Lcg(42);
+    let mut gauge = 20.0;
+    let mut counter = 0.0;
+    let mut gauges = vec![];
+    let mut counters = vec![];
+    for i in 0..SAMPLE_COUNT {
+        // scrape every 15s with a little jitter
+        let timestamp = 1_700_000_000_000 + i * 15_000 + (rng.next() * 3.0) as u64;
+        gauge += (rng.next() - 0.5) * 0.1;
+        counter += (rng.next() * 10.0).floor();
+        gauges.push((timestamp, (gauge * 100.0f64).round() / 100.0));
+        counters.push((timestamp, counter));
+    }
+
+    run("raw", ValueEncoding::Raw, &gauges);
+    run("gauge", ValueEncoding::Gorilla, &gauges);
+    run("counter", ValueEncoding::Gorilla, &counters);
+}
@@ -0,0 +1,85 @@
+// lookups and a full scan on a read only B+tree, pages read with pread, through the buffer pool
+// or out of a mapped file
+//
+// cargo bench --bench mmap_read
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use mintkv::{BTree, BTreeBuilder, BTreeOptions, BufferPool};
+
+const KEY_COUNT: u64 = 50_000;
+const LOOKUP_COUNT: u64 = 20_000;
+
+// keys of blocks are LE128 encoded
+fn varint_encode(mut value: u64) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if value == 0 {
+            return buffer;
+        }
+    }
+}
+
+fn run(name: &str, path: &str, options: BTreeOptions) {
+    let tree = BTree::reader_with_options(path, &options);
+    // the same pseudo random keys for every reader
+    let mut key = 1u64;
+    let start = Instant::now();
+    for _ in 0..LOOKUP_COUNT {
+        key = key.wrapping_mul(6364136223846793005).wrapping_add(1);
+        tree.find(&varint_encode((key >> 33) % KEY_COUNT)).unwrap();
+    }
+    let lookups = start.elapsed();
+
+    let start = Instant::now();
+    assert_eq!(tree.iter().count() as u64, KEY_COUNT);
+    let scan = start.elapsed();
+    println!(
+        "{:<8} lookups: {:>8} time: {:?} ({:?}/lookup) scan: {:?}",
+        name,
+        LOOKUP_COUNT,
+        lookups,
+        lookups / LOOKUP_COUNT as u32,
+        scan,
+    );
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("mintkv-bench-mmap-read");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    let mut tree = BTree::new(path);
+    let mut builder = BTreeBuilder::new(&mut tree);
+    for i in 0..KEY_COUNT {
+        builder.add(&varint_encode(i), format!("value-{:0>24}", i).as_bytes());
+    }
+    builder.finish();
+    tree.flush();
+    drop(tree);
+
+    run("pread", path, BTreeOptions::default());
+    run(
+        "pool",
+        path,
+        BTreeOptions {
+            buffer_pool: Some(Arc::new(BufferPool::new(64 << 20))),
+            ..BTreeOptions::default()
+        },
+    );
+    run(
+        "mmap",
+        path,
+        BTreeOptions {
+            mmap: true,
+            ..BTreeOptions::default()
+        },
+    );
+    let _ = fs::remove_file(path);
+}
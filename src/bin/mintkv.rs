@@ -1,6 +1,28 @@
+use std::process::ExitCode;
+
+use mintkv::{BTree, BTreeOptions};
 use mintkv::db::MintKv;
 const TEST_COUNT: u64 = 1000;
-fn main() {
+
+const USAGE: &str = "usage: mintkv [fsck <data dir> | export <dot|json> <block file>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {
+            demo();
+            ExitCode::SUCCESS
+        }
+        ["fsck", data_dir] => fsck(data_dir),
+        ["export", format @ ("dot" | "json"), path] => export(format, path),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn demo() {
     let mut db = MintKv::new("./data");
     for i in 0..TEST_COUNT {
         let value = format!("value-{}", i);
@@ -10,8 +32,85 @@ fn main() {
     db.commit();
 
     for i in 0..TEST_COUNT {
-        let result = db.get(i);
+        let result = db.get(i).map(|value| String::from_utf8_lossy(&value).to_string());
         println!("Search {:?}, Result: {:?}", i, result);
     }
 }
 
+// check the blocks of a data directory against its MANIFEST and the B+tree of every block, the
+// database must not be open
+fn fsck(data_dir: &str) -> ExitCode {
+    let report = match mintkv::fsck::fsck(data_dir) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{data_dir}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    for (path, check) in report.blocks.iter() {
+        let check = match check {
+            Ok(check) => check,
+            Err(err) => {
+                println!("{path}: can't be opened: {err:?}");
+                continue;
+            }
+        };
+        println!(
+            "{path}: {} keys, depth {}, {} leaf / {} internal / {} overflow / {} freelist / {} free pages",
+            check.keys,
+            check.depth,
+            check.leaf_pages,
+            check.internal_pages,
+            check.overflow_pages,
+            check.freelist_pages,
+            check.free_pages,
+        );
+        for error in check.errors.iter() {
+            println!("    {error}");
+        }
+    }
+    for path in report.missing.iter() {
+        println!("{path}: in the MANIFEST but missing");
+    }
+    for path in report.orphans.iter() {
+        println!("{path}: not in the MANIFEST, removed on next open");
+    }
+    println!(
+        "{} blocks checked, {} damaged, {} missing, {} orphan files",
+        report.blocks.len(),
+        report.damaged(),
+        report.missing.len(),
+        report.orphans.len()
+    );
+    if report.is_ok() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+// print the structure of the B+tree of a block file, e.g. `mintkv export dot data/blocks/block-0`
+fn export(format: &str, path: &str) -> ExitCode {
+    let tree = match BTree::try_reader_with_options(path, &BTreeOptions::default()) {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("{path}: can't be opened: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let exported = if format == "dot" {
+        tree.to_dot()
+    } else {
+        tree.to_json()
+    };
+    match exported {
+        Ok(exported) => {
+            print!("{exported}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{path}: {err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::bytes::VarintCodec;
+
+// 每一个block(segment)都有一个bloom filter, 查询之前先检查filter,
+// 如果key一定不存在就不需要打开B树去读磁盘
+//
+// filter format
+// |------------------------------|
+// | hash num | ....  bits  ....  |
+// |------------------------------|
+// |  1B      | ................. |
+// |------------------------------|
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BloomFilter {
+    num_hashes: u8,
+    bits: Vec<u8>,
+}
+
+// BloomFilter[#TODO] (should add some comments)
+impl BloomFilter {
+    // key hashes are collected while the block is being written and the filter is built at once,
+    // so the bit array can be sized for the final key count
+    pub(super) fn build(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        // k = bits_per_key * ln(2) minimizes the false positive rate
+        let num_hashes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        // at least 64 bits, small filters have a very high false positive rate
+        let num_bits = (key_hashes.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter {
+            num_hashes,
+            bits: vec![0u8; num_bits.div_ceil(8)],
+        };
+        for &hash in key_hashes {
+            filter.insert_hash(hash);
+        }
+        filter
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let num_bits = self.bits.len() as u64 * 8;
+        let (mut h, delta) = (hash, mix64(hash) | 1);
+        for _ in 0..self.num_hashes {
+            let bit = h % num_bits;
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            h = h.wrapping_add(delta);
+        }
+    }
+
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.bits.len() as u64 * 8;
+        if num_bits == 0 {
+            return true;
+        }
+        let hash = key_hash(key);
+        let (mut h, delta) = (hash, mix64(hash) | 1);
+        for _ in 0..self.num_hashes {
+            let bit = h % num_bits;
+            if self.bits[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(1 + self.bits.len());
+        buffer.push(self.num_hashes);
+        buffer.extend_from_slice(&self.bits);
+        buffer
+    }
+
+    pub(super) fn decode(buffer: &[u8]) -> Option<Self> {
+        let (&num_hashes, bits) = buffer.split_first()?;
+        Some(BloomFilter {
+            num_hashes,
+            bits: bits.to_vec(),
+        })
+    }
+}
+
+// hash of a varint encoded u64 key
+#[inline]
+pub(super) fn key_hash(key: &[u8]) -> u64 {
+    mix64(u64::varint_decode(key).1)
+}
+
+// finalizer of murmurhash3, spreads every input bit over the whole u64
+#[inline]
+fn mix64(mut value: u64) -> u64 {
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xff51afd7ed558ccd);
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xc4ceb9fe1a85ec53);
+    value ^= value >> 33;
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negative() {
+        let hashes: Vec<u64> = (0..10_000u64).map(|k| key_hash(&k.varint_encode())).collect();
+        let filter = BloomFilter::build(&hashes, 10);
+        for k in 0..10_000u64 {
+            assert!(filter.may_contain(&k.varint_encode()));
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let hashes: Vec<u64> = (0..10_000u64).map(|k| key_hash(&k.varint_encode())).collect();
+        let filter = BloomFilter::build(&hashes, 10);
+        let false_positives = (10_000..110_000u64)
+            .filter(|k| filter.may_contain(&k.varint_encode()))
+            .count();
+        // about 1% with 10 bits per key
+        assert!(false_positives < 2_000, "false positives: {}", false_positives);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let filter = BloomFilter::build(&[key_hash(&7u64.varint_encode())], 10);
+        let decoded = BloomFilter::decode(&filter.encode()).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(decoded.may_contain(&7u64.varint_encode()));
+        assert!(BloomFilter::decode(&[]).is_none());
+    }
+}
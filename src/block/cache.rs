@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+// TableCache 缓存已经打开的block reader, 避免每次查询都重新打开文件, 读取meta和freelist
+// 缓存按照LRU淘汰, 最多同时打开capacity个文件. block的bloom filter 也用它缓存, 最多capacity个
+pub(crate) struct TableCache<T> {
+    capacity: usize,
+    // block id -> (reader, last used tick)
+    entries: HashMap<u64, (Rc<T>, u64)>,
+    // last used tick -> block id, the smallest tick is the least recently used
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+// TableCache[#TODO] (should add some comments)
+impl<T> TableCache<T> {
+    pub(super) fn new(capacity: usize) -> Self {
+        TableCache {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    // return the cached reader and whether it was a hit, open it with `open` on miss
+    pub(super) fn get_or_open(&mut self, id: u64, open: impl FnOnce() -> T) -> (Rc<T>, bool) {
+        self.tick += 1;
+        if let Some((reader, last_used)) = self.entries.get_mut(&id) {
+            self.lru.remove(last_used);
+            *last_used = self.tick;
+            self.lru.insert(self.tick, id);
+            return (reader.clone(), true);
+        }
+
+        while self.entries.len() >= self.capacity {
+            let (_, lru_id) = self.lru.pop_first().unwrap();
+            self.entries.remove(&lru_id);
+        }
+        let reader = Rc::new(open());
+        self.entries.insert(id, (reader.clone(), self.tick));
+        self.lru.insert(self.tick, id);
+        (reader, false)
+    }
+
+    // must be called when a block is deleted or replaced by compaction; readers still held by
+    // callers stay valid until they are dropped
+    pub(super) fn evict(&mut self, id: u64) {
+        if let Some((_, last_used)) = self.entries.remove(&id) {
+            self.lru.remove(&last_used);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_and_miss() {
+        let mut cache = TableCache::new(2);
+        let (reader, hit) = cache.get_or_open(1, || "block-1".to_string());
+        assert!(!hit);
+        assert_eq!(*reader, "block-1");
+
+        let (reader, hit) = cache.get_or_open(1, || unreachable!());
+        assert!(hit);
+        assert_eq!(*reader, "block-1");
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = TableCache::new(2);
+        cache.get_or_open(1, || 1);
+        cache.get_or_open(2, || 2);
+        // touch 1, so 2 becomes the least recently used
+        cache.get_or_open(1, || unreachable!());
+        cache.get_or_open(3, || 3);
+        assert_eq!(cache.len(), 2);
+
+        assert!(cache.get_or_open(1, || unreachable!()).1);
+        assert!(cache.get_or_open(3, || unreachable!()).1);
+        assert!(!cache.get_or_open(2, || 2).1);
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut cache = TableCache::new(4);
+        let (held, _) = cache.get_or_open(7, || "old".to_string());
+        cache.evict(7);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(*held, "old");
+
+        let (reader, hit) = cache.get_or_open(7, || "new".to_string());
+        assert!(!hit);
+        assert_eq!(*reader, "new");
+        cache.evict(42);
+    }
+}
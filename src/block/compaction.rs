@@ -0,0 +1,586 @@
+// compaction merges blocks of a level into the next one, see meta.rs for the levels.
+//
+// Level 0 holds the blocks written from the memtable, their key ranges may overlap. Once it
+// holds level0_compaction_trigger blocks, all of them are merged with the blocks of level 1
+// they overlap. A deeper level is compacted once it outgrows its size limit: its oldest block is
+// merged with the blocks of the next level it overlaps. The level with the highest ratio of its
+// size (or block count for level 0) to its limit goes first. A block of a level above 0
+// overlapping nothing in the next level is moved there without being rewritten, so are blocks of
+// level 0 that overlap neither each other nor level 1.
+//
+// Inputs are merged by a k-way merge over their entries in key order, one chunk of every input is
+// decoded at a time. The entry of the newest input holding a key wins, and a tombstone is dropped
+// unless a block below the output level may still hold an older entry for it to hide.
+// An entry whose ttl has passed is dropped like a tombstone, or kept as one. An entry hidden by a
+// range tombstone is dropped along with the older entries of its key, the range tombstone keeps
+// hiding the blocks it's older than that are left.
+// The merged entries are cut into chunks and written into temporary files as they come, which are
+// installed as new blocks of the output level by Blocks: renamed to block files and swapped for the input
+// blocks by one manifest record.
+//
+// The block being written is never compacted, everything in it is newer than the finished blocks.
+// A compaction started in the background runs on its own thread and only reads the input files,
+// its reads and writes are rate limited.
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::meta::{BlockMeta, Metadata, RangeTombstone, NUM_LEVELS};
+use super::{block_file, EncodedChunk, Entry, Segment};
+use crate::btree::BTreeOptions;
+use crate::bytes::{self, VarintCodec};
+use crate::chunk::Chunk;
+use crate::db::DBOptions;
+use crate::errors::Error;
+use crate::tombstone;
+
+// temporary files of the new blocks are named compaction-{n}
+pub(super) const COMPACTION_FILE_PREFIX: &str = "compaction-";
+
+// blocks picked to be merged into the next level
+#[derive(Debug)]
+pub(super) struct Task {
+    // level of the picked blocks, the new blocks go into level + 1
+    pub(super) level: usize,
+    // blocks of both levels
+    pub(super) inputs: Vec<BlockMeta>,
+    // blocks below the output level, tombstones of the keys they cover are kept
+    pub(super) deeper: Vec<BlockMeta>,
+    // time of the clock when the task was picked, entries expired by then are removed
+    pub(super) now: u64,
+    // range tombstones when the task was picked
+    pub(super) range_tombstones: Vec<RangeTombstone>,
+}
+
+// blocks merged by a compaction and the blocks written for them
+pub(super) struct Compaction {
+    // level of the new blocks
+    pub(super) level: usize,
+    pub(super) inputs: Vec<BlockMeta>,
+    // temporary file of every new block, ids are assigned when they are installed
+    pub(super) outputs: Vec<(String, BlockMeta)>,
+    pub(super) read_bytes: u64,
+    pub(super) written_bytes: u64,
+    // entries removed because their ttl had passed
+    pub(super) expired: u64,
+}
+
+// the level that needs a compaction the most, the active block is left out. With force, level 0
+// is compacted as long as it holds any block
+pub(super) fn pick(
+    metadata: &Metadata,
+    active: Option<u64>,
+    options: &DBOptions,
+    force: bool,
+) -> Option<Task> {
+    let level0: Vec<&BlockMeta> = metadata.levels[0]
+        .iter()
+        .filter(|block| block.entries > 0 && Some(block.id) != active)
+        .collect();
+    // a level is compacted once its score reaches 1
+    let mut scores = vec![(
+        level0.len() as f64 / options.level0_compaction_trigger.max(1) as f64,
+        0,
+    )];
+    if force && !level0.is_empty() {
+        scores[0].0 = f64::INFINITY;
+    }
+    // the last level is never compacted
+    for level in 1..NUM_LEVELS - 1 {
+        let score = metadata.level_size(level) as f64 / options.level_max_size(level) as f64;
+        scores.push((score, level));
+    }
+    let (score, level) = scores.into_iter().max_by(|a, b| a.0.total_cmp(&b.0))?;
+    if score < 1.0 {
+        return None;
+    }
+
+    let picked: Vec<&BlockMeta> = if level == 0 {
+        level0
+    } else {
+        let oldest = metadata.levels[level]
+            .iter()
+            .min_by_key(|block| (block.max_seq, block.id))?;
+        vec![oldest]
+    };
+    let min_key = picked
+        .iter()
+        .map(|block| &block.min_key)
+        .min_by(|a, b| bytes::compare(a, b))?;
+    let max_key = picked
+        .iter()
+        .map(|block| &block.max_key)
+        .max_by(|a, b| bytes::compare(a, b))?;
+    let next = metadata.overlapping(level + 1, min_key, max_key);
+    Some(Task {
+        level,
+        inputs: picked.into_iter().chain(next).cloned().collect(),
+        deeper: metadata.levels[level + 2..]
+            .iter()
+            .flatten()
+            .cloned()
+            .collect(),
+        now: options.clock.now(),
+        range_tombstones: metadata.range_tombstones.clone(),
+    })
+}
+
+// Task[#TODO] (should add some comments)
+impl Task {
+    // the picked blocks can be moved into the next level as they are if they overlap neither
+    // each other nor a block of the next level
+    pub(super) fn is_move(&self) -> bool {
+        if self.inputs.iter().any(|block| block.level != self.level) {
+            return false;
+        }
+        let mut inputs: Vec<&BlockMeta> = self.inputs.iter().collect();
+        inputs.sort_by(|a, b| bytes::compare(&a.min_key, &b.min_key));
+        inputs
+            .windows(2)
+            .all(|pair| bytes::compare(&pair[0].max_key, &pair[1].min_key).is_lt())
+    }
+
+    // the key of a chunk of the input block is hidden by a range tombstone written after the
+    // chunk
+    fn is_deleted(&self, key: u64, seq: u64, block: &BlockMeta) -> bool {
+        let seq = seq.min(block.max_seq);
+        self.range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.hides(key, seq))
+    }
+
+    // no block below the output level may hold the key
+    fn is_bottom(&self, key: &[u8]) -> bool {
+        !self.deeper.iter().any(|block| {
+            bytes::compare(&block.min_key, key).is_le()
+                && bytes::compare(&block.max_key, key).is_ge()
+        })
+    }
+}
+
+// run a compaction on its own thread
+pub(super) fn spawn(
+    task: Task,
+    block_dir: String,
+    options: DBOptions,
+) -> JoinHandle<Result<Compaction, Error>> {
+    thread::spawn(move || {
+        let mut limiter = RateLimiter::new(options.compaction_bytes_per_sec as u64);
+        Compaction::run(task, &block_dir, &options, &mut limiter)
+    })
+}
+
+// Compaction[#TODO] (should add some comments)
+impl Compaction {
+    pub(super) fn run(
+        mut task: Task,
+        block_dir: &str,
+        options: &DBOptions,
+        limiter: &mut RateLimiter,
+    ) -> Result<Compaction, Error> {
+        // input files are read once, they are not cached in the page cache of the database
+        let tree_options = BTreeOptions {
+            page_size: options.page_size,
+            buffer_pool: None,
+            ..BTreeOptions::default()
+        };
+        // blocks of the next level are older than the picked ones, whose sequence numbers only
+        // tell their order when they are in level 0
+        task.inputs
+            .sort_by_key(|block| (std::cmp::Reverse(block.level), block.max_seq, block.id));
+        let inputs = std::mem::take(&mut task.inputs);
+        let mut read_bytes = 0;
+        let readers: Vec<Segment> = inputs
+            .iter()
+            .map(|block| {
+                let path = block_file(block_dir, block.id);
+                read_bytes += fs::metadata(&path).map_or(0, |meta| meta.len());
+                Segment::reader(&path, &tree_options)
+            })
+            .collect();
+        // chunks are charged as the merge reads them, and blocks as they are written
+        let limiter = RefCell::new(limiter);
+        let charge = |size| limiter.borrow_mut().consume(size);
+        let merge = Merge::new(readers.iter().map(|segment| segment.entries(charge)).collect())?;
+
+        let mut writer = Writer {
+            block_dir,
+            options,
+            tree_options,
+            level: task.level + 1,
+            max_seq: inputs.iter().map(|block| block.max_seq).max().unwrap_or(0),
+            segment: None,
+            outputs: Vec::new(),
+            written_bytes: 0,
+        };
+        let mut chunk = Chunk::with_size(options.chunk_size);
+        let mut partition = 0;
+        let mut expired = 0;
+        for item in merge {
+            let (key, input, seq, mut entry) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    // the blocks written so far are thrown away, the inputs are left as they are
+                    writer.finish_segment();
+                    for (path, _) in writer.outputs {
+                        let _ = fs::remove_file(path);
+                    }
+                    return Err(err);
+                }
+            };
+            if task.is_deleted(key, seq, &inputs[input]) {
+                continue;
+            }
+            let key_partition = options.partition_of(key);
+            let key = key.varint_encode();
+            if tombstone::is_expired(&entry, task.now) {
+                expired += 1;
+                entry = tombstone::tombstone();
+            }
+            if tombstone::is_tombstone(&entry) && task.is_bottom(&key) {
+                continue;
+            }
+            // chunks never span partitions, neither do blocks
+            let other_partition = std::mem::replace(&mut partition, key_partition) != key_partition;
+            if (chunk.is_overflowed(&key, &entry) || other_partition) && chunk.key_nums > 0 {
+                let full = std::mem::replace(&mut chunk, Chunk::with_size(options.chunk_size));
+                writer.write_chunk(full, &mut limiter.borrow_mut());
+            }
+            chunk.insert(&key, &entry).unwrap();
+        }
+        writer.write_chunk(chunk, &mut limiter.borrow_mut());
+        writer.finish_segment();
+
+        Ok(Compaction {
+            level: task.level + 1,
+            inputs,
+            outputs: writer.outputs,
+            read_bytes,
+            written_bytes: writer.written_bytes,
+            expired,
+        })
+    }
+}
+
+// k-way merge of the entries of the inputs, ordered oldest first. Yields every key once in order,
+// with the entry of the newest input holding it, the index of that input and the seq of the chunk
+struct Merge<I> {
+    inputs: Vec<I>,
+    // next entry of every input that isn't used up: the smallest key first, and the newest input
+    // first among equal keys
+    heads: BinaryHeap<Reverse<Head>>,
+}
+
+// decoded key, reversed index of the input, seq of the chunk and entry
+type Head = (u64, Reverse<usize>, u64, Vec<u8>);
+
+// Merge[#TODO] (should add some comments)
+impl<I: Iterator<Item = Result<(u64, Entry), Error>>> Merge<I> {
+    fn new(inputs: Vec<I>) -> Result<Self, Error> {
+        let mut merge = Merge {
+            heads: BinaryHeap::with_capacity(inputs.len()),
+            inputs,
+        };
+        for input in 0..merge.inputs.len() {
+            merge.advance(input)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, input: usize) -> Result<(), Error> {
+        if let Some(item) = self.inputs[input].next() {
+            let (seq, (key, entry)) = item?;
+            let key = u64::varint_decode(&key).1;
+            self.heads.push(Reverse((key, Reverse(input), seq, entry)));
+        }
+        Ok(())
+    }
+}
+
+// Iterator[#TODO] (should add some comments)
+impl<I: Iterator<Item = Result<(u64, Entry), Error>>> Iterator for Merge<I> {
+    type Item = Result<(u64, usize, u64, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, Reverse(input), seq, entry)) = self.heads.pop()?;
+        // older entries of the key are skipped
+        let mut used = vec![input];
+        while matches!(self.heads.peek(), Some(Reverse((next, ..))) if *next == key) {
+            let Reverse((_, Reverse(older), ..)) = self.heads.pop().unwrap();
+            used.push(older);
+        }
+        for input in used {
+            if let Err(err) = self.advance(input) {
+                return Some(Err(err));
+            }
+        }
+        Some(Ok((key, input, seq, entry)))
+    }
+}
+
+// writes merged chunks into new blocks of at most block_size bytes
+struct Writer<'a> {
+    block_dir: &'a str,
+    options: &'a DBOptions,
+    tree_options: BTreeOptions,
+    level: usize,
+    // the newest seq of the input blocks, taken by every new chunk: the entries hidden by a range
+    // tombstone are dropped, and later range tombstones are newer than all inputs
+    max_seq: u64,
+    segment: Option<(Segment, BlockMeta)>,
+    outputs: Vec<(String, BlockMeta)>,
+    written_bytes: u64,
+}
+
+// Writer[#TODO] (should add some comments)
+impl Writer<'_> {
+    fn write_chunk(&mut self, mut chunk: Chunk, limiter: &mut RateLimiter) {
+        chunk.seq = self.max_seq;
+        let chunk = match EncodedChunk::new(&chunk, self.options) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        let size = chunk.key.len() + chunk.value.len();
+        let partition = self.options.partition_of(chunk.first);
+        if let Some((ref segment, ref block)) = self.segment {
+            let block_partition = self
+                .options
+                .partition_of(u64::varint_decode(&block.min_key).1);
+            if segment.is_overflow(size) || block_partition != partition {
+                self.finish_segment();
+            }
+        }
+        if self.segment.is_none() {
+            let path = format!(
+                "{}/{}{}",
+                self.block_dir,
+                COMPACTION_FILE_PREFIX,
+                self.outputs.len()
+            );
+            let _ = fs::remove_file(&path);
+            let segment = Segment::new(
+                &path,
+                self.options.block_size,
+                self.options.bloom_bits_per_key,
+                self.options.fill_factor,
+                &self.tree_options,
+            );
+            let block = BlockMeta {
+                level: self.level,
+                ..BlockMeta::default()
+            };
+            self.segment = Some((segment, block));
+        }
+        let (segment, block) = self.segment.as_mut().unwrap();
+        limiter.consume(size as u64);
+        self.written_bytes += size as u64;
+        block.add_chunk(
+            &chunk.first.varint_encode(),
+            &chunk.last.varint_encode(),
+            chunk.entries,
+            chunk.raw_size,
+            self.max_seq,
+        );
+        segment.insert(Rc::new((chunk.key, chunk.value)));
+        segment.key_hashes.extend(chunk.key_hashes);
+    }
+
+    // the segment is flushed when it's dropped
+    fn finish_segment(&mut self) {
+        if let Some((segment, block)) = self.segment.take() {
+            let path = segment.file_name.clone();
+            drop(segment);
+            self.outputs.push((path, block));
+        }
+    }
+}
+
+// token bucket limiting the bytes compaction reads and writes per second, a burst of one second
+// is allowed. Bytes taken beyond the tokens left are slept off
+pub(super) struct RateLimiter {
+    // 0 means no limit
+    bytes_per_sec: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+// RateLimiter[#TODO] (should add some comments)
+impl RateLimiter {
+    pub(super) fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    pub(super) fn consume(&mut self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let rate = self.bytes_per_sec as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / rate));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::meta::Edit;
+
+    fn block(id: u64, min: u64, max: u64, level: usize) -> Edit {
+        let mut block = BlockMeta::new(id);
+        block.add_chunk(&min.varint_encode(), &max.varint_encode(), 10, 100, id);
+        block.file_size = 1000;
+        block.level = level;
+        Edit::AddBlock(block)
+    }
+
+    fn ids(blocks: &[BlockMeta]) -> Vec<u64> {
+        let mut ids: Vec<u64> = blocks.iter().map(|block| block.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_pick() {
+        let options = DBOptions {
+            level0_compaction_trigger: 4,
+            level_base_size: 3500,
+            level_size_ratio: 2,
+            ..DBOptions::default()
+        };
+        let mut metadata = Metadata::new();
+        for edit in [
+            block(0, 0, 99, 1),
+            block(1, 100, 199, 1),
+            block(2, 300, 399, 1),
+            block(3, 150, 250, 0),
+            block(4, 160, 170, 0),
+            block(5, 500, 600, 0),
+        ] {
+            metadata.apply(&edit);
+        }
+        assert!(pick(&metadata, None, &options, false).is_none());
+        // a forced compaction takes level 0 as long as it holds any block
+        let task = pick(&metadata, None, &options, true).unwrap();
+        assert_eq!((task.level, ids(&task.inputs)), (0, vec![1, 2, 3, 4, 5]));
+
+        // every block of level 0 but the active one, with the blocks of level 1 overlapping the
+        // range they span
+        metadata.apply(&block(6, 50, 60, 0));
+        metadata.apply(&block(7, 1000, 1100, 0));
+        let task = pick(&metadata, Some(7), &options, false).unwrap();
+        assert_eq!(
+            (task.level, ids(&task.inputs)),
+            (0, vec![0, 1, 2, 3, 4, 5, 6])
+        );
+        assert!(!task.is_move());
+        assert!(task.deeper.is_empty());
+        let task = pick(&metadata, None, &options, false).unwrap();
+        assert_eq!(ids(&task.inputs), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        // level 1 over its limit: its oldest block with the blocks of level 2 it overlaps
+        let mut metadata = Metadata::new();
+        for edit in [
+            block(0, 0, 99, 2),
+            block(1, 100, 199, 2),
+            block(2, 300, 399, 3),
+            block(3, 350, 450, 1),
+            block(4, 150, 320, 1),
+            block(5, 500, 600, 1),
+            block(6, 0, 50, 0),
+            block(7, 700, 800, 1),
+        ] {
+            metadata.apply(&edit);
+        }
+        let task = pick(&metadata, None, &options, false).unwrap();
+        assert_eq!((task.level, ids(&task.inputs)), (1, vec![3]));
+        assert!(task.is_move());
+        assert_eq!(ids(&task.deeper), vec![2]);
+        assert!(!task.is_bottom(&350u64.varint_encode()));
+        assert!(task.is_bottom(&299u64.varint_encode()));
+        metadata.apply(&block(3, 350, 450, 2));
+        assert!(pick(&metadata, None, &options, false).is_none());
+        metadata.apply(&block(8, 900, 950, 1));
+        let task = pick(&metadata, None, &options, false).unwrap();
+        assert_eq!((task.level, ids(&task.inputs)), (1, vec![1, 4]));
+        assert!(!task.is_move());
+        // level 1 holds 3000 of 3500 bytes, level 2 3000 of 7000
+        metadata.apply(&Edit::RemoveBlock(4));
+        assert!(pick(&metadata, None, &options, false).is_none());
+
+        // empty blocks are never picked
+        let mut metadata = Metadata::new();
+        for id in 0..4 {
+            metadata.apply(&Edit::AddBlock(BlockMeta::new(id)));
+        }
+        assert!(pick(&metadata, None, &options, true).is_none());
+    }
+
+    #[test]
+    fn test_merge() {
+        // the chunks of an input have the seq 10 * value
+        let input = |keys: &[u64], value: u8| -> Vec<Result<(u64, Entry), Error>> {
+            keys.iter()
+                .map(|key| Ok((value as u64 * 10, (key.varint_encode(), vec![value]))))
+                .collect()
+        };
+        // inputs are ordered oldest first, the newest entry of a key wins
+        let inputs = vec![
+            input(&[1, 3, 5, 7], 0).into_iter(),
+            input(&[2, 3, 4], 1).into_iter(),
+            input(&[], 2).into_iter(),
+            input(&[3, 7, 300], 3).into_iter(),
+        ];
+        let merged: Vec<(u64, usize, u64, Vec<u8>)> = Merge::new(inputs)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            merged,
+            vec![
+                (1, 0, 0, vec![0]),
+                (2, 1, 10, vec![1]),
+                (3, 3, 30, vec![3]),
+                (4, 1, 10, vec![1]),
+                (5, 0, 0, vec![0]),
+                (7, 3, 30, vec![3]),
+                (300, 3, 30, vec![3]),
+            ]
+        );
+
+        // an entry that can't be read fails the merge where it's reached
+        let mut broken = input(&[1, 2], 1);
+        broken.push(Err(Error::Corrupted));
+        let inputs = vec![input(&[1, 5], 0).into_iter(), broken.into_iter()];
+        let mut merged = Merge::new(inputs).unwrap();
+        assert_eq!(merged.next(), Some(Ok((1, 1, 10, vec![1]))));
+        assert_eq!(merged.next(), Some(Err(Error::Corrupted)));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(4 << 20);
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.consume(512 << 10);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let mut limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        limiter.consume(1 << 40);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -1,10 +1,172 @@
+use std::io::Read;
 
+use crate::bytes::VarintCodec;
+use crate::errors::Error;
 
-// Encoder[#TODO] (shoule add some comments )
-struct Encoder {
-    buffer : Vec<u8>
+// 每一个encoded chunk 写入B树之前可以单独压缩, codec id 和数据保存在一起
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
 }
 
+// Compression[#TODO] (should add some comments)
+impl Compression {
+    #[inline]
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+            Compression::Snappy => 3,
+        }
+    }
 
-//
-//
+    #[inline]
+    fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Snappy),
+            _ => Err(Error::Corrupted),
+        }
+    }
+}
+
+// raw size stored in front of a chunk is not trusted to allocate buffers: lz4 and snappy can't
+// produce more than 255 bytes per byte of payload, zstd is read up to the raw size only
+const MAX_EXPANSION: usize = 255;
+
+// compressed chunk layout
+// |--------------------------------------------|
+// | codec id | raw size (varint) | payload     |
+// |--------------------------------------------|
+// |  1B      |  1~10B            | xB          |
+// |--------------------------------------------|
+pub(super) struct Encoder;
+
+// Encoder[#TODO] (should add some comments)
+impl Encoder {
+    // payload is kept uncompressed if the codec doesn't make it smaller
+    pub(super) fn encode(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let payload = match compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::block::compress(data)),
+            Compression::Zstd => Some(ruzstd::encoding::compress_to_vec(
+                data,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(data).ok(),
+        };
+        let (compression, payload) = match payload {
+            Some(payload) if payload.len() < data.len() => (compression, payload),
+            _ => (Compression::None, data.to_vec()),
+        };
+
+        let mut buffer = vec![compression.id()];
+        buffer.append(&mut data.len().varint_encode());
+        buffer.extend_from_slice(&payload);
+        buffer
+    }
+
+    pub(super) fn decode(data: &[u8]) -> Result<Vec<u8>, Error> {
+        let compression = Compression::from_id(*data.first().ok_or(Error::Corrupted)?)?;
+        let (r_byte_cnt, raw_size) = usize::varint_decode(&data[1..]);
+        let payload = &data[1 + r_byte_cnt..];
+        let bounded = match compression {
+            Compression::None => raw_size == payload.len(),
+            Compression::Lz4 | Compression::Snappy => {
+                raw_size <= payload.len().saturating_mul(MAX_EXPANSION)
+            }
+            Compression::Zstd => true,
+        };
+        if !bounded {
+            return Err(Error::Corrupted);
+        }
+
+        let raw = match compression {
+            Compression::None => payload.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(payload, raw_size)
+                .map_err(|_| Error::Corrupted)?,
+            Compression::Zstd => {
+                let mut raw = Vec::new();
+                // one more byte than expected is enough to tell it's corrupted
+                ruzstd::decoding::StreamingDecoder::new(payload)
+                    .map_err(|_| Error::Corrupted)?
+                    .take(raw_size as u64 + 1)
+                    .read_to_end(&mut raw)
+                    .map_err(|_| Error::Corrupted)?;
+                raw
+            }
+            Compression::Snappy => {
+                // snappy allocates the size in its own header
+                if snap::raw::decompress_len(payload).ok() != Some(raw_size) {
+                    return Err(Error::Corrupted);
+                }
+                snap::raw::Decoder::new()
+                    .decompress_vec(payload)
+                    .map_err(|_| Error::Corrupted)?
+            }
+        };
+        if raw.len() != raw_size {
+            return Err(Error::Corrupted);
+        }
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data: Vec<u8> = (0..4096u32).flat_map(|i| (i % 17).to_le_bytes()).collect();
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Zstd,
+            Compression::Snappy,
+        ] {
+            let encoded = Encoder::encode(compression, &data);
+            assert_eq!(encoded[0], compression.id());
+            if compression != Compression::None {
+                assert!(encoded.len() < data.len() / 2);
+            }
+            assert_eq!(Encoder::decode(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_incompressible_fallback() {
+        let data = vec![7u8];
+        let encoded = Encoder::encode(Compression::Zstd, &data);
+        assert_eq!(encoded[0], Compression::None.id());
+        assert_eq!(Encoder::decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        assert_eq!(Encoder::decode(&[]), Err(Error::Corrupted));
+        assert_eq!(Encoder::decode(&[9, 0]), Err(Error::Corrupted));
+        let mut encoded = Encoder::encode(Compression::Lz4, &[1u8; 1024]);
+        encoded.truncate(encoded.len() - 1);
+        assert_eq!(Encoder::decode(&encoded), Err(Error::Corrupted));
+
+        // a raw size no codec could reach is refused before it's allocated
+        for compression in [Compression::None, Compression::Lz4, Compression::Snappy] {
+            let mut encoded = vec![compression.id()];
+            encoded.extend((1usize << 60).varint_encode());
+            encoded.extend_from_slice(&[0u8; 16]);
+            assert_eq!(Encoder::decode(&encoded), Err(Error::Corrupted));
+        }
+        let mut encoded = vec![Compression::Zstd.id()];
+        encoded.extend((1usize << 60).varint_encode());
+        encoded.extend(&Encoder::encode(Compression::Zstd, &[1u8; 1024])[3..]);
+        assert_eq!(Encoder::decode(&encoded), Err(Error::Corrupted));
+    }
+}
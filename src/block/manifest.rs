@@ -0,0 +1,404 @@
+// the manifest records which blocks the database is made of, see meta.rs for the metadata of a
+// block. It's a log of edits, every edit is appended and synced before it's applied, and it's
+// replayed on open.
+//
+// A manifest starts with a snapshot of the whole metadata. Once it holds SNAPSHOT_EDITS edits it
+// is replaced by a new snapshot: the snapshot is written into a temporary file which is renamed
+// over the manifest, so a crash leaves either the old or the new one.
+//
+// record format
+// |---------------------------------------------|
+// | crc32 | size | version | type | payload      |
+// |---------------------------------------------|
+// |  4B   |  4B  | varint  |  1B  | see meta.rs  |
+// |---------------------------------------------|
+// size is the size of version, type and payload, crc32 covers everything after it. A record that is cut short or does not match its checksum
+// ends the log, it's the tail of an append that did not finish and is truncated
+//
+// edits that must be applied together, like the blocks added and removed by a compaction, are
+// logged as one record holding all of them, so a crash never leaves only some of them
+// | Edits | edits_num | type | edit | ... | type | edit |
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+
+use super::meta::{read_u64, BlockMeta, Edit, Metadata, RangeTombstone};
+use crate::bytes::VarintCodec;
+use crate::util::crc32;
+
+pub(super) const MANIFEST_FILE: &str = "MANIFEST";
+const SNAPSHOT_EDITS: usize = 512;
+const RECORD_HEAD_SIZE: usize = 8;
+
+const RECORD_SNAPSHOT: u8 = 1;
+const RECORD_ADD_BLOCK: u8 = 2;
+const RECORD_REMOVE_BLOCK: u8 = 3;
+const RECORD_EDITS: u8 = 4;
+const RECORD_DELETE_RANGE: u8 = 5;
+const RECORD_REMOVE_RANGE: u8 = 6;
+
+pub(super) struct Manifest {
+    path: String,
+    file: File,
+    // edits appended after the snapshot
+    edits: usize,
+}
+
+// Manifest[#TODO] (should add some comments)
+impl Manifest {
+    // replay the manifest of a block dir, an empty one is created if there is none
+    pub(super) fn open_or_create(block_dir: &str) -> (Manifest, Metadata) {
+        let path = format!("{block_dir}/{MANIFEST_FILE}");
+        let buffer = match fs::read(&path) {
+            Ok(buffer) => buffer,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                assert!(
+                    fs::metadata(format!("{block_dir}/metadata.json")).is_err(),
+                    "blocks written by an older version are not supported"
+                );
+                let metadata = Metadata::new();
+                let manifest = Manifest::create(&path, &metadata);
+                return (manifest, metadata);
+            }
+            Err(_) => panic!("open manifest failed"),
+        };
+
+        let (metadata, edits, len) = replay(&buffer).unwrap_or_else(|err| panic!("{err}"));
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("open manifest failed");
+        // drop the torn tail, later edits are appended after the last complete one
+        if len < buffer.len() {
+            file.set_len(len as u64).expect("truncate manifest failed");
+        }
+        (Manifest { path, file, edits }, metadata)
+    }
+
+    // replay the manifest of a block dir without changing anything, for tools reading a closed
+    // database
+    pub(super) fn read(block_dir: &str) -> Result<Metadata, String> {
+        let path = format!("{block_dir}/{MANIFEST_FILE}");
+        let buffer = fs::read(&path).map_err(|err| format!("{path}: {err}"))?;
+        replay(&buffer).map(|(metadata, _, _)| metadata)
+    }
+
+    fn create(path: &str, metadata: &Metadata) -> Manifest {
+        let mut payload = Vec::new();
+        metadata.serialize(&mut payload);
+        let temp_path = format!("{path}.tmp");
+        let mut file = File::create(&temp_path).expect("create manifest failed");
+        file.write_all(&encode_record(metadata.version, RECORD_SNAPSHOT, &payload))
+            .expect("write manifest failed");
+        file.sync_all().expect("sync manifest failed");
+        fs::rename(&temp_path, path).expect("replace manifest failed");
+        if let Some((dir, _)) = path.rsplit_once('/') {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .expect("sync block dir failed");
+        }
+        Manifest {
+            path: path.to_string(),
+            file: OpenOptions::new()
+                .append(true)
+                .open(path)
+                .expect("open manifest failed"),
+            edits: 0,
+        }
+    }
+
+    // append an edit and apply it to the metadata, the manifest is snapshotted once it holds
+    // enough edits
+    pub(super) fn log(&mut self, metadata: &mut Metadata, edit: Edit) {
+        let mut payload = Vec::new();
+        let record_type = encode_edit(&edit, &mut payload);
+        self.append(metadata, record_type, &payload, &[edit]);
+    }
+
+    // append edits as one record, either all of them or none are replayed
+    pub(super) fn log_batch(&mut self, metadata: &mut Metadata, edits: Vec<Edit>) {
+        let mut payload = (edits.len() as u64).varint_encode();
+        for edit in edits.iter() {
+            let at = payload.len();
+            payload.push(0);
+            payload[at] = encode_edit(edit, &mut payload);
+        }
+        self.append(metadata, RECORD_EDITS, &payload, &edits);
+    }
+
+    fn append(&mut self, metadata: &mut Metadata, record_type: u8, payload: &[u8], edits: &[Edit]) {
+        let version = metadata.version + 1;
+        let record = encode_record(version, record_type, payload);
+        self.file.write_all(&record).expect("write manifest failed");
+        self.file.sync_data().expect("sync manifest failed");
+        for edit in edits {
+            metadata.apply(edit);
+        }
+        metadata.version = version;
+
+        self.edits += 1;
+        if self.edits >= SNAPSHOT_EDITS {
+            *self = Manifest::create(&self.path, metadata);
+        }
+    }
+}
+
+// append the payload of an edit, its record type is returned
+// the metadata of a manifest, the number of edits after its snapshot and the length of the
+// complete records; a torn tail is not counted
+fn replay(buffer: &[u8]) -> Result<(Metadata, usize, usize), String> {
+    let mut records = Records { buffer, offset: 0 };
+    let mut metadata = match records.next() {
+        Some((version, RECORD_SNAPSHOT, payload)) => {
+            let mut metadata = Metadata::deserial(payload, &mut 0);
+            metadata.version = version;
+            metadata
+        }
+        _ => return Err("manifest corrupted, it does not start with a snapshot".to_string()),
+    };
+    let mut edits = 0;
+    for (version, record_type, payload) in records.by_ref() {
+        let mut offset = 0;
+        let batch: Vec<Edit> = match record_type {
+            RECORD_EDITS => {
+                let edits_num = read_u64(payload, &mut offset);
+                (0..edits_num)
+                    .map(|_| {
+                        offset += 1;
+                        decode_edit(payload[offset - 1], payload, &mut offset)
+                    })
+                    .collect()
+            }
+            _ => vec![decode_edit(record_type, payload, &mut offset)],
+        };
+        if version <= metadata.version {
+            return Err("manifest corrupted, edits out of order".to_string());
+        }
+        for edit in batch.iter() {
+            metadata.apply(edit);
+        }
+        metadata.version = version;
+        edits += 1;
+    }
+    Ok((metadata, edits, records.offset))
+}
+
+fn encode_edit(edit: &Edit, payload: &mut Vec<u8>) -> u8 {
+    match edit {
+        Edit::AddBlock(block) => {
+            block.serialize(payload);
+            RECORD_ADD_BLOCK
+        }
+        Edit::RemoveBlock(id) => {
+            payload.extend(id.varint_encode());
+            RECORD_REMOVE_BLOCK
+        }
+        Edit::DeleteRange(tombstone) => {
+            tombstone.serialize(payload);
+            RECORD_DELETE_RANGE
+        }
+        Edit::RemoveRange(tombstone) => {
+            tombstone.serialize(payload);
+            RECORD_REMOVE_RANGE
+        }
+    }
+}
+
+fn decode_edit(record_type: u8, payload: &[u8], offset: &mut usize) -> Edit {
+    match record_type {
+        RECORD_ADD_BLOCK => Edit::AddBlock(BlockMeta::deserial(payload, offset)),
+        RECORD_REMOVE_BLOCK => Edit::RemoveBlock(read_u64(payload, offset)),
+        RECORD_DELETE_RANGE => Edit::DeleteRange(RangeTombstone::deserial(payload, offset)),
+        RECORD_REMOVE_RANGE => Edit::RemoveRange(RangeTombstone::deserial(payload, offset)),
+        _ => panic!("manifest corrupted, unknown record type {record_type}"),
+    }
+}
+
+fn encode_record(version: u64, record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut body = version.varint_encode();
+    body.push(record_type);
+    body.extend_from_slice(payload);
+    let mut record = vec![0u8; 4];
+    record.extend_from_slice(&u32::to_le_bytes(body.len() as u32));
+    record.extend_from_slice(&body);
+    let checksum = crc32(&record[4..]);
+    record[0..4].clone_from_slice(&u32::to_le_bytes(checksum));
+    record
+}
+
+// complete records of a manifest, offset is the end of the last one
+struct Records<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+// Iterator[#TODO] (should add some comments)
+impl<'a> Iterator for Records<'a> {
+    // version, type and payload
+    type Item = (u64, u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.buffer[self.offset..];
+        if rest.len() < RECORD_HEAD_SIZE {
+            return None;
+        }
+        let checksum = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        if size < 2 || rest.len() < RECORD_HEAD_SIZE + size {
+            return None;
+        }
+        if checksum != crc32(&rest[4..RECORD_HEAD_SIZE + size]) {
+            return None;
+        }
+        let body = &rest[RECORD_HEAD_SIZE..RECORD_HEAD_SIZE + size];
+        self.offset += RECORD_HEAD_SIZE + size;
+        let mut offset = 0;
+        let version = read_u64(body, &mut offset);
+        Some((version, body[offset], &body[offset + 1..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp_dir;
+
+    fn block(id: u64, seq: u64) -> BlockMeta {
+        let mut block = BlockMeta::new(id);
+        block.add_chunk(
+            &(id * 100).varint_encode(),
+            &(id * 100 + 99).varint_encode(),
+            100,
+            1000,
+            seq,
+        );
+        block.file_size = 4096;
+        block
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = temp_dir("manifest-replay");
+        fs::create_dir_all(&dir).unwrap();
+        let (mut manifest, mut metadata) = Manifest::open_or_create(&dir);
+        assert_eq!(metadata, Metadata::new());
+        for id in 0..10 {
+            manifest.log(&mut metadata, Edit::AddBlock(block(id, id)));
+        }
+        manifest.log(&mut metadata, Edit::RemoveBlock(3));
+        drop(manifest);
+
+        let (mut manifest, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(replayed, metadata);
+        assert_eq!(replayed.version, 11);
+        assert_eq!(replayed.next_block_id, 10);
+        assert_eq!(replayed.next_seq, 10);
+        assert!(replayed.block(3).is_none());
+        assert_eq!(manifest.edits, 11);
+
+        // a torn append is dropped, later edits go after the last complete one
+        let path = format!("{dir}/{MANIFEST_FILE}");
+        let size = fs::metadata(&path).unwrap().len();
+        manifest.log(&mut metadata, Edit::AddBlock(block(10, 10)));
+        drop(manifest);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(size + 5).unwrap();
+        drop(file);
+        let (mut manifest, mut replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert!(replayed.block(10).is_none());
+        manifest.log(&mut replayed, Edit::AddBlock(block(11, 11)));
+        drop(manifest);
+        let (_, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(replayed.version, 12);
+        assert_eq!(replayed.block(11), Some(&block(11, 11)));
+
+        // range tombstones are logged like blocks
+        let (mut manifest, mut metadata) = Manifest::open_or_create(&dir);
+        let tombstone = RangeTombstone {
+            start: 50,
+            end: 250,
+            seq: 12,
+        };
+        // tombstones of one chunk share its seq
+        let removed = RangeTombstone {
+            start: 300,
+            ..tombstone
+        };
+        manifest.log(&mut metadata, Edit::DeleteRange(tombstone));
+        manifest.log(&mut metadata, Edit::DeleteRange(removed));
+        manifest.log(&mut metadata, Edit::RemoveRange(removed));
+        drop(manifest);
+        let (_, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(replayed, metadata);
+        assert_eq!(replayed.range_tombstones, vec![tombstone]);
+        assert_eq!(replayed.next_seq, 13);
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = temp_dir("manifest-batch");
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{dir}/{MANIFEST_FILE}");
+        let (mut manifest, mut metadata) = Manifest::open_or_create(&dir);
+        for id in 0..3 {
+            manifest.log(&mut metadata, Edit::AddBlock(block(id, id)));
+        }
+        let size = fs::metadata(&path).unwrap().len();
+        let mut merged = block(3, 2);
+        merged.min_seq = 0;
+        merged.level = 1;
+        let edits = vec![
+            Edit::AddBlock(merged.clone()),
+            Edit::RemoveBlock(0),
+            Edit::RemoveBlock(1),
+            Edit::RemoveBlock(2),
+        ];
+        manifest.log_batch(&mut metadata, edits);
+        assert_eq!(metadata.version, 4);
+        assert_eq!(metadata.levels[1], vec![merged.clone()]);
+        assert_eq!(metadata.blocks().count(), 1);
+        drop(manifest);
+
+        let (_, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(replayed, metadata);
+
+        // a torn batch leaves none of its edits
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 1)
+            .unwrap();
+        drop(file);
+        let (_, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(replayed.blocks().count(), 3);
+        assert!(replayed.block(3).is_none());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = temp_dir("manifest-snapshot");
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{dir}/{MANIFEST_FILE}");
+        let (mut manifest, mut metadata) = Manifest::open_or_create(&dir);
+        // the same few blocks updated over and over
+        for i in 0..SNAPSHOT_EDITS as u64 * 2 + 10 {
+            manifest.log(&mut metadata, Edit::AddBlock(block(i % 4, i)));
+        }
+        assert_eq!(manifest.edits, 10);
+        let size = fs::metadata(&path).unwrap().len() as usize;
+        assert!(size < 20 * encode_record(0, RECORD_ADD_BLOCK, &[0; 32]).len());
+        assert!(fs::metadata(format!("{path}.tmp")).is_err());
+        drop(manifest);
+
+        let (_, replayed) = Manifest::open_or_create(&dir);
+        assert_eq!(replayed, metadata);
+        assert_eq!(replayed.blocks().count(), 4);
+        assert_eq!(replayed.next_seq, SNAPSHOT_EDITS as u64 * 2 + 10);
+
+        // a flipped byte in the snapshot is not taken for a valid manifest
+        let mut buffer = fs::read(&path).unwrap();
+        buffer[RECORD_HEAD_SIZE + 2] ^= 0x10;
+        fs::write(&path, buffer).unwrap();
+        let result = std::panic::catch_unwind(|| Manifest::open_or_create(&dir));
+        assert!(result.is_err());
+    }
+}
@@ -1,192 +1,612 @@
 use crate::bytes::{self, VarintCodec};
-use std::usize;
 
-// BlocksMeta[#TODO] (shoule add some comments )
 type Key = Vec<u8>;
-type Value = Vec<u8>;
 
-// 用medata文件来记录所有block信息,以及每个block 第一个key
+// blocks are organized in levels 0..NUM_LEVELS
+pub(crate) const NUM_LEVELS: usize = 7;
+
+// 记录所有block的信息, 由manifest里面的edit回放得到, 见manifest.rs
 // 可以根据查询的key来快速定位存储在哪个block里面
 // 每一个block信息是一个B+树, leaf节点存储了chunk
-#[derive(Debug)]
+//
+// block分层存放: memtable flush出来的block都在level 0, key范围可能互相重叠;
+// compaction把level 0合并进level 1, 某一层超过它的大小之后再把它的block合并进下一层,
+// level 1 以及更深的每一层里面block的key范围互不重叠. 同一个key, 浅层的版本总是比深层的新
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(super) struct Metadata {
+    // version of the last edit applied
+    pub version: u64,
     // the max blocked id
     pub next_block_id: u64,
-    // 每个block及其对应的第一个key, 方便二分查询快速定位到某一个具体block去执行查询
-    pub indices: Vec<(Key, Value)>,
+    // sequence number of the next chunk written into a block
+    pub next_seq: u64,
+    // 每一层的block按照min_key排序, 方便二分查询快速定位到某一个具体block去执行查询
+    pub levels: Vec<Vec<BlockMeta>>,
+    // reach[i] 是 levels[0][..=i] 里面最大的max_key, 向前查找覆盖某个key的block时用来提前结束
+    reach: Vec<Key>,
+    // delete_range 留下的范围删除记录, 它隐藏了比它旧的block里面落在范围内的key
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+// keys in [start, end) are deleted from every chunk older than seq. The tombstone is written with
+// the chunk of the memtable it was deleted in, which has this seq: its entries and the chunks
+// written after it are not hidden
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RangeTombstone {
+    pub start: u64,
+    pub end: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct BlockMeta {
+    pub id: u64,
+    // smallest and biggest key of all chunks, varint encoded
+    pub min_key: Key,
+    pub max_key: Key,
+    // key values of all chunks
+    pub entries: u64,
+    // chunk bytes before encoding, and the size of the block file when it was last flushed
+    pub raw_size: u64,
+    pub file_size: u64,
+    // sequence numbers of the first and the last chunk written into the block
+    pub min_seq: u64,
+    pub max_seq: u64,
+    // blocks written from the memtable are in level 0, compaction moves them deeper
+    pub level: usize,
+}
+
+// an edit of the blocks, it's logged into the manifest before it's applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Edit {
+    // a new block, or new stats of a block replacing the ones added before
+    AddBlock(BlockMeta),
+    RemoveBlock(u64),
+    DeleteRange(RangeTombstone),
+    // the range tombstone hides no block anymore, several ones may share a seq
+    RemoveRange(RangeTombstone),
+}
+
+// BlockMeta[#TODO] (should add some comments)
+impl BlockMeta {
+    pub(super) fn new(id: u64) -> Self {
+        BlockMeta {
+            id,
+            ..BlockMeta::default()
+        }
+    }
+
+    // a chunk holding keys in [first, last] was written into the block
+    pub(super) fn add_chunk(
+        &mut self,
+        first: &[u8],
+        last: &[u8],
+        entries: u64,
+        raw_size: u64,
+        seq: u64,
+    ) {
+        if self.entries == 0 || bytes::compare(first, &self.min_key).is_lt() {
+            self.min_key = first.to_vec();
+        }
+        if self.entries == 0 || bytes::compare(last, &self.max_key).is_gt() {
+            self.max_key = last.to_vec();
+        }
+        if self.entries == 0 {
+            self.min_seq = seq;
+        }
+        self.max_seq = seq;
+        self.entries += entries;
+        self.raw_size += raw_size;
+    }
 }
 
 // Meta[#TODO] (should add some comments)
 impl Metadata {
     pub(super) fn new() -> Self {
-        Self {
+        Metadata {
+            version: 0,
             next_block_id: 0,
-            indices: Vec::new(),
+            next_seq: 0,
+            levels: vec![Vec::new(); NUM_LEVELS],
+            reach: Vec::new(),
+            range_tombstones: Vec::new(),
         }
     }
-}
-// metadata format
-//  默认用一个4096页面来保存metatadata, key + value 最大限制124B + 4B ,
-//  一个页面最小可以保存30个block, 每个block是一个B+tree, 最为短期存储已经足够了
-// |--------------------------------------------------------------------------------------|
-// |  next_id   |blocks_num|  k1_size | k1  | v1_size | v1 | ............DATA ........................|
-// |--------------------------------------------------------------------------------------|
-// |   8B       |  8B      |   8B     | xB  |  8B     | yB | .........................................|
-// |--------------------------------------------------------------------------------------|
 
-// Meta[#TODO] (should add some comments)
-impl Metadata {
-    pub(super) fn serialize(&self, buffer: &mut [u8]) {
-        let mut offset = 0;
-        buffer[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(self.next_block_id));
-        offset += 8;
+    pub(super) fn apply(&mut self, edit: &Edit) {
+        match edit {
+            Edit::AddBlock(block) => {
+                self.next_block_id = self.next_block_id.max(block.id + 1);
+                if block.entries > 0 {
+                    self.next_seq = self.next_seq.max(block.max_seq + 1);
+                }
+                self.update(block.clone());
+            }
+            Edit::RemoveBlock(id) => {
+                self.remove(*id);
+                self.update_reach();
+            }
+            Edit::DeleteRange(tombstone) => {
+                self.next_seq = self.next_seq.max(tombstone.seq + 1);
+                self.range_tombstones.push(*tombstone);
+            }
+            Edit::RemoveRange(removed) => {
+                self.range_tombstones
+                    .retain(|tombstone| tombstone != removed);
+            }
+        }
+    }
+
+    // insert or replace a block, it may have moved to another level. Blocks of a level stay
+    // sorted by min_key
+    pub(super) fn update(&mut self, block: BlockMeta) {
+        self.remove(block.id);
+        let level = &mut self.levels[block.level];
+        let index =
+            level.partition_point(|other| bytes::compare(&other.min_key, &block.min_key).is_le());
+        level.insert(index, block);
+        self.update_reach();
+    }
 
-        let key_num = self.indices.len();
-        buffer[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(key_num as u64));
-        offset += 8;
+    fn remove(&mut self, id: u64) {
+        for level in self.levels.iter_mut() {
+            level.retain(|block| block.id != id);
+        }
+    }
 
-        for (key, value) in self.indices.iter() {
-            let key_size = key.len();
-            buffer[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(key_size as u64));
-            offset += 8;
+    fn update_reach(&mut self) {
+        self.reach.clear();
+        for block in self.levels[0].iter() {
+            let reach = match self.reach.last() {
+                Some(reach) if bytes::compare(reach, &block.max_key).is_ge() => reach.clone(),
+                _ => block.max_key.clone(),
+            };
+            self.reach.push(reach);
+        }
+    }
 
-            buffer[offset..offset + key_size].clone_from_slice(key);
-            offset += key_size;
+    pub(super) fn block(&self, id: u64) -> Option<&BlockMeta> {
+        self.blocks().find(|block| block.id == id)
+    }
 
-            let value_size = value.len();
-            buffer[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(value_size as u64));
-            offset += 8;
+    // blocks of every level
+    pub(super) fn blocks(&self) -> impl Iterator<Item = &BlockMeta> {
+        self.levels.iter().flatten()
+    }
 
-            buffer[offset..offset + value_size].clone_from_slice(value);
-            offset += value_size;
+    // blocks whose key range covers the key, the newest first. In level 0, only blocks before
+    // the first one starting after the key can cover it, they are checked backwards until none
+    // of the blocks left reaches the key, and sorted by their sequence numbers. Every deeper level
+    // has at most one such block
+    pub(super) fn get(&self, key: &[u8]) -> Vec<&BlockMeta> {
+        let level0 = &self.levels[0];
+        let end = level0.partition_point(|block| bytes::compare(&block.min_key, key).is_le());
+        let mut blocks: Vec<&BlockMeta> = (0..end)
+            .rev()
+            .take_while(|&index| bytes::compare(&self.reach[index], key).is_ge())
+            .map(|index| &level0[index])
+            .filter(|block| block.entries > 0 && bytes::compare(&block.max_key, key).is_ge())
+            .collect();
+        blocks.sort_by_key(|block| std::cmp::Reverse((block.max_seq, block.id)));
+
+        for level in self.levels[1..].iter() {
+            let index = level.partition_point(|block| bytes::compare(&block.min_key, key).is_le());
+            if index > 0 && bytes::compare(&level[index - 1].max_key, key).is_ge() {
+                blocks.push(&level[index - 1]);
+            }
         }
+        blocks
     }
 
-    pub(super) fn deserial(&mut self, buffer: &[u8]) {
-        let mut offset = 0;
-        self.next_block_id = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-        offset += 8;
+    // non-empty blocks whose key range overlaps [start, end], the oldest first: the deepest level
+    // first and level 0 by sequence numbers. A deeper level is a sorted run, the blocks it has in
+    // the range are found by binary search
+    pub(super) fn oldest_first(&self, start: u64, end: u64) -> Vec<&BlockMeta> {
+        let min_key = |block: &BlockMeta| u64::varint_decode(&block.min_key).1;
+        let max_key = |block: &BlockMeta| u64::varint_decode(&block.max_key).1;
+        let mut level0: Vec<&BlockMeta> = self.levels[0]
+            .iter()
+            .filter(|block| min_key(block) <= end && max_key(block) >= start)
+            .collect();
+        level0.sort_by_key(|block| (block.max_seq, block.id));
+        let mut blocks = Vec::new();
+        for level in self.levels[1..].iter().rev() {
+            let first = level.partition_point(|block| max_key(block) < start);
+            blocks.extend(level[first..].iter().take_while(|block| min_key(block) <= end));
+        }
+        blocks.extend(level0);
+        blocks.retain(|block| block.entries > 0);
+        blocks
+    }
 
-        let key_num = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-        offset += 8;
+    // non-empty blocks of a level whose key ranges overlap [min_key, max_key]
+    pub(super) fn overlapping(
+        &self,
+        level: usize,
+        min_key: &[u8],
+        max_key: &[u8],
+    ) -> Vec<&BlockMeta> {
+        self.levels[level]
+            .iter()
+            .filter(|block| {
+                block.entries > 0
+                    && bytes::compare(&block.min_key, max_key).is_le()
+                    && bytes::compare(&block.max_key, min_key).is_ge()
+            })
+            .collect()
+    }
 
-        for _ in 0..key_num {
-            let key_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
+    // non-empty blocks of all levels grouped by overlapping key ranges: a group holds every block
+    // overlapping another block of the group
+    pub(super) fn overlapping_groups(&self) -> Vec<Vec<&BlockMeta>> {
+        let mut blocks: Vec<&BlockMeta> = self.blocks().filter(|block| block.entries > 0).collect();
+        blocks.sort_by(|a, b| bytes::compare(&a.min_key, &b.min_key));
+        let mut groups: Vec<Vec<&BlockMeta>> = Vec::new();
+        let mut reach: &[u8] = &[];
+        for block in blocks {
+            match groups.last_mut() {
+                Some(group) if bytes::compare(&block.min_key, reach).is_le() => group.push(block),
+                _ => groups.push(vec![block]),
+            }
+            if groups.last().unwrap().len() == 1 || bytes::compare(&block.max_key, reach).is_gt() {
+                reach = &block.max_key;
+            }
+        }
+        groups
+    }
 
-            let key: Vec<u8> = buffer[offset..offset + key_size as usize].into();
-            offset += key_size as usize;
+    // the key of a chunk is hidden by a range tombstone written after the chunk. Passing the
+    // max_seq of a block tells whether the key is hidden in all chunks of the block
+    pub(super) fn is_deleted(&self, key: u64, seq: u64) -> bool {
+        self.range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.hides(key, seq))
+    }
 
-            let value_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
+    // range tombstones that no block older than them overlaps anymore, they can be removed
+    pub(super) fn obsolete_range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones
+            .iter()
+            .filter(|tombstone| {
+                !self.blocks().any(|block| {
+                    block.entries > 0
+                        && block.min_seq < tombstone.seq
+                        && u64::varint_decode(&block.min_key).1 < tombstone.end
+                        && u64::varint_decode(&block.max_key).1 >= tombstone.start
+                })
+            })
+            .cloned()
+            .collect()
+    }
 
-            let value: Vec<u8> = buffer[offset..offset + value_size as usize].into();
-            offset += value_size as usize;
-            self.indices.push((key, value));
-        }
+    // file size of all blocks in a level
+    pub(super) fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|block| block.file_size).sum()
     }
+}
 
-    pub(super) fn insert(&mut self, key: &[u8], value: &[u8]) {
-        let mut insert_index = 0;
-        let mut debug = Vec::new();
-        for (_, elem) in self.indices.iter().enumerate() {
-            debug.push(u64::varint_decode(&elem.0).1);
+// encoding of edits in the manifest, every field is a varint and keys are prefixed by their size
+// |-----------------------------------------------------------------------------------------------|
+// | AddBlock    | id | min_key | max_key | entries | raw_size | file_size | min_seq | max_seq | level |
+// | RemoveBlock | id |
+// | DeleteRange | start | end | seq |
+// | RemoveRange | start | end | seq |
+// | Snapshot    | next_block_id | next_seq | blocks_num | block 1 | ... | block n |
+// |             | tombstones_num | tombstone 1 | ... | tombstone n |
+// |-----------------------------------------------------------------------------------------------|
+
+// BlockMeta[#TODO] (should add some comments)
+impl BlockMeta {
+    pub(super) fn serialize(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.id.varint_encode());
+        for key in [&self.min_key, &self.max_key] {
+            buffer.extend((key.len() as u64).varint_encode());
+            buffer.extend_from_slice(key);
         }
-        let mut found = false;
-        for (idx, elem) in self.indices.iter().enumerate() {
-            match bytes::compare(&elem.0, key) {
-                std::cmp::Ordering::Less => {}
-                std::cmp::Ordering::Equal => {
-                    found = true;
-                    insert_index = idx;
-                    break;
-                }
-                std::cmp::Ordering::Greater => {
-                    found = false;
-                    insert_index = idx;
-                    break;
-                }
-            }
+        for value in [
+            self.entries,
+            self.raw_size,
+            self.file_size,
+            self.min_seq,
+            self.max_seq,
+            self.level as u64,
+        ] {
+            buffer.extend(value.varint_encode());
         }
-        if !found && insert_index == 0 {
-            self.indices.push((key.into(), value.into()));
-        } else {
-            self.indices
-                .insert(insert_index, (key.into(), value.into()))
+    }
+
+    pub(super) fn deserial(buffer: &[u8], offset: &mut usize) -> Self {
+        let mut block = BlockMeta::new(read_u64(buffer, offset));
+        block.min_key = read_key(buffer, offset);
+        block.max_key = read_key(buffer, offset);
+        block.entries = read_u64(buffer, offset);
+        block.raw_size = read_u64(buffer, offset);
+        block.file_size = read_u64(buffer, offset);
+        block.min_seq = read_u64(buffer, offset);
+        block.max_seq = read_u64(buffer, offset);
+        block.level = read_u64(buffer, offset) as usize;
+        block
+    }
+}
+
+// Meta[#TODO] (should add some comments)
+impl Metadata {
+    // the whole metadata but the version, which is stored with every record of the manifest
+    pub(super) fn serialize(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.next_block_id.varint_encode());
+        buffer.extend(self.next_seq.varint_encode());
+        buffer.extend((self.blocks().count() as u64).varint_encode());
+        for block in self.blocks() {
+            block.serialize(buffer);
+        }
+        buffer.extend((self.range_tombstones.len() as u64).varint_encode());
+        for tombstone in self.range_tombstones.iter() {
+            tombstone.serialize(buffer);
         }
     }
 
-    pub(super) fn get(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
-        let mut index = 0;
-        for (idx, elem) in self.indices.iter().enumerate() {
-            match bytes::compare(&elem.0, key) {
-                std::cmp::Ordering::Less => index = idx,
-                std::cmp::Ordering::Equal => break,
-                std::cmp::Ordering::Greater => break,
+    pub(super) fn deserial(buffer: &[u8], offset: &mut usize) -> Self {
+        let mut metadata = Metadata::new();
+        metadata.next_block_id = read_u64(buffer, offset);
+        metadata.next_seq = read_u64(buffer, offset);
+        let blocks_num = read_u64(buffer, offset);
+        // blocks are serialized level by level, each level sorted
+        for _ in 0..blocks_num {
+            let block = BlockMeta::deserial(buffer, offset);
+            metadata.levels[block.level].push(block);
+        }
+        metadata.update_reach();
+        // snapshots written before range deletes end here
+        if *offset < buffer.len() {
+            let tombstones_num = read_u64(buffer, offset);
+            for _ in 0..tombstones_num {
+                let tombstone = RangeTombstone::deserial(buffer, offset);
+                metadata.range_tombstones.push(tombstone);
             }
         }
-        if index == self.indices.len() {
-            None
-        } else {
-            Some(self.indices[index].clone())
+        metadata
+    }
+}
+
+// RangeTombstone[#TODO] (should add some comments)
+impl RangeTombstone {
+    pub(super) fn hides(&self, key: u64, seq: u64) -> bool {
+        self.seq > seq && self.start <= key && key < self.end
+    }
+
+    pub(super) fn serialize(&self, buffer: &mut Vec<u8>) {
+        for value in [self.start, self.end, self.seq] {
+            buffer.extend(value.varint_encode());
         }
     }
+
+    pub(super) fn deserial(buffer: &[u8], offset: &mut usize) -> Self {
+        RangeTombstone {
+            start: read_u64(buffer, offset),
+            end: read_u64(buffer, offset),
+            seq: read_u64(buffer, offset),
+        }
+    }
+}
+
+pub(super) fn read_u64(buffer: &[u8], offset: &mut usize) -> u64 {
+    let (size, value) = u64::varint_decode(&buffer[*offset..]);
+    *offset += size;
+    value
+}
+
+fn read_key(buffer: &[u8], offset: &mut usize) -> Key {
+    let size = read_u64(buffer, offset) as usize;
+    let key = buffer[*offset..*offset + size].to_vec();
+    *offset += size;
+    key
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn block(id: u64, min: u64, max: u64, seq: u64) -> BlockMeta {
+        let mut block = BlockMeta::new(id);
+        block.add_chunk(
+            &min.varint_encode(),
+            &max.varint_encode(),
+            max - min + 1,
+            100,
+            seq,
+        );
+        block
+    }
+
+    // ids of the blocks covering the key
+    fn ids(metadata: &Metadata, key: u64) -> Vec<u64> {
+        metadata
+            .get(&key.varint_encode())
+            .iter()
+            .map(|block| block.id)
+            .collect()
+    }
+
     #[test]
     fn test_new() {
         let metadata = Metadata::new();
         assert_eq!(metadata.next_block_id, 0);
-        assert!(metadata.indices.is_empty());
+        assert_eq!(metadata.blocks().count(), 0);
+        assert_eq!(metadata.levels.len(), NUM_LEVELS);
     }
 
     #[test]
-    fn test_insert() {
+    fn test_apply() {
         let mut metadata = Metadata::new();
-        let key = b"key1".to_vec();
-        let value = b"value1".to_vec();
+        metadata.apply(&Edit::AddBlock(block(0, 300, 399, 0)));
+        metadata.apply(&Edit::AddBlock(block(1, 100, 199, 1)));
+        assert_eq!(metadata.next_block_id, 2);
+        assert_eq!(metadata.next_seq, 2);
+        assert_eq!(
+            metadata.blocks().map(|block| block.id).collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert_eq!(ids(&metadata, 150), vec![1]);
+        assert_eq!(ids(&metadata, 300), vec![0]);
+        assert_eq!(ids(&metadata, 399), vec![0]);
+        assert!(ids(&metadata, 250).is_empty());
+        assert!(ids(&metadata, 1000).is_empty());
+        assert!(ids(&metadata, 50).is_empty());
+
+        // overlapping blocks, the newest first
+        metadata.apply(&Edit::AddBlock(block(2, 0, 1000, 5)));
+        metadata.apply(&Edit::AddBlock(block(3, 350, 360, 3)));
+        assert_eq!(ids(&metadata, 355), vec![2, 3, 0]);
+        assert_eq!(ids(&metadata, 150), vec![2, 1]);
+        assert_eq!(ids(&metadata, 1000), vec![2]);
+        assert!(ids(&metadata, 1001).is_empty());
+        metadata.apply(&Edit::RemoveBlock(2));
+        metadata.apply(&Edit::RemoveBlock(3));
+        assert!(ids(&metadata, 250).is_empty());
 
-        metadata.insert(&key, &value);
+        // a later edit of the same block replaces it
+        let mut updated = block(1, 100, 199, 1);
+        updated.add_chunk(
+            &500u64.varint_encode(),
+            &599u64.varint_encode(),
+            100,
+            100,
+            2,
+        );
+        metadata.apply(&Edit::AddBlock(updated.clone()));
+        assert_eq!(metadata.blocks().count(), 2);
+        assert_eq!(metadata.block(1), Some(&updated));
+        assert_eq!(updated.max_key, 599u64.varint_encode());
+        assert_eq!(
+            (updated.min_seq, updated.max_seq, updated.entries),
+            (1, 2, 200)
+        );
+        assert_eq!(metadata.next_seq, 6);
 
-        assert_eq!(metadata.indices.len(), 1);
-        assert_eq!(metadata.indices[0].0, key);
-        assert_eq!(metadata.indices[0].1, value);
+        metadata.apply(&Edit::RemoveBlock(0));
+        assert_eq!(metadata.levels[0], vec![updated]);
+        assert_eq!(ids(&metadata, 550), vec![1]);
+        // ids of removed blocks are not reused
+        assert_eq!(metadata.next_block_id, 4);
     }
 
     #[test]
     fn test_serialize_and_deserial() {
         let mut metadata = Metadata::new();
-        metadata.next_block_id = 1;
+        metadata.apply(&Edit::AddBlock(block(0, 300, 399, 0)));
+        metadata.apply(&Edit::AddBlock(block(7, 1 << 40, 1 << 41, 1)));
+        metadata.apply(&Edit::AddBlock(BlockMeta::new(9)));
+        let mut deeper = block(10, 0, 99, 2);
+        deeper.level = 3;
+        metadata.apply(&Edit::AddBlock(deeper));
+        metadata.apply(&Edit::DeleteRange(RangeTombstone {
+            start: 10,
+            end: 20,
+            seq: 3,
+        }));
 
-        let key1 = b"key1".to_vec();
-        let value1 = b"value1".to_vec();
-        let key2 = b"key2".to_vec();
-        let value2 = b"value2".to_vec();
+        let mut buffer = Vec::new();
+        metadata.serialize(&mut buffer);
+        let mut offset = 0;
+        let deserialized = Metadata::deserial(&buffer, &mut offset);
+        assert_eq!(offset, buffer.len());
+        assert_eq!(deserialized, metadata);
+    }
 
-        metadata.insert(&key1, &value1);
-        metadata.insert(&key2, &value2);
+    #[test]
+    fn test_levels() {
+        let mut metadata = Metadata::new();
+        let leveled = |id, min, max, seq, level| {
+            let mut block = block(id, min, max, seq);
+            block.level = level;
+            Edit::AddBlock(block)
+        };
+        // level 0 overlaps everything, level 1 and 2 are sorted runs
+        metadata.apply(&leveled(0, 0, 99, 0, 2));
+        metadata.apply(&leveled(1, 100, 199, 1, 2));
+        metadata.apply(&leveled(2, 50, 149, 2, 1));
+        metadata.apply(&leveled(3, 150, 250, 3, 1));
+        metadata.apply(&leveled(4, 120, 130, 5, 0));
+        metadata.apply(&leveled(5, 0, 1000, 4, 0));
+        // level 0 by sequence numbers, then one block of every deeper level
+        assert_eq!(ids(&metadata, 125), vec![4, 5, 2, 1]);
+        assert_eq!(ids(&metadata, 149), vec![5, 2, 1]);
+        assert_eq!(ids(&metadata, 150), vec![5, 3, 1]);
+        assert_eq!(ids(&metadata, 10), vec![5, 0]);
+        assert_eq!(ids(&metadata, 300), vec![5]);
+        let oldest_first = |start, end| -> Vec<u64> {
+            metadata
+                .oldest_first(start, end)
+                .iter()
+                .map(|block| block.id)
+                .collect()
+        };
+        assert_eq!(oldest_first(0, u64::MAX), vec![0, 1, 2, 3, 5, 4]);
+        // only the blocks overlapping a range are scanned
+        assert_eq!(oldest_first(120, 130), vec![1, 2, 5, 4]);
+        assert_eq!(oldest_first(200, 300), vec![3, 5]);
+        assert_eq!(oldest_first(99, 100), vec![0, 1, 2, 5]);
+        assert!(oldest_first(1001, u64::MAX).is_empty());
+        let overlapping = metadata.overlapping(2, &120u64.varint_encode(), &500u64.varint_encode());
+        assert_eq!(overlapping.len(), 1);
+        assert_eq!(overlapping[0].id, 1);
 
-        let mut buffer = vec![0u8; 128]; // 假设我们有足够的空间来序列化metadata
-        metadata.serialize(&mut buffer);
+        // a block moved down a level is found there only
+        metadata.apply(&leveled(3, 150, 250, 3, 2));
+        assert_eq!(metadata.levels[1].len(), 1);
+        assert_eq!(metadata.levels[2].len(), 3);
+        assert_eq!(ids(&metadata, 200), vec![5, 3]);
+        assert_eq!(metadata.level_size(2), 0);
 
-        // 创建一个新的Metadata实例来反序列化
-        let mut deserialized_metadata = Metadata::new();
-        deserialized_metadata.deserial(&buffer);
+        // groups of overlapping blocks across levels
+        let groups = |metadata: &Metadata| -> Vec<Vec<u64>> {
+            metadata
+                .overlapping_groups()
+                .iter()
+                .map(|group| group.iter().map(|block| block.id).collect())
+                .collect()
+        };
+        assert_eq!(groups(&metadata), vec![vec![5, 0, 2, 1, 4, 3]]);
+        metadata.apply(&Edit::RemoveBlock(5));
+        assert_eq!(groups(&metadata), vec![vec![0, 2, 1, 4, 3]]);
+        metadata.apply(&Edit::RemoveBlock(2));
+        assert_eq!(groups(&metadata), vec![vec![0], vec![1, 4, 3]]);
+    }
 
-        // 验证反序列化后的Metadata是否与原始的一致
-        assert_eq!(deserialized_metadata.next_block_id, metadata.next_block_id);
-        assert_eq!(deserialized_metadata.indices.len(), metadata.indices.len());
+    #[test]
+    fn test_range_tombstones() {
+        let mut metadata = Metadata::new();
+        metadata.apply(&Edit::AddBlock(block(0, 0, 99, 0)));
+        metadata.apply(&Edit::AddBlock(block(1, 100, 199, 1)));
+        let tombstone = RangeTombstone {
+            start: 50,
+            end: 100,
+            seq: 2,
+        };
+        let kept = RangeTombstone {
+            start: 150,
+            end: 160,
+            seq: 2,
+        };
+        metadata.apply(&Edit::DeleteRange(tombstone));
+        metadata.apply(&Edit::DeleteRange(kept));
+        assert_eq!(metadata.next_seq, 3);
+        metadata.apply(&Edit::AddBlock(block(2, 0, 199, 2)));
 
-        for (idx, (deserialized_key, deserialized_value)) in
-            deserialized_metadata.indices.iter().enumerate()
-        {
-            let (key, value) = &metadata.indices[idx];
-            assert_eq!(deserialized_key, key);
-            assert_eq!(deserialized_value, value);
-        }
+        assert!(metadata.is_deleted(50, 0));
+        assert!(metadata.is_deleted(99, 0));
+        assert!(!metadata.is_deleted(49, 0));
+        assert!(!metadata.is_deleted(100, 0));
+        assert!(metadata.is_deleted(155, 1));
+        // the chunk of the delete and the chunks written after it are not hidden
+        assert!(!metadata.is_deleted(60, 2));
+        assert!(!metadata.is_deleted(155, 3));
+
+        // the tombstone is needed as long as an older block overlaps it
+        assert!(metadata.obsolete_range_tombstones().is_empty());
+        metadata.apply(&Edit::RemoveBlock(0));
+        assert_eq!(metadata.obsolete_range_tombstones(), vec![tombstone]);
+        metadata.apply(&Edit::RemoveRange(tombstone));
+        assert_eq!(metadata.range_tombstones, vec![kept]);
     }
 }
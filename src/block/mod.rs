@@ -1,18 +1,47 @@
+mod bloom;
+mod cache;
+mod compaction;
 mod encoder;
+mod manifest;
 mod meta;
+mod snapshot;
 /* mod varint; */
 
 // block is ask sstable
 
-use std::fs::{self, File, OpenOptions};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::FileExt;
-use std::{u8, usize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 
-use crate::btree::BTree;
-use crate::bytes;
+use crate::btree::buffer::BufferPool;
+use crate::btree::builder::BTreeBuilder;
+use crate::btree::concurrent::BTreeReader;
+use crate::btree::error::Error as BTreeError;
+use crate::btree::node::KeyValue;
+use crate::btree::{BTree, BTreeOptions};
+use crate::bytes::{self, VarintCodec};
 use crate::chunk::Chunk;
+use crate::db::DBOptions;
 use crate::errors::Error;
+use crate::stats::Stats;
+
+use self::bloom::BloomFilter;
+use self::cache::TableCache;
+use self::compaction::{Compaction, RateLimiter, Task, COMPACTION_FILE_PREFIX};
+use self::encoder::Encoder;
+use self::manifest::Manifest;
+use self::meta::{BlockMeta, Edit, RangeTombstone};
+use self::snapshot::Pins;
+pub use self::encoder::Compression;
+pub(crate) use self::snapshot::BlockSnapshot;
+pub(crate) use self::meta::NUM_LEVELS;
+
+// encoded key and stored entry
+type Entry = (Vec<u8>, Vec<u8>);
 
 // disk file layout
 // blocks
@@ -24,103 +53,659 @@ pub(crate) struct Blocks {
     data_dir: String,
     metadata: meta::Metadata,
     segment: Option<Segment>,
-    metafile: File,
+    // chunks written into the segment since it was last flushed, snapshots read them since they
+    // may not be in its file yet
+    uncommitted: Vec<Rc<Entry>>,
+    // seq of the newest chunk written, and of the newest one in a flushed block: the chunks up
+    // to it are durable, their records can be dropped from the wal
+    written_seq: Option<u64>,
+    durable_seq: Option<u64>,
+    manifest: Manifest,
+    options: DBOptions,
+    pub(crate) stats: RefCell<Stats>,
+    // bloom filters of finished blocks, lazily loaded by block id; None if a block has none. At
+    // most max_open_files of them are kept, like readers
+    filters: RefCell<TableCache<Option<BloomFilter>>>,
+    // opened readers of finished blocks
+    pub(crate) table_cache: RefCell<TableCache<Segment>>,
+    // page cache shared by the btrees of all blocks
+    buffer_pool: Option<Arc<BufferPool>>,
+    // compaction running in the background, at most one at a time
+    compaction: Option<JoinHandle<Result<Compaction, Error>>>,
+    // blocks pinned by snapshots, their files are kept after they're removed
+    pins: Arc<Mutex<Pins>>,
 }
 
 // Blocks[#TODO] (should add some comments)
 impl Blocks {
-    pub(crate) fn open_or_create(root_dir: &str) -> Blocks {
+    pub(crate) fn open_or_create(root_dir: &str, options: &DBOptions) -> Blocks {
         let block_dir = format!("{root_dir}/blocks");
-        let block_meta = format!("{block_dir}/metadata.json");
-        let mut metadata = meta::Metadata::new();
-        let meta_file = match OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(block_meta.as_str())
-        {
-            Ok(mut file_ptr) => {
-                let mut buffer = [0u8; 4096];
-                file_ptr.read_exact(&mut buffer).unwrap();
-                metadata.deserial(buffer.as_slice());
-                file_ptr
-            }
-            Err(err) => {
-                if err.kind() == ErrorKind::NotFound {
-                    let _ = fs::create_dir_all(block_dir.clone());
-                    let meta_file =
-                        File::create_new(block_meta.as_str()).expect("crate new database failed");
-                    let mut buffer = [0u8; 4096];
-                    metadata.serialize(&mut buffer);
-                    meta_file
-                        .write_at(&buffer, 0)
-                        .expect("Persistent Metadata Initial data failed");
-                    meta_file
-                } else {
-                    panic!("open database failed");
-                }
-            }
-        };
+        fs::create_dir_all(&block_dir).expect("crate new database failed");
+        let (manifest, metadata) = Manifest::open_or_create(&block_dir);
 
-        Self {
+        let blocks = Self {
             data_dir: block_dir.clone(),
             metadata,
-            metafile: meta_file,
+            manifest,
             segment: None,
+            uncommitted: Vec::new(),
+            written_seq: None,
+            durable_seq: None,
+            options: options.clone(),
+            stats: RefCell::new(Stats::default()),
+            filters: RefCell::new(TableCache::new(options.max_open_files)),
+            table_cache: RefCell::new(TableCache::new(options.max_open_files)),
+            buffer_pool: (options.page_cache_size > 0)
+                .then(|| Arc::new(BufferPool::new(options.page_cache_size))),
+            compaction: None,
+            pins: Arc::new(Mutex::new(Pins::default())),
+        };
+        blocks.remove_orphans();
+        blocks
+    }
+
+    // files left by a crash: blocks written or compacted away but not logged in the manifest,
+    // and new blocks of a compaction that was not installed
+    fn remove_orphans(&self) {
+        let entries = fs::read_dir(&self.data_dir).expect("read block dir failed");
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if is_orphan(name.to_str().unwrap_or_default(), &self.metadata) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    // a chunk spanning partitions is split, every block holds keys of one partition only. The
+    // range tombstones of the chunk are logged after its entries are written, with the seq of the
+    // chunk: they hide the chunks written before it, see Metadata::is_deleted
+    pub(crate) fn write_block(&mut self, chunk: &Chunk) {
+        match chunk.split(self.options.partition_size) {
+            Some(pieces) => pieces.iter().for_each(|piece| self.write_chunk(piece)),
+            None => self.write_chunk(chunk),
+        }
+        let seq = chunk.seq;
+        self.written_seq = Some(seq);
+        if chunk.range_tombstones.is_empty() {
+            return;
         }
+        let edits = chunk
+            .range_tombstones
+            .iter()
+            .map(|range| {
+                Edit::DeleteRange(RangeTombstone {
+                    start: range.start,
+                    end: range.end,
+                    seq,
+                })
+            })
+            .collect();
+        self.manifest.log_batch(&mut self.metadata, edits);
+        self.remove_range_tombstones();
     }
 
-    pub(crate) fn write_block(&mut self, chunk: Chunk) {
-        let (key, value) = chunk.encode();
-        if self.segment.is_none()
-            || self
-                .segment
-                .as_ref()
-                .unwrap()
-                .is_overflow(key.len() + value.len())
-        {
-            self.rotate(&key);
+    fn write_chunk(&mut self, chunk: &Chunk) {
+        let seq = chunk.seq;
+        let chunk = match EncodedChunk::new(chunk, &self.options) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        let EncodedChunk {
+            first,
+            last,
+            entries,
+            raw_size,
+            ..
+        } = chunk;
+        let mut stats = self.stats.borrow_mut();
+        stats.chunk_raw_bytes += raw_size;
+        stats.chunk_encoded_bytes += chunk.encoded_size;
+        stats.chunk_compressed_bytes += chunk.value.len() as u64;
+        drop(stats);
+
+        // chunks of a block never overlap, a chunk overlapping the block being written goes into
+        // a new block so that the newest entry of a key is told by the sequence numbers of blocks.
+        // So does a chunk of another partition
+        let rotate = match self.segment {
+            Some(ref segment) => {
+                let block = self.metadata.block(segment.id).unwrap();
+                let overlaps = block.entries > 0
+                    && bytes::compare(&first.varint_encode(), &block.max_key).is_le()
+                    && bytes::compare(&last.varint_encode(), &block.min_key).is_ge();
+                let partition = self.options.partition_of(u64::varint_decode(&block.min_key).1);
+                let other_partition =
+                    block.entries > 0 && self.options.partition_of(first) != partition;
+                let size = chunk.key.len() + chunk.value.len();
+                overlaps || other_partition || segment.is_overflow(size)
+            }
+            None => true,
+        };
+        if rotate {
+            self.rotate();
+        }
+        let segment = self.segment.as_mut().unwrap();
+        let entry = Rc::new((chunk.key, chunk.value));
+        self.uncommitted.push(entry.clone());
+        segment.insert(entry);
+        segment.key_hashes.extend(chunk.key_hashes);
+
+        // the stats of a block are logged when it's flushed, a new block is logged with its
+        // first chunk so that it's found after a crash
+        let id = segment.id;
+        let mut block = self.metadata.block(id).cloned().unwrap();
+        self.metadata.next_seq = self.metadata.next_seq.max(seq + 1);
+        let is_new = block.entries == 0;
+        block.add_chunk(&first.varint_encode(), &last.varint_encode(), entries, raw_size, seq);
+        if is_new {
+            self.manifest.log(&mut self.metadata, Edit::AddBlock(block));
+        } else {
+            self.metadata.update(block);
+        }
+        self.maybe_compact();
+    }
+
+    // install the background compaction once it's done, drop the blocks out of retention, and
+    // start the next compaction if a level needs it. Blocks that are only moved down a level are
+    // moved right away
+    fn maybe_compact(&mut self) {
+        if matches!(self.compaction, Some(ref handle) if handle.is_finished()) {
+            // counted in stats, compact() reports the error of the blocks again
+            let _ = self.wait_compaction();
+        }
+        if self.compaction.is_some() {
+            return;
+        }
+        if self.options.retention > 0 {
+            let newest = self
+                .metadata
+                .blocks()
+                .filter(|block| block.entries > 0)
+                .map(|block| u64::varint_decode(&block.max_key).1)
+                .max();
+            if let Some(newest) = newest {
+                // partitions are dropped whole, the one holding the cutoff is kept
+                let cutoff = newest.saturating_sub(self.options.retention);
+                self.expire(match self.options.partition_size {
+                    0 => cutoff,
+                    _ => self.options.partition_of(cutoff),
+                });
+            }
+        }
+        if !self.options.auto_compaction {
+            return;
+        }
+        let active = self.segment.as_ref().map(|segment| segment.id);
+        while let Some(task) = compaction::pick(&self.metadata, active, &self.options, false) {
+            if task.is_move() {
+                self.move_blocks(task);
+                continue;
+            }
+            let options = self.options.clone();
+            self.compaction = Some(compaction::spawn(task, self.data_dir.clone(), options));
+            break;
+        }
+    }
+
+    // a compaction that read a corrupted block leaves its inputs as they are and stops the
+    // background compaction, it would fail again on the same blocks
+    fn wait_compaction(&mut self) -> Result<(), Error> {
+        if let Some(handle) = self.compaction.take() {
+            match handle.join().expect("compaction failed") {
+                Ok(compaction) => self.install(compaction),
+                Err(err) => {
+                    self.stats.borrow_mut().compaction_errors += 1;
+                    self.options.auto_compaction = false;
+                    return Err(err);
+                }
+            }
         }
-        self.segment.as_mut().unwrap().insert(key.clone(), value)
+        Ok(())
     }
 
-    fn rotate(&mut self, key: &[u8]) {
-        let new_sg_file = format!("{}/block-{}", self.data_dir, self.metadata.next_block_id);
+    // merge all of level 0 into level 1 now, and every level over its size limit into the next
+    // one, without rate limiting. The block being written is finished first so that it's merged too
+    pub(crate) fn compact(&mut self) -> Result<(), Error> {
+        self.wait_compaction()?;
+        self.finish_segment();
+        while let Some(task) = compaction::pick(&self.metadata, None, &self.options, true) {
+            if task.is_move() {
+                self.move_blocks(task);
+                continue;
+            }
+            let mut limiter = RateLimiter::new(0);
+            match Compaction::run(task, &self.data_dir, &self.options, &mut limiter) {
+                Ok(compaction) => self.install(compaction),
+                Err(err) => {
+                    self.stats.borrow_mut().compaction_errors += 1;
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the blocks keep their files, only their level changes
+    fn move_blocks(&mut self, task: Task) {
+        let edits = task
+            .inputs
+            .into_iter()
+            .map(|mut block| {
+                block.level = task.level + 1;
+                Edit::AddBlock(block)
+            })
+            .collect::<Vec<_>>();
+        self.stats.borrow_mut().block_moves += edits.len() as u64;
+        self.manifest.log_batch(&mut self.metadata, edits);
+    }
+
+    // seq of the next chunk, chunks of the memtable are numbered from it
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.metadata.next_seq
+    }
+
+    // chunks up to this seq are in flushed blocks, see WalManager::truncate
+    pub(crate) fn durable_seq(&self) -> Option<u64> {
+        self.durable_seq
+    }
+
+    // range tombstones hiding no block anymore, since compaction or retention removed the
+    // blocks older than them, are removed from the manifest
+    fn remove_range_tombstones(&mut self) {
+        let edits: Vec<Edit> = self
+            .metadata
+            .obsolete_range_tombstones()
+            .into_iter()
+            .map(Edit::RemoveRange)
+            .collect();
+        if !edits.is_empty() {
+            self.manifest.log_batch(&mut self.metadata, edits);
+        }
+    }
+
+    // drop every block holding only keys below the cutoff, its file and its manifest entry are
+    // deleted. Returns the number of blocks dropped
+    pub(crate) fn drop_before(&mut self, cutoff: u64) -> usize {
+        // a failed compaction is counted in stats, its inputs are dropped like any block
+        let _ = self.wait_compaction();
+        self.expire(cutoff)
+    }
+
+    // a block is dropped together with every block it overlaps in any level, or not at all, so
+    // that no older entry of a key it holds shows up again. The block being written is kept
+    fn expire(&mut self, cutoff: u64) -> usize {
+        let cutoff = cutoff.varint_encode();
+        let active = self.segment.as_ref().map(|segment| segment.id);
+        let expired: Vec<u64> = self
+            .metadata
+            .overlapping_groups()
+            .into_iter()
+            .filter(|group| {
+                group.iter().all(|block| {
+                    Some(block.id) != active && bytes::compare(&block.max_key, &cutoff).is_lt()
+                })
+            })
+            .flatten()
+            .map(|block| block.id)
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+        let edits = expired.iter().map(|id| Edit::RemoveBlock(*id)).collect();
+        self.manifest.log_batch(&mut self.metadata, edits);
+        for id in expired.iter() {
+            self.remove_block_file(*id);
+        }
+        self.stats.borrow_mut().expired_blocks += expired.len() as u64;
+        self.remove_range_tombstones();
+        expired.len()
+    }
+
+    // partitions of the data in blocks ordered by their keys, the whole key space is one
+    // partition without partitioning
+    pub(crate) fn partitions(&self) -> Vec<Partition> {
+        let mut partitions: BTreeMap<u64, Partition> = BTreeMap::new();
+        for block in self.metadata.blocks().filter(|block| block.entries > 0) {
+            let min_key = u64::varint_decode(&block.min_key).1;
+            let max_key = u64::varint_decode(&block.max_key).1;
+            let start = self.options.partition_of(min_key);
+            let partition = partitions.entry(start).or_insert_with(|| Partition {
+                start,
+                end: match self.options.partition_size {
+                    0 => u64::MAX,
+                    size => start.saturating_add(size),
+                },
+                min_key,
+                max_key,
+                blocks: 0,
+                bytes: 0,
+            });
+            partition.min_key = partition.min_key.min(min_key);
+            partition.max_key = partition.max_key.max(max_key);
+            partition.blocks += 1;
+            partition.bytes += block.file_size;
+        }
+        partitions.into_values().collect()
+    }
+
+    // the new blocks get their ids and replace the input blocks in one manifest record, the
+    // input files are deleted after that
+    fn install(&mut self, compaction: Compaction) {
+        let mut edits = Vec::new();
+        for (path, mut block) in compaction.outputs {
+            block.id = self.metadata.next_block_id;
+            self.metadata.next_block_id += 1;
+            let block_path = self.block_path(block.id);
+            fs::rename(&path, &block_path).expect("install compacted block failed");
+            block.file_size = fs::metadata(&block_path).map_or(0, |meta| meta.len());
+            edits.push(Edit::AddBlock(block));
+        }
+        File::open(&self.data_dir)
+            .and_then(|dir| dir.sync_all())
+            .expect("sync block dir failed");
+        edits.extend(
+            compaction
+                .inputs
+                .iter()
+                .map(|block| Edit::RemoveBlock(block.id)),
+        );
+        self.manifest.log_batch(&mut self.metadata, edits);
+
+        for block in compaction.inputs.iter() {
+            self.remove_block_file(block.id);
+        }
+        self.remove_range_tombstones();
+        let mut stats = self.stats.borrow_mut();
+        stats.compactions += 1;
+        stats.compaction_read_bytes += compaction.read_bytes;
+        stats.compaction_written_bytes += compaction.written_bytes;
+        stats.expired_entries += compaction.expired;
+        let level = &mut stats.levels[compaction.level];
+        level.compactions += 1;
+        level.compaction_read_bytes += compaction.read_bytes;
+        level.compaction_written_bytes += compaction.written_bytes;
+    }
+
+    // the segment being written is flushed when it's dropped
+    fn finish_segment(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            let id = segment.id;
+            drop(segment);
+            self.log_block(id);
+        }
+        self.uncommitted.clear();
+        self.durable_seq = self.written_seq;
+    }
+
+    fn rotate(&mut self) {
+        self.finish_segment();
+        let id = self.metadata.next_block_id;
         self.metadata.next_block_id += 1;
-        let new_segment = Some(Segment::new(new_sg_file.as_str()));
-        // drop previous segment
-        let _ = self.segment.take();
-        self.segment = new_segment;
-        self.metadata.insert(key, new_sg_file.as_bytes());
-        self.write_metadata();
+        self.segment = Some(Segment::new(
+            &self.block_path(id),
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+            self.options.fill_factor,
+            &self.tree_options(),
+        ));
+        self.metadata.update(BlockMeta::new(id));
     }
 
-    pub fn remove(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
-        Err(Error::KeyNotFound)
+    // log the current stats of a block
+    fn log_block(&mut self, id: u64) {
+        let mut block = self.metadata.block(id).cloned().unwrap();
+        block.file_size = fs::metadata(self.block_path(id)).map_or(0, |meta| meta.len());
+        self.manifest.log(&mut self.metadata, Edit::AddBlock(block));
+    }
+
+    #[inline]
+    fn block_path(&self, id: u64) -> String {
+        block_file(&self.data_dir, id)
     }
 
+    // the key ranges of blocks in level 0 may overlap, the newest block holding the key wins and
+    // level 0 is newer than any deeper level. A chunk hidden by a range tombstone ends the lookup,
+    // the chunks after it are older still
     pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
-        if let Some((_, seg_file)) = self.metadata.get(key) {
-            let path = String::from_utf8(seg_file).unwrap();
-            let segment = Segment::reader(path.as_str());
-            segment.search(key)
+        let decoded = u64::varint_decode(key).1;
+        for block in self.metadata.get(key) {
+            if self.metadata.is_deleted(decoded, block.max_seq) {
+                return Err(Error::KeyNotFound);
+            }
+            let path = self.block_path(block.id);
+            let filtered = !self.is_active(&path);
+            if filtered && !self.may_contain(&path, key) {
+                self.stats.borrow_mut().bloom_hits += 1;
+                continue;
+            }
+            match self.with_segment(path.as_str(), |segment| segment.search(key)) {
+                Ok((entry, seq)) if !self.metadata.is_deleted(decoded, seq.min(block.max_seq)) => {
+                    return Ok(entry)
+                }
+                Ok(_) => return Err(Error::KeyNotFound),
+                Err(Error::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+            if filtered {
+                self.stats.borrow_mut().bloom_false_positives += 1;
+            }
+        }
+        Err(Error::KeyNotFound)
+    }
+
+    // check the bloom filter of a finished block, the filter is loaded from the block file once
+    fn may_contain(&self, path: &str, key: &[u8]) -> bool {
+        let load = || {
+            self.reader(path)
+                .btree
+                .read_attachment()
+                .and_then(|data| BloomFilter::decode(&data))
+        };
+        let (filter, _) = self.filters.borrow_mut().get_or_open(block_id(path), load);
+        match filter.as_ref() {
+            Some(filter) => filter.may_contain(key),
+            None => true,
+        }
+    }
+
+    #[inline]
+    fn is_active(&self, path: &str) -> bool {
+        matches!(self.segment, Some(ref segment) if segment.file_name == path)
+    }
+
+    // merge all key-values in the range into result, newer blocks overwrite older ones. Only the
+    // blocks overlapping the range are read, the deepest level first and level 0 last. Keys
+    // hidden by a range tombstone are removed along with the older values merged before
+    pub fn scan(
+        &self,
+        range: &impl RangeBounds<u64>,
+        result: &mut BTreeMap<u64, Vec<u8>>,
+    ) -> Result<(), Error> {
+        let Some((start, end)) = key_bounds(range) else {
+            return Ok(());
+        };
+        for block in self.metadata.oldest_first(start, end) {
+            let path = self.block_path(block.id);
+            let entries = self.with_segment(path.as_str(), |segment| segment.scan(range))?;
+            for (seq, (key, value)) in entries {
+                let key = u64::varint_decode(&key).1;
+                if self.metadata.is_deleted(key, seq.min(block.max_seq)) {
+                    result.remove(&key);
+                } else {
+                    result.insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the segment being written keeps its root in memory until flush, so it must not be reopened
+    fn with_segment<R>(&self, path: &str, f: impl FnOnce(&Segment) -> R) -> R {
+        match self.segment {
+            Some(ref segment) if segment.file_name == path => f(segment),
+            _ => f(&self.reader(path)),
+        }
+    }
+
+    // reader of a finished block, served from the table cache
+    fn reader(&self, path: &str) -> Rc<Segment> {
+        let (reader, hit) = self
+            .table_cache
+            .borrow_mut()
+            .get_or_open(block_id(path), || Segment::reader(path, &self.tree_options()));
+        let mut stats = self.stats.borrow_mut();
+        if hit {
+            stats.table_cache_hits += 1;
         } else {
-            Err(Error::KeyNotFound)
+            stats.table_cache_misses += 1;
+        }
+        reader
+    }
+
+    // the file of a removed block is deleted, unless a snapshot still reads it; it's deleted
+    // with the last snapshot pinning it then
+    fn remove_block_file(&self, id: u64) {
+        self.forget_block(id);
+        if !self.pins.lock().unwrap().defer_removal(id) {
+            let _ = fs::remove_file(self.block_path(id));
         }
     }
 
+    // pin the blocks written so far for a snapshot of the chunks before seq. Snapshots read
+    // through their own readers and not through the page cache, which forgets the pages of a
+    // block once it's removed. The block being written is read with the chunks not flushed yet
+    pub(crate) fn snapshot(&self, seq: u64) -> BlockSnapshot {
+        let tree_options = BTreeOptions {
+            buffer_pool: None,
+            ..self.tree_options()
+        };
+        // sorted for Segment::search_chunks, chunks with the same key keep their order
+        let active = self.segment.as_ref().map(|segment| {
+            let mut uncommitted = self.uncommitted.clone();
+            uncommitted.sort_by(|a, b| bytes::compare(&a.0, &b.0));
+            let reader = segment.shared.clone().expect("block being written is not shared");
+            (segment.id, uncommitted, reader)
+        });
+        BlockSnapshot::new(
+            self.metadata.clone(),
+            seq,
+            active,
+            self.data_dir.clone(),
+            tree_options,
+            self.options.max_open_files,
+            self.pins.clone(),
+        )
+    }
+
+    // drop everything cached for a block, must be called before its file is deleted or
+    // replaced by compaction
+    pub(crate) fn forget_block(&self, id: u64) {
+        self.table_cache.borrow_mut().evict(id);
+        self.filters.borrow_mut().evict(id);
+        if let Some(ref pool) = self.buffer_pool {
+            pool.purge_file(id);
+        }
+    }
+
+    #[inline]
+    fn tree_options(&self) -> BTreeOptions {
+        BTreeOptions {
+            page_size: self.options.page_size,
+            buffer_pool: self.buffer_pool.clone(),
+            mmap: self.options.mmap_blocks,
+            ..BTreeOptions::default()
+        }
+    }
+
+    // runtime stats merged with the page cache counters and the blocks of every level
+    pub(crate) fn stats(&self) -> Stats {
+        let mut stats = *self.stats.borrow();
+        for (level, blocks) in self.metadata.levels.iter().enumerate() {
+            stats.levels[level].blocks = blocks.len() as u64;
+            stats.levels[level].bytes = self.metadata.level_size(level);
+        }
+        let pins = self.pins.lock().unwrap();
+        stats.snapshots = pins.snapshots() as u64;
+        stats.snapshot_kept_blocks = pins.kept_blocks() as u64;
+        if let Some(ref pool) = self.buffer_pool {
+            let pool_stats = pool.stats();
+            stats.page_cache_hits = pool_stats.hits;
+            stats.page_cache_misses = pool_stats.misses;
+            stats.page_cache_write_backs = pool_stats.write_backs;
+        }
+        stats
+    }
+
     pub fn flush(&mut self) {
         if let Some(ref mut segment) = self.segment {
+            segment.write_filter();
             segment.flush();
+            let id = segment.id;
+            self.log_block(id);
         }
+        self.uncommitted.clear();
+        self.durable_seq = self.written_seq;
+        self.maybe_compact();
     }
+}
 
-    #[inline]
-    fn write_metadata(&self) {
-        let mut buffer = [0u8; 4096];
-        self.metadata.serialize(&mut buffer);
-        self.metafile
-            .write_at(&buffer, 0)
-            .expect("Persistent Metadata Initial data failed");
+// Drop[#TODO] (should add some comments)
+impl Drop for Blocks {
+    fn drop(&mut self) {
+        self.finish_segment();
+        let _ = self.wait_compaction();
+    }
+}
+
+// a window of keys (timestamps) and the blocks holding keys in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    // keys of the partition are in [start, end), the end of the last one is u64::MAX
+    pub start: u64,
+    pub end: u64,
+    // the smallest and the biggest key in its blocks
+    pub min_key: u64,
+    pub max_key: u64,
+    pub blocks: usize,
+    // file size of its blocks
+    pub bytes: u64,
+}
+
+// a chunk encoded and compressed as it's stored in a block, the btree key is the first key of
+// the chunk followed by its seq
+struct EncodedChunk {
+    first: u64,
+    last: u64,
+    entries: u64,
+    raw_size: u64,
+    // size before compression
+    encoded_size: u64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    key_hashes: Vec<u64>,
+}
+
+// EncodedChunk[#TODO] (should add some comments)
+impl EncodedChunk {
+    // None for an empty chunk
+    fn new(chunk: &Chunk, options: &DBOptions) -> Option<Self> {
+        let (first, last) = chunk.key_range()?;
+        let encoding = options.value_encoding_for(first, last);
+        let entries = chunk.key_nums as u64;
+        let raw_size = Chunk::raw_size(chunk.key_nums, chunk.used_size) as u64;
+        let key_hashes = chunk.keys().iter().map(|key| bloom::key_hash(key)).collect();
+        let seq = chunk.seq;
+        let (mut key, value) = chunk.encode(encoding);
+        key.extend(seq.to_le_bytes());
+        Some(EncodedChunk {
+            first,
+            last,
+            entries,
+            raw_size,
+            encoded_size: value.len() as u64,
+            key,
+            value: Encoder::encode(options.compression, &value),
+            key_hashes,
+        })
     }
 }
 
@@ -130,49 +715,230 @@ pub(crate) struct Segment {
     btree: BTree,
     used_size: usize,
     max_segment_size: usize,
-    id: usize, // id according a filename 按照时间
+    id: u64, // id according a filename 按照时间
+    // hash of every key written into this segment, used to build the bloom filter
+    key_hashes: Vec<u64>,
+    // 0 means the segment is read only or bloom filter is disabled
+    bits_per_key: usize,
+    filter_dirty: bool,
+    // chunks written into an empty tree, bulk loaded into it on flush instead of being inserted
+    // one by one; later chunks are inserted directly. Kept sorted by key, a chunk is after the
+    // earlier ones with the same key
+    pending: Vec<Rc<Entry>>,
+    fill_factor: f64,
+    // reader of the tree of a block being written, snapshots read the block through it while
+    // it's written, see snapshot.rs
+    shared: Option<BTreeReader>,
 }
 
 // Segment[#TODO] (should add some comments)
 impl Segment {
-    fn reader(path: &str) -> Self {
+    // pages of the block are cached under its id, see Blocks::forget_block
+    fn reader(path: &str, options: &BTreeOptions) -> Self {
+        let options = BTreeOptions {
+            file_id: block_id(path),
+            ..options.clone()
+        };
         Segment {
-            btree: BTree::reader(path),
+            btree: BTree::reader_with_options(path, &options),
             max_segment_size: 4096,
             used_size: 0,
-            id: 0,
+            id: block_id(path),
             file_name: path.into(),
+            key_hashes: Vec::new(),
+            bits_per_key: 0,
+            filter_dirty: false,
+            pending: Vec::new(),
+            fill_factor: 0.0,
+            shared: None,
         }
     }
-    fn new(path: &str) -> Self {
+    fn new(
+        path: &str,
+        max_segment_size: usize,
+        bits_per_key: usize,
+        fill_factor: f64,
+        options: &BTreeOptions,
+    ) -> Self {
+        let options = BTreeOptions {
+            file_id: block_id(path),
+            ..options.clone()
+        };
+        let mut btree = BTree::with_options(path, &options);
+        let shared = Some(btree.share());
         Segment {
-            btree: BTree::new(path),
-            max_segment_size: 4096 * 10,
+            btree,
+            max_segment_size,
             used_size: 0,
-            id: 0,
+            id: block_id(path),
             file_name: path.into(),
+            key_hashes: Vec::new(),
+            bits_per_key,
+            filter_dirty: false,
+            pending: Vec::new(),
+            fill_factor,
+            shared,
         }
     }
 
-    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.btree.insert(&key, &value);
-        self.used_size += key.len() + value.len();
+    fn insert(&mut self, chunk: Rc<Entry>) {
+        self.used_size += chunk.0.len() + chunk.1.len();
+        self.filter_dirty = true;
+        if self.btree.metadata.root == 0 {
+            let idx = self
+                .pending
+                .partition_point(|pending| bytes::compare(&pending.0, &chunk.0).is_le());
+            self.pending.insert(idx, chunk);
+        } else {
+            self.btree.insert(&chunk.0, &chunk.1);
+        }
     }
 
-    fn search(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
-        if let Ok(may_found_stable) = self.btree.fuzz_find(key) {
+    // build the tree from the pending chunks, a chunk written later wins over an earlier one
+    // with the same key
+    fn load_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let mut builder = BTreeBuilder::new(&mut self.btree).with_fill_factor(self.fill_factor);
+        for (idx, chunk) in pending.iter().enumerate() {
+            let replaced = pending
+                .get(idx + 1)
+                .is_some_and(|next| bytes::compare(&next.0, &chunk.0).is_eq());
+            if !replaced {
+                builder.add(&chunk.0, &chunk.1);
+            }
+        }
+        builder.finish();
+    }
+
+    // build the bloom filter of all keys written so far and store it in the block file
+    fn write_filter(&mut self) {
+        if self.bits_per_key == 0 || !self.filter_dirty {
+            return;
+        }
+        let filter = BloomFilter::build(&self.key_hashes, self.bits_per_key);
+        self.btree.write_attachment(&filter.encode());
+        self.filter_dirty = false;
+    }
 
-            let chunks = Chunk::decode(&may_found_stable.value).unwrap();
+    // the entry of the key and the seq of the chunk holding it
+    fn search(&self, key: &[u8]) -> Result<(Vec<u8>, u64), Error> {
+        if !self.pending.is_empty() {
+            // the chunk with the biggest key not greater than the key, the latest one wins
+            return Self::search_chunks(&self.pending, key);
+        }
+        Self::search_found(self.btree.fuzz_find(key), key)
+    }
 
-            for item in chunks.into_iter() {
-                if bytes::compare(item.0.as_ref(), key) == std::cmp::Ordering::Equal {
-                    return Ok(item.1);
+    // search the chunk found by fuzz_find of a tree
+    fn search_found(
+        found: Result<KeyValue, BTreeError>,
+        key: &[u8],
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let may_found_stable = match found {
+            Ok(kv) => kv,
+            Err(BTreeError::Corrupted) => return Err(Error::Corrupted),
+            Err(_) => return Err(Error::KeyNotFound),
+        };
+        Self::search_chunk(&may_found_stable.value, key)
+            .map(|entry| (entry, chunk_seq(&may_found_stable.key)))
+    }
+
+    // search the chunk with the biggest key not greater than the key in chunks sorted by key,
+    // the last of the ones with the same key wins
+    fn search_chunks(chunks: &[Rc<Entry>], key: &[u8]) -> Result<(Vec<u8>, u64), Error> {
+        let idx = chunks.partition_point(|chunk| bytes::compare(&chunk.0, key).is_le());
+        if idx == 0 {
+            return Err(Error::KeyNotFound);
+        }
+        let chunk = &chunks[idx - 1];
+        Self::search_chunk(&chunk.1, key).map(|entry| (entry, chunk_seq(&chunk.0)))
+    }
+
+    fn search_chunk(value: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks = Chunk::decode(&Encoder::decode(value)?)?;
+
+        for item in chunks.into_iter() {
+            if bytes::compare(item.0.as_ref(), key) == std::cmp::Ordering::Equal {
+                return Ok(item.1);
+            }
+        }
+        Err(Error::KeyNotFound)
+    }
+
+    // every entry in key order with the seq of its chunk, one chunk is decoded at a time and read
+    // is called with its size when it's read. A chunk that can't be decoded or a page that can't
+    // be read yields its error in place of its entries
+    fn entries<'a>(
+        &'a self,
+        mut read: impl FnMut(u64) + 'a,
+    ) -> impl Iterator<Item = Result<(u64, Entry), Error>> + 'a {
+        self.chunks(0, u64::MAX).flat_map(move |chunk| match chunk {
+            Ok((key, chunk)) => {
+                read((key.len() + chunk.len()) as u64);
+                chunk_entries(&key, &chunk)
+            }
+            Err(err) => vec![Err(err)],
+        })
+    }
+
+    // the chunks that may hold keys in [start, end] in key order. Chunks of a block don't
+    // overlap: they are the chunk with the biggest key not greater than start, found by
+    // fuzz_find, and the ones after it up to end
+    fn chunks(&self, start: u64, end: u64) -> impl Iterator<Item = Result<Entry, Error>> + '_ {
+        let start_key = start.varint_encode();
+        let tree = match self.btree.fuzz_find(&start_key) {
+            Ok(first) => self.btree.iter_from(&first.key),
+            Err(BTreeError::KeyNotFound | BTreeError::EmptyTree) => {
+                self.btree.iter_from(&start_key)
+            }
+            Err(err) => Err(err),
+        };
+        let (tree, failed) = match tree {
+            Ok(iter) => (Some(iter), None),
+            Err(err) => (None, Some(Err(read_error(err)))),
+        };
+        let tree = tree
+            .into_iter()
+            .flatten()
+            .map(|kv| kv.map(|kv| (kv.key, kv.value)).map_err(read_error))
+            .take_while(move |chunk| before_end(chunk, end));
+        let pending = Self::chunks_in(&self.pending, start, end)
+            .iter()
+            .map(|chunk| Ok(chunk.as_ref().clone()));
+        failed.into_iter().chain(tree).chain(pending)
+    }
+
+    // the chunks sorted by key that may hold keys in [start, end], see Segment::chunks. All the
+    // chunks with the key of the first one are kept, like in entries the last of them wins
+    fn chunks_in(chunks: &[Rc<Entry>], start: u64, end: u64) -> &[Rc<Entry>] {
+        let key = |chunk: &Rc<Entry>| u64::varint_decode(&chunk.0).1;
+        let first = chunks.partition_point(|chunk| key(chunk) <= start);
+        let from = match first.checked_sub(1) {
+            Some(last) => chunks.partition_point(|chunk| key(chunk) < key(&chunks[last])),
+            None => 0,
+        };
+        &chunks[from..chunks.partition_point(|chunk| key(chunk) <= end)]
+    }
+
+    // a chunk that can't be decoded fails the whole scan
+    fn scan(&self, range: &impl RangeBounds<u64>) -> Result<Vec<(u64, Entry)>, Error> {
+        let Some((start, end)) = key_bounds(range) else {
+            return Ok(Vec::new());
+        };
+        let mut result = Vec::new();
+        for chunk in self.chunks(start, end) {
+            let (key, chunk) = chunk?;
+            for item in chunk_entries(&key, &chunk) {
+                let item = item?;
+                if range.contains(&u64::varint_decode(&item.1 .0).1) {
+                    result.push(item);
                 }
             }
-            Err(Error::KeyNotFound)
-        } else {
-            Err(Error::KeyNotFound)
         }
+        Ok(result)
     }
 
     fn is_overflow(&self, size: usize) -> bool {
@@ -180,7 +946,627 @@ impl Segment {
     }
 
     fn flush(&mut self) {
+        self.load_pending();
         self.btree.flush();
     }
 }
+
 // Drop[#TODO] (should add some comments)
+impl Drop for Segment {
+    fn drop(&mut self) {
+        self.load_pending();
+        self.write_filter();
+    }
+}
+
+// the block files of a closed database against its manifest, nothing is changed: the files of
+// the blocks in the manifest, and the files of the block dir it doesn't know, which are left by a
+// crash and removed on next open
+pub(crate) fn manifest_files(root_dir: &str) -> Result<(Vec<String>, Vec<String>), String> {
+    let block_dir = format!("{root_dir}/blocks");
+    let metadata = Manifest::read(&block_dir)?;
+    let mut orphans: Vec<String> = fs::read_dir(&block_dir)
+        .map_err(|err| format!("{block_dir}: {err}"))?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_orphan(name, &metadata))
+        .map(|name| format!("{block_dir}/{name}"))
+        .collect();
+    orphans.sort();
+    let mut ids: Vec<u64> = metadata.blocks().map(|block| block.id).collect();
+    ids.sort();
+    let blocks = ids.into_iter().map(|id| block_file(&block_dir, id)).collect();
+    Ok((blocks, orphans))
+}
+
+fn is_orphan(name: &str, metadata: &meta::Metadata) -> bool {
+    name.starts_with(COMPACTION_FILE_PREFIX)
+        || (name.starts_with("block-") && metadata.block(block_id(name)).is_none())
+}
+
+// block file is named as block-{id}
+#[inline]
+fn block_file(block_dir: &str, id: u64) -> String {
+    format!("{block_dir}/block-{id}")
+}
+
+fn block_id(path: &str) -> u64 {
+    path.rsplit('-').next().and_then(|id| id.parse().ok()).unwrap_or(0)
+}
+
+// the keys of a range as [start, end], None if it holds no key
+fn key_bounds(range: &impl RangeBounds<u64>) -> Option<(u64, u64)> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end,
+        Bound::Excluded(&end) => end.checked_sub(1)?,
+        Bound::Unbounded => u64::MAX,
+    };
+    (start <= end).then_some((start, end))
+}
+
+// whether a chunk of a tree walked from the start of a range may hold keys not after its end,
+// errors are passed on
+fn before_end(chunk: &Result<Entry, Error>, end: u64) -> bool {
+    chunk
+        .as_ref()
+        .map_or(true, |(key, _)| u64::varint_decode(key).1 <= end)
+}
+
+// error of a btree page that can't be read while the tree is walked
+fn read_error(err: BTreeError) -> Error {
+    match err {
+        BTreeError::PageLoadErr => Error::IOError,
+        _ => Error::Corrupted,
+    }
+}
+
+// the entries of an encoded chunk with its seq, or the error decoding it
+fn chunk_entries(key: &[u8], chunk: &[u8]) -> Vec<Result<(u64, Entry), Error>> {
+    let seq = chunk_seq(key);
+    match Encoder::decode(chunk).and_then(|raw| Chunk::decode(&raw)) {
+        Ok(entries) => entries.into_iter().map(|entry| Ok((seq, entry))).collect(),
+        Err(err) => vec![Err(err)],
+    }
+}
+
+// seq of a chunk from its btree key. Chunks written before it was stored have none, they take the
+// max_seq of their block, which is never bigger
+fn chunk_seq(key: &[u8]) -> u64 {
+    let len = u64::varint_decode(key).0;
+    match key.get(len..len + 8) {
+        Some(seq) => u64::from_le_bytes(seq.try_into().unwrap()),
+        None => u64::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::{temp_dir, Lcg};
+
+    // chunks of random, overlapping key ranges are written into blocks of one chunk each, get
+    // must return what a map written in the same order holds
+    #[test]
+    fn test_get_matches_model() {
+        for seed in 0..4u64 {
+            let root_dir = &temp_dir(&format!("blocks-model-{seed}"));
+            let options = DBOptions {
+                page_size: 4096,
+                block_size: 1,
+                ..DBOptions::default()
+            };
+            let mut rng = Lcg(seed);
+            let mut model = BTreeMap::new();
+            let mut blocks = Blocks::open_or_create(root_dir, &options);
+            let check = |blocks: &Blocks, model: &BTreeMap<u64, Vec<u8>>, key: u64| {
+                let result = blocks.get(&key.varint_encode());
+                match model.get(&key) {
+                    Some(value) => assert_eq!(result.as_ref(), Ok(value), "key {key}"),
+                    None => assert_eq!(result, Err(Error::KeyNotFound), "key {key}"),
+                }
+            };
+            for round in 0..60u64 {
+                let mut chunk = Chunk::with_size(1 << 20);
+                chunk.seq = round;
+                let start = rng.below(2000);
+                let step = rng.below(5) + 1;
+                for key in (start..start + rng.below(100) * step + 1).step_by(step as usize) {
+                    let value = format!("{round}-{key}").into_bytes();
+                    chunk.insert(&key.varint_encode(), &value).unwrap();
+                    model.insert(key, value);
+                }
+                blocks.write_block(&chunk);
+                for _ in 0..50 {
+                    check(&blocks, &model, rng.below(2200));
+                }
+            }
+            blocks.flush();
+            drop(blocks);
+
+            // ranges are replayed from the manifest
+            let blocks = Blocks::open_or_create(root_dir, &options);
+            for key in 0..2200 {
+                check(&blocks, &model, key);
+            }
+        }
+    }
+
+    // chunks written out of key order into one block are found before and after it's flushed
+    #[test]
+    fn test_pending_chunks() {
+        let root_dir = &temp_dir("pending");
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1 << 20,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for round in 0..10u64 {
+            let mut chunk = Chunk::new();
+            chunk.seq = round;
+            for key in (900 - round * 100)..(1000 - round * 100) {
+                chunk.insert(&key.varint_encode(), format!("value-{key}").as_bytes()).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        assert_eq!(blocks.segment.as_ref().unwrap().pending.len(), 10);
+        let check = |blocks: &Blocks| {
+            for key in 0..1000u64 {
+                let value = format!("value-{key}").into_bytes();
+                assert_eq!(blocks.get(&key.varint_encode()), Ok(value), "key {key}");
+            }
+            assert_eq!(blocks.get(&1000u64.varint_encode()), Err(Error::KeyNotFound));
+        };
+        check(&blocks);
+        blocks.flush();
+        assert!(blocks.segment.as_ref().unwrap().pending.is_empty());
+        check(&blocks);
+    }
+
+    // every level below 0 is a sorted run within its size limit, level 0 is left empty by compact
+    fn check_levels(blocks: &Blocks) {
+        let metadata = &blocks.metadata;
+        assert!(metadata.levels[0].is_empty());
+        for level in 1..NUM_LEVELS {
+            let run = &metadata.levels[level];
+            assert!(run.iter().all(|block| block.level == level && block.entries > 0));
+            assert!(run
+                .windows(2)
+                .all(|pair| bytes::compare(&pair[0].max_key, &pair[1].min_key).is_lt()));
+            if level < NUM_LEVELS - 1 {
+                assert!(metadata.level_size(level) < blocks.options.level_max_size(level));
+            }
+        }
+        // a tombstone is only kept while a deeper block may hold an older entry of its key
+        for block in metadata.blocks() {
+            let path = blocks.block_path(block.id);
+            for (_, (key, entry)) in blocks.reader(&path).scan(&..).unwrap() {
+                if crate::tombstone::is_tombstone(&entry) {
+                    let covered = metadata.levels[block.level + 1..].iter().flatten().any(|deeper| {
+                        bytes::compare(&deeper.min_key, &key).is_le()
+                            && bytes::compare(&deeper.max_key, &key).is_ge()
+                    });
+                    assert!(covered, "tombstone of {:?} in level {}", key, block.level);
+                }
+            }
+        }
+    }
+
+    // puts and tombstones of overlapping chunks are compacted, what's left must be what a map
+    // written in the same order holds, in levels of blocks that don't overlap
+    // a range scan reads the blocks overlapping the range only, and the chunks of a block from
+    // the one that may hold its start
+    #[test]
+    fn test_range_scan() {
+        let root_dir = &temp_dir("range-scan");
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let write = |blocks: &mut Blocks, first: u64| {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = first;
+            for key in first..first + 100 {
+                chunk.insert(&key.varint_encode(), &crate::tombstone::put(b"v")).unwrap();
+            }
+            blocks.write_block(&chunk);
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for first in (0..2000u64).step_by(100) {
+            write(&mut blocks, first);
+        }
+        blocks.flush();
+        let scan = |blocks: &Blocks, range: std::ops::Range<u64>| {
+            let stats = blocks.stats();
+            let mut result = BTreeMap::new();
+            blocks.scan(&range, &mut result).unwrap();
+            assert_eq!(result.into_keys().collect::<Vec<_>>(), range.collect::<Vec<_>>());
+            let after = blocks.stats();
+            after.table_cache_hits + after.table_cache_misses
+                - stats.table_cache_hits
+                - stats.table_cache_misses
+        };
+        assert_eq!(scan(&blocks, 250..260), 1);
+        assert_eq!(scan(&blocks, 250..450), 3);
+        assert_eq!(scan(&blocks, 1000..1000), 0);
+        drop(blocks);
+
+        // chunks of one block, in the tree and pending before the first flush
+        let _ = fs::remove_dir_all(root_dir);
+        let options = DBOptions {
+            block_size: 1 << 20,
+            ..options
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for first in (0..2000u64).step_by(100) {
+            write(&mut blocks, first);
+        }
+        for flushed in [false, true] {
+            if flushed {
+                blocks.flush();
+            }
+            let segment = blocks.segment.as_ref().unwrap();
+            assert_eq!(segment.pending.is_empty(), flushed);
+            assert_eq!(segment.chunks(250, 259).count(), 1);
+            assert_eq!(segment.chunks(250, 450).count(), 3);
+            assert_eq!(segment.chunks(0, u64::MAX).count(), 20);
+            assert_eq!(segment.chunks(5000, u64::MAX).count(), 1);
+            assert_eq!(segment.scan(&(250..450)).unwrap().len(), 200);
+            assert_eq!(segment.scan(&(5000..)).unwrap().len(), 0);
+        }
+    }
+
+    // compaction charges its rate limiter for every chunk as the merge reads it
+    #[test]
+    fn test_entries_report_reads() {
+        let root_dir = &temp_dir("entries-reads");
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1 << 20,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for first in (0..2000u64).step_by(100) {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = first;
+            for key in first..first + 100 {
+                chunk.insert(&key.varint_encode(), &crate::tombstone::put(b"v")).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        blocks.flush();
+        let segment = blocks.segment.as_ref().unwrap();
+        let reads = std::cell::Cell::new(0);
+        let read = std::cell::Cell::new(0);
+        let mut entries = segment.entries(|size| {
+            reads.set(reads.get() + 1);
+            read.set(read.get() + size);
+        });
+        assert!(entries.next().unwrap().is_ok());
+        assert_eq!(reads.get(), 1);
+        assert_eq!(entries.nth(99).unwrap().unwrap().1 .0, 100u64.varint_encode());
+        assert_eq!(reads.get(), 2);
+        assert_eq!(entries.count(), 1899);
+        assert_eq!(reads.get(), 20);
+        let size: usize = segment
+            .chunks(0, u64::MAX)
+            .map(|chunk| chunk.map(|(key, chunk)| key.len() + chunk.len()).unwrap())
+            .sum();
+        assert_eq!(read.get(), size as u64);
+    }
+
+    #[test]
+    fn test_compaction_matches_model() {
+        for seed in 0..4u64 {
+            let root_dir = &temp_dir(&format!("blocks-compaction-{seed}"));
+            let options = DBOptions {
+                page_size: 4096,
+                block_size: 8192,
+                chunk_size: 1024,
+                auto_compaction: false,
+                level_base_size: 16 << 10,
+                level_size_ratio: 2,
+                ..DBOptions::default()
+            };
+            let mut rng = Lcg(seed);
+            let mut model = BTreeMap::new();
+            let mut blocks = Blocks::open_or_create(root_dir, &options);
+            let check = |blocks: &Blocks, model: &BTreeMap<u64, Vec<u8>>| {
+                for key in 0..2200u64 {
+                    let result = blocks
+                        .get(&key.varint_encode())
+                        .and_then(|entry| crate::tombstone::value(entry, 0));
+                    match model.get(&key) {
+                        Some(value) => assert_eq!(result.as_ref(), Ok(value), "key {key}"),
+                        None => assert_eq!(result, Err(Error::KeyNotFound), "key {key}"),
+                    }
+                }
+                let mut scanned = BTreeMap::new();
+                blocks.scan(&.., &mut scanned).unwrap();
+                scanned.retain(|_, entry| !crate::tombstone::is_tombstone(entry));
+                assert_eq!(scanned.len(), model.len());
+            };
+            for round in 0..40u64 {
+                let mut chunk = Chunk::with_size(1 << 20);
+                chunk.seq = round;
+                let start = rng.below(2000);
+                let step = rng.below(5) + 1;
+                for key in (start..start + rng.below(100) * step + 1).step_by(step as usize) {
+                    if rng.below(3) == 0 {
+                        chunk
+                            .insert(&key.varint_encode(), &crate::tombstone::tombstone())
+                            .unwrap();
+                        model.remove(&key);
+                    } else {
+                        let value = format!("{round}-{key}").into_bytes();
+                        chunk
+                            .insert(&key.varint_encode(), &crate::tombstone::put(&value))
+                            .unwrap();
+                        model.insert(key, value);
+                    }
+                }
+                blocks.write_block(&chunk);
+                if round % 10 == 9 {
+                    blocks.compact().unwrap();
+                    check_levels(&blocks);
+                    check(&blocks, &model);
+                }
+            }
+            let stats = blocks.stats();
+            assert!(stats.compactions >= 4);
+            // level 1 outgrew its limit and was pushed down
+            assert!(stats.levels[2..].iter().any(|level| level.blocks > 0), "{:?}", stats);
+            let compactions: u64 = stats.levels.iter().map(|level| level.compactions).sum();
+            assert_eq!(compactions, stats.compactions);
+            drop(blocks);
+
+            // the files left are the blocks in the manifest
+            let blocks = Blocks::open_or_create(root_dir, &options);
+            let files = fs::read_dir(format!("{root_dir}/blocks")).unwrap().count();
+            assert_eq!(files, blocks.metadata.blocks().count() + 1);
+            check_levels(&blocks);
+            check(&blocks, &model);
+        }
+    }
+
+    // blocks never span partitions, neither when written nor when compacted, and whole blocks
+    // are dropped below a cutoff
+    #[test]
+    fn test_partitions() {
+        let root_dir = &temp_dir("partitions");
+        let options = DBOptions {
+            page_size: 4096,
+            chunk_size: 1024,
+            auto_compaction: false,
+            partition_size: 100,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        // overlapping chunks crossing partitions
+        let ranges = [(0, 250), (150, 420), (90, 110), (300, 330)];
+        for (seq, (start, end)) in ranges.into_iter().enumerate() {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = seq as u64;
+            for key in start..end {
+                let value = crate::tombstone::put(format!("{start}-{key}").as_bytes());
+                chunk.insert(&(key as u64).varint_encode(), &value).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        let check_blocks = |blocks: &Blocks| {
+            for block in blocks.metadata.blocks().filter(|block| block.entries > 0) {
+                let min = u64::varint_decode(&block.min_key).1;
+                let max = u64::varint_decode(&block.max_key).1;
+                assert_eq!(min / 100, max / 100, "block {} spans [{min}, {max}]", block.id);
+            }
+        };
+        check_blocks(&blocks);
+        blocks.flush();
+        let partitions = blocks.partitions();
+        let bounds: Vec<(u64, u64, u64, u64)> = partitions
+            .iter()
+            .map(|partition| (partition.start, partition.end, partition.min_key, partition.max_key))
+            .collect();
+        assert_eq!(
+            bounds,
+            vec![
+                (0, 100, 0, 99),
+                (100, 200, 100, 199),
+                (200, 300, 200, 299),
+                (300, 400, 300, 399),
+                (400, 500, 400, 419)
+            ]
+        );
+        assert!(partitions.iter().all(|partition| partition.blocks > 0 && partition.bytes > 0));
+
+        blocks.compact().unwrap();
+        check_blocks(&blocks);
+        assert_eq!(blocks.partitions().len(), 5);
+        assert_eq!(blocks.get(&100u64.varint_encode()), Ok(crate::tombstone::put(b"90-100")));
+
+        // a cutoff inside a partition keeps it
+        let dropped = blocks.drop_before(250);
+        assert!(dropped >= 2);
+        assert_eq!(blocks.stats().expired_blocks, dropped as u64);
+        assert_eq!(blocks.partitions()[0].start, 200);
+        assert_eq!(blocks.get(&199u64.varint_encode()), Err(Error::KeyNotFound));
+        assert_eq!(blocks.get(&200u64.varint_encode()), Ok(crate::tombstone::put(b"150-200")));
+        drop(blocks);
+
+        let blocks = Blocks::open_or_create(root_dir, &options);
+        let files = fs::read_dir(format!("{root_dir}/blocks")).unwrap().count();
+        assert_eq!(files, blocks.metadata.blocks().count() + 1);
+        assert_eq!(blocks.partitions()[0].start, 200);
+    }
+
+    // without partitions, a block below the cutoff overlapping a block that's kept is kept too
+    #[test]
+    fn test_drop_before() {
+        let root_dir = &temp_dir("drop-before");
+        // a block for every chunk
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        let ranges = [(0, 100), (200, 300), (250, 400), (500, 600)];
+        for (seq, (start, end)) in ranges.into_iter().enumerate() {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = seq as u64;
+            for key in start..end {
+                chunk.insert(&(key as u64).varint_encode(), &crate::tombstone::put(b"v")).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        blocks.flush();
+        assert_eq!(blocks.partitions().len(), 1);
+        // the block of [200, 300) overlaps the one of [250, 400)
+        assert_eq!(blocks.drop_before(350), 1);
+        assert_eq!(blocks.get(&50u64.varint_encode()), Err(Error::KeyNotFound));
+        assert_eq!(blocks.get(&220u64.varint_encode()), Ok(crate::tombstone::put(b"v")));
+        // the block being written is never dropped
+        assert_eq!(blocks.drop_before(1000), 2);
+        assert_eq!(blocks.get(&550u64.varint_encode()), Ok(crate::tombstone::put(b"v")));
+        let partition = &blocks.partitions()[0];
+        assert_eq!(
+            (partition.start, partition.end, partition.min_key, partition.max_key),
+            (0, u64::MAX, 500, 599)
+        );
+    }
+
+    // cached pages are keyed by block id: they don't keep the files of blocks open and are
+    // dropped with their block
+    #[test]
+    fn test_page_cache() {
+        let root_dir = &temp_dir("block-page-cache");
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1,
+            auto_compaction: false,
+            max_open_files: 1,
+            page_cache_size: 16 << 20,
+            mmap_blocks: false,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for seq in 0..8u64 {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = seq;
+            // overlapping chunks, compaction rewrites all of them
+            for key in (seq..800).step_by(8) {
+                chunk.insert(&key.varint_encode(), &crate::tombstone::put(b"v")).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        blocks.flush();
+        for key in 0..800u64 {
+            assert_eq!(blocks.get(&key.varint_encode()), Ok(crate::tombstone::put(b"v")));
+        }
+        let ids: Vec<u64> = blocks.metadata.blocks().map(|block| block.id).collect();
+        let pool = blocks.buffer_pool.clone().unwrap();
+        assert!(ids.iter().all(|id| pool.contains_file(*id)));
+
+        // readers and bloom filters are bounded by max_open_files
+        assert_eq!(blocks.table_cache.borrow().len(), 1);
+        assert_eq!(blocks.filters.borrow().len(), 1);
+
+        // the reader in the table cache and the block being written
+        let block_dir = format!("{root_dir}/");
+        let open_files = fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| fs::read_link(fd.unwrap().path()).ok())
+            .filter(|path| path.to_str().unwrap_or_default().starts_with(&block_dir))
+            .filter(|path| path.file_name().unwrap().to_str().unwrap().starts_with("block-"))
+            .count();
+        assert!(open_files <= 2, "{open_files} block files open");
+
+        blocks.compact().unwrap();
+        for id in ids {
+            assert!(blocks.metadata.block(id).is_none());
+            assert!(!pool.contains_file(id));
+        }
+        assert_eq!(blocks.get(&42u64.varint_encode()), Ok(crate::tombstone::put(b"v")));
+    }
+
+    // files of a compaction that was not installed, and of blocks it removed, are deleted on open
+    #[test]
+    fn test_remove_orphans() {
+        let root_dir = &temp_dir("orphans");
+        let options = DBOptions {
+            page_size: 4096,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), &crate::tombstone::put(b"value")).unwrap();
+        blocks.write_block(&chunk);
+        drop(blocks);
+        for name in ["compaction-0", "block-7", "block-0"] {
+            if name != "block-0" {
+                fs::write(format!("{root_dir}/blocks/{name}"), b"orphan").unwrap();
+            }
+        }
+
+        let blocks = Blocks::open_or_create(root_dir, &options);
+        let mut names: Vec<String> = fs::read_dir(format!("{root_dir}/blocks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["MANIFEST", "block-0"]);
+        assert_eq!(
+            blocks.get(&1u64.varint_encode()),
+            Ok(crate::tombstone::put(b"value"))
+        );
+    }
+
+    // a range tombstone is logged with its chunk and hides the chunks written before it, also
+    // those of the block it's written into, not the chunks written after it
+    #[test]
+    fn test_range_tombstones() {
+        let root_dir = &temp_dir("range-tombstones");
+        let options = DBOptions {
+            page_size: 4096,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        for (seq, start) in [(5, 0u64), (6, 100), (7, 200)] {
+            let mut chunk = Chunk::with_size(1 << 20);
+            chunk.seq = seq;
+            if seq == 6 {
+                chunk.insert_range_tombstone(50..250);
+            }
+            for key in start..start + 100 {
+                chunk.insert(&key.varint_encode(), &crate::tombstone::put(b"v")).unwrap();
+            }
+            blocks.write_block(&chunk);
+        }
+        assert_eq!(blocks.metadata.blocks().count(), 1);
+        assert_eq!(blocks.next_seq(), 8);
+
+        let check = |blocks: &Blocks| {
+            let get = |key: u64| blocks.get(&key.varint_encode()).is_ok();
+            assert!(get(49) && !get(50) && !get(99));
+            assert!(get(100) && get(250) && get(299));
+            let mut result = BTreeMap::new();
+            blocks.scan(&.., &mut result).unwrap();
+            let keys: Vec<u64> = result.into_keys().collect();
+            assert_eq!(keys, (0..50).chain(100..300).collect::<Vec<_>>());
+        };
+        check(&blocks);
+        blocks.flush();
+        drop(blocks);
+        let blocks = Blocks::open_or_create(root_dir, &options);
+        check(&blocks);
+    }
+}
@@ -0,0 +1,363 @@
+// a snapshot of the blocks reads them as they were when it was taken.
+//
+// Blocks are never changed once they are finished, compaction and retention only add new blocks
+// and remove old ones. A snapshot keeps a copy of the metadata, which tells the blocks it reads
+// and the range tombstones hiding their keys, and pins those blocks: a pinned block removed from
+// the database keeps its file until the last snapshot pinning it is dropped.
+//
+// The block being written when the snapshot was taken keeps changing. It's read through the
+// reader its tree shares with the writer (see btree/concurrent.rs), which sees every chunk
+// inserted into the tree so far, together with the chunks written into it since the flush before
+// the snapshot, which may not be in the tree yet. Chunks written after the snapshot are told by
+// their seq and skipped.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::ops::RangeBounds;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use super::cache::TableCache;
+use super::meta::{BlockMeta, Metadata};
+use super::{before_end, block_file, chunk_entries, key_bounds, read_error, Entry, Segment};
+use crate::btree::concurrent::BTreeReader;
+use crate::btree::error::Error as BTreeError;
+use crate::btree::BTreeOptions;
+use crate::bytes::VarintCodec;
+use crate::errors::Error;
+
+// blocks pinned by live snapshots, shared by Blocks and every snapshot
+#[derive(Debug, Default)]
+pub(super) struct Pins {
+    // live snapshots
+    snapshots: usize,
+    // number of snapshots pinning every block
+    counts: HashMap<u64, usize>,
+    // pinned blocks removed from the metadata, their files are deleted with the last pin
+    removed: HashSet<u64>,
+}
+
+// Pins[#TODO] (should add some comments)
+impl Pins {
+    // the file of a removed block is kept if a snapshot pins it, true is returned then
+    pub(super) fn defer_removal(&mut self, id: u64) -> bool {
+        if !self.counts.contains_key(&id) {
+            return false;
+        }
+        self.removed.insert(id);
+        true
+    }
+
+    pub(super) fn snapshots(&self) -> usize {
+        self.snapshots
+    }
+
+    // removed blocks whose files are kept for snapshots
+    pub(super) fn kept_blocks(&self) -> usize {
+        self.removed.len()
+    }
+}
+
+// the blocks of a snapshot, see the top of this file
+pub(crate) struct BlockSnapshot {
+    metadata: Metadata,
+    // chunks from this seq on were written after the snapshot
+    seq: u64,
+    // the block being written, its chunks not flushed when the snapshot was taken in key order,
+    // and the reader of its tree
+    active: Option<(u64, Vec<Rc<Entry>>, BTreeReader)>,
+    data_dir: String,
+    tree_options: BTreeOptions,
+    // readers of the pinned blocks, they are not shared with the table cache of Blocks
+    readers: RefCell<TableCache<Segment>>,
+    pins: Arc<Mutex<Pins>>,
+}
+
+// BlockSnapshot[#TODO] (should add some comments)
+impl BlockSnapshot {
+    pub(super) fn new(
+        metadata: Metadata,
+        seq: u64,
+        active: Option<(u64, Vec<Rc<Entry>>, BTreeReader)>,
+        data_dir: String,
+        tree_options: BTreeOptions,
+        max_open_files: usize,
+        pins: Arc<Mutex<Pins>>,
+    ) -> Self {
+        {
+            let mut pins = pins.lock().unwrap();
+            pins.snapshots += 1;
+            for block in metadata.blocks() {
+                *pins.counts.entry(block.id).or_insert(0) += 1;
+            }
+        }
+        BlockSnapshot {
+            metadata,
+            seq,
+            active,
+            data_dir,
+            tree_options,
+            readers: RefCell::new(TableCache::new(max_open_files)),
+            pins,
+        }
+    }
+
+    // seq of the first chunk written after the snapshot, the snapshot reads the chunks before it
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // like Blocks::get, over the pinned blocks
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let decoded = u64::varint_decode(key).1;
+        for block in self.metadata.get(key) {
+            if self.metadata.is_deleted(decoded, block.max_seq) {
+                return Err(Error::KeyNotFound);
+            }
+            match self.search(block, key) {
+                Ok((entry, seq)) if !self.metadata.is_deleted(decoded, seq) => return Ok(entry),
+                Ok(_) => return Err(Error::KeyNotFound),
+                Err(Error::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::KeyNotFound)
+    }
+
+    // like Blocks::scan, over the pinned blocks
+    pub(crate) fn scan(
+        &self,
+        range: &impl RangeBounds<u64>,
+        result: &mut BTreeMap<u64, Vec<u8>>,
+    ) -> Result<(), Error> {
+        let Some((start, end)) = key_bounds(range) else {
+            return Ok(());
+        };
+        for block in self.metadata.oldest_first(start, end) {
+            for (seq, (key, value)) in self.entries(block, start, end)? {
+                let key = u64::varint_decode(&key).1;
+                if self.metadata.is_deleted(key, seq) {
+                    result.remove(&key);
+                } else {
+                    result.insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the entry of the key in a pinned block and the seq of its chunk, see Blocks::get
+    fn search(&self, block: &BlockMeta, key: &[u8]) -> Result<(Vec<u8>, u64), Error> {
+        let (uncommitted, reader) = match self.active {
+            Some((id, ref uncommitted, ref reader)) if id == block.id => (uncommitted, reader),
+            _ => {
+                let (entry, seq) = self.reader(block.id).search(key)?;
+                return Ok((entry, seq.min(block.max_seq)));
+            }
+        };
+        // chunks of a block never overlap, the key is in one chunk at most
+        if let Ok(found) = Segment::search_chunks(uncommitted, key) {
+            return Ok(found);
+        }
+        match Segment::search_found(reader.fuzz_find(key), key)? {
+            (_, seq) if seq >= self.seq => Err(Error::KeyNotFound),
+            found => Ok(found),
+        }
+    }
+
+    // the entries of a pinned block in [start, end] with the seq of their chunk. A chunk of the
+    // block being written may be both in its tree and in the chunks not flushed at the snapshot,
+    // its entries show up twice then. Its tree is walked from start, see Segment::chunks
+    fn entries(
+        &self,
+        block: &BlockMeta,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<(u64, Entry)>, Error> {
+        let (uncommitted, reader) = match self.active {
+            Some((id, ref uncommitted, ref reader)) if id == block.id => (uncommitted, reader),
+            _ => {
+                let entries = self.reader(block.id).scan(&(start..=end))?;
+                return Ok(entries
+                    .into_iter()
+                    .map(|(seq, entry)| (seq.min(block.max_seq), entry))
+                    .collect());
+            }
+        };
+        let start_key = start.varint_encode();
+        let first = match reader.fuzz_find(&start_key) {
+            Ok(first) => first.key,
+            Err(BTreeError::KeyNotFound | BTreeError::EmptyTree) => start_key,
+            Err(err) => return Err(read_error(err)),
+        };
+        let tree = reader
+            .iter_from(&first)
+            .map(|kv| kv.map(|kv| (kv.key, kv.value)).map_err(read_error))
+            .take_while(|chunk| before_end(chunk, end));
+        let uncommitted = Segment::chunks_in(uncommitted, start, end)
+            .iter()
+            .map(|chunk| Ok(chunk.as_ref().clone()));
+        let mut entries = Vec::new();
+        for chunk in tree.chain(uncommitted) {
+            let (key, chunk) = chunk?;
+            for item in chunk_entries(&key, &chunk) {
+                let item = item?;
+                if item.0 < self.seq && (start..=end).contains(&u64::varint_decode(&item.1 .0).1) {
+                    entries.push(item);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn reader(&self, id: u64) -> Rc<Segment> {
+        let path = block_file(&self.data_dir, id);
+        let open = || Segment::reader(&path, &self.tree_options);
+        self.readers.borrow_mut().get_or_open(id, open).0
+    }
+}
+
+// Drop[#TODO] (should add some comments)
+impl Drop for BlockSnapshot {
+    fn drop(&mut self) {
+        // readers are closed before the files are deleted
+        self.readers = RefCell::new(TableCache::new(1));
+        let mut pins = self.pins.lock().unwrap();
+        pins.snapshots -= 1;
+        for block in self.metadata.blocks() {
+            let count = pins.counts.get_mut(&block.id).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            pins.counts.remove(&block.id);
+            if pins.removed.remove(&block.id) {
+                let _ = fs::remove_file(block_file(&self.data_dir, block.id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Blocks;
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::db::DBOptions;
+    use crate::tombstone;
+    use crate::util::testing::temp_dir;
+
+    fn write(blocks: &mut Blocks, seq: u64, keys: std::ops::Range<u64>, value: &[u8]) {
+        let mut chunk = Chunk::with_size(1 << 20);
+        chunk.seq = seq;
+        for key in keys {
+            chunk
+                .insert(&key.varint_encode(), &tombstone::put(value))
+                .unwrap();
+        }
+        blocks.write_block(&chunk);
+    }
+
+    #[test]
+    fn test_pinned_blocks() {
+        let root_dir = &temp_dir("block-snapshot");
+        let options = DBOptions {
+            page_size: 4096,
+            block_size: 1,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+        write(&mut blocks, 0, 0..100, b"old");
+        write(&mut blocks, 1, 50..150, b"old");
+        let snapshot = blocks.snapshot(2);
+        let pinned: Vec<u64> = snapshot.metadata.blocks().map(|block| block.id).collect();
+        assert_eq!(pinned.len(), 2);
+        assert_eq!(snapshot.seq(), 2);
+
+        // newer blocks, range deletes and compaction are not seen by the snapshot
+        write(&mut blocks, 2, 0..10, b"new");
+        let mut chunk = Chunk::with_size(1 << 20);
+        chunk.seq = 3;
+        chunk.insert_range_tombstone(100..120);
+        blocks.write_block(&chunk);
+        blocks.compact().unwrap();
+        assert_eq!(
+            blocks.get(&5u64.varint_encode()),
+            Ok(tombstone::put(b"new"))
+        );
+        assert_eq!(blocks.get(&110u64.varint_encode()), Err(Error::KeyNotFound));
+        assert_eq!(
+            snapshot.get(&5u64.varint_encode()),
+            Ok(tombstone::put(b"old"))
+        );
+        assert_eq!(
+            snapshot.get(&110u64.varint_encode()),
+            Ok(tombstone::put(b"old"))
+        );
+        let mut result = BTreeMap::new();
+        snapshot.scan(&.., &mut result).unwrap();
+        assert_eq!(result.len(), 150);
+
+        // the compacted blocks keep their files until the snapshot is dropped
+        let stats = blocks.stats();
+        assert_eq!((stats.snapshots, stats.snapshot_kept_blocks), (1, 2));
+        for id in pinned.iter() {
+            assert!(blocks.metadata.block(*id).is_none());
+            assert!(fs::metadata(blocks.block_path(*id)).is_ok());
+        }
+        drop(snapshot);
+        for id in pinned.iter() {
+            assert!(fs::metadata(blocks.block_path(*id)).is_err());
+        }
+        let stats = blocks.stats();
+        assert_eq!((stats.snapshots, stats.snapshot_kept_blocks), (0, 0));
+
+        // blocks that are still in use are not deleted with the snapshot
+        let snapshot = blocks.snapshot(2);
+        drop(snapshot);
+        assert_eq!(
+            blocks.get(&5u64.varint_encode()),
+            Ok(tombstone::put(b"new"))
+        );
+    }
+
+    #[test]
+    fn test_active_block() {
+        let root_dir = &temp_dir("active-snapshot");
+        let options = DBOptions {
+            page_size: 4096,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let mut blocks = Blocks::open_or_create(root_dir, &options);
+
+        // a block not flushed yet
+        write(&mut blocks, 0, 0..10, b"v0");
+        let snapshot = blocks.snapshot(1);
+        write(&mut blocks, 1, 10..20, b"v1");
+        assert_eq!(snapshot.get(&5u64.varint_encode()), Ok(tombstone::put(b"v0")));
+        assert_eq!(snapshot.get(&15u64.varint_encode()), Err(Error::KeyNotFound));
+        drop(snapshot);
+        blocks.flush();
+
+        // the flushed chunks and the chunks written since the flush are read, chunks written
+        // after the snapshot are skipped whether they are flushed or not
+        write(&mut blocks, 2, 20..30, b"v2");
+        let snapshot = blocks.snapshot(3);
+        assert_eq!(blocks.metadata.blocks().count(), 1);
+        write(&mut blocks, 3, 30..40, b"v3");
+        blocks.flush();
+        write(&mut blocks, 4, 40..50, b"v4");
+        for (key, value) in [(5u64, b"v0"), (15, b"v1"), (25, b"v2")] {
+            assert_eq!(snapshot.get(&key.varint_encode()), Ok(tombstone::put(value)));
+        }
+        for key in [35u64, 45, 55] {
+            assert_eq!(snapshot.get(&key.varint_encode()), Err(Error::KeyNotFound));
+        }
+        let mut result = BTreeMap::new();
+        snapshot.scan(&.., &mut result).unwrap();
+        assert_eq!(result.keys().copied().collect::<Vec<_>>(), (0..30).collect::<Vec<_>>());
+        assert_eq!(result[&25], tombstone::put(b"v2"));
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, Range};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use memmap2::Mmap;
+
+// BufferPool 是所有B树(block)共享的页面缓存, 按照(文件id, 页号)缓存页面内容
+//
+// - 文件id 由调用方给出(block id), 同一个文件的Pager 共享缓存页面, 文件删除前按id 清掉它的页面
+// - 按key hash 分成多个shard, 每个shard一把锁, 每个shard 用一个双向链表做LRU淘汰
+// - 被PinnedPage引用的页面不会被淘汰
+// - write_page 只把页面标记为dirty, 淘汰或者flush的时候才写回磁盘
+// - 缓存的页面不持有文件, 打开的文件数只由table cache 决定
+
+const DEFAULT_SHARD_NUM: usize = 16;
+
+// id of a file given by the owner of the pool, Pagers of the same file share cached pages and
+// the ids of different files never collide
+pub type FileId = u64;
+type PageKey = (FileId, u64);
+
+// PinnedPage keeps a page in the pool while it is in use
+pub struct PinnedPage {
+    data: PageData,
+}
+
+enum PageData {
+    Cached(Arc<Vec<u8>>),
+    // a page of a mapped file, the mapping stays valid while the page is in use even if the
+    // file is mapped again
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+// PinnedPage[#TODO] (should add some comments)
+impl PinnedPage {
+    // a page that is not cached by any pool
+    pub(super) fn unpooled(data: Vec<u8>) -> Self {
+        Self::cached(Arc::new(data))
+    }
+
+    pub(super) fn mapped(map: Arc<Mmap>, range: Range<usize>) -> Self {
+        PinnedPage {
+            data: PageData::Mapped(map, range),
+        }
+    }
+
+    fn cached(data: Arc<Vec<u8>>) -> Self {
+        PinnedPage {
+            data: PageData::Cached(data),
+        }
+    }
+}
+
+// Deref[#TODO] (should add some comments)
+impl Deref for PinnedPage {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match &self.data {
+            PageData::Cached(data) => data,
+            PageData::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+struct Frame {
+    // a frame is pinned while any PinnedPage holds a clone of data
+    data: Arc<Vec<u8>>,
+    dirty: bool,
+    // the file a dirty page is written back to. Pagers flush their dirty pages when they are
+    // dropped, so the file is open while the page is dirty
+    file: Weak<File>,
+    offset: u64,
+    // neighbours in the LRU list of the shard, prev is the more recently used one
+    prev: Option<PageKey>,
+    next: Option<PageKey>,
+}
+
+// Frame[#TODO] (should add some comments)
+impl Frame {
+    #[inline]
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.data) > 1
+    }
+
+    fn write_back(&mut self) {
+        let file = self.file.upgrade().expect("dirty page of a closed file");
+        file.write_all_at(&self.data, self.offset)
+            .expect("write back page failed");
+        self.dirty = false;
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    frames: HashMap<PageKey, Frame>,
+    // the most and the least recently used page
+    head: Option<PageKey>,
+    tail: Option<PageKey>,
+    used_bytes: usize,
+}
+
+// Shard[#TODO] (should add some comments)
+impl Shard {
+    // move the page to the head of the LRU list
+    fn touch(&mut self, key: PageKey) {
+        if self.head == Some(key) {
+            return;
+        }
+        self.unlink(key);
+        let frame = self.frames.get_mut(&key).unwrap();
+        frame.prev = None;
+        frame.next = self.head;
+        match self.head {
+            Some(head) => self.frames.get_mut(&head).unwrap().prev = Some(key),
+            None => self.tail = Some(key),
+        }
+        self.head = Some(key);
+    }
+
+    fn unlink(&mut self, key: PageKey) {
+        let frame = &self.frames[&key];
+        let (prev, next) = (frame.prev, frame.next);
+        match prev {
+            Some(prev) => self.frames.get_mut(&prev).unwrap().next = next,
+            None if self.head == Some(key) => self.head = next,
+            None => {}
+        }
+        match next {
+            Some(next) => self.frames.get_mut(&next).unwrap().prev = prev,
+            None if self.tail == Some(key) => self.tail = prev,
+            None => {}
+        }
+    }
+
+    fn remove(&mut self, key: &PageKey) -> Option<Frame> {
+        if !self.frames.contains_key(key) {
+            return None;
+        }
+        self.unlink(*key);
+        let frame = self.frames.remove(key).unwrap();
+        self.used_bytes -= frame.data.len();
+        Some(frame)
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+pub struct BufferPool {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+}
+
+// BufferPool[#TODO] (should add some comments)
+impl BufferPool {
+    // capacity is the total bytes of cached pages over all shards
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            shards: (0..DEFAULT_SHARD_NUM)
+                .map(|_| Mutex::new(Shard::default()))
+                .collect(),
+            shard_capacity: capacity / DEFAULT_SHARD_NUM,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            write_backs: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &PageKey) -> &Mutex<Shard> {
+        &self.shards[Self::shard_index(key)]
+    }
+
+    fn shard_index(key: &PageKey) -> usize {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % DEFAULT_SHARD_NUM
+    }
+
+    pub fn get(&self, file_id: FileId, page_number: u64) -> Option<PinnedPage> {
+        let key = (file_id, page_number);
+        let mut shard = self.shard(&key).lock().unwrap();
+        if !shard.frames.contains_key(&key) {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        shard.touch(key);
+        Some(PinnedPage::cached(shard.frames[&key].data.clone()))
+    }
+
+    // cache a page just read from disk; if another reader cached it first, that copy wins
+    pub fn insert_clean(
+        &self,
+        file_id: FileId,
+        page_number: u64,
+        data: Vec<u8>,
+        file: &Arc<File>,
+        offset: u64,
+    ) -> PinnedPage {
+        let key = (file_id, page_number);
+        let mut shard = self.shard(&key).lock().unwrap();
+        if shard.frames.contains_key(&key) {
+            shard.touch(key);
+            return PinnedPage::cached(shard.frames[&key].data.clone());
+        }
+        let data = Arc::new(data);
+        self.insert_frame(&mut shard, key, data.clone(), false, file, offset);
+        PinnedPage::cached(data)
+    }
+
+    // cache a modified page, it is written to disk on eviction or flush
+    pub fn insert_dirty(
+        &self,
+        file_id: FileId,
+        page_number: u64,
+        data: Vec<u8>,
+        file: &Arc<File>,
+        offset: u64,
+    ) {
+        let key = (file_id, page_number);
+        let mut shard = self.shard(&key).lock().unwrap();
+        // readers holding the old content keep it until they unpin
+        shard.remove(&key);
+        self.insert_frame(&mut shard, key, Arc::new(data), true, file, offset);
+    }
+
+    fn insert_frame(
+        &self,
+        shard: &mut Shard,
+        key: PageKey,
+        data: Arc<Vec<u8>>,
+        dirty: bool,
+        file: &Arc<File>,
+        offset: u64,
+    ) {
+        shard.used_bytes += data.len();
+        shard.frames.insert(
+            key,
+            Frame {
+                data,
+                dirty,
+                file: Arc::downgrade(file),
+                offset,
+                prev: None,
+                next: None,
+            },
+        );
+        shard.touch(key);
+        self.evict(shard);
+    }
+
+    // evict least recently used unpinned pages until the shard fits its capacity, walking the
+    // LRU list from its tail
+    fn evict(&self, shard: &mut Shard) {
+        let mut cursor = shard.tail;
+        while shard.used_bytes > self.shard_capacity {
+            let key = match cursor {
+                Some(key) => key,
+                None => break,
+            };
+            cursor = shard.frames[&key].prev;
+            if shard.frames[&key].is_pinned() {
+                continue;
+            }
+            let mut frame = shard.remove(&key).unwrap();
+            if frame.dirty {
+                frame.write_back();
+                self.write_backs.fetch_add(1, Ordering::Relaxed);
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // write every dirty page of the file back to disk
+    pub fn flush_file(&self, file_id: FileId) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            for (key, frame) in shard.frames.iter_mut() {
+                if key.0 == file_id && frame.dirty {
+                    frame.write_back();
+                    self.write_backs.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    // drop every page of the file without writing it back, used before the file is deleted.
+    // The file is only known by its id here, so it may be gone already
+    pub fn purge_file(&self, file_id: FileId) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<PageKey> = shard
+                .frames
+                .keys()
+                .filter(|key| key.0 == file_id)
+                .copied()
+                .collect();
+            for key in keys {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    // true if any page of the file is cached
+    pub fn contains_file(&self, file_id: FileId) -> bool {
+        self.shards.iter().any(|shard| {
+            shard
+                .lock()
+                .unwrap()
+                .frames
+                .keys()
+                .any(|key| key.0 == file_id)
+        })
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().used_bytes)
+            .sum()
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            write_backs: self.write_backs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp_path;
+    use std::fs::OpenOptions;
+
+    const PAGE: usize = 64;
+
+    fn temp_file(name: &str) -> Arc<File> {
+        let path = temp_path(&format!("buffer-{name}"));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        Arc::new(file)
+    }
+
+    fn page(value: u8) -> Vec<u8> {
+        vec![value; PAGE]
+    }
+
+    #[test]
+    fn test_hit_miss_and_eviction() {
+        let file = temp_file("lru");
+        let id = 1;
+        // 2 pages per shard
+        let pool = BufferPool::new(PAGE * 2 * DEFAULT_SHARD_NUM);
+        assert!(pool.get(id, 1).is_none());
+        pool.insert_clean(id, 1, page(1), &file, 0);
+        assert_eq!(pool.get(id, 1).unwrap()[0], 1);
+        assert_eq!(pool.stats().hits, 1);
+        assert_eq!(pool.stats().misses, 1);
+
+        for pn in 2..200 {
+            pool.insert_clean(id, pn, page(pn as u8), &file, pn * PAGE as u64);
+        }
+        assert!(pool.used_bytes() <= PAGE * 2 * DEFAULT_SHARD_NUM);
+        assert!(pool.stats().evictions > 0);
+        // the most recently inserted page is always kept
+        assert_eq!(pool.get(id, 199).unwrap()[0], 199);
+    }
+
+    #[test]
+    fn test_pinned_page_is_not_evicted() {
+        let file = temp_file("pin");
+        let id = 2;
+        // every shard holds less than a single page
+        let pool = BufferPool::new(PAGE / 2 * DEFAULT_SHARD_NUM);
+        let pinned = pool.insert_clean(id, 1, page(7), &file, 0);
+        for pn in 2..100 {
+            pool.insert_clean(id, pn, page(0), &file, pn * PAGE as u64);
+        }
+        assert_eq!(pinned[0], 7);
+        assert!(pool.get(id, 1).is_some());
+        drop(pinned);
+
+        // once unpinned, the page is evicted as soon as its shard is over capacity
+        pool.insert_dirty(id, 1, page(8), &file, PAGE as u64);
+        assert!(pool.get(id, 1).is_none());
+        let mut buffer = vec![0u8; PAGE];
+        file.read_exact_at(&mut buffer, PAGE as u64).unwrap();
+        assert_eq!(buffer, page(8));
+    }
+
+    #[test]
+    fn test_dirty_write_back() {
+        let file = temp_file("dirty");
+        let id = 3;
+        let pool = BufferPool::new(PAGE * 4 * DEFAULT_SHARD_NUM);
+        pool.insert_dirty(id, 3, page(9), &file, 3 * PAGE as u64);
+
+        // nothing on disk before flush
+        let mut buffer = vec![0u8; PAGE];
+        assert!(file.read_exact_at(&mut buffer, 3 * PAGE as u64).is_err());
+
+        pool.flush_file(id);
+        file.read_exact_at(&mut buffer, 3 * PAGE as u64).unwrap();
+        assert_eq!(buffer, page(9));
+        assert_eq!(pool.stats().write_backs, 1);
+        // clean pages are not written again
+        pool.flush_file(id);
+        assert_eq!(pool.stats().write_backs, 1);
+
+        // dirty pages are written back on eviction too
+        let tiny = BufferPool::new(0);
+        tiny.insert_dirty(id, 1, page(6), &file, PAGE as u64);
+        file.read_exact_at(&mut buffer, PAGE as u64).unwrap();
+        assert_eq!(buffer, page(6));
+        assert_eq!(tiny.stats().evictions, 1);
+
+        pool.purge_file(id);
+        assert_eq!(pool.used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_lru_order() {
+        let file = temp_file("order");
+        // 3 pages per shard, pages of one shard are evicted least recently used first
+        let pool = BufferPool::new(PAGE * 3 * DEFAULT_SHARD_NUM);
+        let shard = BufferPool::shard_index(&(1, 0));
+        let pages: Vec<u64> = (0..)
+            .filter(|pn| BufferPool::shard_index(&(1, *pn)) == shard)
+            .take(5)
+            .collect();
+        for pn in pages[..3].iter() {
+            pool.insert_clean(1, *pn, page(0), &file, 0);
+        }
+        pool.get(1, pages[0]).unwrap();
+        pool.insert_clean(1, pages[3], page(0), &file, 0);
+        assert!(pool.get(1, pages[1]).is_none());
+        pool.get(1, pages[2]).unwrap();
+        pool.insert_clean(1, pages[4], page(0), &file, 0);
+        assert!(pool.get(1, pages[0]).is_none());
+        for pn in [pages[2], pages[3], pages[4]] {
+            assert!(pool.get(1, pn).is_some());
+        }
+
+        // pages are cached by file id, purging one file keeps the others
+        pool.insert_clean(2, pages[0], page(0), &file, 0);
+        pool.purge_file(1);
+        assert!(!pool.contains_file(1));
+        assert!(pool.contains_file(2));
+    }
+}
@@ -0,0 +1,296 @@
+// bulk load a tree bottom-up from key values sorted by key. Leaves are filled left to right up
+// to the fill factor, and every finished node hands its page and smallest key to the level above,
+// so each page is written once instead of being split again and again by insert
+use super::constant::{DEFAULT_MAX_THRESHOLD, DEFAULT_MIN_THRESHOLD};
+use super::node::{internal_key_size, max_inline_value_size, KeyValue, Node};
+use super::BTree;
+use crate::bytes;
+
+// node of a level that is being filled
+struct Level {
+    node: Node,
+    // smallest key of the subtree, the separator of the node in its parent
+    first_key: Vec<u8>,
+    // the last finished node of the level, the node being filled is merged into it at the end
+    // if it's underflow; 0 means the level has no finished node yet
+    prev_page: u64,
+}
+
+pub struct BTreeBuilder<'a> {
+    tree: &'a mut BTree,
+    fill_factor: f64,
+    // levels[0] is the leaf level
+    levels: Vec<Level>,
+    last_key: Option<Vec<u8>>,
+}
+
+// BTreeBuilder[#TODO] (should add some comments)
+impl<'a> BTreeBuilder<'a> {
+    // the tree must be empty, it's committed by the next BTree::flush
+    pub fn new(tree: &'a mut BTree) -> Self {
+        assert_eq!(tree.metadata.root, 0, "bulk load into a non empty tree");
+        BTreeBuilder {
+            tree,
+            fill_factor: DEFAULT_MAX_THRESHOLD,
+            levels: Vec::new(),
+            last_key: None,
+        }
+    }
+
+    // fraction of a page filled before a new node is started, a lower fill factor leaves room
+    // for later inserts
+    pub fn with_fill_factor(mut self, fill_factor: f64) -> Self {
+        assert!(
+            fill_factor > DEFAULT_MIN_THRESHOLD && fill_factor <= DEFAULT_MAX_THRESHOLD,
+            "fill factor must be in ({DEFAULT_MIN_THRESHOLD}, {DEFAULT_MAX_THRESHOLD}]"
+        );
+        self.fill_factor = fill_factor;
+        self
+    }
+
+    #[inline]
+    fn target_size(&self) -> usize {
+        (self.fill_factor * self.tree.pager.page_size() as f64) as usize
+    }
+
+    // keys must be added in strictly increasing order
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let page_size = self.tree.pager.page_size();
+        assert!(
+            key.len() <= max_inline_value_size(page_size),
+            "key too large for page size {page_size}"
+        );
+        if let Some(ref last_key) = self.last_key {
+            assert!(
+                bytes::compare(last_key, key) == std::cmp::Ordering::Less,
+                "keys of a bulk load must be sorted"
+            );
+        }
+        self.last_key = Some(key.to_vec());
+
+        let kv = if value.len() > max_inline_value_size(page_size) {
+            KeyValue::new_overflow(key, self.tree.write_overflow(value))
+        } else {
+            KeyValue::new(key, value)
+        };
+        if self.levels.is_empty() {
+            self.levels.push(Level {
+                node: Node::new_leaf(0),
+                first_key: key.to_vec(),
+                prev_page: 0,
+            });
+        }
+
+        let target_size = self.target_size();
+        let leaf = &mut self.levels[0];
+        if !leaf.node.leaf_data().keyvalues.is_empty() && leaf.node.size() + kv.size() > target_size
+        {
+            let mut finished = std::mem::replace(&mut leaf.node, Node::new_leaf(0));
+            let first_key = std::mem::replace(&mut leaf.first_key, key.to_vec());
+            self.tree.write_node(&mut finished);
+            self.levels[0].prev_page = finished.offset;
+            self.push(1, first_key, finished.offset);
+        }
+        self.levels[0].node.leaf_data().keyvalues.push(kv);
+    }
+
+    // add a finished child to the internal node of the level
+    fn push(&mut self, level: usize, first_key: Vec<u8>, page_number: u64) {
+        if level == self.levels.len() {
+            let mut node = Node::new_internal(0);
+            node.internal_data().children.push(page_number);
+            self.levels.push(Level {
+                node,
+                first_key,
+                prev_page: 0,
+            });
+            return;
+        }
+
+        let target_size = self.target_size();
+        let current = &mut self.levels[level];
+        if current.node.size() + internal_key_size(&first_key) > target_size {
+            let mut started = Node::new_internal(0);
+            started.internal_data().children.push(page_number);
+            let mut finished = std::mem::replace(&mut current.node, started);
+            let finished_key = std::mem::replace(&mut current.first_key, first_key);
+            self.tree.write_node(&mut finished);
+            self.levels[level].prev_page = finished.offset;
+            self.push(level + 1, finished_key, finished.offset);
+        } else {
+            let node = current.node.internal_data();
+            node.keys.push(first_key);
+            node.children.push(page_number);
+        }
+    }
+
+    // write the nodes being filled and point the tree to the new root
+    pub fn finish(mut self) {
+        let page_size = self.tree.pager.page_size();
+        let mut level = 0;
+        while level < self.levels.len() {
+            let mut current = std::mem::replace(&mut self.levels[level].node, Node::new_empty(0));
+            let first_key = std::mem::take(&mut self.levels[level].first_key);
+            let prev_page = self.levels[level].prev_page;
+
+            if prev_page == 0 && level + 1 == self.levels.len() {
+                // the only node of the top level, a root with a single child is skipped
+                if !current.is_leaf && current.internal_data().keys.is_empty() {
+                    self.tree.metadata.root = current.internal_data().children[0];
+                } else {
+                    self.tree.write_node(&mut current);
+                    self.tree.metadata.root = current.offset;
+                }
+                return;
+            }
+
+            if prev_page != 0 && current.is_underflow(page_size) {
+                // the last node takes what's left of the stream, share it with the previous
+                // one; the previous node keeps its page and separator
+                let mut prev = self.tree.get_node(prev_page).unwrap();
+                if current.is_leaf {
+                    prev.leaf_data()
+                        .keyvalues
+                        .append(&mut current.leaf_data().keyvalues);
+                } else {
+                    let prev_node = prev.internal_data();
+                    prev_node.keys.push(first_key);
+                    prev_node.keys.append(&mut current.internal_data().keys);
+                    prev_node
+                        .children
+                        .append(&mut current.internal_data().children);
+                }
+                if prev.is_overflow(page_size) {
+                    let (middle, mut right) = prev.split(0, page_size).unwrap();
+                    self.tree.write_node(&mut prev);
+                    self.tree.write_node(&mut right);
+                    self.push(level + 1, middle, right.offset);
+                } else {
+                    self.tree.write_node(&mut prev);
+                }
+            } else {
+                self.tree.write_node(&mut current);
+                self.push(level + 1, first_key, current.offset);
+            }
+            level += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::constant::MIN_PAGE_SIZE;
+    use crate::btree::BTreeOptions;
+    use crate::bytes::VarintCodec;
+    use crate::util::testing::temp_path;
+
+    // every node but the root is within the split and merge thresholds, leaves are at one depth
+    fn check_fill(tree: &BTree, page_number: u64, depth: usize, leaf_depth: &mut Option<usize>) {
+        let page_size = tree.pager.page_size();
+        let mut node = tree.get_node(page_number).unwrap();
+        assert!(!node.is_overflow(page_size));
+        if page_number != tree.metadata.root {
+            assert!(!node.is_underflow(page_size), "page {page_number} underflow");
+        }
+        if node.is_leaf {
+            assert_eq!(*leaf_depth.get_or_insert(depth), depth);
+            return;
+        }
+        for child in node.internal_data().children.clone() {
+            check_fill(tree, child, depth + 1, leaf_depth);
+        }
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let value = |i: u64| -> Vec<u8> {
+            // a few values go to overflow pages
+            let size = if i.is_multiple_of(97) { 5000 } else { 60 };
+            (0..size).map(|j| (i + j) as u8).collect()
+        };
+        for count in [0u64, 1, 10, 100, 3000] {
+            for fill_factor in [0.5, DEFAULT_MAX_THRESHOLD] {
+                let path = temp_path(&format!("builder-{count}-{fill_factor}"));
+                let mut tree = BTree::with_options(&path, &options);
+                let mut builder = BTreeBuilder::new(&mut tree).with_fill_factor(fill_factor);
+                for i in 0..count {
+                    builder.add(&(i * 2).varint_encode(), &value(i));
+                }
+                builder.finish();
+                drop(tree);
+
+                let mut tree = BTree::with_options(&path, &options);
+                if count > 0 {
+                    check_fill(&tree, tree.metadata.root, 0, &mut None);
+                }
+                let items: Vec<KeyValue> = tree.iter().collect::<Result<_, _>>().unwrap();
+                assert_eq!(items.len() as u64, count);
+                for (i, kv) in items.iter().enumerate() {
+                    assert_eq!(kv.key, (i as u64 * 2).varint_encode());
+                    assert_eq!(kv.value, value(i as u64));
+                }
+                for i in (0..count).step_by(7) {
+                    assert_eq!(tree.find(&(i * 2).varint_encode()).unwrap().value, value(i));
+                    assert!(tree.find(&(i * 2 + 1).varint_encode()).is_err());
+                }
+
+                // the loaded tree is a regular tree
+                for i in 0..count {
+                    tree.insert(&(i * 2 + 1).varint_encode(), b"odd");
+                }
+                for i in (0..count).step_by(2) {
+                    tree.delete(&(i * 2).varint_encode()).unwrap();
+                }
+                assert_eq!(tree.iter().count() as u64, count + count / 2);
+                drop(tree);
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bulk_load_is_packed() {
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let value = |i: u64| format!("value-{:0>60}", i).into_bytes();
+        let loaded = temp_path("builder-packed-loaded");
+        let inserted = temp_path("builder-packed-inserted");
+        {
+            let mut tree = BTree::with_options(&loaded, &options);
+            let mut builder = BTreeBuilder::new(&mut tree);
+            for i in 0..5000u64 {
+                builder.add(&i.varint_encode(), &value(i));
+            }
+            builder.finish();
+
+            let mut tree = BTree::with_options(&inserted, &options);
+            for i in 0..5000u64 {
+                tree.insert(&i.varint_encode(), &value(i));
+            }
+        }
+        let loaded_size = std::fs::metadata(&loaded).unwrap().len();
+        let inserted_size = std::fs::metadata(&inserted).unwrap().len();
+        assert!(
+            loaded_size * 3 < inserted_size * 2,
+            "{loaded_size} vs {inserted_size}"
+        );
+        let _ = std::fs::remove_file(&loaded);
+        let _ = std::fs::remove_file(&inserted);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be sorted")]
+    fn test_unsorted_keys() {
+        let mut tree = BTree::new(&temp_path("builder-unsorted"));
+        let mut builder = BTreeBuilder::new(&mut tree);
+        builder.add(&300u64.varint_encode(), b"a");
+        builder.add(&20u64.varint_encode(), b"b");
+    }
+}
@@ -0,0 +1,461 @@
+// integrity check of a tree, nothing is modified. The tree is walked from the root with the key
+// range every subtree must be within, and every page of the file must be used exactly once: by
+// the tree, an overflow chain, the freelist chain, or as a free page.
+//
+// prev/next links of leaves are not kept up to date under copy on write (see LeafNode), the leaf
+// chain is the leaves reached left to right instead. After the walk it's checked as a sibling
+// chain would be: every leaf must start after the leaf before it ends
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use super::constant::{PAGE_TYPE_FREELIST, PAGE_TYPE_INTERNAL, PAGE_TYPE_LEAF, PAGE_TYPE_OVERFLOW};
+use super::node::{is_overflow_size, is_underflow_size};
+use super::overflow::HEAD_OVERFLOW_PAGE_SIZE;
+use super::pager::Page;
+use super::slotted::{SlottedPage, ValueRef};
+use super::BTree;
+use crate::bytes;
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    // levels of the tree, 0 for an empty tree
+    pub depth: usize,
+    pub keys: usize,
+    pub leaf_pages: usize,
+    pub internal_pages: usize,
+    // pages of overflowed values and of the attachment
+    pub overflow_pages: usize,
+    pub freelist_pages: usize,
+    pub free_pages: usize,
+    // leaf pages in key order, what the prev/next links of a leaf would point to
+    pub leaf_chain: Vec<u64>,
+    // problems found, the tree is consistent when it's empty
+    pub errors: Vec<String>,
+}
+
+// CheckReport[#TODO] (should add some comments)
+impl CheckReport {
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+struct Checker<'a> {
+    tree: &'a BTree,
+    report: CheckReport,
+    // what every page seen so far is used as
+    owners: HashMap<u64, &'static str>,
+    leaf_depth: Option<usize>,
+    // first and last key of every non-empty leaf of the chain
+    leaf_ranges: Vec<(u64, Vec<u8>, Vec<u8>)>,
+}
+
+// BTree[#TODO] (should add some comments)
+impl BTree {
+    // check the working version of the tree, uncommitted changes included; pages released by
+    // them are free once committed and are counted as free
+    pub fn check(&self) -> CheckReport {
+        let mut checker = Checker {
+            tree: self,
+            report: CheckReport::default(),
+            owners: HashMap::new(),
+            leaf_depth: None,
+            leaf_ranges: Vec::new(),
+        };
+        if self.metadata.root != 0 {
+            checker.check_node(self.metadata.root, 0, None, None);
+        }
+        checker.check_leaf_chain();
+        checker.report.depth = checker.leaf_depth.map_or(0, |depth| depth + 1);
+        checker.report.overflow_pages +=
+            checker.check_chain(self.metadata.attachment, PAGE_TYPE_OVERFLOW, "attachment");
+        checker.report.freelist_pages =
+            checker.check_chain(self.metadata.freelist_page, PAGE_TYPE_FREELIST, "freelist");
+        for &page_number in self
+            .freelist
+            .released_pages
+            .iter()
+            .chain(self.pending_pages.iter())
+            .chain(self.retired_pages.iter().map(|(_, page_number)| page_number))
+        {
+            if checker.mark(page_number, "free page") {
+                checker.report.free_pages += 1;
+            }
+        }
+        for page_number in 1..=self.freelist.max_page {
+            if !checker.owners.contains_key(&page_number) {
+                checker
+                    .report
+                    .errors
+                    .push(format!("page {page_number} is neither reachable nor free"));
+            }
+        }
+        checker.report
+    }
+}
+
+// Checker[#TODO] (should add some comments)
+impl Checker<'_> {
+    // record what the page is used as, return false if it can't be used
+    fn mark(&mut self, page_number: u64, owner: &'static str) -> bool {
+        let max_page = self.tree.freelist.max_page;
+        if page_number == 0 || page_number > max_page {
+            self.report.errors.push(format!(
+                "{owner} page {page_number} is out of range, max page is {max_page}"
+            ));
+            return false;
+        }
+        if let Some(used_as) = self.owners.insert(page_number, owner) {
+            self.report.errors.push(format!(
+                "page {page_number} is used as {used_as} and {owner}"
+            ));
+            self.owners.insert(page_number, used_as);
+            return false;
+        }
+        true
+    }
+
+    // keys of the subtree must be >= lower and < upper
+    fn check_node(
+        &mut self,
+        page_number: u64,
+        depth: usize,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) {
+        if !self.mark(page_number, "node") {
+            return;
+        }
+        let page = match self.tree.pager.pin_page(page_number) {
+            Ok(page) => page,
+            Err(err) => {
+                self.report
+                    .errors
+                    .push(format!("page {page_number} can't be read: {err:?}"));
+                return;
+            }
+        };
+        let view = SlottedPage::new(&page[..self.tree.pager.usable_size()]);
+        let page_type = Page::page_type(&page);
+        if !view.validate()
+            || (page_type != PAGE_TYPE_LEAF && page_type != PAGE_TYPE_INTERNAL)
+            || view.is_leaf() != (page_type == PAGE_TYPE_LEAF)
+        {
+            self.report
+                .errors
+                .push(format!("page {page_number} is not a valid node"));
+            return;
+        }
+
+        for idx in 0..view.len() {
+            let key = view.key(idx);
+            if idx > 0 && bytes::compare(view.key(idx - 1), key) != Ordering::Less {
+                self.report.errors.push(format!(
+                    "keys of page {page_number} are not sorted at {idx}"
+                ));
+            }
+            if lower.is_some_and(|lower| bytes::compare(key, lower) == Ordering::Less) {
+                self.report.errors.push(format!(
+                    "key {idx} of page {page_number} is smaller than its separator"
+                ));
+            }
+            if upper.is_some_and(|upper| bytes::compare(key, upper) != Ordering::Less) {
+                self.report.errors.push(format!(
+                    "key {idx} of page {page_number} is not smaller than the next separator"
+                ));
+            }
+        }
+
+        let page_size = self.tree.pager.page_size();
+        let used_size = view.used_size();
+        if is_overflow_size(used_size, page_size) {
+            self.report.errors.push(format!(
+                "page {page_number} overflows, {used_size} bytes used"
+            ));
+        }
+        if page_number != self.tree.metadata.root && is_underflow_size(used_size, page_size) {
+            self.report.errors.push(format!(
+                "page {page_number} underflows, {used_size} bytes used"
+            ));
+        }
+
+        if view.is_leaf() {
+            self.check_leaf(page_number, depth, view);
+            return;
+        }
+        self.report.internal_pages += 1;
+        if view.is_empty() && page_number == self.tree.metadata.root {
+            self.report
+                .errors
+                .push(format!("root page {page_number} has a single child"));
+        }
+        for idx in 0..=view.len() {
+            let child_lower = if idx == 0 {
+                lower
+            } else {
+                Some(view.key(idx - 1))
+            };
+            let child_upper = if idx == view.len() {
+                upper
+            } else {
+                Some(view.key(idx))
+            };
+            self.check_node(view.child(idx), depth + 1, child_lower, child_upper);
+        }
+    }
+
+    fn check_leaf(&mut self, page_number: u64, depth: usize, view: SlottedPage) {
+        self.report.leaf_pages += 1;
+        self.report.keys += view.len();
+        self.report.leaf_chain.push(page_number);
+        if *self.leaf_depth.get_or_insert(depth) != depth {
+            self.report.errors.push(format!(
+                "leaf {page_number} is at depth {depth}, other leaves are at {}",
+                self.leaf_depth.unwrap()
+            ));
+        }
+        if view.is_empty() {
+            return;
+        }
+        self.leaf_ranges.push((
+            page_number,
+            view.key(0).to_vec(),
+            view.key(view.len() - 1).to_vec(),
+        ));
+        for idx in 0..view.len() {
+            if let ValueRef::Overflow(first_page) = view.value(idx) {
+                if first_page == 0 {
+                    self.report.errors.push(format!(
+                        "value {idx} of page {page_number} has no overflow page"
+                    ));
+                    continue;
+                }
+                self.report.overflow_pages +=
+                    self.check_chain(first_page, PAGE_TYPE_OVERFLOW, "overflow");
+            }
+        }
+    }
+
+    // consecutive leaves of the chain must be in order and must not overlap
+    fn check_leaf_chain(&mut self) {
+        for pair in self.leaf_ranges.windows(2) {
+            let (prev, _, prev_last) = &pair[0];
+            let (next, next_first, _) = &pair[1];
+            if bytes::compare(prev_last, next_first) != Ordering::Less {
+                self.report.errors.push(format!(
+                    "leaf {next} overlaps leaf {prev} before it in the leaf chain"
+                ));
+            }
+        }
+    }
+
+    // walk a chain of overflow pages, return the number of pages in it
+    fn check_chain(&mut self, first_page: u64, page_type: u8, owner: &'static str) -> usize {
+        let capacity = self.tree.pager.usable_size() - HEAD_OVERFLOW_PAGE_SIZE;
+        let mut count = 0;
+        let mut page_number = first_page;
+        while page_number != 0 {
+            if !self.mark(page_number, owner) {
+                break;
+            }
+            count += 1;
+            let page = match self.tree.pager.pin_page(page_number) {
+                Ok(page) => page,
+                Err(err) => {
+                    self.report
+                        .errors
+                        .push(format!("page {page_number} can't be read: {err:?}"));
+                    break;
+                }
+            };
+            let len = u32::from_le_bytes(page[8..12].try_into().unwrap()) as usize;
+            if Page::page_type(&page) != page_type || len > capacity {
+                self.report
+                    .errors
+                    .push(format!("page {page_number} is not a valid {owner} page"));
+                break;
+            }
+            page_number = u64::from_le_bytes(page[0..8].try_into().unwrap());
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::builder::BTreeBuilder;
+    use crate::btree::constant::MIN_PAGE_SIZE;
+    use crate::btree::BTreeOptions;
+    use crate::bytes::VarintCodec;
+    use crate::util::testing::{temp_path, Lcg};
+
+    fn assert_ok(tree: &BTree) -> CheckReport {
+        let report = tree.check();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        report
+    }
+
+    #[test]
+    fn test_check_after_updates() {
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let path = temp_path("check-updates");
+        let mut tree = BTree::with_options(&path, &options);
+        assert_eq!(assert_ok(&tree).depth, 0);
+
+        // values of every size class, a few of them overflowed
+        let value = |i: u64| vec![i as u8; [10, 300, 5000][(i % 3) as usize]];
+        let mut rng = Lcg(7);
+        let mut keys = std::collections::BTreeSet::new();
+        for round in 0..6 {
+            for _ in 0..800 {
+                let key = rng.below(3000);
+                if round % 2 == 1 && keys.remove(&key) {
+                    tree.delete(&key.varint_encode()).unwrap();
+                } else if keys.insert(key) {
+                    tree.insert(&key.varint_encode(), &value(key));
+                }
+            }
+            tree.write_attachment(&vec![round as u8; 6000]);
+            // uncommitted changes are checked as well
+            assert_eq!(assert_ok(&tree).keys, keys.len());
+            tree.flush();
+            let report = assert_ok(&tree);
+            assert_eq!(report.keys, keys.len());
+            assert!(report.depth >= 2 && report.overflow_pages > 0);
+        }
+        drop(tree);
+
+        let tree = BTree::reader_with_options(&path, &options);
+        let report = assert_ok(&tree);
+        assert_eq!(report.keys, keys.len());
+        assert!(report.free_pages > 0);
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_check_bulk_loaded() {
+        let path = temp_path("check-bulk");
+        let mut tree = BTree::with_options(
+            &path,
+            &BTreeOptions {
+                page_size: MIN_PAGE_SIZE,
+                ..BTreeOptions::default()
+            },
+        );
+        let mut builder = BTreeBuilder::new(&mut tree).with_fill_factor(0.6);
+        for i in 0..5000u64 {
+            builder.add(&i.varint_encode(), format!("value-{i}").as_bytes());
+        }
+        builder.finish();
+        tree.flush();
+        let report = assert_ok(&tree);
+        assert_eq!(report.keys, 5000);
+        // every page is a node, a freelist page or free
+        assert_eq!(
+            report.leaf_pages + report.internal_pages + report.freelist_pages + report.free_pages,
+            tree.freelist.max_page as usize
+        );
+        // the leaf chain holds every leaf once, in key order
+        assert_eq!(report.leaf_chain.len(), report.leaf_pages);
+        let mut last = 0;
+        for i in (0..5000u64).step_by(7) {
+            let leaf = tree.find_path(&i.varint_encode()).unwrap().0.last().unwrap().0;
+            let position = report.leaf_chain.iter().position(|&page| page == leaf).unwrap();
+            assert!(position >= last);
+            last = position;
+        }
+        assert_eq!(last, report.leaf_chain.len() - 1);
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_check_finds_problems() {
+        let path = temp_path("check-problems");
+        let mut tree = BTree::with_options(
+            &path,
+            &BTreeOptions {
+                page_size: MIN_PAGE_SIZE,
+                ..BTreeOptions::default()
+            },
+        );
+        for i in 0..2000u64 {
+            tree.insert(&i.varint_encode(), format!("value-{i}").as_bytes());
+        }
+        tree.flush();
+        assert_ok(&tree);
+
+        // a page that is allocated but never used
+        let leaked = tree.allocate_page_number();
+        let report = tree.check();
+        assert_eq!(
+            report.errors,
+            vec![format!("page {leaked} is neither reachable nor free")]
+        );
+        tree.freelist.release_page(leaked);
+        tree.dirty_pages.remove(&leaked);
+        assert_ok(&tree);
+
+        // keys of the first leaf are swapped in place
+        let leaf = tree
+            .find_path(&0u64.varint_encode())
+            .unwrap()
+            .0
+            .last()
+            .unwrap()
+            .0;
+        let mut node = tree.get_node(leaf).unwrap();
+        node.leaf_data().keyvalues.swap(0, 1);
+        let mut page = tree.pager.read_page(leaf).unwrap();
+        let usable_size = tree.pager.usable_size();
+        node.serialize(&mut page.data[..usable_size]);
+        tree.pager.write_page(&mut page, PAGE_TYPE_LEAF);
+        let report = tree.check();
+        assert!(!report.is_ok());
+        assert!(
+            report.errors[0].contains("not sorted"),
+            "{:?}",
+            report.errors
+        );
+
+        // a page used both by the tree and the freelist
+        node.leaf_data().keyvalues.swap(0, 1);
+        node.serialize(&mut page.data[..usable_size]);
+        tree.pager.write_page(&mut page, PAGE_TYPE_LEAF);
+        tree.freelist.release_page(leaf);
+        let report = tree.check();
+        assert_eq!(
+            report.errors,
+            vec![format!("page {leaf} is used as node and free page")]
+        );
+        tree.freelist.released_pages.retain(|&page| page != leaf);
+        assert_ok(&tree);
+
+        // the first two leaves are swapped in their parent, the chain goes backwards
+        let nodes = tree.find_path(&0u64.varint_encode()).unwrap().0;
+        let parent = nodes[nodes.len() - 2].0;
+        let mut node = tree.get_node(parent).unwrap();
+        node.internal_data().children.swap(0, 1);
+        let mut page = tree.pager.read_page(parent).unwrap();
+        node.serialize(&mut page.data[..usable_size]);
+        tree.pager.write_page(&mut page, PAGE_TYPE_INTERNAL);
+        let report = tree.check();
+        let chain = &report.leaf_chain;
+        assert_eq!(chain[1], leaf);
+        let overlap = format!("leaf {leaf} overlaps leaf {} before it in the leaf chain", chain[0]);
+        assert!(
+            report.errors.contains(&overlap),
+            "{:?}",
+            report.errors
+        );
+
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+}
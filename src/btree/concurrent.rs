@@ -0,0 +1,640 @@
+// a tree shared by threads: lookups and cursors run concurrently with inserts and deletes.
+//
+// There is a single writer: the owner of the BTree, or the holder of the mutex of a SharedBTree.
+// Copy on write ties every change to one transaction (dirty pages, freelist, new root), so
+// writers can't run side by side; readers never wait on the writer as a whole, only on the
+// pages it is changing.
+//
+// Readers descend with latch crabbing: the tree latch (of the root pointer) is held until the
+// root is latched, and the latch of a page until the latch of its child is taken, so a reader
+// never follows a pointer that is being changed and holds at most two latches.
+//
+// - a writer latches exclusively, from the top down and before changing anything, every page
+//   it changes in place or frees: the dirty pages of its path, and the siblings it merges with
+//   or rebalances against. Pages of the committed version are never changed, readers on them
+//   go on undisturbed
+// - the tree latch is taken when the root may move, readers in the tree are not waited for
+// - overflowed values are read while the leaf is latched, a writer frees them only after it
+//   latched the leaf
+// - pages of older versions are freed on commit, but reused only once the readers that
+//   entered before the commit are gone, see Readers
+//
+// A cursor copies one leaf at a time and finds the next leaf from the root by the separator
+// above the copied one (leaf sibling links are not kept up to date under copy on write), so it
+// holds no latch between two calls of next. Keys are returned in order and at most once; keys
+// inserted or deleted while it runs may or may not be seen
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
+
+use super::buffer::PinnedPage;
+use super::check::CheckReport;
+use super::constant::{PAGE_TYPE_INTERNAL, PAGE_TYPE_LEAF, PAGE_TYPE_OVERFLOW};
+use super::error::Error;
+use super::latch::{LatchGuard, Latches, TREE_LATCH};
+use super::node::KeyValue;
+use super::overflow::read_chain;
+use super::pager::{Page, Pager};
+use super::slotted::SlottedPage;
+use super::BTree;
+use crate::bytes;
+
+// state shared by the writer and the readers of a tree
+pub(super) struct Shared {
+    pub latches: Arc<Latches>,
+    // root of the tree as seen by readers, changed under the tree latch
+    pub root: AtomicU64,
+    pub readers: Mutex<Readers>,
+}
+
+// readers in the tree by the number of commits when they entered. Pages freed by commit c may
+// still be read by readers that entered before it, they're reused once no reader with a
+// smaller number is left
+#[derive(Default)]
+pub(super) struct Readers {
+    commits: u64,
+    active: BTreeMap<u64, usize>,
+}
+
+// Readers[#TODO] (should add some comments)
+impl Readers {
+    // the number of the commit, pages it freed are tagged with it
+    pub fn commit(&mut self) -> u64 {
+        self.commits += 1;
+        self.commits
+    }
+
+    // pages tagged with a number up to this can be reused
+    pub fn reusable(&self) -> u64 {
+        self.active.keys().next().copied().unwrap_or(self.commits)
+    }
+}
+
+// a reader in the tree, it leaves when dropped
+struct Reading {
+    shared: Arc<Shared>,
+    entered: u64,
+}
+
+// Reading[#TODO] (should add some comments)
+impl Reading {
+    fn enter(shared: &Arc<Shared>) -> Self {
+        let mut readers = shared.readers.lock().unwrap();
+        let entered = readers.commits;
+        *readers.active.entry(entered).or_default() += 1;
+        Reading {
+            shared: shared.clone(),
+            entered,
+        }
+    }
+}
+
+// Drop[#TODO] (should add some comments)
+impl Drop for Reading {
+    fn drop(&mut self) {
+        let mut readers = self.shared.readers.lock().unwrap();
+        let count = readers.active.get_mut(&self.entered).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            readers.active.remove(&self.entered);
+        }
+    }
+}
+
+pub struct SharedBTree {
+    tree: Mutex<BTree>,
+    reader: BTreeReader,
+}
+
+// read handle of a shared tree, it can be cloned and sent to other threads
+#[derive(Clone)]
+pub struct BTreeReader {
+    pager: Arc<Pager>,
+    shared: Arc<Shared>,
+}
+
+// a leaf reached from the root, latched until it's dropped
+struct LatchedLeaf {
+    page: PinnedPage,
+    // smallest separator above the leaf that is greater than the searched key, where the next
+    // leaf starts; None for the last leaf
+    upper: Option<Vec<u8>>,
+    _latch: LatchGuard,
+    _reading: Reading,
+}
+
+// BTree[#TODO] (should add some comments)
+impl BTree {
+    // a reader of the tree for other threads while this one keeps writing it; the tree latches
+    // its pages from now on, as long as readers may be around
+    pub fn share(&mut self) -> BTreeReader {
+        let root = self.metadata.root;
+        let shared = self.shared.get_or_insert_with(|| {
+            Arc::new(Shared {
+                latches: Latches::new(),
+                root: AtomicU64::new(root),
+                readers: Mutex::new(Readers::default()),
+            })
+        });
+        BTreeReader {
+            pager: self.pager.clone(),
+            shared: shared.clone(),
+        }
+    }
+
+    // share the tree with writers as well, they take turns
+    pub fn into_shared(mut self) -> SharedBTree {
+        SharedBTree {
+            reader: self.share(),
+            tree: Mutex::new(self),
+        }
+    }
+}
+
+// SharedBTree[#TODO] (should add some comments)
+impl SharedBTree {
+    pub fn reader(&self) -> BTreeReader {
+        self.reader.clone()
+    }
+
+    pub fn find(&self, key: &[u8]) -> Result<KeyValue, Error> {
+        self.reader.find(key)
+    }
+
+    pub fn fuzz_find(&self, key: &[u8]) -> Result<KeyValue, Error> {
+        self.reader.fuzz_find(key)
+    }
+
+    pub fn iter(&self) -> ReaderIter {
+        self.reader.iter()
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8]) {
+        self.tree.lock().unwrap().insert(key, value)
+    }
+
+    pub fn try_insert(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.tree.lock().unwrap().try_insert(key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<KeyValue, Error> {
+        self.tree.lock().unwrap().delete(key)
+    }
+
+    pub fn flush(&self) {
+        self.tree.lock().unwrap().flush()
+    }
+
+    // readers go on while the tree is checked, writers wait
+    pub fn check(&self) -> CheckReport {
+        self.tree.lock().unwrap().check()
+    }
+
+    pub fn into_inner(self) -> BTree {
+        self.tree.into_inner().unwrap()
+    }
+}
+
+// BTreeReader[#TODO] (should add some comments)
+impl BTreeReader {
+    pub fn find(&self, key: &[u8]) -> Result<KeyValue, Error> {
+        let leaf = self.find_leaf(Some(key))?;
+        let view = SlottedPage::new(&leaf.page[..self.pager.usable_size()]);
+        match view.find_key_in_leaf(key) {
+            (true, index) => self.load_value(view.key_value(index)),
+            (false, _) => Err(Error::KeyNotFound),
+        }
+    }
+
+    // the key value with the biggest key not greater than the key, see BTree::fuzz_find
+    pub fn fuzz_find(&self, key: &[u8]) -> Result<KeyValue, Error> {
+        let leaf = self.find_leaf(Some(key))?;
+        let view = SlottedPage::new(&leaf.page[..self.pager.usable_size()]);
+        let (found, index) = view.find_key_in_leaf(key);
+        if !found && index == 0 {
+            return Err(Error::KeyNotFound);
+        }
+        let index = if found { index } else { index - 1 };
+        self.load_value(view.key_value(index))
+    }
+
+    pub fn iter(&self) -> ReaderIter {
+        ReaderIter {
+            reader: self.clone(),
+            items: Vec::new().into_iter(),
+            next_leaf: Some(None),
+            last_key: None,
+            from: None,
+        }
+    }
+
+    // iterate from the first key not less than the key, see BTree::iter_from
+    pub fn iter_from(&self, key: &[u8]) -> ReaderIter {
+        ReaderIter {
+            reader: self.clone(),
+            items: Vec::new().into_iter(),
+            next_leaf: Some(Some(key.to_vec())),
+            last_key: None,
+            from: Some(key.to_vec()),
+        }
+    }
+
+    // descend with latch crabbing to the leaf that may hold the key, or to the first leaf if
+    // the key is None
+    fn find_leaf(&self, key: Option<&[u8]>) -> Result<LatchedLeaf, Error> {
+        let reading = Reading::enter(&self.shared);
+        let tree_latch = self.shared.latches.shared(TREE_LATCH);
+        let root = self.shared.root.load(atomic::Ordering::Acquire);
+        if root == 0 {
+            return Err(Error::EmptyTree);
+        }
+        let mut latch = self.shared.latches.shared(root);
+        drop(tree_latch);
+        let mut page_number = root;
+        let mut upper = None;
+        loop {
+            let page = self.pager.pin_page(page_number)?;
+            let view = SlottedPage::new(&page[..self.pager.usable_size()]);
+            match Page::page_type(&page) {
+                PAGE_TYPE_LEAF if view.is_leaf() => {
+                    return Ok(LatchedLeaf {
+                        page,
+                        upper,
+                        _latch: latch,
+                        _reading: reading,
+                    });
+                }
+                PAGE_TYPE_INTERNAL if !view.is_leaf() => {}
+                _ => return Err(Error::Corrupted),
+            }
+            let (index, child) = match key {
+                Some(key) => view.find_key_in_internal(key),
+                None => (0, view.child(0)),
+            };
+            if index < view.len() {
+                upper = Some(view.key(index).to_vec());
+            }
+            // the parent latch is released once the child is latched
+            latch = self.shared.latches.shared(child);
+            page_number = child;
+        }
+    }
+
+    fn load_value(&self, mut kv: KeyValue) -> Result<KeyValue, Error> {
+        if kv.overflow != 0 {
+            kv.value = read_chain(&self.pager, kv.overflow, PAGE_TYPE_OVERFLOW)?.0;
+            kv.overflow = 0;
+        }
+        Ok(kv)
+    }
+}
+
+// ReaderIter[#TODO] (should add some comments)
+pub struct ReaderIter {
+    reader: BTreeReader,
+    // rest of the copied leaf
+    items: std::vec::IntoIter<KeyValue>,
+    // where the next leaf starts, Some(None) for the first leaf and None after the last one
+    next_leaf: Option<Option<Vec<u8>>>,
+    last_key: Option<Vec<u8>>,
+    // the key iter_from started at, keys before it are left out of the first leaf
+    from: Option<Vec<u8>>,
+}
+
+// ReaderIter[#TODO] (should add some comments)
+impl ReaderIter {
+    // copy the next leaf, with the keys returned already left out
+    fn load_leaf(&mut self) -> Result<(), Error> {
+        let Some(start) = self.next_leaf.take() else {
+            return Ok(());
+        };
+        let from = self.from.take();
+        let leaf = match self.reader.find_leaf(start.as_deref()) {
+            Err(Error::EmptyTree) => return Ok(()),
+            leaf => leaf?,
+        };
+        let view = SlottedPage::new(&leaf.page[..self.reader.pager.usable_size()]);
+        let mut items = Vec::with_capacity(view.len());
+        for index in 0..view.len() {
+            let key = view.key(index);
+            if self
+                .last_key
+                .as_deref()
+                .is_some_and(|last_key| bytes::compare(key, last_key) != Ordering::Greater)
+                || from
+                    .as_deref()
+                    .is_some_and(|from| bytes::compare(key, from) == Ordering::Less)
+            {
+                continue;
+            }
+            items.push(self.reader.load_value(view.key_value(index))?);
+        }
+        self.items = items.into_iter();
+        self.next_leaf = leaf.upper.map(Some);
+        Ok(())
+    }
+}
+
+// Iterator[#TODO] (should add some comments)
+impl Iterator for ReaderIter {
+    type Item = Result<KeyValue, Error>;
+
+    // a page that can't be read ends the iteration with its error, load_leaf has taken the
+    // next leaf already
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.items.next() {
+                self.last_key = Some(item.key.clone());
+                return Some(Ok(item));
+            }
+            self.next_leaf.as_ref()?;
+            if let Err(err) = self.load_leaf() {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::buffer::BufferPool;
+    use crate::btree::constant::{MIN_PAGE_SIZE, PAGE_TRAILER_SIZE, PAGE_TYPE_LEAF};
+    use crate::btree::BTreeOptions;
+    use crate::bytes::VarintCodec;
+    use crate::util::testing::{temp_path, Lcg};
+    use std::collections::BTreeMap;
+
+    // values of stable keys never change, a few of them are overflowed
+    fn stable_value(key: u64) -> Vec<u8> {
+        let size = if key.is_multiple_of(100) { 5000 } else { 40 };
+        (0..size).map(|i| (key + i) as u8).collect()
+    }
+
+    #[test]
+    fn test_reader() {
+        let path = &temp_path("reader");
+        let tree = BTree::new(path).into_shared();
+        assert_eq!(
+            tree.find(&1u64.varint_encode()).err(),
+            Some(Error::EmptyTree)
+        );
+        assert_eq!(tree.iter().count(), 0);
+
+        for i in 0..3000u64 {
+            tree.insert(&(i * 2).varint_encode(), &stable_value(i * 2));
+        }
+        let reader = tree.reader();
+        assert_eq!(
+            reader.find(&200u64.varint_encode()).unwrap().value,
+            stable_value(200)
+        );
+        assert_eq!(
+            reader.find(&201u64.varint_encode()).err(),
+            Some(Error::KeyNotFound)
+        );
+        assert_eq!(
+            reader.fuzz_find(&201u64.varint_encode()).unwrap().key,
+            200u64.varint_encode()
+        );
+        let keys: Vec<Vec<u8>> = reader.iter().map(|kv| kv.unwrap().key).collect();
+        assert_eq!(keys.len(), 3000);
+        assert!(keys
+            .iter()
+            .zip((0..3000u64).map(|i| (i * 2).varint_encode()))
+            .all(|(key, expected)| *key == expected));
+        let from: Vec<Vec<u8>> = reader
+            .iter_from(&201u64.varint_encode())
+            .map(|kv| kv.unwrap().key)
+            .collect();
+        assert_eq!(from.len(), 3000 - 101);
+        assert_eq!(from[0], 202u64.varint_encode());
+
+        // a cursor goes on after the tree changed under it
+        let mut iter = reader.iter();
+        assert_eq!(iter.nth(10).unwrap().unwrap().key, 20u64.varint_encode());
+        for i in 0..3000u64 {
+            tree.delete(&(i * 2).varint_encode()).unwrap();
+            tree.insert(&(i * 2 + 1).varint_encode(), b"odd");
+        }
+        // the rest of the copied leaf, then the keys of the changed tree after it
+        let rest: Vec<u64> = iter.map(|kv| u64::varint_decode(&kv.unwrap().key).1).collect();
+        assert!(rest[0] > 20);
+        assert!(rest.windows(2).all(|keys| keys[0] < keys[1]));
+        let last_even = rest
+            .iter()
+            .copied()
+            .filter(|key| key % 2 == 0)
+            .max()
+            .unwrap();
+        let odd: Vec<u64> = rest.iter().copied().filter(|key| key % 2 == 1).collect();
+        assert_eq!(odd, (last_even + 1..6000).step_by(2).collect::<Vec<_>>());
+
+        let mut tree = tree.into_inner();
+        tree.flush();
+        assert!(tree.check().is_ok());
+        drop(tree);
+        let _ = std::fs::remove_file(path);
+    }
+
+    // a leaf that fails its checksum ends the iteration with Error::Corrupted
+    #[test]
+    fn test_damaged_leaf() {
+        let path = temp_path("reader-damaged-leaf");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..2000u64 {
+            tree.insert(&i.varint_encode(), &stable_value(i));
+        }
+        drop(tree);
+        let mut file = std::fs::read(&path).unwrap();
+        let leaf = (1..file.len() / MIN_PAGE_SIZE)
+            .map(|page| page * MIN_PAGE_SIZE)
+            .find(|&page| file[page + MIN_PAGE_SIZE - PAGE_TRAILER_SIZE] == PAGE_TYPE_LEAF)
+            .unwrap();
+        file[leaf + MIN_PAGE_SIZE / 2] ^= 0xff;
+        std::fs::write(&path, file).unwrap();
+
+        let tree = BTree::reader_with_options(&path, &options).into_shared();
+        let mut items: Vec<Result<KeyValue, Error>> = tree.iter().collect();
+        assert_eq!(items.pop().unwrap().err(), Some(Error::Corrupted));
+        assert!(items.iter().all(Result::is_ok));
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // a reader on a leaf neither keeps writers out of the rest of the tree nor loses its pages
+    #[test]
+    fn test_crabbing() {
+        let path = temp_path("crabbing");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..3000u64 {
+            tree.insert(&i.varint_encode(), &stable_value(i));
+        }
+        tree.flush();
+        let reader = tree.share();
+        let first_leaf = tree.find_path(&0u64.varint_encode()).unwrap().0.last().unwrap().0;
+
+        // a reader stopped on the first leaf, it entered before the commits below
+        let reading = Reading::enter(&reader.shared);
+        let latch = reader.shared.latches.shared(first_leaf);
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = std::thread::spawn(move || {
+            // splits and merges at the other end of the tree, the root is copied
+            for i in 3000..4000u64 {
+                tree.insert(&i.varint_encode(), &stable_value(i));
+            }
+            for i in 2000..4000u64 {
+                tree.delete(&i.varint_encode()).unwrap();
+            }
+            tree.flush();
+            done.send(()).unwrap();
+            tree
+        });
+        finished
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .expect("writer waits on a reader it doesn't touch");
+        let mut tree = writer.join().unwrap();
+        assert!(!tree.retired_pages.is_empty());
+        assert!(tree
+            .retired_pages
+            .iter()
+            .all(|(_, page)| !tree.freelist.released_pages.contains(page)));
+        assert!(tree.check().is_ok());
+        // the leaf of the old version is still there
+        let page = reader.pager.pin_page(first_leaf).unwrap();
+        let view = SlottedPage::new(&page[..reader.pager.usable_size()]);
+        assert_eq!(view.key(0), 0u64.varint_encode());
+        drop((page, latch, reading));
+
+        // pages are reused once the reader is gone, only those of the last commit are left
+        let retired: Vec<u64> = tree.retired_pages.iter().map(|&(_, page)| page).collect();
+        tree.insert(&0u64.varint_encode(), b"zero");
+        tree.flush();
+        assert!(tree.retired_pages.iter().all(|(_, page)| !retired.contains(page)));
+        assert!(tree.check().is_ok());
+        assert_eq!(reader.find(&0u64.varint_encode()).unwrap().value, b"zero");
+        assert_eq!(reader.iter().count(), 2000);
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_concurrent_stress() {
+        for pool in [None, Some(Arc::new(BufferPool::new(64 << 20)))] {
+            let path = &temp_path(&format!("concurrent-{}", pool.is_some()));
+            let options = BTreeOptions {
+                page_size: MIN_PAGE_SIZE,
+                buffer_pool: pool,
+                ..BTreeOptions::default()
+            };
+            // even keys are stable, writers change odd keys and rewrite even keys with the same
+            // value
+            let mut tree = BTree::with_options(path, &options);
+            for key in (0..4000u64).step_by(2) {
+                tree.insert(&key.varint_encode(), &stable_value(key));
+            }
+            tree.flush();
+            let tree = tree.into_shared();
+
+            let writers: Vec<BTreeMap<u64, Vec<u8>>> = std::thread::scope(|scope| {
+                let writers: Vec<_> = (0..2u64)
+                    .map(|id| {
+                        let tree = &tree;
+                        scope.spawn(move || {
+                            let mut rng = Lcg(id + 1);
+                            let mut written = BTreeMap::new();
+                            for op in 0..3000 {
+                                // writer i owns the odd keys 4k + 2i + 1
+                                let key = rng.below(1000) * 4 + id * 2 + 1;
+                                match rng.below(4) {
+                                    0 => {
+                                        let removed = tree.delete(&key.varint_encode());
+                                        assert_eq!(
+                                            removed.ok().map(|kv| kv.value),
+                                            written.remove(&key)
+                                        );
+                                    }
+                                    1 => {
+                                        let stable = rng.below(2000) * 2;
+                                        tree.insert(&stable.varint_encode(), &stable_value(stable));
+                                    }
+                                    _ => {
+                                        let value = vec![op as u8; rng.below(3000) as usize];
+                                        tree.insert(&key.varint_encode(), &value);
+                                        written.insert(key, value);
+                                    }
+                                }
+                                if op % 500 == 499 {
+                                    tree.flush();
+                                }
+                            }
+                            written
+                        })
+                    })
+                    .collect();
+
+                for id in 0..3u64 {
+                    let reader = tree.reader();
+                    scope.spawn(move || {
+                        let mut rng = Lcg(id + 100);
+                        for round in 0..1500 {
+                            let key = rng.below(2000) * 2;
+                            let found = reader.find(&key.varint_encode()).unwrap();
+                            assert_eq!(found.value, stable_value(key));
+                            let fuzz = reader.fuzz_find(&key.varint_encode()).unwrap();
+                            assert_eq!(fuzz.key, found.key);
+
+                            if round % 300 == 0 {
+                                let mut last = None;
+                                let mut stable = 0;
+                                for kv in reader.iter() {
+                                    let kv = kv.unwrap();
+                                    let key = u64::varint_decode(&kv.key).1;
+                                    assert!(last < Some(key), "{last:?} {key}");
+                                    last = Some(key);
+                                    if key % 2 == 0 {
+                                        assert_eq!(kv.value, stable_value(key));
+                                        stable += 1;
+                                    }
+                                }
+                                assert_eq!(stable, 2000);
+                            }
+                        }
+                    });
+                }
+                writers.into_iter().map(|w| w.join().unwrap()).collect()
+            });
+
+            let report = tree.check();
+            assert!(report.is_ok(), "{:?}", report.errors);
+            let mut expected: BTreeMap<u64, Vec<u8>> = (0..4000u64)
+                .step_by(2)
+                .map(|key| (key, stable_value(key)))
+                .collect();
+            writers
+                .into_iter()
+                .for_each(|written| expected.extend(written));
+            let items: Vec<(u64, Vec<u8>)> = tree
+                .iter()
+                .map(|kv| kv.unwrap())
+                .map(|kv| (u64::varint_decode(&kv.key).1, kv.value))
+                .collect();
+            assert_eq!(items, expected.into_iter().collect::<Vec<_>>());
+
+            let mut tree = tree.into_inner();
+            tree.flush();
+            assert!(tree.check().is_ok());
+            drop(tree);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
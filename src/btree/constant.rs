@@ -1,23 +1,49 @@
-use std::usize;
-
 // 1B nodetype
+// 4B for slot count
+// 4B for heap start
 // 8B pointer to prev node
 // 8B pointer to next node
-// 8B for key count
-pub const HEAD_LEAF_NODE_SIZE: usize = 1 + 8 + 8 + 8;
+pub const HEAD_LEAF_NODE_SIZE: usize = 1 + 4 + 4 + 8 + 8;
 
 // 1B for node type
-// 8B for key count
-// 8B for child count
-pub const HEAD_INTERNAL_NODE_SIZE: usize = 1 + 8 + 8;
-
-pub const DEFAULT_PAGE_SIZE: usize = 1024 * 1024;
+// 4B for slot count
+// 4B for heap start
+// 8B for the leftmost child
+pub const HEAD_INTERNAL_NODE_SIZE: usize = 1 + 4 + 4 + 8;
+
+// page size is chosen per tree and stored in the meta page, must be a power of two in
+// [MIN_PAGE_SIZE, MAX_PAGE_SIZE]
+pub const DEFAULT_PAGE_SIZE: usize = 16 * 1024;
+pub const MIN_PAGE_SIZE: usize = 4 * 1024;
+pub const MAX_PAGE_SIZE: usize = 1024 * 1024;
+
+// every page ends with a trailer, nodes and overflow data only use the bytes before it
+// |----------------------------------|
+// | page type | checksum(crc32)      |
+// |----------------------------------|
+// |  1B       |  4B                  |
+// |----------------------------------|
+pub const PAGE_TRAILER_SIZE: usize = 1 + 4;
+
+pub const PAGE_TYPE_META: u8 = 1;
+pub const PAGE_TYPE_LEAF: u8 = 2;
+pub const PAGE_TYPE_INTERNAL: u8 = 3;
+pub const PAGE_TYPE_OVERFLOW: u8 = 4;
+pub const PAGE_TYPE_FREELIST: u8 = 5;
+
+// first bytes of every btree file
+pub const MAGIC: [u8; 8] = *b"MINTKVBT";
+// bumped on every incompatible change of the file format. Version 1 is the first versioned
+// format: the header, checked pages, slotted nodes and the page size of the tree. Files written
+// before it have no header and are rejected, they are not read or converted
+pub const FORMAT_VERSION: u32 = 1;
 
 pub const DEFAULT_META_PN: u64 = 0;
 /* pub const DEFAULT_FREELIST_PN: u64 = 1; */
 
-pub const DEFAULT_MAX_THRESHOLD: f64 = 0.90 * DEFAULT_PAGE_SIZE as f64;
-pub const DEFAULT_MIN_THRESHOLD: f64 = 0.25 * DEFAULT_PAGE_SIZE as f64;
+// node split / merge thresholds, as a fraction of the page size
+pub const DEFAULT_MAX_THRESHOLD: f64 = 0.90;
+pub const DEFAULT_MIN_THRESHOLD: f64 = 0.25;
 
 /* pub const DEFAULT_MAX_KEY_SIZE: usize = 32;
 pub const DEFAULT_MAX_VALUE_SIZE: usize = 128; */
@@ -32,5 +58,3 @@ pub const DEFAULT_MAX_LEAF_ITEMS_NUM: f64 = ((DEFAULT_PAGE_SIZE - HEAD_LEAF_NODE
 
 pub const DEFAULT_MAX_INTERNAL_ITEMS_NUM: f64 =
     ((DEFAULT_PAGE_SIZE - HEAD_INTERNAL_NODE_SIZE) as f64).div(DEFAULT_MAX_KEY_SIZE as f64 + 8.0); */
-
-
@@ -1,7 +1,13 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     EmptyTree,
     KeyNotFound,
     PageLoadErr,
     Generic,
+    // checksum or type of a page does not match, the page must not be used
+    Corrupted,
+    // not a btree file, or written by an unsupported format version
+    IncompatibleFormat,
+    // a key larger than max_inline_value_size, it can't be stored in a node
+    KeyTooLarge,
 }
@@ -0,0 +1,254 @@
+// export the structure of a tree for debugging splits and merges, as a Graphviz DOT graph
+// (`dot -Tsvg`) or as JSON. Keys are shown as the u64 they encode, or in hex if they are not
+// a varint. Leaves are not linked on disk (see LeafNode), their prev/next are derived from the
+// walk, as CheckReport::leaf_chain is
+use std::fmt::Write;
+
+use super::error::Error;
+use super::slotted::SlottedPage;
+use super::BTree;
+use crate::bytes::VarintCodec;
+
+struct NodeInfo {
+    page_number: u64,
+    // 0 is the root
+    level: usize,
+    is_leaf: bool,
+    keys: Vec<String>,
+    // used bytes of the page
+    fill_ratio: f64,
+    children: Vec<u64>,
+    // neighbours in the leaf chain, 0 at either end
+    prev_leaf: u64,
+    next_leaf: u64,
+}
+
+fn key_label(key: &[u8]) -> String {
+    // a u64 takes at most 10 bytes
+    if key.len() <= 10 {
+        let (size, value) = u64::varint_decode(key);
+        if size == key.len() && value.varint_encode() == key {
+            return value.to_string();
+        }
+    }
+    key.iter().fold(String::from("0x"), |mut label, byte| {
+        let _ = write!(label, "{byte:02x}");
+        label
+    })
+}
+
+// BTree[#TODO] (should add some comments)
+impl BTree {
+    // nodes level by level, left to right
+    fn collect_nodes(&self) -> Result<Vec<NodeInfo>, Error> {
+        let mut nodes = Vec::new();
+        if self.metadata.root == 0 {
+            return Ok(nodes);
+        }
+        let usable_size = self.pager.usable_size();
+        let mut level = vec![self.metadata.root];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for page_number in level {
+                let page = self.get_page(page_number)?;
+                let view = SlottedPage::new(&page[..usable_size]);
+                let mut node = NodeInfo {
+                    page_number,
+                    level: depth,
+                    is_leaf: view.is_leaf(),
+                    keys: (0..view.len())
+                        .map(|idx| key_label(view.key(idx)))
+                        .collect(),
+                    fill_ratio: view.used_size() as f64 / self.pager.page_size() as f64,
+                    children: Vec::new(),
+                    prev_leaf: 0,
+                    next_leaf: 0,
+                };
+                if !view.is_leaf() {
+                    node.children = view.children();
+                    next_level.extend(&node.children);
+                }
+                nodes.push(node);
+            }
+            level = next_level;
+            depth += 1;
+        }
+        // leaves are reached left to right, every one is linked to the one reached before it
+        let leaves: Vec<usize> = (0..nodes.len()).filter(|&idx| nodes[idx].is_leaf).collect();
+        for pair in leaves.windows(2) {
+            nodes[pair[0]].next_leaf = nodes[pair[1]].page_number;
+            nodes[pair[1]].prev_leaf = nodes[pair[0]].page_number;
+        }
+        Ok(nodes)
+    }
+
+    // internal nodes list all their keys with a port per child, leaves only their key range
+    pub fn to_dot(&self) -> Result<String, Error> {
+        let nodes = self.collect_nodes()?;
+        let mut dot = String::from("digraph btree {\n    node [shape=record];\n");
+        for node in nodes.iter() {
+            let fill = node.fill_ratio * 100.0;
+            let label = if node.is_leaf {
+                let range = match (node.keys.first(), node.keys.last()) {
+                    (Some(first), Some(last)) => format!("{first} .. {last}"),
+                    _ => String::from("empty"),
+                };
+                format!(
+                    "{{page {} ({fill:.0}%)|{range}|{} keys}}",
+                    node.page_number,
+                    node.keys.len()
+                )
+            } else {
+                let mut cells = String::from("<c0>");
+                for (idx, key) in node.keys.iter().enumerate() {
+                    let _ = write!(cells, "|{key}|<c{}>", idx + 1);
+                }
+                format!("{{page {} ({fill:.0}%)|{{{cells}}}}}", node.page_number)
+            };
+            let _ = writeln!(dot, "    p{} [label=\"{label}\"];", node.page_number);
+        }
+        for node in nodes.iter() {
+            for (idx, child) in node.children.iter().enumerate() {
+                let _ = writeln!(dot, "    p{}:c{idx} -> p{child};", node.page_number);
+            }
+            if node.next_leaf != 0 {
+                let _ = writeln!(
+                    dot,
+                    "    p{} -> p{} [style=dashed, constraint=false];",
+                    node.page_number, node.next_leaf
+                );
+            }
+        }
+        let depth = nodes.last().map_or(0, |node| node.level + 1);
+        for level in 0..depth {
+            let pages: Vec<String> = nodes
+                .iter()
+                .filter(|node| node.level == level)
+                .map(|node| format!("p{}", node.page_number))
+                .collect();
+            let _ = writeln!(dot, "    {{ rank=same; {}; }}", pages.join("; "));
+        }
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    // keys are strings since a u64 does not always fit in a JSON number
+    pub fn to_json(&self) -> Result<String, Error> {
+        let nodes = self.collect_nodes()?;
+        let mut json = format!(
+            "{{\"page_size\":{},\"root\":{},\"depth\":{},\"nodes\":[",
+            self.pager.page_size(),
+            self.metadata.root,
+            nodes.last().map_or(0, |node| node.level + 1)
+        );
+        for (idx, node) in nodes.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            let keys: Vec<String> = node.keys.iter().map(|key| format!("\"{key}\"")).collect();
+            let _ = write!(
+                json,
+                "{{\"page\":{},\"level\":{},\"type\":\"{}\",\"fill_ratio\":{:.3},\"keys\":[{}]",
+                node.page_number,
+                node.level,
+                if node.is_leaf { "leaf" } else { "internal" },
+                node.fill_ratio,
+                keys.join(",")
+            );
+            if node.is_leaf {
+                let _ = write!(
+                    json,
+                    ",\"prev\":{},\"next\":{}}}",
+                    node.prev_leaf, node.next_leaf
+                );
+            } else {
+                let children: Vec<String> = node.children.iter().map(u64::to_string).collect();
+                let _ = write!(json, ",\"children\":[{}]}}", children.join(","));
+            }
+        }
+        json.push_str("]}\n");
+        Ok(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::constant::MIN_PAGE_SIZE;
+    use crate::btree::BTreeOptions;
+    use crate::util::testing::temp_path;
+
+    #[test]
+    fn test_export() {
+        let path = &temp_path("export");
+        let mut tree = BTree::with_options(
+            path,
+            &BTreeOptions {
+                page_size: MIN_PAGE_SIZE,
+                ..BTreeOptions::default()
+            },
+        );
+        assert_eq!(
+            tree.to_dot().unwrap(),
+            "digraph btree {\n    node [shape=record];\n}\n"
+        );
+        assert_eq!(
+            tree.to_json().unwrap(),
+            "{\"page_size\":4096,\"root\":0,\"depth\":0,\"nodes\":[]}\n"
+        );
+
+        for i in 0..1000u64 {
+            tree.insert(
+                &(i * 3).varint_encode(),
+                format!("value-{i:0>40}").as_bytes(),
+            );
+        }
+        let report = tree.check();
+        let pages = report.leaf_pages + report.internal_pages;
+
+        let dot = tree.to_dot().unwrap();
+        assert!(dot.starts_with("digraph btree {"));
+        assert_eq!(dot.matches(" [label=").count(), pages);
+        // every node but the root has an edge from its parent
+        assert_eq!(
+            dot.matches(" -> ").count() - dot.matches("dashed").count(),
+            pages - 1
+        );
+        assert!(dot.contains(&format!(
+            "p{} [label=\"{{page {} (",
+            tree.metadata.root, tree.metadata.root
+        )));
+        assert!(dot.contains("|0 .. "));
+
+        let json = tree.to_json().unwrap();
+        assert!(json.starts_with(&format!(
+            "{{\"page_size\":4096,\"root\":{},\"depth\":{},\"nodes\":[{{\"page\":{},\"level\":0,\"type\":\"internal\"",
+            tree.metadata.root, report.depth, tree.metadata.root
+        )));
+        assert_eq!(json.matches("\"type\":\"leaf\"").count(), report.leaf_pages);
+        assert!(json.contains("\"keys\":[\"0\",\"3\","));
+        assert!(json.contains("\"2997\"]"));
+        // prev/next follow the leaf chain of the check
+        let chain = &report.leaf_chain;
+        assert_eq!(dot.matches("dashed").count(), chain.len() - 1);
+        for (idx, leaf) in chain.iter().enumerate() {
+            let prev = if idx == 0 { 0 } else { chain[idx - 1] };
+            let next = chain.get(idx + 1).copied().unwrap_or(0);
+            assert!(json.contains(&format!(
+                "{{\"page\":{leaf},\"level\":{},\"type\":\"leaf\"",
+                report.depth - 1
+            )));
+            assert!(json.contains(&format!(",\"prev\":{prev},\"next\":{next}}}")));
+        }
+
+        assert_eq!(key_label(&[0x80, 0x01]), "128");
+        // not the shortest encoding, so not a varint key
+        assert_eq!(key_label(&[0x80, 0x00]), "0x8000");
+        assert_eq!(key_label(b"ab"), "0x6162");
+        assert_eq!(key_label(&[0xff; 12]), format!("0x{}", "ff".repeat(12)));
+
+        drop(tree);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,3 +1,11 @@
+// freelist 记录已经释放的页面, 分配页面时优先复用
+//
+// freelist format, the whole list may span several chained pages (see overflow.rs)
+// |-------------------------------------------------|
+// | max page | released count | released pages ...  |
+// |-------------------------------------------------|
+// |   8B     |   4B           | 8B * count          |
+// |-------------------------------------------------|
 #[derive(Default, Debug)]
 pub struct Freelist {
     pub max_page: u64, // 8B
@@ -14,9 +22,18 @@ impl Freelist {
         })
     }
 
-    pub fn release_page(&mut self, _page_number: u64) {
-        // todo 可能需要多个页面来存放released page
-        /* self.released_pages.push(page_number) */
+    // the meta page and pages released twice are ignored
+    pub fn release_page(&mut self, page_number: u64) {
+        if page_number == 0 || self.released_pages.contains(&page_number) {
+            return;
+        }
+        self.released_pages.push(page_number)
+    }
+
+    // serialized size in bytes
+    #[inline]
+    pub fn size(&self) -> usize {
+        8 + 4 + self.released_pages.len() * 8
     }
 
     pub fn serialize(&self, buffer: &mut [u8]) {
@@ -26,8 +43,8 @@ impl Freelist {
         offset += 8;
 
         let freeed_cnt = self.released_pages.len();
-        buffer[offset..offset + 2].clone_from_slice(u16::to_le_bytes(freeed_cnt as u16).as_ref());
-        offset += 2;
+        buffer[offset..offset + 4].clone_from_slice(u32::to_le_bytes(freeed_cnt as u32).as_ref());
+        offset += 4;
 
         for i in 0..freeed_cnt {
             let element = self.released_pages[i];
@@ -36,22 +53,25 @@ impl Freelist {
         }
     }
 
+    // an incomplete buffer leaves an empty freelist
     pub fn deserialize(&mut self, buffer: &[u8]) {
-        if buffer.len() < 2 {
+        *self = Freelist::default();
+        if buffer.len() < 12 {
             return;
         }
         let mut offset = 0;
 
-        self.max_page = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+        let max_page = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
         offset += 8;
 
         // get freed cnt
-        let freeed_cnt = u16::from_le_bytes(buffer[offset..offset + 2].try_into().unwrap());
-        offset += 2;
-        if freeed_cnt == 0 {
+        let freeed_cnt = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+        offset += 4;
+        if buffer.len() < offset + freeed_cnt as usize * 8 {
             return;
         }
 
+        self.max_page = max_page;
         for _ in 0..freeed_cnt {
             let element = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
             self.released_pages.push(element);
@@ -63,10 +83,11 @@ impl Freelist {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
     fn test_freelist_basic_operations() {
         let free_size = |freelist: &Freelist| -> usize {
             8 + // max_page
-        2 + // released_pages计数
+        4 + // released_pages计数
         freelist.released_pages.len() * 8 // 每个released页面的大小
         };
         // 创建一个Freelist实例
@@ -87,7 +108,7 @@ mod tests {
         assert_eq!(freelist.get_next_page(), 1);
 
         // 释放多页并验证序列化和反序列化
-        let pages_to_release = vec![300, 400, 500];
+        let pages_to_release = [300, 400, 500];
         for page in pages_to_release.iter() {
             freelist.release_page(*page);
         }
@@ -114,6 +135,7 @@ mod tests {
         assert_eq!(deserialized_freelist.get_next_page(), freelist.max_page + 1);
     }
 
+    #[test]
     fn test_freelist_basic_functionality() {
         let mut freelist = Freelist::default();
         assert_eq!(freelist.get_next_page(), 1); // 第一个获取的页面应该是1
@@ -122,6 +144,7 @@ mod tests {
         freelist.release_page(2); // 尝试释放一个未分配的页面，不会有影响
     }
 
+    #[test]
     fn test_freelist_serialization_deserialization() {
         let mut freelist = Freelist::default();
         freelist.get_next_page(); // 获取页面1
@@ -143,6 +166,7 @@ mod tests {
         assert_eq!(deserialized_freelist.get_next_page(), 4); // 接下来是新分配的页面3
     }
 
+    #[test]
     fn test_freelist_boundary_conditions() {
         let mut freelist = Freelist::default();
         for _ in 0..10 {
@@ -157,6 +181,7 @@ mod tests {
         assert_eq!(freelist.get_next_page(), 11); // 接下来应该是新分配的页面11
     }
 
+    #[test]
     fn test_freelist_exception_handling() {
         let mut freelist = Freelist::default();
         freelist.get_next_page(); // 获取页面1
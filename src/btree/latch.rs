@@ -0,0 +1,168 @@
+// page latches of a tree shared by threads, see concurrent.rs
+//
+// a latch is held by any number of readers or by one writer, and a waiting writer keeps new
+// readers out so it is not starved. Latches are always taken from the root down, so holders
+// never wait on each other in a cycle. The latch of page 0, the meta page holding the root,
+// is the tree latch
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::constant::DEFAULT_META_PN;
+
+const LATCH_SHARD_NUM: usize = 16;
+
+pub const TREE_LATCH: u64 = DEFAULT_META_PN;
+
+#[derive(Default)]
+struct LatchState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+#[derive(Default)]
+struct LatchShard {
+    // only pages that are latched or waited for have a state
+    latches: Mutex<HashMap<u64, LatchState>>,
+    released: Condvar,
+}
+
+pub struct Latches {
+    shards: Vec<LatchShard>,
+}
+
+// LatchGuard releases the latch when dropped
+pub struct LatchGuard {
+    latches: Arc<Latches>,
+    page_number: u64,
+    exclusive: bool,
+}
+
+// Latches[#TODO] (should add some comments)
+impl Latches {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Latches {
+            shards: (0..LATCH_SHARD_NUM)
+                .map(|_| LatchShard::default())
+                .collect(),
+        })
+    }
+
+    #[inline]
+    fn shard(&self, page_number: u64) -> &LatchShard {
+        &self.shards[page_number as usize % self.shards.len()]
+    }
+
+    pub fn shared(self: &Arc<Self>, page_number: u64) -> LatchGuard {
+        let shard = self.shard(page_number);
+        let mut latches = shard.latches.lock().unwrap();
+        loop {
+            let state = latches.entry(page_number).or_default();
+            if !state.writer && state.waiting_writers == 0 {
+                state.readers += 1;
+                break;
+            }
+            latches = shard.released.wait(latches).unwrap();
+        }
+        LatchGuard {
+            latches: self.clone(),
+            page_number,
+            exclusive: false,
+        }
+    }
+
+    pub fn exclusive(self: &Arc<Self>, page_number: u64) -> LatchGuard {
+        let shard = self.shard(page_number);
+        let mut latches = shard.latches.lock().unwrap();
+        latches.entry(page_number).or_default().waiting_writers += 1;
+        loop {
+            let state = latches.get_mut(&page_number).unwrap();
+            if !state.writer && state.readers == 0 {
+                state.waiting_writers -= 1;
+                state.writer = true;
+                break;
+            }
+            latches = shard.released.wait(latches).unwrap();
+        }
+        LatchGuard {
+            latches: self.clone(),
+            page_number,
+            exclusive: true,
+        }
+    }
+
+    fn release(&self, page_number: u64, exclusive: bool) {
+        let shard = self.shard(page_number);
+        let mut latches = shard.latches.lock().unwrap();
+        let state = latches.get_mut(&page_number).unwrap();
+        if exclusive {
+            state.writer = false;
+        } else {
+            state.readers -= 1;
+        }
+        if state.readers == 0 && !state.writer && state.waiting_writers == 0 {
+            latches.remove(&page_number);
+        }
+        shard.released.notify_all();
+    }
+}
+
+// Drop[#TODO] (should add some comments)
+impl Drop for LatchGuard {
+    fn drop(&mut self) {
+        self.latches.release(self.page_number, self.exclusive);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_latches() {
+        let latches = Latches::new();
+        // readers share a latch, latches of other pages are independent
+        let first = latches.shared(1);
+        let second = latches.shared(1);
+        let other = latches.exclusive(17);
+        drop((first, second, other));
+
+        let writer = latches.exclusive(1);
+        let readers = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _reader = latches.shared(1);
+                readers.fetch_add(1, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(readers.load(Ordering::SeqCst), 0);
+            drop(writer);
+        });
+        assert_eq!(readers.load(Ordering::SeqCst), 1);
+
+        // a waiting writer keeps new readers out
+        let reader = latches.shared(1);
+        let order = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _writer = latches.exclusive(1);
+                order.lock().unwrap().push("writer");
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            scope.spawn(|| {
+                let _reader = latches.shared(1);
+                order.lock().unwrap().push("reader");
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(order.lock().unwrap().is_empty());
+            drop(reader);
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["writer", "reader"]);
+        assert!(latches
+            .shards
+            .iter()
+            .all(|shard| shard.latches.lock().unwrap().is_empty()));
+    }
+}
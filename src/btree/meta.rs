@@ -1,11 +1,94 @@
+use super::constant::{FORMAT_VERSION, MAGIC, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+use super::error::Error;
+use crate::util::crc32;
+
+// meta page starts with a header that never changes after the tree is created, it's checked
+// before anything else of the file is trusted
+//
+// header format
+// |------------------------------------------|
+// | magic | format version | page size       |
+// |------------------------------------------|
+// |  8B   |  4B            |  4B             |
+// |------------------------------------------|
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    pub format_version: u32,
+    pub page_size: u32,
+}
+
+pub const HEADER_SIZE: usize = 8 + 4 + 4;
+
+// FileHeader[#TODO] (should add some comments)
+impl FileHeader {
+    pub fn new(page_size: usize) -> Self {
+        FileHeader {
+            format_version: FORMAT_VERSION,
+            page_size: page_size as u32,
+        }
+    }
+
+    // reject foreign files, files of other format versions and impossible page sizes
+    pub fn deserialize(buffer: &[u8]) -> Result<Self, Error> {
+        if buffer.len() < HEADER_SIZE || buffer[0..8] != MAGIC {
+            return Err(Error::IncompatibleFormat);
+        }
+        let header = FileHeader {
+            format_version: u32::from_le_bytes(buffer[8..12].try_into().unwrap()),
+            page_size: u32::from_le_bytes(buffer[12..16].try_into().unwrap()),
+        };
+        let page_size = header.page_size as usize;
+        if header.format_version != FORMAT_VERSION
+            || !page_size.is_power_of_two()
+            || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+        {
+            return Err(Error::IncompatibleFormat);
+        }
+        Ok(header)
+    }
+
+    pub fn serialize(&self, buffer: &mut [u8]) {
+        buffer[0..8].clone_from_slice(&MAGIC);
+        buffer[8..12].clone_from_slice(&u32::to_le_bytes(self.format_version));
+        buffer[12..16].clone_from_slice(&u32::to_le_bytes(self.page_size));
+    }
+}
+
+// after the header, meta page keeps two copies of Meta (double buffered), a commit always writes the copy that
+// is not used by the current version, so a torn write never loses the committed version
+//
+// meta format
+// |--------------------------------------------------------------------------------|
+// | root | freelist page | attachment | version | checksum(crc32)                  |
+// |--------------------------------------------------------------------------------|
+// |  8B  |  8B           |  8B        |  8B     |  4B                              |
+// |--------------------------------------------------------------------------------|
 #[derive(Default, Debug)]
 pub struct Meta {
     pub freelist_page: u64,
     pub root: u64,
+    // first overflow page of the attachment, 0 means no attachment
+    pub attachment: u64,
+    // increased by every commit, the copy with the biggest valid version wins
+    pub version: u64,
 }
 
+// bytes used by the serialized meta, always fits in the smallest page
+pub const META_SIZE: usize = 8 * 4 + 4;
+
+// byte offsets of the two copies in the meta page, both in the first MIN_PAGE_SIZE bytes
+pub const META_SLOT_OFFSETS: [usize; 2] = [64, 2048];
+
+// Meta[#TODO] (should add some comments)
 impl Meta {
-    pub fn deserialize(&mut self, buffer: &[u8]) {
+    // offset of the copy this version is written to
+    #[inline]
+    pub fn slot_offset(&self) -> usize {
+        META_SLOT_OFFSETS[(self.version % 2) as usize]
+    }
+
+    // return false if the checksum does not match
+    pub fn deserialize(&mut self, buffer: &[u8]) -> bool {
         let mut offset = 0;
         self.root = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
         offset += 8;
@@ -13,8 +96,14 @@ impl Meta {
         self.freelist_page = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
         offset += 8;
 
-        // this code is ommit the warnning by compiler
-        _ = offset;
+        self.attachment = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+        offset += 8;
+
+        self.version = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+        offset += 8;
+
+        let checksum = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+        checksum == crc32(&buffer[..offset])
     }
 
     pub fn serialize(&self, buffer: &mut [u8]) {
@@ -25,12 +114,51 @@ impl Meta {
 
         buffer[offset..offset + 8].clone_from_slice(u64::to_le_bytes(self.freelist_page).as_ref());
         offset += 8;
-        // for extend
-        //
-        // this code is ommit the warnning by compiler
-        _ = offset;
+
+        buffer[offset..offset + 8].clone_from_slice(u64::to_le_bytes(self.attachment).as_ref());
+        offset += 8;
+
+        buffer[offset..offset + 8].clone_from_slice(u64::to_le_bytes(self.version).as_ref());
+        offset += 8;
+
+        let checksum = crc32(&buffer[..offset]);
+        buffer[offset..offset + 4].clone_from_slice(u32::to_le_bytes(checksum).as_ref());
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_header() {
+        let mut buffer = [0u8; HEADER_SIZE];
+        FileHeader::new(MIN_PAGE_SIZE).serialize(&mut buffer);
+        assert_eq!(
+            FileHeader::deserialize(&buffer),
+            Ok(FileHeader::new(MIN_PAGE_SIZE))
+        );
+
+        // newer format version
+        let mut newer = buffer;
+        newer[8..12].clone_from_slice(&u32::to_le_bytes(FORMAT_VERSION + 1));
+        assert_eq!(
+            FileHeader::deserialize(&newer),
+            Err(Error::IncompatibleFormat)
+        );
+
+        // page size out of range
+        let mut bad_page_size = buffer;
+        bad_page_size[12..16].clone_from_slice(&u32::to_le_bytes(5000));
+        assert_eq!(
+            FileHeader::deserialize(&bad_page_size),
+            Err(Error::IncompatibleFormat)
+        );
+
+        // foreign file
+        assert_eq!(
+            FileHeader::deserialize(b"{\"indices\": []}"),
+            Err(Error::IncompatibleFormat)
+        );
+    }
+}
@@ -1,55 +1,139 @@
+pub mod buffer;
+pub mod builder;
+pub mod check;
+pub mod concurrent;
 pub mod constant;
 pub mod error;
+pub mod export;
 pub mod freelist;
+pub mod latch;
 pub mod meta;
 pub mod node;
+pub mod overflow;
 pub mod pager;
+pub mod slotted;
 
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
 use std::rc::Rc;
-use std::usize;
-
-use constant::{DEFAULT_META_PN, DEFAULT_PAGE_SIZE};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use buffer::{BufferPool, PinnedPage};
+use concurrent::Shared;
+use constant::{
+    DEFAULT_META_PN, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE, PAGE_TYPE_FREELIST,
+    PAGE_TYPE_INTERNAL, PAGE_TYPE_LEAF,
+};
 use error::Error;
 use freelist::Freelist;
-use meta::Meta;
-use node::{KeyValue, Node, TypedNode};
-use pager::Pager;
-
+use latch::{LatchGuard, TREE_LATCH};
+use meta::{FileHeader, Meta, HEADER_SIZE, META_SIZE, META_SLOT_OFFSETS};
+use node::{is_overflow_size, is_underflow_size, max_inline_value_size, KeyValue, Node, TypedNode};
+use pager::{Page, Pager};
+use slotted::{SlottedPage, SlottedPageMut};
 
 // default max size is 40GB for a single tree
 
+#[derive(Clone)]
+pub struct BTreeOptions {
+    // size of a node, only used when the tree is created; an existing tree keeps the page
+    // size stored in its meta page
+    pub page_size: usize,
+    // page cache shared with other trees, pages are read from file directly when it's None
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    // id of the file in the buffer pool, trees of the same file share it and trees of different
+    // files sharing a pool must not
+    pub file_id: u64,
+    // readers map the file and read pages out of the mapping instead of pread-ing a copy of
+    // every page, the buffer pool is not used then. Trees opened for writing ignore it
+    pub mmap: bool,
+}
+
+// Default[#TODO] (should add some comments)
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        BTreeOptions {
+            page_size: DEFAULT_PAGE_SIZE,
+            buffer_pool: None,
+            file_id: 0,
+            mmap: false,
+        }
+    }
+}
+
 pub struct BTree {
-    pub pager: Rc<Pager>,
+    pub pager: Arc<Pager>,
     pub metadata: Meta,
     pub freelist: Freelist,
+    // pages holding the freelist, the first one is recorded in meta page
+    freelist_pages: Vec<u64>,
+    // pages allocated since the last commit, they are not reachable from the committed meta
+    // and can be overwritten in place
+    dirty_pages: HashSet<u64>,
+    // pages of the committed version that are no longer used, released after next commit
+    pending_pages: Vec<u64>,
+    // pages released by a commit of a shared tree with the number of the commit, reused once
+    // the readers that entered before it are gone, see concurrent.rs
+    retired_pages: Vec<(u64, u64)>,
     read_only: bool,
+    // set once the tree is shared with concurrent readers, see concurrent.rs
+    shared: Option<Arc<Shared>>,
+    // latches held by the running insert, delete or commit
+    latched: Vec<LatchGuard>,
 }
 
 impl BTree {
     pub fn reader(path: &str) -> Self {
+        Self::reader_with_options(path, &BTreeOptions::default())
+    }
+
+    pub fn reader_with_options(path: &str, options: &BTreeOptions) -> Self {
+        Self::try_reader_with_options(path, options).expect("open Btree failed")
+    }
+
+    // like reader_with_options, but a file that is not a btree or is corrupted is reported
+    // instead of panicking
+    pub fn try_reader_with_options(path: &str, options: &BTreeOptions) -> Result<Self, Error> {
         let fp = OpenOptions::new()
             .read(true)
             .open(path)
-            .expect("open Btree failed");
-        let pager = Pager::new(fp);
-        let mut metadata = Meta::default();
-        let mut freelist = Freelist::default();
-        let mta_page = pager.read_page(DEFAULT_META_PN).unwrap();
-        metadata.deserialize(&mta_page.data);
-
-        let fls_page = pager.read_page(metadata.freelist_page).unwrap();
-        freelist.deserialize(&fls_page.data);
-        BTree {
-            pager: Rc::new(pager),
+            .map_err(|_| Error::PageLoadErr)?;
+        let (header, metadata) = read_meta(&fp)?;
+        let page_size = header.page_size as usize;
+        let pager = if options.mmap {
+            Pager::with_mmap(fp, page_size)?
+        } else {
+            Pager::with_pool(fp, page_size, options.buffer_pool.clone(), options.file_id)
+        };
+        let mut tree = BTree {
+            pager: Arc::new(pager),
             metadata,
-            freelist,
+            freelist: Freelist::default(),
+            freelist_pages: Vec::new(),
+            dirty_pages: HashSet::new(),
+            pending_pages: Vec::new(),
+            retired_pages: Vec::new(),
             read_only: true,
-        }
+            shared: None,
+            latched: Vec::new(),
+        };
+        tree.read_freelist()?;
+        Ok(tree)
     }
     pub fn new(path: &str) -> Self {
+        Self::with_options(path, &BTreeOptions::default())
+    }
+
+    pub fn with_options(path: &str, options: &BTreeOptions) -> Self {
+        let page_size = options.page_size;
+        assert!(
+            page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size),
+            "invalid page size {page_size}"
+        );
         let mut should_initial = false;
         let fp = match OpenOptions::new().write(true).read(true).open(path) {
             Ok(file_ptr) => file_ptr,
@@ -62,29 +146,46 @@ impl BTree {
                 }
             }
         };
-        let pager = Pager::new(fp);
-        let mut metadata = Meta::default();
-        let mut freelist = Freelist::default();
         if should_initial {
-            let mut meta_page = pager.allocate_page(DEFAULT_META_PN);
-            metadata.serialize(&mut meta_page.data);
-            pager.write_page(&meta_page);
-
-            let mut fls_page = pager.allocate_page(freelist.get_next_page());
-            freelist.serialize(&mut fls_page.data);
-            pager.write_page(&fls_page);
+            let metadata = Meta::default();
+            let pager =
+                Pager::with_pool(fp, page_size, options.buffer_pool.clone(), options.file_id);
+            let mut tree = BTree {
+                pager: Arc::new(pager),
+                metadata,
+                freelist: Freelist::default(),
+                freelist_pages: Vec::new(),
+                dirty_pages: HashSet::new(),
+                pending_pages: Vec::new(),
+                retired_pages: Vec::new(),
+                read_only: false,
+                shared: None,
+                latched: Vec::new(),
+            };
+            tree.flush();
+            tree
         } else {
-            let mta_page = pager.read_page(DEFAULT_META_PN).unwrap();
-            metadata.deserialize(&mta_page.data);
-
-            let fls_page = pager.read_page(metadata.freelist_page).unwrap();
-            freelist.deserialize(&fls_page.data);
-        }
-        BTree {
-            pager: Rc::new(pager),
-            metadata,
-            freelist,
-            read_only: false,
+            let (header, metadata) = read_meta(&fp).expect("open Btree failed");
+            let pager = Pager::with_pool(
+                fp,
+                header.page_size as usize,
+                options.buffer_pool.clone(),
+                options.file_id,
+            );
+            let mut tree = BTree {
+                pager: Arc::new(pager),
+                metadata,
+                freelist: Freelist::default(),
+                freelist_pages: Vec::new(),
+                dirty_pages: HashSet::new(),
+                pending_pages: Vec::new(),
+                retired_pages: Vec::new(),
+                read_only: false,
+                shared: None,
+                latched: Vec::new(),
+            };
+            tree.read_freelist().expect("open Btree failed");
+            tree
         }
     }
 
@@ -111,21 +212,63 @@ impl BTree {
     }
 
     pub fn insert(&mut self, key: &[u8], value: &[u8]) {
-        let kv = KeyValue::new(key, value);
-        if self.metadata.root == 0 {
-            let mut node_page = self.pager.allocate_page(self.freelist.get_next_page());
-            let mut new_node = Node::new_leaf(node_page.page_number);
-            if let TypedNode::Leaf(ref mut leaf) = new_node.data {
-                leaf.keyvalues.push(kv);
-                leaf.prev_offset = node_page.page_number;
-            }
-            new_node.serialize(&mut node_page.data);
+        self.try_insert(key, value).expect("insert failed")
+    }
+
+    // like insert, but a key too large for the page size is reported instead of panicking. Keys
+    // are stored in internal nodes too, so they are never moved into overflow pages like values
+    pub fn try_insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() > max_inline_value_size(self.pager.page_size()) {
+            return Err(Error::KeyTooLarge);
+        }
+        self.insert_entry(key, value);
+        self.release_latches();
+        Ok(())
+    }
 
+    fn insert_entry(&mut self, key: &[u8], value: &[u8]) {
+        self.reclaim_pages();
+        let kv = if value.len() > max_inline_value_size(self.pager.page_size()) {
+            KeyValue::new_overflow(key, self.write_overflow(value))
+        } else {
+            KeyValue::new(key, value)
+        };
+        if self.metadata.root == 0 {
+            self.latch_tree();
+            let mut new_node = Node::new_leaf(0);
+            new_node.leaf_data().keyvalues.push(kv);
             self.write_node(&mut new_node);
             self.metadata.root = new_node.offset;
             return;
         }
 
+        // most inserts fit in the leaf, only the leaf and the child pointers on the path are
+        // changed then
+        let (path, mut leaf) = self.find_path(key).unwrap();
+        let usable_size = self.pager.usable_size();
+        let mut leaf_page = SlottedPageMut::new(&mut leaf[..usable_size]);
+        let (found, index) = leaf_page.as_page().find_key_in_leaf(key);
+        let old = found.then(|| leaf_page.as_page().key_value(index));
+        if found {
+            leaf_page.remove(index);
+        }
+        // an update with a smaller value may leave the leaf underflowing
+        if leaf_page.insert_leaf(index, &kv)
+            && !is_overflow_size(leaf_page.as_page().used_size(), self.pager.page_size())
+            && (path.len() == 1
+                || !is_underflow_size(leaf_page.as_page().used_size(), self.pager.page_size()))
+        {
+            self.latch_path(&path);
+            if let Some(old) = old.filter(|old| old.overflow != 0) {
+                self.free_overflow(old.overflow);
+            }
+            self.write_path(&path, leaf);
+            return;
+        }
+
+        // splits and merges may reach the root
+        self.latch_tree();
+        self.latch_pages(&path);
         let mut ancestor_idx = vec![0];
         let (mut node, index, found) = self
             .find_node(self.metadata.root, key, &mut ancestor_idx)
@@ -135,9 +278,12 @@ impl BTree {
         if let TypedNode::Leaf(ref mut leaf_node) = node.data {
             if found {
                 // directory update
-                leaf_node.keyvalues[index].value = value.into();
+                let old = std::mem::replace(&mut leaf_node.keyvalues[index], kv);
+                if old.overflow != 0 {
+                    self.free_overflow(old.overflow);
+                }
             } else {
-                leaf_node.keyvalues.insert(index, KeyValue::new(key, value));
+                leaf_node.keyvalues.insert(index, kv);
             }
         }
 
@@ -149,14 +295,88 @@ impl BTree {
             ancestors.push(Rc::new(RefCell::new(node)));
         }
 
+        self.write_ancestors(&ancestors, &ancestor_idx);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<KeyValue, Error> {
+        let removed = self.delete_entry(key);
+        self.release_latches();
+        removed
+    }
+
+    fn delete_entry(&mut self, key: &[u8]) -> Result<KeyValue, Error> {
+        self.reclaim_pages();
+        if self.metadata.root == 0 {
+            return Err(Error::EmptyTree);
+        }
+        // the leaf is changed in place if it does not underflow, or it's the root
+        let (path, mut leaf) = self.find_path(key)?;
+        let usable_size = self.pager.usable_size();
+        let mut leaf_page = SlottedPageMut::new(&mut leaf[..usable_size]);
+        let (found, index) = leaf_page.as_page().find_key_in_leaf(key);
+        if !found {
+            return Err(Error::KeyNotFound);
+        }
+        let removed = leaf_page.as_page().key_value(index);
+        leaf_page.remove(index);
+        if path.len() == 1
+            || !is_underflow_size(leaf_page.as_page().used_size(), self.pager.page_size())
+        {
+            self.latch_path(&path);
+            let overflow = removed.overflow;
+            let removed = self.load_value(removed)?;
+            if overflow != 0 {
+                self.free_overflow(overflow);
+            }
+            self.write_path(&path, leaf);
+            return Ok(removed);
+        }
+
+        // merges may reach the root
+        self.latch_tree();
+        self.latch_pages(&path);
+        let mut ancestor_idx = vec![0];
+        let (mut removed_node, removed_index, found) =
+            self.find_node(self.metadata.root, key, &mut ancestor_idx)?;
+        if !found {
+            return Err(Error::KeyNotFound);
+        }
+        let mut removed_item = removed_node.leaf_data().keyvalues.remove(removed_index);
+        if removed_item.overflow != 0 {
+            removed_item.value = self.read_overflow(removed_item.overflow)?;
+            self.free_overflow(removed_item.overflow);
+            removed_item.overflow = 0;
+        }
+        // removed_node must be leaf node
+
+        if !found {
+            return Err(Error::KeyNotFound);
+        }
+
+        let mut ancestors = self.get_nodes(&ancestor_idx);
+        if removed_node.offset == self.metadata.root {
+            ancestors[0] = Rc::new(RefCell::new(removed_node));
+        } else {
+            ancestors.push(Rc::new(RefCell::new(removed_node)));
+        }
+
+        self.write_ancestors(&ancestors, &ancestor_idx);
+        Ok(removed_item)
+    }
+
+    // write a changed path from the bottom up: a node that overflows is split, one that
+    // underflows is merged with or rebalanced against a sibling, and the root is replaced when
+    // it splits or is left with a single child
+    fn write_ancestors(&mut self, ancestors: &[Rc<RefCell<Node>>], ancestor_idx: &[usize]) {
+        let page_size = self.pager.page_size();
         for i in (0..ancestors.len() - 1).rev() {
             let parent = ancestors[i].clone();
             let child = ancestors[i + 1].clone();
             let child_index = ancestor_idx[i + 1];
-            if child.borrow().is_overflow() {
+            if child.borrow().is_overflow(page_size) {
                 let (mid, mut sibling) = child
                     .borrow_mut()
-                    .split(self.freelist.get_next_page())
+                    .split(self.allocate_page_number(), page_size)
                     .unwrap();
                 parent
                     .borrow_mut()
@@ -169,90 +389,40 @@ impl BTree {
                     .children
                     .insert(child_index + 1, sibling.offset);
 
-                self.write_nodes(&mut [&mut child.borrow_mut(), &mut sibling]);
-            } else {
-                self.write_node(&mut child.borrow_mut());
+                self.write_node(&mut sibling);
+            } else if child.borrow().is_underflow(page_size) {
+                self.redistribute(
+                    &mut parent.borrow_mut(),
+                    &mut child.borrow_mut(),
+                    child_index,
+                );
+                continue;
             }
+            self.write_child(
+                &mut parent.borrow_mut(),
+                child_index,
+                &mut child.borrow_mut(),
+            );
         }
 
         let root_node = ancestors[0].clone();
-        if root_node.borrow().is_overflow() {
-            let mut new_root = Node::new_internal(self.freelist.get_next_page());
+        if root_node.borrow().is_overflow(page_size) {
             let (middle_item, mut sibling) = root_node
                 .borrow_mut()
-                .split(self.freelist.get_next_page())
+                .split(self.allocate_page_number(), page_size)
                 .unwrap();
+            self.write_nodes(&mut [&mut root_node.borrow_mut(), &mut sibling]);
 
+            let mut new_root = Node::new_internal(0);
             new_root.internal_data().keys.push(middle_item);
             new_root
                 .internal_data()
                 .children
                 .push(root_node.borrow().offset);
             new_root.internal_data().children.push(sibling.offset);
+            self.write_node(&mut new_root);
             self.metadata.root = new_root.offset;
-
-            self.write_nodes(&mut [&mut new_root, &mut root_node.borrow_mut(), &mut sibling]);
-        } else {
-            self.write_node(&mut root_node.borrow_mut());
-        }
-    }
-
-    pub fn delete(&mut self, key: &[u8]) -> Result<String, Error> {
-        if self.metadata.root == 0 {
-            return Err(Error::EmptyTree);
-        }
-        let mut ancestor_idx = vec![0];
-        let (mut removed_node, removed_index, found) = self
-            .find_node(self.metadata.root, key, &mut ancestor_idx)
-            .unwrap();
-        if !found {
-            return Err(Error::KeyNotFound);
-        }
-        let removed_item = removed_node.leaf_data().keyvalues.remove(removed_index);
-        // removed_node must be leaf node
-
-        if !found {
-            return Err(Error::KeyNotFound);
-        }
-
-        let mut ancestors = self.get_nodes(&ancestor_idx);
-        if removed_node.offset == self.metadata.root {
-            ancestors[0] = Rc::new(RefCell::new(removed_node));
-        } else {
-            ancestors.push(Rc::new(RefCell::new(removed_node)));
-        }
-
-        for i in (0..ancestors.len() - 1).rev() {
-            let parent = ancestors[i].clone();
-            let child = ancestors[i + 1].clone();
-            let child_index = ancestor_idx[i + 1];
-            if child.borrow().is_underflow() {
-                if child.borrow().is_leaf {
-                    self.redistribution_leaf(
-                        &mut parent.borrow_mut(),
-                        &mut child.borrow_mut(),
-                        child_index,
-                    );
-                } else {
-                    self.redistribution_internal(
-                        &mut parent.borrow_mut(),
-                        &mut child.borrow_mut(),
-                        child_index,
-                    );
-                }
-            } else {
-                self.write_node(&mut child.borrow_mut());
-            }
-        }
-
-        let root_node = ancestors.first().unwrap();
-        if root_node.borrow().is_leaf {
-            // leaf node
-            self.write_node(&mut root_node.borrow_mut());
-            return Ok(String::from_utf8(removed_item.key).unwrap());
-        }
-
-        if root_node.borrow_mut().internal_data().keys.is_empty()
+        } else if !root_node.borrow().is_leaf
             && root_node.borrow_mut().internal_data().children.len() == 1
         {
             self.metadata.root = root_node
@@ -264,213 +434,60 @@ impl BTree {
             self.delete_node(root_node.borrow().offset);
         } else {
             self.write_node(&mut root_node.borrow_mut());
+            self.metadata.root = root_node.borrow().offset;
         }
-
-        Ok(String::from_utf8(removed_item.key).unwrap())
     }
 
-    // leaf node is underflow, then do re-distribution
-    // adopt data from it's neighbor ; then update the parent
-    fn redistribution_leaf(
-        &mut self,
-        parent_node: &mut Node,
-        deficient_node: &mut Node,
-        deficient_indx: usize,
-    ) {
-        if deficient_indx > 0 {
-            // if deficient node's left sibling exists and has more than minimum number of
-            // elements, then rotate right
-            let mut l_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_indx - 1])
-                .unwrap();
-            if l_sibling.can_spare_element() {
-                // adopt item from left sibling node
-                let l_item = l_sibling.leaf_data().keyvalues.pop().unwrap();
-
-                deficient_node.leaf_data().keyvalues.insert(0, l_item);
-
-                // update parent node;
-                let new_sep = l_sibling.leaf_data().keyvalues.last().unwrap().key.clone();
-                parent_node.internal_data().keys[deficient_indx - 1] = new_sep;
-
-                // persistent nodes
-                self.write_node(&mut l_sibling);
-                self.write_node(deficient_node);
-                return;
-            }
-        }
-
-        if deficient_indx < parent_node.internal_data().keys.len() - 1 {
-            // if deficient node's right sibling exists and has more than minimum number of
-            // elements, then rotate left
-            let mut r_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_indx + 1])
-                .unwrap();
-            if r_sibling.can_spare_element() {
-                // adopt item from right sibling node
-                let r_item = r_sibling.leaf_data().keyvalues.remove(0);
-
-                deficient_node.leaf_data().keyvalues.push(r_item);
-                // update parent node
-                let new_sep = r_sibling.leaf_data().keyvalues.first().unwrap().key.clone();
-                parent_node.internal_data().keys[deficient_indx] = new_sep;
-
-                // persistent nodes
-                self.write_node(&mut r_sibling);
-                self.write_node(deficient_node);
-                return;
-            }
-        }
-        // delete the node and merge with neighbor
-        if deficient_indx == 0 {
-            if let Some(mut r_sibling) = self.get_node(parent_node.internal_data().children[1]) {
-                // merge with negihbor
-                deficient_node
-                    .leaf_data()
-                    .keyvalues
-                    .append(&mut r_sibling.leaf_data().keyvalues);
-                // delete node
-                parent_node.internal_data().keys.remove(deficient_indx);
-                parent_node
-                    .internal_data()
-                    .children
-                    .remove(deficient_indx + 1);
-
-                deficient_node.leaf_data().next_offset = r_sibling.leaf_data().next_offset;
-
-                self.write_node(deficient_node);
-                self.delete_node(r_sibling.offset);
-            }
-        } else {
-            let mut l_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_indx - 1])
-                .unwrap();
-            l_sibling
-                .leaf_data()
-                .keyvalues
-                .append(&mut deficient_node.leaf_data().keyvalues);
-            // delete node
-            parent_node.internal_data().keys.remove(deficient_indx - 1);
-            parent_node.internal_data().children.remove(deficient_indx);
-
-            l_sibling.leaf_data().next_offset = deficient_node.leaf_data().next_offset;
-
-            self.write_node(&mut l_sibling);
-            self.delete_node(deficient_node.offset);
-        }
-    }
-    // redistribution internal ,
-    // if an internal node ends up with a fewer nodes, underflow
-    // adopt from a neighbor ; then update parent
-    // if adopt doesn't work, then merge
-    fn redistribution_internal(
+    // an underflowing node is merged with a sibling if both fit in one page. Otherwise the
+    // entries of both are split again, by size, so neither of them underflows. Moving single
+    // entries over is not enough since entries are of any size
+    fn redistribute(
         &mut self,
         parent_node: &mut Node,
         deficient_node: &mut Node,
         deficient_idx: usize,
     ) {
-        // try to rotate from left sibling
-        if deficient_idx > 0 {
-            // if deficient node's left sibling exists and has more than minimum number of
-            // elements, then rotate right
-            let mut l_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_idx - 1])
-                .unwrap();
-            if l_sibling.can_spare_element() {
-                let old_sep = parent_node.internal_data().keys.remove(deficient_idx - 1);
-                let leftest_child = l_sibling.internal_data().children.pop().unwrap();
-
-                deficient_node.internal_data().keys.insert(0, old_sep);
-                deficient_node
-                    .internal_data()
-                    .children
-                    .insert(0, leftest_child);
-
-                let new_sep = l_sibling.internal_data().keys.pop().unwrap();
-
-                parent_node
-                    .internal_data()
-                    .keys
-                    .insert(deficient_idx - 1, new_sep);
-
-                self.write_node(&mut l_sibling);
-                self.write_node(deficient_node);
-                return;
-            }
-        }
-
-        // try roate from right sibling
-        if deficient_idx < parent_node.internal_data().keys.len() - 1 {
-            // borrow from right
-            // if deficient node's right sibling exists and has more than minimum number of
-            // elements, then rotate left
-            let mut r_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_idx + 1])
-                .unwrap();
-            if r_sibling.can_spare_element() {
-                let old_sep = parent_node.internal_data().keys.remove(deficient_idx);
-
-                let r_first_item = r_sibling.internal_data().keys.remove(0);
-                let ship_child = r_sibling.internal_data().children.remove(0);
-
-                parent_node
-                    .internal_data()
-                    .keys
-                    .insert(deficient_idx, r_first_item);
-
-                deficient_node.internal_data().keys.push(old_sep);
-                deficient_node.internal_data().children.push(ship_child);
-
-                self.write_node(&mut r_sibling);
-                self.write_node(deficient_node);
-                return;
-            }
+        let page_size = self.pager.page_size();
+        if parent_node.internal_data().children.len() < 2 {
+            self.write_child(parent_node, deficient_idx, deficient_node);
+            return;
         }
-        // immediate sibling have only the minimum number of elements, then merge with a sibling
-        // sandwiching their separator take off from their parents
-        if deficient_idx == 0 {
-            if let Some(mut r_sibling) = self.get_node(parent_node.internal_data().children[1]) {
-                let old_sep = parent_node.internal_data().keys.remove(deficient_idx);
-
-                deficient_node.internal_data().keys.push(old_sep);
-                deficient_node
-                    .internal_data()
-                    .keys
-                    .append(&mut r_sibling.internal_data().keys);
-                deficient_node
-                    .internal_data()
-                    .children
-                    .append(&mut r_sibling.internal_data().children);
-
-                parent_node
-                    .internal_data()
-                    .children
-                    .remove(deficient_idx + 1);
-
-                self.write_node(deficient_node);
-                self.delete_node(r_sibling.offset);
-            }
+        // the left sibling is taken, the first child only has a right one
+        let (left_idx, sibling_idx) = if deficient_idx > 0 {
+            (deficient_idx - 1, deficient_idx - 1)
         } else {
-            let mut l_sibling = self
-                .get_node(parent_node.internal_data().children[deficient_idx - 1])
-                .unwrap();
-            let old_sep = parent_node.internal_data().keys.remove(deficient_idx - 1);
-            l_sibling.internal_data().keys.push(old_sep);
+            (0, 1)
+        };
+        // the parent is latched, no reader gets into the sibling while it's changed
+        let sibling_page = parent_node.internal_data().children[sibling_idx];
+        self.latch_pages(&[(sibling_page, 0)]);
+        let mut sibling = self.get_node(sibling_page).unwrap();
+        let (left, right) = if deficient_idx > 0 {
+            (&mut sibling, deficient_node)
+        } else {
+            (deficient_node, &mut sibling)
+        };
 
-            l_sibling
+        let separator = parent_node.internal_data().keys.remove(left_idx);
+        parent_node.internal_data().children.remove(left_idx + 1);
+        let right_offset = right.offset;
+        left.merge(right, separator);
+        if left.is_overflow(page_size) {
+            // the right page is written again with the upper half
+            let (separator, mut new_right) = left.split(right_offset, page_size).unwrap();
+            self.write_node(&mut new_right);
+            parent_node
                 .internal_data()
                 .keys
-                .append(&mut deficient_node.internal_data().keys);
-            l_sibling
+                .insert(left_idx, separator);
+            parent_node
                 .internal_data()
                 .children
-                .append(&mut deficient_node.internal_data().children);
-
-            parent_node.internal_data().children.remove(deficient_idx);
-
-            self.write_node(&mut l_sibling);
-            self.delete_node(deficient_node.offset);
+                .insert(left_idx + 1, new_right.offset);
+        } else {
+            self.delete_node(right_offset);
         }
+        self.write_child(parent_node, left_idx, left);
     }
 
     fn get_nodes(&self, indexes: &[usize]) -> Vec<Rc<RefCell<Node>>> {
@@ -497,17 +514,14 @@ impl BTree {
             return Err(Error::EmptyTree);
         }
 
-        let mut ancestors = vec![];
-        if let Ok((node, index, found)) = self.find_node(self.metadata.root, key, &mut ancestors) {
-            if !found && index == 0 {
-                return Err(Error::KeyNotFound);
-            }
-            if let TypedNode::Leaf(ref leaf_node) = node.data {
-                let index = if found { index } else { index - 1 };
-                return Ok(leaf_node.keyvalues[index].clone());
-            }
+        let leaf = self.find_leaf(key)?;
+        let view = SlottedPage::new(&leaf[..self.pager.usable_size()]);
+        let (found, index) = view.find_key_in_leaf(key);
+        if !found && index == 0 {
+            return Err(Error::KeyNotFound);
         }
-        Err(Error::KeyNotFound)
+        let index = if found { index } else { index - 1 };
+        self.load_value(view.key_value(index))
     }
 
     pub fn find(&self, key: &[u8]) -> Result<KeyValue, Error> {
@@ -515,15 +529,75 @@ impl BTree {
             return Err(Error::EmptyTree);
         }
 
-        let mut ancestors = vec![];
-        if let Ok((node, index, found)) = self.find_node(self.metadata.root, key, &mut ancestors) {
-            if found {
-                if let TypedNode::Leaf(ref leaf_node) = node.data {
-                    return Ok(leaf_node.keyvalues[index].clone());
-                }
+        let leaf = self.find_leaf(key)?;
+        let view = SlottedPage::new(&leaf[..self.pager.usable_size()]);
+        match view.find_key_in_leaf(key) {
+            (true, index) => self.load_value(view.key_value(index)),
+            (false, _) => Err(Error::KeyNotFound),
+        }
+    }
+
+    // descend to the leaf that may hold the key, pages are searched in place
+    fn find_leaf(&self, key: &[u8]) -> Result<PinnedPage, Error> {
+        let mut page = self.get_page(self.metadata.root)?;
+        loop {
+            let view = SlottedPage::new(&page[..self.pager.usable_size()]);
+            if view.is_leaf() {
+                return Ok(page);
+            }
+            let (_, child) = view.find_key_in_internal(key);
+            page = self.get_page(child)?;
+        }
+    }
+
+    // read an overflowed value back from its overflow pages
+    fn load_value(&self, mut kv: KeyValue) -> Result<KeyValue, Error> {
+        if kv.overflow != 0 {
+            kv.value = self.read_overflow(kv.overflow)?;
+            kv.overflow = 0;
+        }
+        Ok(kv)
+    }
+
+    // iterate all key-values in key order; leaves are reached from the root, since sibling
+    // pointers can not be kept up to date with copy-on-write
+    pub fn iter(&self) -> Iter<'_> {
+        let roots = if self.metadata.root == 0 {
+            vec![]
+        } else {
+            vec![self.metadata.root]
+        };
+        Iter {
+            tree: self,
+            leaf: None,
+            stack: vec![roots.into_iter()],
+        }
+    }
+
+    // iterate the key-values from the first key not less than the key, in key order; the
+    // children left of the path to its leaf are never read
+    pub fn iter_from(&self, key: &[u8]) -> Result<Iter<'_>, Error> {
+        let mut iter = Iter {
+            tree: self,
+            leaf: None,
+            stack: vec![],
+        };
+        if self.metadata.root == 0 {
+            return Ok(iter);
+        }
+        let usable_size = self.pager.usable_size();
+        let mut page = self.get_page(self.metadata.root)?;
+        loop {
+            let view = SlottedPage::new(&page[..usable_size]);
+            if view.is_leaf() {
+                let (_, index) = view.find_key_in_leaf(key);
+                iter.leaf = Some((page, index));
+                return Ok(iter);
             }
+            let (index, child) = view.find_key_in_internal(key);
+            iter.stack.push(view.children().split_off(index + 1).into_iter());
+            page = self.get_page(child)?;
         }
-        Err(Error::KeyNotFound)
     }
 
     fn find_node(
@@ -532,28 +606,94 @@ impl BTree {
         key: &[u8],
         ancestors: &mut Vec<usize>,
     ) -> Result<(Node, usize, bool), Error> {
-        let node: Node = if let Some(node) = self.get_node(node_offset) {
-            node
-        } else {
-            return Err(Error::PageLoadErr);
-        };
-
-        if node.is_leaf {
-            let (found, index) = node.find_key_in_leaf(key);
-            Ok((node, index, found))
-        } else {
-            let (idx, child) = node.find_key_in_internal(key);
+        // internal nodes on the way are searched in place, only the leaf is deserialized
+        let mut page_number = node_offset;
+        loop {
+            let page = self.get_page(page_number)?;
+            let view = SlottedPage::new(&page[..self.pager.usable_size()]);
+            if view.is_leaf() {
+                let (found, index) = view.find_key_in_leaf(key);
+                let mut node = Node::new_empty(page_number);
+                node.deserialize(&page[..self.pager.usable_size()]);
+                return Ok((node, index, found));
+            }
+            let (idx, child) = view.find_key_in_internal(key);
             ancestors.push(idx);
-            self.find_node(child, key, ancestors)
+            page_number = child;
+        }
+    }
+
+    // pages from the root to the leaf that may hold the key, with the index of the child taken
+    // in every internal page, and a copy of the leaf to be changed
+    fn find_path(&self, key: &[u8]) -> Result<(Path, Vec<u8>), Error> {
+        let mut path = vec![];
+        let mut page_number = self.metadata.root;
+        loop {
+            let page = self.get_page(page_number)?;
+            let view = SlottedPage::new(&page[..self.pager.usable_size()]);
+            if view.is_leaf() {
+                path.push((page_number, 0));
+                return Ok((path, page.to_vec()));
+            }
+            let (idx, child) = view.find_key_in_internal(key);
+            path.push((page_number, idx));
+            page_number = child;
+        }
+    }
+
+    // write a changed leaf of the path, ancestors are copied with the pointer to the new child
+    // page changed, up to the first one that already points to it
+    fn write_path(&mut self, path: &Path, leaf: Vec<u8>) {
+        let (leaf_page_number, _) = *path.last().unwrap();
+        let mut child = self.write_page_copy(leaf_page_number, leaf, PAGE_TYPE_LEAF);
+        for &(page_number, idx) in path.iter().rev().skip(1) {
+            let mut parent = self.pager.read_page(page_number).unwrap().data;
+            let usable_size = self.pager.usable_size();
+            let mut parent_page = SlottedPageMut::new(&mut parent[..usable_size]);
+            if parent_page.as_page().child(idx) == child {
+                return;
+            }
+            parent_page.set_child(idx, child);
+            child = self.write_page_copy(page_number, parent, PAGE_TYPE_INTERNAL);
+        }
+        self.metadata.root = child;
+    }
+
+    // write the content of a page, return the page it is written to
+    fn write_page_copy(&mut self, page_number: u64, data: Vec<u8>, page_type: u8) -> u64 {
+        let page_number = self.cow_page_number(page_number);
+        let mut page = Page { data, page_number };
+        self.pager.write_page(&mut page, page_type);
+        page_number
+    }
+
+    // pages of the committed version are never overwritten, a new page is allocated for them
+    fn cow_page_number(&mut self, page_number: u64) -> u64 {
+        if self.dirty_pages.contains(&page_number) {
+            return page_number;
+        }
+        if page_number != 0 {
+            self.pending_pages.push(page_number);
+        }
+        self.allocate_page_number()
+    }
+
+    // pin a node page, a page that is not a node, e.g. a dangling child pointer to a freed page,
+    // is reported as corrupted
+    fn get_page(&self, page_number: u64) -> Result<PinnedPage, Error> {
+        let page = self.pager.pin_page(page_number)?;
+        match Page::page_type(&page) {
+            PAGE_TYPE_LEAF | PAGE_TYPE_INTERNAL => Ok(page),
+            _ => Err(Error::Corrupted),
         }
     }
 
-    fn get_node(&self, page_number: u64) -> Option<Node> {
+    fn get_node(&self, page_number: u64) -> Result<Node, Error> {
         let mut node = Node::new_empty(page_number);
-        let node_page = self.pager.read_page(page_number).unwrap();
-        node.deserialize(&node_page.data);
+        let node_page = self.get_page(page_number)?;
+        node.deserialize(&node_page[..self.pager.usable_size()]);
         node.offset = page_number;
-        Some(node)
+        Ok(node)
     }
 
     pub fn write_nodes(&mut self, nodes: &mut [&mut Node]) {
@@ -562,32 +702,275 @@ impl BTree {
         }
     }
 
+    // nodes of the committed version are never overwritten, they are copied to a new page
+    // and node.offset is updated, so the caller must point the parent to the new page
     pub fn write_node(&mut self, node: &mut Node) {
-        if node.offset == 0 {
-            node.offset = self.freelist.get_next_page();
-        }
+        node.offset = self.cow_page_number(node.offset);
         let mut page = self.pager.allocate_page(node.offset);
 
-        node.serialize(&mut page.data);
-        self.pager.write_page(&page);
+        let usable_size = self.pager.usable_size();
+        node.serialize(&mut page.data[..usable_size]);
+        let page_type = if node.is_leaf {
+            PAGE_TYPE_LEAF
+        } else {
+            PAGE_TYPE_INTERNAL
+        };
+        self.pager.write_page(&mut page, page_type);
+    }
+
+    // write a child node and point the parent to where it is written
+    fn write_child(&mut self, parent: &mut Node, index: usize, child: &mut Node) {
+        self.write_node(child);
+        parent.internal_data().children[index] = child.offset;
+    }
+
+    // allocate a page for the running transaction
+    pub fn allocate_page_number(&mut self) -> u64 {
+        let page_number = self.freelist.get_next_page();
+        self.dirty_pages.insert(page_number);
+        page_number
     }
 
+    // pages of the committed version stay readable until the next commit
     pub fn delete_node(&mut self, node: u64) {
-        if let Some(mut page) = self.pager.read_page(node) {
-            page.data[0..DEFAULT_PAGE_SIZE].clone_from_slice(vec![0; DEFAULT_PAGE_SIZE].as_ref());
-            self.pager.write_page(&page);
+        if self.dirty_pages.remove(&node) {
+            self.freelist.release_page(node);
+        } else {
+            self.pending_pages.push(node);
         }
-        self.freelist.release_page(node);
     }
 
+    // commit: every page of the new version is made durable before the meta page switches to
+    // it, and pages of the old version are reused only after the switch
     pub fn flush(&mut self) {
-        let mut meta_page = self.pager.allocate_page(DEFAULT_META_PN);
-        self.metadata.serialize(&mut meta_page.data);
-        self.pager.write_page(&meta_page);
+        if self.dirty_pages.is_empty()
+            && self.pending_pages.is_empty()
+            && self.metadata.version != 0
+        {
+            return;
+        }
+        self.reclaim_pages();
+        self.write_freelist();
+        self.pager.sync();
+
+        // only the copy of the new version is written, the header and the committed copy are
+        // left alone. The first commit of a new tree writes the whole meta page with the header
+        self.metadata.version += 1;
+        let slot = self.metadata.slot_offset();
+        if self.metadata.version == 1 {
+            let mut meta_page = self.pager.allocate_page(DEFAULT_META_PN);
+            FileHeader::new(self.pager.page_size()).serialize(&mut meta_page.data[..HEADER_SIZE]);
+            self.metadata
+                .serialize(&mut meta_page.data[slot..slot + META_SIZE]);
+            self.pager.write_meta_page(&mut meta_page);
+        } else {
+            let mut buffer = [0u8; META_SIZE];
+            self.metadata.serialize(&mut buffer);
+            self.pager.write_meta(slot, &buffer);
+        }
+        self.pager.sync();
+
+        // readers may still be on pages of the old version
+        match &self.shared {
+            Some(shared) => {
+                let commit = shared.readers.lock().unwrap().commit();
+                let pending = std::mem::take(&mut self.pending_pages);
+                self.retired_pages
+                    .extend(pending.into_iter().map(|page_number| (commit, page_number)));
+            }
+            None => {
+                for page_number in std::mem::take(&mut self.pending_pages) {
+                    self.freelist.release_page(page_number);
+                }
+            }
+        }
+        self.dirty_pages.clear();
+        self.release_latches();
+    }
+
+    // retired pages no reader can be on are free to use
+    fn reclaim_pages(&mut self) {
+        let Some(shared) = self.shared.as_ref().filter(|_| !self.retired_pages.is_empty()) else {
+            return;
+        };
+        let reusable = shared.readers.lock().unwrap().reusable();
+        let (reclaimed, retired) = std::mem::take(&mut self.retired_pages)
+            .into_iter()
+            .partition(|&(commit, _)| commit <= reusable);
+        self.retired_pages = retired;
+        for (_, page_number) in reclaimed {
+            self.freelist.release_page(page_number);
+        }
+    }
+
+    // take the tree latch for a change that may move the root; readers wait for it before they
+    // latch the root. No-op unless the tree is shared
+    fn latch_tree(&mut self) {
+        if let Some(shared) = &self.shared {
+            self.latched.push(shared.latches.exclusive(TREE_LATCH));
+        }
+    }
+
+    // take the latches of a change that only rewrites the leaf of the path: pages are copied
+    // from the leaf up to the first one that is changed in place, which keeps its page number.
+    // The tree latch is taken instead if the root is copied
+    fn latch_path(&mut self, path: &Path) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let mut top = path.len() - 1;
+        while top > 0 && !self.dirty_pages.contains(&path[top].0) {
+            top -= 1;
+        }
+        if !self.dirty_pages.contains(&path[top].0) {
+            self.latched.push(shared.latches.exclusive(TREE_LATCH));
+            return;
+        }
+        for &(page_number, _) in path[top..].iter() {
+            self.latched.push(shared.latches.exclusive(page_number));
+        }
+    }
+
+    // latch pages a change rewrites in place or frees, in the order of the path from the top
+    fn latch_pages(&mut self, path: &[(u64, usize)]) {
+        if let Some(shared) = &self.shared {
+            for &(page_number, _) in path.iter() {
+                self.latched.push(shared.latches.exclusive(page_number));
+            }
+        }
+    }
+
+    // readers see the new root from here on
+    fn release_latches(&mut self) {
+        if let Some(shared) = &self.shared {
+            shared.root.store(self.metadata.root, Ordering::Release);
+        }
+        self.latched.clear();
+    }
+
+    fn read_freelist(&mut self) -> Result<(), Error> {
+        let (data, pages) =
+            self.read_overflow_chain(self.metadata.freelist_page, PAGE_TYPE_FREELIST)?;
+        self.freelist.deserialize(&data);
+        self.freelist_pages = pages;
+        Ok(())
+    }
+
+    // freelist is written into a new chain of overflow pages on every commit. The persisted
+    // list already contains pending pages, they are free once the new meta page is written.
+    // Retired pages are free in the file, only readers in memory may still use them
+    fn write_freelist(&mut self) {
+        for page_number in std::mem::take(&mut self.freelist_pages) {
+            self.delete_node(page_number);
+        }
+        let persisted = |tree: &BTree| {
+            let mut released_pages = tree.freelist.released_pages.clone();
+            released_pages.extend(&tree.pending_pages);
+            released_pages.extend(tree.retired_pages.iter().map(|&(_, page_number)| page_number));
+            released_pages.sort_unstable();
+            released_pages.dedup();
+            Freelist {
+                max_page: tree.freelist.max_page,
+                released_pages,
+            }
+        };
+        while self.freelist_pages.len() < self.overflow_pages_needed(persisted(self).size()) {
+            let page_number = self.allocate_page_number();
+            self.freelist_pages.push(page_number);
+        }
+        let freelist = persisted(self);
+        let mut buffer = vec![0u8; freelist.size()];
+        freelist.serialize(&mut buffer);
+        let pages = self.freelist_pages.clone();
+        self.write_overflow_pages(&pages, &buffer, PAGE_TYPE_FREELIST);
+        self.metadata.freelist_page = self.freelist_pages[0];
+    }
+}
+
+// meta page must be read before the pager is created, since it holds the page size. The
+// valid copy with the biggest version is the committed one
+fn read_meta(fp: &File) -> Result<(FileHeader, Meta), Error> {
+    let mut buffer = [0u8; HEADER_SIZE];
+    fp.read_exact_at(&mut buffer, 0)
+        .map_err(|_| Error::IncompatibleFormat)?;
+    let header = FileHeader::deserialize(&buffer)?;
+
+    let mut committed: Option<Meta> = None;
+    for slot in META_SLOT_OFFSETS {
+        let mut buffer = [0u8; META_SIZE];
+        if fp.read_exact_at(&mut buffer, slot as u64).is_err() {
+            continue;
+        }
+        let mut metadata = Meta::default();
+        if metadata.deserialize(&buffer)
+            && committed
+                .as_ref()
+                .is_none_or(|meta| meta.version < metadata.version)
+        {
+            committed = Some(metadata);
+        }
+    }
+    committed
+        .map(|metadata| (header, metadata))
+        .ok_or(Error::Corrupted)
+}
+
+// page numbers from the root to a leaf, with the index of the child taken in every internal page
+type Path = Vec<(u64, usize)>;
 
-        let mut fls_page = self.pager.allocate_page(self.metadata.freelist_page);
-        self.freelist.serialize(&mut fls_page.data);
-        self.pager.write_page(&fls_page);
+// Iter[#TODO] (shoule add some comments )
+pub struct Iter<'a> {
+    tree: &'a BTree,
+    // the leaf being iterated and the index of the next key value in it
+    leaf: Option<(PinnedPage, usize)>,
+    // children not visited yet of every internal node on the path to the current leaf
+    stack: Vec<std::vec::IntoIter<u64>>,
+}
+
+// Iterator[#TODO] (should add some comments)
+impl Iterator for Iter<'_> {
+    type Item = Result<KeyValue, Error>;
+
+    // a page that can't be read ends the iteration with its error
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.try_next().transpose();
+        if let Some(Err(_)) = next {
+            self.leaf = None;
+            self.stack.clear();
+        }
+        next
+    }
+}
+
+// Iter[#TODO] (should add some comments)
+impl Iter<'_> {
+    fn try_next(&mut self) -> Result<Option<KeyValue>, Error> {
+        loop {
+            let usable_size = self.tree.pager.usable_size();
+            if let Some((page, index)) = self.leaf.as_mut() {
+                let view = SlottedPage::new(&page[..usable_size]);
+                if *index < view.len() {
+                    let item = view.key_value(*index);
+                    *index += 1;
+                    return self.tree.load_value(item).map(Some);
+                }
+                self.leaf = None;
+            }
+            let Some(children) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            let Some(page_number) = children.next() else {
+                self.stack.pop();
+                continue;
+            };
+            let page = self.tree.get_page(page_number)?;
+            let view = SlottedPage::new(&page[..usable_size]);
+            if view.is_leaf() {
+                self.leaf = Some((page, 0));
+            } else {
+                self.stack.push(view.children().into_iter());
+            }
+        }
     }
 }
 
@@ -596,12 +979,441 @@ impl Drop for BTree {
         if self.read_only {
             return;
         }
-        let mut meta_page = self.pager.allocate_page(DEFAULT_META_PN);
-        self.metadata.serialize(&mut meta_page.data);
-        self.pager.write_page(&meta_page);
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::VarintCodec;
+    use crate::util::testing::temp_path;
+
+    #[test]
+    fn test_page_sizes() {
+        for page_size in [MIN_PAGE_SIZE, DEFAULT_PAGE_SIZE, 64 * 1024, MAX_PAGE_SIZE] {
+            let path = temp_path(&format!("btree-page-size-{}", page_size));
+            let options = BTreeOptions {
+                page_size,
+                ..BTreeOptions::default()
+            };
+            let mut tree = BTree::with_options(&path, &options);
+            for i in 0..1200u64 {
+                tree.insert(&i.varint_encode(), format!("value-{:0>100}", i).as_bytes());
+            }
+            for i in (0..1200u64).step_by(3) {
+                tree.delete(&i.varint_encode()).unwrap();
+            }
+            drop(tree);
+
+            // page size comes from the meta page, not from the options
+            let tree = BTree::reader(&path);
+            assert_eq!(tree.pager.page_size(), page_size);
+            for i in 0..1200u64 {
+                let found = tree.find(&i.varint_encode());
+                if i.is_multiple_of(3) {
+                    assert!(found.is_err());
+                } else {
+                    assert_eq!(
+                        found.unwrap().value,
+                        format!("value-{:0>100}", i).into_bytes()
+                    );
+                }
+            }
+            assert_eq!(tree.iter().count(), 800);
+            // 800 values of about 110 bytes take several leaves unless a page holds them all
+            let is_leaf = tree.get_node(tree.metadata.root).unwrap().is_leaf;
+            assert_eq!(is_leaf, page_size >= 128 * 1024);
+            let file_size = std::fs::metadata(&path).unwrap().len();
+            assert_eq!(file_size % page_size as u64, 0);
+            drop(tree);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn test_in_place_updates() {
+        let path = temp_path("btree-in-place");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        for i in (0..3000u64).step_by(2) {
+            tree.insert(&i.varint_encode(), format!("value-{:0>50}", i).as_bytes());
+        }
+        tree.flush();
+
+        // a key that fits in its leaf only copies the pages from the root to the leaf
+        let (path_pages, _) = tree.find_path(&1001u64.varint_encode()).unwrap();
+        assert!(path_pages.len() >= 2);
+        tree.insert(&1001u64.varint_encode(), b"new");
+        assert_eq!(tree.dirty_pages.len(), path_pages.len());
+        assert_eq!(tree.pending_pages.len(), path_pages.len());
+
+        // the second change of the same leaf is written in place
+        tree.delete(&1000u64.varint_encode()).unwrap();
+        tree.insert(&1001u64.varint_encode(), b"newer");
+        assert_eq!(tree.dirty_pages.len(), path_pages.len());
+        drop(tree);
+
+        let tree = BTree::reader(&path);
+        assert_eq!(tree.find(&1001u64.varint_encode()).unwrap().value, b"newer");
+        assert!(tree.find(&1000u64.varint_encode()).is_err());
+        assert_eq!(
+            tree.fuzz_find(&1000u64.varint_encode()).unwrap().value,
+            format!("value-{:0>50}", 998).into_bytes()
+        );
+        assert_eq!(tree.iter().count(), 1500);
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let path = temp_path("btree-iter-from");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        assert_eq!(tree.iter_from(&0u64.varint_encode()).unwrap().count(), 0);
+        for i in (0..3000u64).step_by(2) {
+            tree.insert(&i.varint_encode(), format!("value-{:0>50}", i).as_bytes());
+        }
+        assert!(tree.check().depth >= 2);
+        for start in [0u64, 1, 999, 1000, 2997, 2998, 2999, 5000] {
+            let keys: Vec<u64> = tree
+                .iter_from(&start.varint_encode())
+                .unwrap()
+                .map(|kv| u64::varint_decode(&kv.unwrap().key).1)
+                .collect();
+            let expected: Vec<u64> = (start.next_multiple_of(2)..3000).step_by(2).collect();
+            assert_eq!(keys, expected, "{start}");
+        }
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_incompatible_files_are_rejected() {
+        let path = temp_path("btree-foreign-file");
+        let options = BTreeOptions::default();
+        std::fs::write(&path, vec![7u8; MIN_PAGE_SIZE]).unwrap();
+        assert_eq!(
+            BTree::try_reader_with_options(&path, &options).err(),
+            Some(Error::IncompatibleFormat)
+        );
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(
+            BTree::try_reader_with_options(&path, &options).err(),
+            Some(Error::IncompatibleFormat)
+        );
+
+        // a tree written before files were versioned starts with the number of its root page
+        let mut unversioned = vec![0u8; MIN_PAGE_SIZE];
+        unversioned[0..8].clone_from_slice(&u64::to_le_bytes(2));
+        std::fs::write(&path, unversioned).unwrap();
+        assert_eq!(
+            BTree::try_reader_with_options(&path, &options).err(),
+            Some(Error::IncompatibleFormat)
+        );
+
+        // a btree file of another format version
+        let _ = std::fs::remove_file(&path);
+        drop(BTree::new(&path));
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&u32::to_le_bytes(constant::FORMAT_VERSION + 1), 8)
+            .unwrap();
+        drop(file);
+        assert_eq!(
+            BTree::try_reader_with_options(&path, &options).err(),
+            Some(Error::IncompatibleFormat)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_corrupted_page() {
+        let path = temp_path("btree-corrupted-page");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..500u64 {
+            tree.insert(&i.varint_encode(), format!("value-{:0>100}", i).as_bytes());
+        }
+        drop(tree);
+
+        let tree = BTree::reader(&path);
+        let kv = tree.find(&250u64.varint_encode()).unwrap();
+        let mut ancestors = vec![];
+        let (leaf, _, _) = tree
+            .find_node(tree.metadata.root, &kv.key, &mut ancestors)
+            .unwrap();
+        drop(tree);
+
+        // flip a byte in the middle of the leaf holding the key
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let offset = leaf.offset * MIN_PAGE_SIZE as u64 + 100;
+        let mut byte = [0u8; 1];
+        file.read_exact_at(&mut byte, offset).unwrap();
+        file.write_all_at(&[byte[0] ^ 0x40], offset).unwrap();
+        drop(file);
+
+        for mmap in [false, true] {
+            let tree = BTree::reader_with_options(
+                &path,
+                &BTreeOptions {
+                    mmap,
+                    ..BTreeOptions::default()
+                },
+            );
+            assert_eq!(
+                tree.find(&250u64.varint_encode()).err(),
+                Some(Error::Corrupted)
+            );
+            assert_eq!(
+                tree.fuzz_find(&250u64.varint_encode()).err(),
+                Some(Error::Corrupted)
+            );
+            // other leaves are still readable
+            assert!(tree.find(&0u64.varint_encode()).is_ok());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mmap_reader() {
+        let path = temp_path("btree-mmap-reader");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let value = |i: u64| -> Vec<u8> {
+            let size = if i.is_multiple_of(50) { 3 * MIN_PAGE_SIZE } else { 60 };
+            (0..size).map(|j| (i + j as u64) as u8).collect()
+        };
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..2000u64 {
+            tree.insert(&(i * 2).varint_encode(), &value(i * 2));
+        }
+        drop(tree);
+
+        let mapped_options = BTreeOptions {
+            mmap: true,
+            ..BTreeOptions::default()
+        };
+        let mapped = BTree::reader_with_options(&path, &mapped_options);
+        let copied = BTree::reader(&path);
+        for i in 0..4000u64 {
+            let key = i.varint_encode();
+            assert_eq!(
+                mapped.find(&key).map(|kv| kv.value),
+                copied.find(&key).map(|kv| kv.value)
+            );
+            assert_eq!(
+                mapped.fuzz_find(&key).map(|kv| kv.value),
+                copied.fuzz_find(&key).map(|kv| kv.value)
+            );
+        }
+        assert!(mapped
+            .iter()
+            .zip(copied.iter())
+            .map(|(left, right)| (left.unwrap(), right.unwrap()))
+            .all(|(left, right)| left.key == right.key && left.value == right.value));
+        assert_eq!(mapped.iter().count(), 2000);
+        drop(copied);
+
+        // pages appended after the file was mapped are read from a new mapping
+        let size = std::fs::metadata(&path).unwrap().len();
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 2000..4000u64 {
+            tree.insert(&(i * 2).varint_encode(), &value(i * 2));
+        }
+        tree.flush();
+        let last_page = std::fs::metadata(&path).unwrap().len() / MIN_PAGE_SIZE as u64 - 1;
+        assert!(last_page * MIN_PAGE_SIZE as u64 >= size);
+        assert_eq!(
+            *mapped.pager.pin_page(last_page).unwrap(),
+            *tree.pager.pin_page(last_page).unwrap()
+        );
+        assert_eq!(
+            mapped.pager.pin_page(last_page + 1).err(),
+            Some(Error::PageLoadErr)
+        );
+        drop(tree);
+        drop(mapped);
+
+        let mapped = BTree::reader_with_options(&path, &mapped_options);
+        assert_eq!(mapped.iter().count(), 4000);
+        assert_eq!(
+            mapped.find(&7000u64.varint_encode()).unwrap().value,
+            value(7000)
+        );
+        assert!(mapped.check().is_ok());
+        drop(mapped);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid page size")]
+    fn test_invalid_page_size() {
+        let options = BTreeOptions {
+            page_size: 5000,
+            ..BTreeOptions::default()
+        };
+        BTree::with_options(&temp_path("btree-invalid-page-size"), &options);
+    }
+
+    #[test]
+    fn test_freelist_reuse() {
+        let path = temp_path("btree-freelist-reuse");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let value = |i: u64| format!("value-{:0>100}", i).into_bytes();
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..20000u64 {
+            tree.insert(&i.varint_encode(), &value(i));
+        }
+        // pages of the uncommitted version are reused right away, only committed pages are
+        // released to the freelist
+        tree.flush();
+        for i in 0..20000u64 {
+            tree.delete(&i.varint_encode()).unwrap();
+        }
+        drop(tree);
+        let file_size = std::fs::metadata(&path).unwrap().len();
+
+        let mut tree = BTree::with_options(&path, &options);
+        // more released pages than a single freelist page can hold
+        assert!(tree.freelist.size() > MIN_PAGE_SIZE);
+        assert!(tree.freelist_pages.len() > 1);
+        for page in tree.freelist_pages.iter() {
+            assert!(!tree.freelist.released_pages.contains(page));
+        }
+        assert!(!tree.freelist.released_pages.contains(&DEFAULT_META_PN));
+
+        // released pages are reused instead of growing the file
+        for i in 0..20000u64 {
+            tree.insert(&i.varint_encode(), &value(i));
+        }
+        drop(tree);
+        let grown = std::fs::metadata(&path).unwrap().len() - file_size;
+        assert!(grown <= 8 * MIN_PAGE_SIZE as u64, "file grown by {grown}");
+
+        let tree = BTree::reader(&path);
+        for i in (0..20000u64).step_by(17) {
+            assert_eq!(tree.find(&i.varint_encode()).unwrap().value, value(i));
+        }
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_uncommitted_changes_are_invisible() {
+        let path = temp_path("btree-cow-crash");
+        let options = BTreeOptions {
+            page_size: MIN_PAGE_SIZE,
+            ..BTreeOptions::default()
+        };
+        let value = |i: u64, round: u64| format!("value-{}-{:0>100}", round, i).into_bytes();
+        let mut tree = BTree::with_options(&path, &options);
+        for i in 0..500u64 {
+            tree.insert(&i.varint_encode(), &value(i, 0));
+        }
+        tree.flush();
+        let version = tree.metadata.version;
+
+        // a crash in the middle of splits and merges, nothing of it is committed
+        for i in 0..500u64 {
+            tree.insert(&i.varint_encode(), &value(i, 1));
+        }
+        for i in (0..500u64).step_by(2) {
+            tree.delete(&i.varint_encode()).unwrap();
+        }
+        for i in 500..1000u64 {
+            tree.insert(&i.varint_encode(), &value(i, 1));
+        }
+        std::mem::forget(tree);
+
+        let tree = BTree::reader(&path);
+        assert_eq!(tree.metadata.version, version);
+        let items: Vec<KeyValue> = tree.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(items.len(), 500);
+        for (i, kv) in items.iter().enumerate() {
+            assert_eq!(kv.key, (i as u64).varint_encode());
+            assert_eq!(kv.value, value(i as u64, 0));
+        }
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // a commit writes the copy of the new version only
+    #[test]
+    fn test_commit_writes_one_meta_slot() {
+        let path = temp_path("btree-cow-meta-slot");
+        let mut tree = BTree::new(&path);
+        tree.insert(&1u64.varint_encode(), b"first");
+        tree.flush();
+        let read_meta_page = || {
+            let mut buffer = vec![0u8; DEFAULT_PAGE_SIZE];
+            File::open(&path).unwrap().read_exact_at(&mut buffer, 0).unwrap();
+            buffer
+        };
+        for round in 0..4u64 {
+            let before = read_meta_page();
+            tree.insert(&1u64.varint_encode(), &round.to_le_bytes());
+            tree.flush();
+            let after = read_meta_page();
+            let slot = tree.metadata.slot_offset();
+            let changed: Vec<usize> =
+                (0..before.len()).filter(|i| before[*i] != after[*i]).collect();
+            assert!(!changed.is_empty());
+            assert!(changed.iter().all(|i| (slot..slot + META_SIZE).contains(i)));
+        }
+        drop(tree);
+        let tree = BTree::reader(&path);
+        assert_eq!(tree.find(&1u64.varint_encode()).unwrap().value, 3u64.to_le_bytes());
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
+    }
 
-        let mut fls_page = self.pager.allocate_page(self.metadata.freelist_page);
-        self.freelist.serialize(&mut fls_page.data);
-        self.pager.write_page(&fls_page);
+    #[test]
+    fn test_torn_meta_falls_back() {
+        let path = temp_path("btree-cow-torn-meta");
+        let mut tree = BTree::new(&path);
+        tree.insert(&1u64.varint_encode(), b"first");
+        tree.flush();
+        tree.insert(&1u64.varint_encode(), b"second");
+        tree.insert(&2u64.varint_encode(), b"second");
+        tree.flush();
+        let slot = tree.metadata.slot_offset();
+        drop(tree);
+        assert_eq!(
+            BTree::reader(&path)
+                .find(&2u64.varint_encode())
+                .unwrap()
+                .value,
+            b"second"
+        );
+
+        // break the copy written by the last commit
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xff; 4], slot as u64 + 8).unwrap();
+        drop(file);
+
+        let tree = BTree::reader(&path);
+        assert_eq!(tree.find(&1u64.varint_encode()).unwrap().value, b"first");
+        assert!(tree.find(&2u64.varint_encode()).is_err());
+        drop(tree);
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -1,12 +1,10 @@
-use core::panic;
-use std::usize;
-
 use crate::bytes::{self, VarintCodec};
 
 use super::constant::{
     DEFAULT_MAX_THRESHOLD, DEFAULT_MIN_THRESHOLD, HEAD_INTERNAL_NODE_SIZE, HEAD_LEAF_NODE_SIZE,
 };
 use super::error::Error;
+use super::slotted::{SlottedPage, SlottedPageMut, SLOT_SIZE};
 
 type Offset = u64;
 type Key = Vec<u8>;
@@ -16,6 +14,9 @@ type Value = Vec<u8>;
 pub struct KeyValue {
     pub key: Key,
     pub value: Value,
+    // first overflow page of the value, 0 means the value is stored inline (value is empty
+    // otherwise, page 0 is always the meta page)
+    pub overflow: Offset,
 }
 
 // KeyValue[#TODO] (should add some comments)
@@ -24,8 +25,38 @@ impl KeyValue {
         Self {
             key: key.into(),
             value: value.into(),
+            overflow: 0,
+        }
+    }
+
+    pub fn new_overflow(key: &[u8], first_page: Offset) -> Self {
+        Self {
+            key: key.into(),
+            value: Vec::new(),
+            overflow: first_page,
         }
     }
+
+    // bytes used in a leaf page, 4B slot + 2B key size + 2B value size + data
+    #[inline]
+    pub fn size(&self) -> usize {
+        let value_size = if self.overflow == 0 {
+            self.value.len()
+        } else {
+            8
+        };
+        SLOT_SIZE + 4 + self.key.len() + value_size
+    }
+}
+
+// value size marker of a key value whose value lives in overflow pages
+pub(super) const OVERFLOW_VALUE_SIZE: u16 = u16::MAX;
+
+// values bigger than this are moved into overflow pages, so that a leaf always holds
+// several key values
+#[inline]
+pub fn max_inline_value_size(page_size: usize) -> usize {
+    (page_size / 4).min(OVERFLOW_VALUE_SIZE as usize - 1)
 }
 
 pub enum TypedNode {
@@ -35,11 +66,12 @@ pub enum TypedNode {
 }
 
 // LeafNode[#TODO] (shoule add some comments )
+// leaves are not linked to their siblings: a node copied on write gets a new page number, and
+// fixing the links of its siblings would copy them too, and their siblings after them. Leaves
+// are reached from the root, the order of leaves is the order they are reached in
 #[derive(Default)]
 pub struct LeafNode {
     pub keyvalues: Vec<KeyValue>,
-    pub prev_offset: Offset,
-    pub next_offset: Offset,
 }
 
 #[derive(Default)]
@@ -48,8 +80,26 @@ pub struct InternalNode {
     pub children: Vec<Offset>,
 }
 
-const NODEASLEAF: u8 = 0;
-const NODEASINTERNAL: u8 = 1;
+pub(super) const NODEASLEAF: u8 = 0;
+pub(super) const NODEASINTERNAL: u8 = 1;
+
+// bytes used in an internal page by a separator key, 4B slot + 2B key size + 8B child + key
+#[inline]
+pub(super) fn internal_key_size(key: &[u8]) -> usize {
+    SLOT_SIZE + 2 + 8 + key.len()
+}
+
+// a node of `size` bytes must be split
+#[inline]
+pub fn is_overflow_size(size: usize, page_size: usize) -> bool {
+    size as f64 > DEFAULT_MAX_THRESHOLD * page_size as f64
+}
+
+// a node of `size` bytes must borrow from or be merged with a sibling
+#[inline]
+pub fn is_underflow_size(size: usize, page_size: usize) -> bool {
+    size < (DEFAULT_MIN_THRESHOLD * page_size as f64) as usize
+}
 
 pub struct Node {
     pub offset: Offset, // cost 8B
@@ -118,198 +168,71 @@ impl Node {
     }
 }
 
-///  node format , value is offset if the node is internal node, else value is String
-/// |-----------------------------------------------------------------------------------|
-/// | node type | key number  |   .............. Data ....                              |
-/// |  1B       |  2B         |   ........................                              |
-/// |-----------------------------------------------------------------------------------|
-/// |                          DATA                                                     |
-/// | c0 | len(k0) |  k0 | len(v0)  | v0 |  c1 |  len(k1) | k2 | len(v2) | v2 | ........|
-/// | 8B |  1B     | xx  |  1B      | xx |  8B |  1B      | x  |  1B     | xxx| ........|
-/// |-----------------------------------------------------------------------------------|
-///
+/// nodes are stored as slotted pages, see slotted.rs for the format. Node is the owned form
+/// used when the structure of the tree changes (split, merge, redistribution); lookups and
+/// updates that fit in the page work on the page directly
 impl Node {
     pub fn serialize(&self, buf: &mut [u8]) {
         match self.data {
-            TypedNode::Internal(ref internal_node) => self.serialize_internal(internal_node, buf),
-            TypedNode::Leaf(ref leaf_node) => self.serialize_leaf(leaf_node, buf),
+            TypedNode::Internal(ref internal_node) => {
+                let mut page = SlottedPageMut::init(buf, NODEASINTERNAL);
+                page.set_child(0, internal_node.children[0]);
+                for (idx, key) in internal_node.keys.iter().enumerate() {
+                    let inserted = page.insert_internal(idx, key, internal_node.children[idx + 1]);
+                    assert!(inserted, "internal node {} too large for page", self.offset);
+                }
+            }
+            TypedNode::Leaf(ref leaf_node) => {
+                let mut page = SlottedPageMut::init(buf, NODEASLEAF);
+                for (idx, kv) in leaf_node.keyvalues.iter().enumerate() {
+                    let inserted = page.insert_leaf(idx, kv);
+                    assert!(inserted, "leaf node {} too large for page", self.offset);
+                }
+            }
             _ => {}
         };
     }
 
-    fn serialize_internal(&self, internal_node: &InternalNode, buf: &mut [u8]) {
-        let mut offset = 0;
-        // write node type, which cost 1B
-        buf[offset] = NODEASINTERNAL;
-        offset += 1;
-
-        // write numbers of keys, which cost 8B
-        let key_num = internal_node.keys.len() as u64;
-        buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(key_num).as_ref());
-        offset += 8;
-
-        // write keys into page
-        for key in internal_node.keys.iter() {
-            // write the length of keys into page , which cost 2B
-            let key_size = key.len();
-            buf[offset..offset + 2].clone_from_slice(u16::to_le_bytes(key_size as u16).as_ref());
-            offset += 2;
-            // write key into page, which cost key_size
-            buf[offset..offset + key_size].clone_from_slice(key);
-            offset += key_size;
-        }
-
-        // write number of child into page, which cost 8B
-        let child_num = internal_node.children.len() as u64;
-        buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(child_num).as_ref());
-        offset += 8;
-
-        // write children into page, which child cost 8B
-        for &child in internal_node.children.iter() {
-            buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(child).as_ref());
-            offset += 8;
-        }
-    }
-
-    fn serialize_leaf(&self, leaf_node: &LeafNode, buf: &mut [u8]) {
-        let mut offset = 0;
-        // write node type, which cost 1B
-        buf[offset] = NODEASLEAF;
-        offset += 1;
-
-        // write prev_offset, which cost 8B
-        buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(leaf_node.prev_offset).as_ref());
-        offset += 8;
-
-        // write next_offset, which cost 8B
-        buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(leaf_node.next_offset).as_ref());
-        offset += 8;
-
-        // write numbers of keys, which cost 8B
-        let key_num = leaf_node.keyvalues.len() as u64;
-        buf[offset..offset + 8].clone_from_slice(u64::to_le_bytes(key_num).as_ref());
-        offset += 8;
-
-        // write key value into page
-        for kv in leaf_node.keyvalues.iter() {
-            // write key size into page,which cost 2B
-            let key_size = kv.key.len();
-            buf[offset..offset + 2].clone_from_slice(u16::to_le_bytes(key_size as u16).as_ref());
-            offset += 2;
-
-            // write key into page
-            buf[offset..offset + key_size].clone_from_slice(kv.key.as_ref());
-            offset += key_size;
-
-            // write value size into page, which cost 2B
-            let value_size = kv.value.len();
-            buf[offset..offset + 2].clone_from_slice(u16::to_le_bytes(value_size as u16).as_ref());
-            offset += 2;
-
-            // write value bytes into page, which cost value_size * B
-            buf[offset..offset + value_size].clone_from_slice(kv.value.as_ref());
-            offset += value_size;
-        }
-    }
-
     pub fn deserialize(&mut self, buf: &[u8]) {
-        // get node type at first Byte
-        let ntype = buf[0];
-        if ntype == NODEASLEAF {
-            self.deserialize_leaf(buf);
+        let page = SlottedPage::new(buf);
+        self.is_leaf = page.is_leaf();
+        self.data = if page.is_leaf() {
+            TypedNode::Leaf(LeafNode {
+                keyvalues: (0..page.len()).map(|idx| page.key_value(idx)).collect(),
+            })
         } else {
-            self.deserialize_internal(buf);
-        }
-    }
-    fn deserialize_internal(&mut self, buf: &[u8]) {
-        let mut offset = 0;
-        self.is_leaf = buf[offset] == NODEASLEAF;
-        offset += 1;
-
-        // get number of keys, which should read 8B from buffer
-        let keys_num = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-        // get keys from buffer,
-        let mut keys = vec![];
-
-        for _ in 0..keys_num {
-            // get size of key, which should be read 2B
-            let key_size = u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-            offset += 2;
-
-            // get key String from buffer, which should be read key_size * B
-            let mut key_bytes = vec![0; key_size];
-            key_bytes.clone_from_slice(buf[offset..offset + key_size].into());
-            offset += key_size;
-
-            keys.push(key_bytes);
-        }
-        // get children
-        let mut children = vec![];
-        let children_num = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-        for _ in 0..children_num {
-            let child = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-            offset += 8;
-            children.push(child);
-        }
-
-        self.data = TypedNode::Internal(InternalNode { keys, children });
+            TypedNode::Internal(InternalNode {
+                keys: (0..page.len()).map(|idx| page.key(idx).to_vec()).collect(),
+                children: page.children(),
+            })
+        };
     }
 
-    fn deserialize_leaf(&mut self, buf: &[u8]) {
-        let mut offset = 0;
-
-        // read nodetype
-        self.is_leaf = buf[offset] == NODEASLEAF;
-        offset += 1;
-
-        // get preoffset
-        let prev_offset = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-
-        //
-        // get nextoffset
-        let next_offset = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-
-        let kv_num = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-
-        let mut key_values = Vec::with_capacity(kv_num as usize);
-        for _ in 0..kv_num {
-            // get key size, which we should read 2B from buffer
-            let key_size = u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-            offset += 2;
-
-            // get key bytes, which we should read key_size * B from buffer
-            let mut key_bytes = vec![0; key_size];
-            key_bytes.clone_from_slice(buf[offset..offset + key_size].try_into().unwrap());
-            offset += key_size;
-
-            // get value size, which we should read 2B from buffer
-            let val_size = u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-            offset += 2;
-
-            // get value bytes, which we should read value_size * B from buffer
-            let mut value_bytes = vec![0; val_size];
-            value_bytes.clone_from_slice(buf[offset..offset + val_size].try_into().unwrap());
-            offset += val_size;
-
-            key_values.push(KeyValue {
-                key: key_bytes,
-                value: value_bytes,
-            });
+    // bytes used by the serialized node
+    pub fn size(&self) -> usize {
+        match self.data {
+            TypedNode::Internal(ref internal_node) => {
+                HEAD_INTERNAL_NODE_SIZE
+                    + internal_node
+                        .keys
+                        .iter()
+                        .map(|key| internal_key_size(key))
+                        .sum::<usize>()
+            }
+            TypedNode::Leaf(ref leaf_node) => {
+                HEAD_LEAF_NODE_SIZE
+                    + leaf_node
+                        .keyvalues
+                        .iter()
+                        .map(KeyValue::size)
+                        .sum::<usize>()
+            }
+            TypedNode::Empty => 0,
         }
-        self.data = TypedNode::Leaf(LeafNode {
-            keyvalues: key_values,
-            prev_offset,
-            next_offset,
-        });
     }
 
-    pub fn split(&mut self, new_offset: Offset) -> Result<(Key, Node), Error> {
-        let split_index = self.get_split_index();
+    pub fn split(&mut self, new_offset: Offset, page_size: usize) -> Result<(Key, Node), Error> {
+        let split_index = self.get_split_index(page_size);
         if split_index == -1 {
             return Err(Error::Generic);
         }
@@ -332,10 +255,6 @@ impl Node {
                 Ok((middle_item, new_node))
             }
             TypedNode::Leaf(ref mut leaf_node) => {
-                if leaf_node.keyvalues.len() < 2 {
-                    return Err(Error::Generic);
-                }
-                let splited_index = 1;
                 let middle_item = leaf_node.keyvalues[splited_index].clone();
                 let mut new_node = Node::new_leaf(new_offset);
                 new_node
@@ -343,83 +262,97 @@ impl Node {
                     .keyvalues
                     .extend_from_slice(&leaf_node.keyvalues[splited_index..]);
                 leaf_node.keyvalues.drain(splited_index..);
-                leaf_node.next_offset = new_node.offset;
-                new_node.leaf_data().prev_offset = self.offset;
                 Ok((middle_item.key, new_node))
             }
             TypedNode::Empty => Err(Error::Generic),
         }
     }
 
-    pub fn can_spare_element(&self) -> bool {
-        match self.data {
-            TypedNode::Internal(ref internal_node) => internal_node.keys.len() > 1,
-            TypedNode::Leaf(ref leaf_node) => leaf_node.keyvalues.len() > 1,
-            TypedNode::Empty => todo!(),
+    // append the entries of the right sibling, the separator of both is pulled down into an
+    // internal node
+    pub fn merge(&mut self, right: &mut Node, separator: Key) {
+        match (&mut self.data, &mut right.data) {
+            (TypedNode::Internal(ref mut left), TypedNode::Internal(ref mut right)) => {
+                left.keys.push(separator);
+                left.keys.append(&mut right.keys);
+                left.children.append(&mut right.children);
+            }
+            (TypedNode::Leaf(ref mut left), TypedNode::Leaf(ref mut right)) => {
+                left.keyvalues.append(&mut right.keyvalues);
+            }
+            _ => panic!("only nodes of the same type can be merged"),
         }
     }
 
+    // binary search over the sorted keys, return whether the key exists and the index it is at
+    // or should be inserted at
     pub fn find_key_in_leaf(&self, key: &[u8]) -> (bool, usize) {
         if let TypedNode::Leaf(ref leaf_node) = self.data {
-            for (idx, elem) in leaf_node.keyvalues.iter().enumerate() {
-                match bytes::compare(&elem.key, key) {
-                    std::cmp::Ordering::Equal => {
-                        return (true, idx);
-                    }
-                    std::cmp::Ordering::Greater => {
-                        return (false, idx);
-                    }
-                    std::cmp::Ordering::Less => {}
-                }
+            match leaf_node
+                .keyvalues
+                .binary_search_by(|elem| bytes::compare(&elem.key, key))
+            {
+                Ok(idx) => (true, idx),
+                Err(idx) => (false, idx),
             }
-            (false, leaf_node.keyvalues.len())
         } else {
             panic!("this is not leaf node");
         }
     }
 
+    // keys >= separator go to the right child, so the child index is the number of separators
+    // not greater than the key
     pub fn find_key_in_internal(&self, key: &[u8]) -> (usize, Offset) {
         if let TypedNode::Internal(ref internal_node) = self.data {
-            for (idx, elem) in internal_node.keys.iter().enumerate() {
-                /* match elem.as_str().cmp(key) { */
-                match bytes::compare(elem, key) {
-                    std::cmp::Ordering::Equal => {
-                        return (idx + 1, internal_node.children[idx + 1]);
-                    }
-                    std::cmp::Ordering::Greater => {
-                        return (idx, internal_node.children[idx]);
-                    }
-                    std::cmp::Ordering::Less => {}
-                }
-            }
-            (
-                internal_node.keys.len(),
-                *internal_node.children.last().unwrap(),
-            )
+            let idx = internal_node
+                .keys
+                .partition_point(|elem| bytes::compare(elem, key) != std::cmp::Ordering::Greater);
+            (idx, internal_node.children[idx])
         } else {
             panic!("this is not internal node");
         }
     }
 
-    fn get_split_index(&self) -> i32 {
+    // split where the left node takes about half of the bytes, both nodes keep at least one key
+    fn get_split_index(&self, _page_size: usize) -> i32 {
+        let half = self.size() / 2;
         match self.data {
             TypedNode::Internal(ref internal_node) => {
+                if internal_node.keys.len() < 2 {
+                    return -1;
+                }
                 let mut threshold_value = HEAD_INTERNAL_NODE_SIZE;
-                for idx in 0..internal_node.keys.len() {
-                    threshold_value += internal_node.keys[idx].len() + 2 + 8;
-                    if threshold_value > DEFAULT_MIN_THRESHOLD as usize {
-                        return idx as i32;
+                for (idx, key) in internal_node.keys.iter().enumerate() {
+                    let before = threshold_value;
+                    threshold_value += internal_key_size(key);
+                    if threshold_value > half {
+                        let idx = if threshold_value - half < half - before {
+                            idx + 1
+                        } else {
+                            idx
+                        };
+                        return idx.clamp(1, internal_node.keys.len() - 1) as i32;
                     }
                 }
                 -1
             }
             TypedNode::Leaf(ref leaf_node) => {
-                // 8B for the last child
-                let mut threshold_value = HEAD_LEAF_NODE_SIZE + 8;
+                if leaf_node.keyvalues.len() < 2 {
+                    return -1;
+                }
+                let mut threshold_value = HEAD_LEAF_NODE_SIZE;
                 for (idx, kv) in leaf_node.keyvalues.iter().enumerate() {
-                    threshold_value += kv.key.len() + kv.value.len() + 4;
-                    if threshold_value > DEFAULT_MIN_THRESHOLD as usize {
-                        return idx as i32;
+                    let before = threshold_value;
+                    threshold_value += kv.size();
+                    if threshold_value > half {
+                        // the entry crossing the half goes to the side that splits more evenly,
+                        // entries may take up to a quarter of a page
+                        let idx = if threshold_value - half < half - before {
+                            idx + 1
+                        } else {
+                            idx
+                        };
+                        return idx.clamp(1, leaf_node.keyvalues.len() - 1) as i32;
                     }
                 }
                 -1
@@ -428,50 +361,73 @@ impl Node {
         }
     }
 
-    pub fn is_underflow(&self) -> bool {
+    pub fn is_underflow(&self, page_size: usize) -> bool {
         match self.data {
-            TypedNode::Internal(ref internal_node) => {
-                let mut threshold_value = HEAD_INTERNAL_NODE_SIZE;
-                for idx in 0..internal_node.keys.len() {
-                    threshold_value += internal_node.keys[idx].len() + 2 + 8;
-                }
-                threshold_value < DEFAULT_MIN_THRESHOLD as usize
-            }
-            TypedNode::Leaf(ref leaf_node) => {
-                let mut threshold_value = HEAD_LEAF_NODE_SIZE + 8;
-                for kv in leaf_node.keyvalues.iter() {
-                    threshold_value += kv.key.len() + kv.value.len() + 4;
-                }
-                threshold_value < DEFAULT_MIN_THRESHOLD as usize
-            }
             TypedNode::Empty => todo!(),
+            _ => is_underflow_size(self.size(), page_size),
         }
-        /* self.leaf_items.len() < max_kvs().div(2) */
     }
 
-    pub fn is_overflow(&self) -> bool {
+    pub fn is_overflow(&self, page_size: usize) -> bool {
         match self.data {
-            TypedNode::Internal(ref internal_node) => {
-                let mut threshold_value = HEAD_INTERNAL_NODE_SIZE;
-                for idx in 0..internal_node.keys.len() {
-                    /* if idx > DEFAULT_MAX_LEAF_ITEMS_NUM as usize {
-                        return true;
-                    } */
-                    threshold_value += internal_node.keys[idx].len() + 2 + 8;
-                }
-                (threshold_value as f64) > DEFAULT_MAX_THRESHOLD
-            }
-            TypedNode::Leaf(ref leaf_node) => {
-                let mut threshold_value = HEAD_INTERNAL_NODE_SIZE + 8;
-                for kv in leaf_node.keyvalues.iter() {
-                    /* if idx > DEFAULT_MAX_LEAF_ITEMS_NUM as usize{
-                        return true;
-                    } */
-                    threshold_value += kv.key.len() + kv.value.len() + 4;
-                }
-                (threshold_value as f64) > DEFAULT_MAX_THRESHOLD
-            }
             TypedNode::Empty => todo!(),
+            _ => is_overflow_size(self.size(), page_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_key() {
+        // keys whose varint encoding is not ordered bytewise
+        let keys: Vec<u64> = (0..300u64).map(|i| i * 7).collect();
+        let mut leaf = Node::new_leaf(1);
+        let mut internal = Node::new_internal(2);
+        internal.internal_data().children.push(100);
+        for (idx, key) in keys.iter().enumerate() {
+            leaf.leaf_data()
+                .keyvalues
+                .push(KeyValue::new(&key.varint_encode(), b"v"));
+            internal.internal_data().keys.push(key.varint_encode());
+            internal.internal_data().children.push(101 + idx as u64);
+        }
+
+        for probe in 0..2200u64 {
+            let encoded = probe.varint_encode();
+            let expected = keys.iter().filter(|&&key| key < probe).count();
+            let exists = probe % 7 == 0 && probe < 2100;
+            assert_eq!(leaf.find_key_in_leaf(&encoded), (exists, expected));
+
+            let child = if exists { expected + 1 } else { expected };
+            assert_eq!(
+                internal.find_key_in_internal(&encoded),
+                (child, 100 + child as u64)
+            );
+        }
+
+        let empty = Node::new_leaf(3);
+        assert_eq!(empty.find_key_in_leaf(&1u64.varint_encode()), (false, 0));
+    }
+
+    // leaves are not linked, the link fields are cleared even over an old page
+    #[test]
+    fn test_leaves_are_not_linked() {
+        let page_size = super::super::constant::MIN_PAGE_SIZE;
+        let mut leaf = Node::new_leaf(1);
+        for key in 0..80u64 {
+            leaf.leaf_data()
+                .keyvalues
+                .push(KeyValue::new(&key.varint_encode(), &[0u8; 40]));
+        }
+        let (_, right) = leaf.split(2, page_size).unwrap();
+        for node in [&leaf, &right] {
+            let mut buf = vec![0xffu8; page_size];
+            node.serialize(&mut buf);
+            // prev/next leaf of the header
+            assert_eq!(buf[9..25], [0u8; 16]);
         }
     }
 }
@@ -0,0 +1,248 @@
+// overflow pages 用来保存放不进一个B树节点的数据, 多个页面通过next指针串成链表
+//
+// overflow page format
+// |--------------------------------------------------|
+// | next page | data len | ....... data ......       |
+// |--------------------------------------------------|
+// |   8B      |  4B      | ......................... |
+// |--------------------------------------------------|
+pub const HEAD_OVERFLOW_PAGE_SIZE: usize = 8 + 4;
+
+use super::constant::PAGE_TYPE_OVERFLOW;
+use super::error::Error;
+use super::pager::{Page, Pager};
+use super::BTree;
+
+// BTree[#TODO] (should add some comments)
+impl BTree {
+    // write data into a chain of overflow pages, return the first page number
+    pub fn write_overflow(&mut self, data: &[u8]) -> u64 {
+        let page_numbers: Vec<u64> = (0..self.overflow_pages_needed(data.len()))
+            .map(|_| self.allocate_page_number())
+            .collect();
+        self.write_overflow_pages(&page_numbers, data, PAGE_TYPE_OVERFLOW);
+        page_numbers[0]
+    }
+
+    #[inline]
+    pub(super) fn overflow_pages_needed(&self, len: usize) -> usize {
+        len.div_ceil(self.pager.usable_size() - HEAD_OVERFLOW_PAGE_SIZE)
+            .max(1)
+    }
+
+    // write data into the given pages, pages left over are kept in the chain with no data.
+    // page_type tells overflowed values and the freelist apart
+    pub(super) fn write_overflow_pages(
+        &mut self,
+        page_numbers: &[u64],
+        data: &[u8],
+        page_type: u8,
+    ) {
+        let capacity = self.pager.usable_size() - HEAD_OVERFLOW_PAGE_SIZE;
+        let mut pieces = data.chunks(capacity);
+        for (idx, &page_number) in page_numbers.iter().enumerate() {
+            let piece = pieces.next().unwrap_or_default();
+            let next_page = page_numbers.get(idx + 1).copied().unwrap_or(0);
+            let mut page = self.pager.allocate_page(page_number);
+            let mut offset = 0;
+            page.data[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(next_page));
+            offset += 8;
+            page.data[offset..offset + 4].clone_from_slice(&u32::to_le_bytes(piece.len() as u32));
+            offset += 4;
+            page.data[offset..offset + piece.len()].clone_from_slice(piece);
+            self.pager.write_page(&mut page, page_type);
+        }
+    }
+
+    pub fn read_overflow(&self, first_page: u64) -> Result<Vec<u8>, Error> {
+        self.read_overflow_chain(first_page, PAGE_TYPE_OVERFLOW)
+            .map(|(data, _)| data)
+    }
+
+    // read data of a chain together with the page numbers of the chain
+    pub(super) fn read_overflow_chain(
+        &self,
+        first_page: u64,
+        page_type: u8,
+    ) -> Result<(Vec<u8>, Vec<u64>), Error> {
+        read_chain(&self.pager, first_page, page_type)
+    }
+
+    pub fn free_overflow(&mut self, first_page: u64) {
+        let mut page_number = first_page;
+        while page_number != 0 {
+            let next_page = match self.pager.pin_page(page_number) {
+                Ok(page) => u64::from_le_bytes(page[0..8].try_into().unwrap()),
+                Err(_) => 0,
+            };
+            self.delete_node(page_number);
+            page_number = next_page;
+        }
+    }
+
+    // attachment is an opaque blob stored by the owner of the tree, e.g. the bloom filter of
+    // a segment; it is referenced from the meta page
+    pub fn write_attachment(&mut self, data: &[u8]) {
+        if self.metadata.attachment != 0 {
+            self.free_overflow(self.metadata.attachment);
+        }
+        self.metadata.attachment = self.write_overflow(data);
+    }
+
+    pub fn read_attachment(&self) -> Option<Vec<u8>> {
+        if self.metadata.attachment == 0 {
+            return None;
+        }
+        self.read_overflow(self.metadata.attachment).ok()
+    }
+}
+
+// page_type tells overflowed values and the freelist apart, a page of another type ends the read
+// as corrupted. Only the pager is needed, so readers of a shared tree can use it as well
+pub(super) fn read_chain(
+    pager: &Pager,
+    first_page: u64,
+    page_type: u8,
+) -> Result<(Vec<u8>, Vec<u64>), Error> {
+    let capacity = pager.usable_size() - HEAD_OVERFLOW_PAGE_SIZE;
+    let mut data = Vec::new();
+    let mut page_numbers = Vec::new();
+    let mut page_number = first_page;
+    while page_number != 0 {
+        page_numbers.push(page_number);
+        let page = pager.pin_page(page_number)?;
+        if Page::page_type(&page) != page_type {
+            return Err(Error::Corrupted);
+        }
+        let mut offset = 0;
+        page_number = u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap());
+        offset += 8;
+        let len = u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap()) as usize;
+        offset += 4;
+        if len > capacity {
+            return Err(Error::Corrupted);
+        }
+        data.extend_from_slice(&page[offset..offset + len]);
+    }
+    Ok((data, page_numbers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::VarintCodec;
+    use crate::util::testing::temp_path;
+
+    #[test]
+    fn test_overflow_chain() {
+        let path = &temp_path("overflow");
+
+        let data: Vec<u8> = (0..super::super::constant::DEFAULT_PAGE_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        {
+            let mut tree = BTree::new(path);
+            tree.write_attachment(b"first");
+            tree.write_attachment(&data);
+            assert_eq!(tree.read_attachment(), Some(data.clone()));
+        }
+        let tree = BTree::reader(path);
+        assert_eq!(tree.read_attachment(), Some(data.clone()));
+
+        // pages of a replaced attachment are reused, rewriting it doesn't grow the file
+        let mut tree = BTree::new(path);
+        let file_size = || std::fs::metadata(path).unwrap().len();
+        tree.write_attachment(&data);
+        tree.flush();
+        tree.write_attachment(&data);
+        tree.flush();
+        let size = file_size();
+        for _ in 0..10 {
+            tree.write_attachment(&data);
+            tree.flush();
+        }
+        assert_eq!(file_size(), size);
+        assert_eq!(tree.read_attachment(), Some(data));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_overflow_values() {
+        let path = &temp_path("overflow-values");
+
+        let options = super::super::BTreeOptions {
+            page_size: super::super::constant::MIN_PAGE_SIZE,
+            ..Default::default()
+        };
+        // every 3rd value is larger than a page
+        let value = |i: u64, round: u64| -> Vec<u8> {
+            let size = if i.is_multiple_of(3) {
+                10_000 + i as usize
+            } else {
+                20
+            };
+            (0..size).map(|j| (j as u64 + i + round) as u8).collect()
+        };
+        {
+            let mut tree = BTree::with_options(path, &options);
+            for i in 0..200u64 {
+                tree.insert(&i.varint_encode(), &value(i, 0));
+            }
+            // overwrite big values with small ones and small values with big ones
+            for i in (0..200u64).step_by(2) {
+                tree.insert(&i.varint_encode(), &value(i + 1, 1));
+            }
+            for i in (0..200u64).step_by(5) {
+                let removed = tree.delete(&i.varint_encode()).unwrap();
+                let expected = if i.is_multiple_of(2) {
+                    value(i + 1, 1)
+                } else {
+                    value(i, 0)
+                };
+                assert_eq!(removed.value, expected);
+            }
+        }
+
+        let tree = BTree::reader(path);
+        let mut count = 0;
+        for kv in tree.iter() {
+            let kv = kv.unwrap();
+            let i = u64::varint_decode(&kv.key).1;
+            let expected = if i.is_multiple_of(2) {
+                value(i + 1, 1)
+            } else {
+                value(i, 0)
+            };
+            assert_eq!(kv.value, expected);
+            assert_eq!(tree.find(&kv.key).unwrap().value, expected);
+            count += 1;
+        }
+        assert_eq!(count, 160);
+        let _ = std::fs::remove_file(path);
+    }
+
+    // values of any size go into overflow pages, keys must fit a node
+    #[test]
+    fn test_large_keys() {
+        let path = &temp_path("large-keys");
+
+        let options = super::super::BTreeOptions {
+            page_size: super::super::constant::MIN_PAGE_SIZE,
+            ..Default::default()
+        };
+        let max_key_size = super::super::node::max_inline_value_size(options.page_size);
+        let mut tree = BTree::with_options(path, &options);
+        let key = |len: usize| -> Vec<u8> {
+            let mut key = 7u64.varint_encode();
+            key.resize(len, 0xab);
+            key
+        };
+        let value = vec![1u8; 100_000];
+        assert_eq!(tree.try_insert(&key(max_key_size + 1), b"value"), Err(Error::KeyTooLarge));
+        assert_eq!(tree.metadata.root, 0);
+        assert_eq!(tree.try_insert(&key(max_key_size), &value), Ok(()));
+        tree.flush();
+        assert_eq!(tree.find(&key(max_key_size)).unwrap().value, value);
+        let _ = std::fs::remove_file(path);
+    }
+}
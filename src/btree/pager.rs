@@ -1,8 +1,14 @@
 use std::fs::File;
 use std::os::unix::fs::FileExt;
-use std::{u64, usize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
-use super::constant::DEFAULT_PAGE_SIZE;
+use memmap2::Mmap;
+
+use super::buffer::{BufferPool, FileId, PinnedPage};
+use super::constant::{DEFAULT_META_PN, PAGE_TRAILER_SIZE, PAGE_TYPE_META};
+use super::error::Error;
+use crate::util::crc32;
 
 #[derive(Default, Debug)]
 pub struct Page {
@@ -19,23 +25,107 @@ impl Page {
             page_number,
         }
     }
+
+    // type tag of the page, written by Pager::write_page
+    #[inline]
+    pub fn page_type(data: &[u8]) -> u8 {
+        data[data.len() - PAGE_TRAILER_SIZE]
+    }
+
+    // fill in the trailer of the page
+    fn seal(&mut self, page_type: u8) {
+        let type_offset = self.data.len() - PAGE_TRAILER_SIZE;
+        self.data[type_offset] = page_type;
+        let checksum = crc32(&self.data[..type_offset + 1]);
+        self.data[type_offset + 1..].clone_from_slice(&u32::to_le_bytes(checksum));
+    }
+
+    // return false if the trailer does not match the page content
+    fn verify(data: &[u8]) -> bool {
+        let checksum_offset = data.len() - PAGE_TRAILER_SIZE + 1;
+        let checksum = u32::from_le_bytes(data[checksum_offset..].try_into().unwrap());
+        checksum == crc32(&data[..checksum_offset])
+    }
 }
 
 // Pager[#TODO] (shoule add some comments )
 pub struct Pager {
-    file: File,
+    file: Arc<File>,
     page_size: usize,
+    // shared page cache, pages are read and written through the pool when set
+    pool: Option<Arc<BufferPool>>,
+    file_id: FileId,
+    // read only pagers may map the file, pages are then slices of the mapping and the pool is
+    // not used
+    mapping: Option<RwLock<Mapping>>,
+}
+
+struct Mapping {
+    map: Arc<Mmap>,
+    // one bit per page, set once the checksum of the page is verified
+    verified: Vec<AtomicU64>,
 }
 
 // Pager[#TODO] (should add some comments)
 impl Pager {
-    pub fn new(file: File) -> Self {
+    pub fn new(file: File, page_size: usize) -> Self {
+        Self::with_pool(file, page_size, None, 0)
+    }
+
+    // pages of the file are cached in the pool under file_id
+    pub fn with_pool(
+        file: File,
+        page_size: usize,
+        pool: Option<Arc<BufferPool>>,
+        file_id: FileId,
+    ) -> Self {
         Pager {
-            file,
-            page_size: DEFAULT_PAGE_SIZE,
+            file: Arc::new(file),
+            page_size,
+            pool,
+            file_id,
+            mapping: None,
         }
     }
 
+    // a read only pager over the mapped file, pages are not copied when they are read
+    pub fn with_mmap(file: File, page_size: usize) -> Result<Self, Error> {
+        let map = unsafe { Mmap::map(&file) }.map_err(|_| Error::PageLoadErr)?;
+        let verified = Self::verified_words(map.len(), page_size);
+        Ok(Pager {
+            file: Arc::new(file),
+            page_size,
+            pool: None,
+            file_id: 0,
+            mapping: Some(RwLock::new(Mapping {
+                map: Arc::new(map),
+                verified,
+            })),
+        })
+    }
+
+    fn verified_words(len: usize, page_size: usize) -> Vec<AtomicU64> {
+        (0..len / page_size / 64 + 1)
+            .map(|_| AtomicU64::new(0))
+            .collect()
+    }
+
+    #[inline]
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // bytes of a page that can be used by its content
+    #[inline]
+    pub fn usable_size(&self) -> usize {
+        self.page_size - PAGE_TRAILER_SIZE
+    }
+
+    #[inline]
+    fn offset(&self, page_number: u64) -> u64 {
+        page_number * self.page_size as u64
+    }
+
     pub fn allocate_page(&self, page_number: u64) -> Page {
         Page {
             data: vec![0; self.page_size],
@@ -43,20 +133,133 @@ impl Pager {
         }
     }
 
-    pub fn write_page(&self, page: &Page) {
-        let offset = page.page_number * DEFAULT_PAGE_SIZE as u64;
+    // tag the page with its type and checksum, then write it
+    pub fn write_page(&self, page: &mut Page, page_type: u8) {
+        assert!(self.mapping.is_none(), "write to a mapped file");
+        page.seal(page_type);
+        let offset = self.offset(page.page_number);
+        match &self.pool {
+            Some(pool) => pool.insert_dirty(
+                self.file_id,
+                page.page_number,
+                page.data.clone(),
+                &self.file,
+                offset,
+            ),
+            None => self
+                .file
+                .write_all_at(page.data.as_ref(), offset)
+                .expect("write page failed"),
+        }
+    }
+
+    // the meta page is read straight from the file when the tree is opened and is never cached,
+    // it's written around the pool as well
+    pub fn write_meta_page(&self, page: &mut Page) {
+        page.seal(PAGE_TYPE_META);
+        self.write_meta(0, &page.data);
+    }
+
+    // write part of the meta page in place, e.g. one copy of the meta
+    pub fn write_meta(&self, offset: usize, data: &[u8]) {
+        assert!(self.mapping.is_none(), "write to a mapped file");
         self.file
-            .write_at(page.data.as_ref(), offset)
-            .expect("write page failed");
+            .write_all_at(data, offset as u64)
+            .expect("write meta failed");
+    }
+
+    pub fn read_page(&self, page_number: u64) -> Result<Page, Error> {
+        let pinned = self.pin_page(page_number)?;
+        Ok(Page {
+            data: pinned.to_vec(),
+            page_number,
+        })
+    }
+
+    // read a page without copying it out of the pool, the page can not be evicted until
+    // the returned PinnedPage is dropped. Pages are verified when they are read from disk, the
+    // meta page is not, its copies carry their own checksums and survive a torn write
+    pub fn pin_page(&self, page_number: u64) -> Result<PinnedPage, Error> {
+        if let Some(mapping) = &self.mapping {
+            return self.pin_mapped_page(mapping, page_number);
+        }
+        if let Some(pool) = &self.pool {
+            if let Some(pinned) = pool.get(self.file_id, page_number) {
+                return Ok(pinned);
+            }
+        }
+        let mut data = vec![0u8; self.page_size];
+        let offset = self.offset(page_number);
+        self.file
+            .read_exact_at(&mut data, offset)
+            .map_err(|_| Error::PageLoadErr)?;
+        if page_number != DEFAULT_META_PN && !Page::verify(&data) {
+            return Err(Error::Corrupted);
+        }
+        Ok(match &self.pool {
+            Some(pool) => pool.insert_clean(self.file_id, page_number, data, &self.file, offset),
+            None => PinnedPage::unpooled(data),
+        })
     }
 
-    pub fn read_page(&self, page_number: u64) -> Option<Page> {
-        let mut new_page = Page::new_empty_with_pn(self.page_size, page_number);
-        let offset = new_page.page_number * DEFAULT_PAGE_SIZE as u64;
-        if self.file.read_exact_at(&mut new_page.data, offset).is_ok() {
-            Some(new_page)
-        } else {
-            None
+    // a page of a mapped file is verified the first time it's read
+    fn pin_mapped_page(
+        &self,
+        mapping: &RwLock<Mapping>,
+        page_number: u64,
+    ) -> Result<PinnedPage, Error> {
+        let start = self.offset(page_number) as usize;
+        let end = start + self.page_size;
+        if mapping.read().unwrap().map.len() < end {
+            self.remap(mapping, end)?;
         }
+        let mapping = mapping.read().unwrap();
+        let word = &mapping.verified[page_number as usize / 64];
+        let bit = 1 << (page_number % 64);
+        if page_number != DEFAULT_META_PN && word.load(Ordering::Relaxed) & bit == 0 {
+            if !Page::verify(&mapping.map[start..end]) {
+                return Err(Error::Corrupted);
+            }
+            word.fetch_or(bit, Ordering::Relaxed);
+        }
+        Ok(PinnedPage::mapped(mapping.map.clone(), start..end))
+    }
+
+    // the file grew after it was mapped, map it again. Pages in use keep the old mapping
+    fn remap(&self, mapping: &RwLock<Mapping>, len: usize) -> Result<(), Error> {
+        let mut mapping = mapping.write().unwrap();
+        if mapping.map.len() >= len {
+            return Ok(());
+        }
+        let map = unsafe { Mmap::map(&*self.file) }.map_err(|_| Error::PageLoadErr)?;
+        if map.len() < len {
+            return Err(Error::PageLoadErr);
+        }
+        let words = Self::verified_words(map.len(), self.page_size).len();
+        mapping
+            .verified
+            .resize_with(words, || AtomicU64::new(0));
+        mapping.map = Arc::new(map);
+        Ok(())
+    }
+
+    // write dirty pages of this file back to disk
+    pub fn flush(&self) {
+        if let Some(pool) = &self.pool {
+            pool.flush_file(self.file_id);
+        }
+    }
+
+    // flush and wait until everything written so far is durable
+    pub fn sync(&self) {
+        self.flush();
+        self.file.sync_data().expect("sync pages failed");
+    }
+}
+
+// Drop[#TODO] (should add some comments)
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
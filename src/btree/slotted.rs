@@ -0,0 +1,436 @@
+// slotted page, the layout of both leaf and internal nodes. Cells are read in place from the
+// page buffer (a pinned page of the buffer pool or a mapped file), and a single key can be
+// inserted or removed without rewriting the other cells
+//
+// slotted page format
+// |-------------------------------------------------------------------------------------|
+// | header | slot 0 | slot 1 | ... | slot n-1 | ... free ... | cell n-1 | ... | cell 0   |
+// |-------------------------------------------------------------------------------------|
+// |        |  4B    |  4B    |     |  4B      |              |      heap               |
+// |-------------------------------------------------------------------------------------|
+// slot i is the offset of cell i, slots are kept in key order while cells are in the heap in
+// any order. The heap grows from the end of the page towards the slots, and holes left by
+// removed cells are reclaimed by compact()
+//
+// leaf header, prev/next leaf are not used since leaves are not linked (see LeafNode). They are
+// written as 0 and never read
+// | node type | slot count | heap start | prev leaf | next leaf |
+// |  1B       |  4B        |  4B        |  8B       |  8B       |
+//
+// internal header, the leftmost child has no key so it is kept in the header
+// | node type | slot count | heap start | child 0   |
+// |  1B       |  4B        |  4B        |  8B       |
+//
+// leaf cell, len(v) = 0xFFFF means the value is the 8B number of its first overflow page
+// | len(k) | len(v) | k  | v  |
+// |  2B    |  2B    | xx | xx |
+//
+// internal cell, child i + 1 holds keys >= k of cell i
+// | len(k) | child i + 1 | k  |
+// |  2B    |  8B         | xx |
+use std::cmp::Ordering;
+
+use crate::bytes;
+
+use super::constant::{HEAD_INTERNAL_NODE_SIZE, HEAD_LEAF_NODE_SIZE};
+use super::node::{KeyValue, NODEASINTERNAL, NODEASLEAF, OVERFLOW_VALUE_SIZE};
+
+pub const SLOT_SIZE: usize = 4;
+
+const COUNT_OFFSET: usize = 1;
+const HEAP_START_OFFSET: usize = 5;
+// prev leaf of a leaf node, child 0 of an internal node
+const LINK_OFFSET: usize = 9;
+const NEXT_LEAF_OFFSET: usize = 17;
+
+// value of a leaf cell, borrowed from the page
+pub enum ValueRef<'a> {
+    Inline(&'a [u8]),
+    Overflow(u64),
+}
+
+#[derive(Clone, Copy)]
+pub struct SlottedPage<'a> {
+    buf: &'a [u8],
+}
+
+// SlottedPage[#TODO] (should add some comments)
+impl<'a> SlottedPage<'a> {
+    #[inline]
+    pub fn new(buf: &'a [u8]) -> Self {
+        SlottedPage { buf }
+    }
+
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.buf[0] == NODEASLEAF
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        read_u32(self.buf, COUNT_OFFSET) as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    fn header_size(&self) -> usize {
+        if self.is_leaf() {
+            HEAD_LEAF_NODE_SIZE
+        } else {
+            HEAD_INTERNAL_NODE_SIZE
+        }
+    }
+
+    #[inline]
+    fn heap_start(&self) -> usize {
+        read_u32(self.buf, HEAP_START_OFFSET) as usize
+    }
+
+    #[inline]
+    fn cell(&self, idx: usize) -> usize {
+        read_u32(self.buf, self.header_size() + idx * SLOT_SIZE) as usize
+    }
+
+    // bytes taken by cell i
+    fn cell_size(&self, idx: usize) -> usize {
+        let cell = self.cell(idx);
+        let key_size = read_u16(self.buf, cell) as usize;
+        if !self.is_leaf() {
+            return 2 + 8 + key_size;
+        }
+        match read_u16(self.buf, cell + 2) {
+            OVERFLOW_VALUE_SIZE => 4 + key_size + 8,
+            value_size => 4 + key_size + value_size as usize,
+        }
+    }
+
+    pub fn key(&self, idx: usize) -> &'a [u8] {
+        let cell = self.cell(idx);
+        let key_size = read_u16(self.buf, cell) as usize;
+        let key_offset = if self.is_leaf() { cell + 4 } else { cell + 10 };
+        &self.buf[key_offset..key_offset + key_size]
+    }
+
+    pub fn value(&self, idx: usize) -> ValueRef<'a> {
+        let cell = self.cell(idx);
+        let key_size = read_u16(self.buf, cell) as usize;
+        let value_offset = cell + 4 + key_size;
+        match read_u16(self.buf, cell + 2) {
+            OVERFLOW_VALUE_SIZE => ValueRef::Overflow(read_u64(self.buf, value_offset)),
+            value_size => {
+                ValueRef::Inline(&self.buf[value_offset..value_offset + value_size as usize])
+            }
+        }
+    }
+
+    // copy the key value out of the page
+    pub fn key_value(&self, idx: usize) -> KeyValue {
+        match self.value(idx) {
+            ValueRef::Inline(value) => KeyValue::new(self.key(idx), value),
+            ValueRef::Overflow(first_page) => KeyValue::new_overflow(self.key(idx), first_page),
+        }
+    }
+
+    // an internal node with n keys has n + 1 children
+    pub fn child(&self, idx: usize) -> u64 {
+        if idx == 0 {
+            read_u64(self.buf, LINK_OFFSET)
+        } else {
+            read_u64(self.buf, self.cell(idx - 1) + 2)
+        }
+    }
+
+    pub fn children(&self) -> Vec<u64> {
+        (0..=self.len()).map(|idx| self.child(idx)).collect()
+    }
+
+    // slots and cells are within the page and don't overlap each other; the accessors above
+    // trust the page and may panic on one that fails this check
+    pub fn validate(&self) -> bool {
+        let size = self.buf.len();
+        if self.buf[0] != NODEASLEAF && self.buf[0] != NODEASINTERNAL {
+            return false;
+        }
+        let slots_end = self.header_size() + self.len() * SLOT_SIZE;
+        if slots_end > self.heap_start() || self.heap_start() > size {
+            return false;
+        }
+        let mut cells: Vec<(usize, usize)> = Vec::with_capacity(self.len());
+        for idx in 0..self.len() {
+            let cell = self.cell(idx);
+            let head_size = if self.is_leaf() { 4 } else { 10 };
+            if cell < self.heap_start() || cell + head_size > size {
+                return false;
+            }
+            let cell_size = self.cell_size(idx);
+            if cell + cell_size > size {
+                return false;
+            }
+            cells.push((cell, cell + cell_size));
+        }
+        cells.sort_unstable();
+        cells.windows(2).all(|pair| pair[0].1 <= pair[1].0)
+    }
+
+    // bytes used by header, slots and live cells, the same as Node::size of the node
+    pub fn used_size(&self) -> usize {
+        self.header_size()
+            + (0..self.len())
+                .map(|idx| SLOT_SIZE + self.cell_size(idx))
+                .sum::<usize>()
+    }
+
+    // binary search over the slots, return whether the key exists and the index it is at or
+    // should be inserted at
+    pub fn find_key_in_leaf(&self, key: &[u8]) -> (bool, usize) {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match bytes::compare(self.key(mid), key) {
+                Ordering::Equal => return (true, mid),
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        (false, low)
+    }
+
+    // keys >= separator go to the right child, so the child index is the number of separators
+    // not greater than the key
+    pub fn find_key_in_internal(&self, key: &[u8]) -> (usize, u64) {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if bytes::compare(self.key(mid), key) == Ordering::Greater {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        (low, self.child(low))
+    }
+}
+
+pub struct SlottedPageMut<'a> {
+    buf: &'a mut [u8],
+}
+
+// SlottedPageMut[#TODO] (should add some comments)
+impl<'a> SlottedPageMut<'a> {
+    #[inline]
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        SlottedPageMut { buf }
+    }
+
+    // format the buffer as an empty node
+    pub fn init(buf: &'a mut [u8], node_type: u8) -> Self {
+        let heap_start = buf.len() as u32;
+        buf[0] = node_type;
+        write_u32(buf, COUNT_OFFSET, 0);
+        write_u32(buf, HEAP_START_OFFSET, heap_start);
+        write_u64(buf, LINK_OFFSET, 0);
+        if node_type == NODEASLEAF {
+            write_u64(buf, NEXT_LEAF_OFFSET, 0);
+        }
+        SlottedPageMut { buf }
+    }
+
+    #[inline]
+    pub fn as_page(&self) -> SlottedPage<'_> {
+        SlottedPage::new(self.buf)
+    }
+
+    pub fn insert_leaf(&mut self, idx: usize, kv: &KeyValue) -> bool {
+        let cell_size = kv.size() - SLOT_SIZE;
+        let Some(cell) = self.allocate_cell(idx, cell_size) else {
+            return false;
+        };
+        write_u16(self.buf, cell, kv.key.len() as u16);
+        let mut offset = cell + 4;
+        self.buf[offset..offset + kv.key.len()].clone_from_slice(&kv.key);
+        offset += kv.key.len();
+        if kv.overflow != 0 {
+            write_u16(self.buf, cell + 2, OVERFLOW_VALUE_SIZE);
+            write_u64(self.buf, offset, kv.overflow);
+        } else {
+            write_u16(self.buf, cell + 2, kv.value.len() as u16);
+            self.buf[offset..offset + kv.value.len()].clone_from_slice(&kv.value);
+        }
+        true
+    }
+
+    // insert key i, its right child becomes child i + 1
+    pub fn insert_internal(&mut self, idx: usize, key: &[u8], right_child: u64) -> bool {
+        let Some(cell) = self.allocate_cell(idx, 2 + 8 + key.len()) else {
+            return false;
+        };
+        write_u16(self.buf, cell, key.len() as u16);
+        write_u64(self.buf, cell + 2, right_child);
+        self.buf[cell + 10..cell + 10 + key.len()].clone_from_slice(key);
+        true
+    }
+
+    // drop the slot, bytes of the cell are reclaimed by the next compaction
+    pub fn remove(&mut self, idx: usize) {
+        let count = self.as_page().len();
+        let slots = self.as_page().header_size();
+        self.buf.copy_within(
+            slots + (idx + 1) * SLOT_SIZE..slots + count * SLOT_SIZE,
+            slots + idx * SLOT_SIZE,
+        );
+        write_u32(self.buf, COUNT_OFFSET, count as u32 - 1);
+    }
+
+    pub fn set_child(&mut self, idx: usize, child: u64) {
+        if idx == 0 {
+            write_u64(self.buf, LINK_OFFSET, child);
+        } else {
+            let cell = self.as_page().cell(idx - 1);
+            write_u64(self.buf, cell + 2, child);
+        }
+    }
+
+    // move live cells to the end of the page, so all free space is between slots and heap
+    pub fn compact(&mut self) {
+        let page = SlottedPage::new(self.buf);
+        let cells: Vec<(usize, usize)> = (0..page.len())
+            .map(|idx| (page.cell(idx), page.cell_size(idx)))
+            .collect();
+        let mut heap = Vec::with_capacity(cells.iter().map(|(_, size)| size).sum());
+        for &(cell, size) in cells.iter() {
+            heap.extend_from_slice(&self.buf[cell..cell + size]);
+        }
+
+        let slots = page.header_size();
+        let mut heap_start = self.buf.len() - heap.len();
+        self.buf[heap_start..].clone_from_slice(&heap);
+        write_u32(self.buf, HEAP_START_OFFSET, heap_start as u32);
+        for (idx, &(_, size)) in cells.iter().enumerate() {
+            write_u32(self.buf, slots + idx * SLOT_SIZE, heap_start as u32);
+            heap_start += size;
+        }
+    }
+
+    // reserve a cell of the given size and a slot at idx, return the offset of the cell or
+    // None if the page is full
+    fn allocate_cell(&mut self, idx: usize, cell_size: usize) -> Option<usize> {
+        let page = self.as_page();
+        let count = page.len();
+        let slots = page.header_size();
+        let slots_end = slots + (count + 1) * SLOT_SIZE;
+        if page.heap_start() < slots_end + cell_size {
+            if page.used_size() + SLOT_SIZE + cell_size > self.buf.len() {
+                return None;
+            }
+            self.compact();
+        }
+
+        let cell = self.as_page().heap_start() - cell_size;
+        write_u32(self.buf, HEAP_START_OFFSET, cell as u32);
+        self.buf.copy_within(
+            slots + idx * SLOT_SIZE..slots + count * SLOT_SIZE,
+            slots + (idx + 1) * SLOT_SIZE,
+        );
+        write_u32(self.buf, slots + idx * SLOT_SIZE, cell as u32);
+        write_u32(self.buf, COUNT_OFFSET, count as u32 + 1);
+        Some(cell)
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+#[inline]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[inline]
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].clone_from_slice(&u16::to_le_bytes(value));
+}
+
+#[inline]
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].clone_from_slice(&u32::to_le_bytes(value));
+}
+
+#[inline]
+fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].clone_from_slice(&u64::to_le_bytes(value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::node::NODEASINTERNAL;
+    use crate::bytes::VarintCodec;
+
+    #[test]
+    fn test_leaf_insert_remove_compact() {
+        let mut buf = vec![0u8; 512];
+        let mut page = SlottedPageMut::init(&mut buf, NODEASLEAF);
+        let kv = |i: u64| KeyValue::new(&i.varint_encode(), format!("value-{}", i).as_bytes());
+
+        // insert out of order
+        let mut inserted = 0;
+        for i in [5u64, 1, 9, 3, 7] {
+            let (found, idx) = page.as_page().find_key_in_leaf(&i.varint_encode());
+            assert!(!found);
+            assert!(page.insert_leaf(idx, &kv(i)));
+            inserted += kv(i).size();
+        }
+        assert!(page.insert_leaf(0, &KeyValue::new_overflow(&0u64.varint_encode(), 42)));
+        inserted += KeyValue::new_overflow(&0u64.varint_encode(), 42).size();
+        assert_eq!(page.as_page().used_size(), HEAD_LEAF_NODE_SIZE + inserted);
+
+        let view = page.as_page();
+        let keys: Vec<u64> = (0..view.len())
+            .map(|idx| u64::varint_decode(view.key(idx)).1)
+            .collect();
+        assert_eq!(keys, vec![0, 1, 3, 5, 7, 9]);
+        assert!(matches!(view.value(0), ValueRef::Overflow(42)));
+        assert_eq!(view.key_value(3).value, b"value-5");
+
+        // fill the page, removed cells are reclaimed by compaction
+        let mut next = 10u64;
+        while page.insert_leaf(page.as_page().len(), &kv(next)) {
+            next += 1;
+        }
+        for _ in 0..3 {
+            page.remove(1);
+        }
+        assert!(page.insert_leaf(page.as_page().len(), &kv(next)));
+        let view = page.as_page();
+        assert_eq!(u64::varint_decode(view.key(1)).1, 7);
+        assert_eq!(view.key_value(view.len() - 1).value, kv(next).value);
+        assert!(view.used_size() <= 512);
+    }
+
+    #[test]
+    fn test_internal_children() {
+        let mut buf = vec![0u8; 256];
+        let mut page = SlottedPageMut::init(&mut buf, NODEASINTERNAL);
+        page.set_child(0, 100);
+        for i in 0..5u64 {
+            assert!(page.insert_internal(i as usize, &(i * 10).varint_encode(), 101 + i));
+        }
+        page.set_child(2, 7);
+        let view = page.as_page();
+        assert_eq!(view.children(), vec![100, 101, 7, 103, 104, 105]);
+        assert_eq!(view.find_key_in_internal(&0u64.varint_encode()), (1, 101));
+        assert_eq!(view.find_key_in_internal(&15u64.varint_encode()), (2, 7));
+        assert_eq!(view.find_key_in_internal(&100u64.varint_encode()), (5, 105));
+    }
+}
@@ -0,0 +1,341 @@
+// Gorilla 时序压缩 (Facebook Gorilla paper, VLDB 2015)
+//
+// key(时间戳) 使用 delta-of-delta 编码, value(f64) 使用 XOR 编码
+//
+// timestamp encoding (dod = (t_n - t_n-1) - (t_n-1 - t_n-2))
+// |--------------------------------------------------|
+// |  dod == 0             | '0'                      |
+// |  dod in [-64, 63]     | '10'   + 7 bits          |
+// |  dod in [-256, 255]   | '110'  + 9 bits          |
+// |  dod in [-2048, 2047] | '1110' + 12 bits         |
+// |  otherwise            | '1111' + 64 bits         |
+// |--------------------------------------------------|
+//
+// value encoding (xor = v_n ^ v_n-1)
+// |----------------------------------------------------------------------------|
+// |  xor == 0                          | '0'                                   |
+// |  meaningful bits fit in prev window| '10' + meaningful bits                |
+// |  otherwise                         | '11' + 5 bits leading + 6 bits length |
+// |                                    |      + meaningful bits                |
+// |----------------------------------------------------------------------------|
+//
+// 第一个时间戳和第一个value 都是原始的 64 bits
+
+pub(super) struct BitWriter {
+    buffer: Vec<u8>,
+    // 最后一个字节里面已经使用的bit数
+    used_bits: u8,
+}
+
+// BitWriter[#TODO] (should add some comments)
+impl BitWriter {
+    pub(super) fn new() -> Self {
+        BitWriter {
+            buffer: Vec::new(),
+            used_bits: 8,
+        }
+    }
+
+    pub(super) fn write_bit(&mut self, bit: bool) {
+        if self.used_bits == 8 {
+            self.buffer.push(0);
+            self.used_bits = 0;
+        }
+        if bit {
+            let last = self.buffer.len() - 1;
+            self.buffer[last] |= 1 << (7 - self.used_bits);
+        }
+        self.used_bits += 1;
+    }
+
+    // write the lowest `nbits` bits of value, most significant bit first
+    pub(super) fn write_bits(&mut self, value: u64, nbits: u8) {
+        for shift in (0..nbits).rev() {
+            self.write_bit((value >> shift) & 1 == 1);
+        }
+    }
+
+    pub(super) fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+pub(super) struct BitReader<'a> {
+    buffer: &'a [u8],
+    // 下一个要读取的bit位置
+    position: usize,
+}
+
+// BitReader[#TODO] (should add some comments)
+impl<'a> BitReader<'a> {
+    pub(super) fn new(buffer: &'a [u8]) -> Self {
+        BitReader {
+            buffer,
+            position: 0,
+        }
+    }
+
+    pub(super) fn read_bit(&mut self) -> Option<bool> {
+        let byte = *self.buffer.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1 == 1;
+        self.position += 1;
+        Some(bit)
+    }
+
+    pub(super) fn read_bits(&mut self, nbits: u8) -> Option<u64> {
+        let mut value = 0u64;
+        for _ in 0..nbits {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+// (prefix bits, prefix length, value bits)
+const DOD_BUCKETS: [(u64, u8, u8); 3] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12)];
+
+pub(super) struct Encoder {
+    writer: BitWriter,
+    count: usize,
+    prev_timestamp: u64,
+    prev_delta: i64,
+    prev_value: u64,
+    prev_leading: u8,
+    prev_trailing: u8,
+}
+
+// Encoder[#TODO] (should add some comments)
+impl Encoder {
+    pub(super) fn new() -> Self {
+        Encoder {
+            writer: BitWriter::new(),
+            count: 0,
+            prev_timestamp: 0,
+            prev_delta: 0,
+            prev_value: 0,
+            // u8::MAX 表示还没有可复用的 leading/trailing 窗口
+            prev_leading: u8::MAX,
+            prev_trailing: 0,
+        }
+    }
+
+    // timestamps must be appended in ascending order
+    pub(super) fn append(&mut self, timestamp: u64, value: u64) {
+        if self.count == 0 {
+            self.writer.write_bits(timestamp, 64);
+            self.writer.write_bits(value, 64);
+        } else {
+            self.write_timestamp(timestamp);
+            self.write_value(value);
+        }
+        self.prev_timestamp = timestamp;
+        self.prev_value = value;
+        self.count += 1;
+    }
+
+    fn write_timestamp(&mut self, timestamp: u64) {
+        let delta = timestamp.wrapping_sub(self.prev_timestamp) as i64;
+        let dod = delta.wrapping_sub(self.prev_delta);
+        self.prev_delta = delta;
+
+        if dod == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        for (prefix, prefix_len, nbits) in DOD_BUCKETS {
+            let bound = 1i64 << (nbits - 1);
+            if (-bound..bound).contains(&dod) {
+                self.writer.write_bits(prefix, prefix_len);
+                self.writer.write_bits(dod as u64 & ((1 << nbits) - 1), nbits);
+                return;
+            }
+        }
+        self.writer.write_bits(0b1111, 4);
+        self.writer.write_bits(dod as u64, 64);
+    }
+
+    fn write_value(&mut self, value: u64) {
+        let xor = value ^ self.prev_value;
+        if xor == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        self.writer.write_bit(true);
+
+        // leading zeros is stored in 5 bits
+        let leading = (xor.leading_zeros() as u8).min(31);
+        let trailing = xor.trailing_zeros() as u8;
+        if self.prev_leading != u8::MAX
+            && leading >= self.prev_leading
+            && trailing >= self.prev_trailing
+        {
+            // reuse previous window
+            self.writer.write_bit(false);
+            let meaningful = 64 - self.prev_leading - self.prev_trailing;
+            self.writer.write_bits(xor >> self.prev_trailing, meaningful);
+        } else {
+            self.writer.write_bit(true);
+            let meaningful = 64 - leading - trailing;
+            self.writer.write_bits(leading as u64, 5);
+            // meaningful is in [1, 64], 64 is stored as 0
+            self.writer.write_bits(meaningful as u64 & 0x3f, 6);
+            self.writer.write_bits(xor >> trailing, meaningful);
+            self.prev_leading = leading;
+            self.prev_trailing = trailing;
+        }
+    }
+
+    pub(super) fn finish(self) -> Vec<u8> {
+        self.writer.finish()
+    }
+}
+
+pub(super) struct Decoder<'a> {
+    reader: BitReader<'a>,
+    remain: usize,
+    decoded: usize,
+    prev_timestamp: u64,
+    prev_delta: i64,
+    prev_value: u64,
+    prev_leading: u8,
+    prev_trailing: u8,
+}
+
+// Decoder[#TODO] (should add some comments)
+impl<'a> Decoder<'a> {
+    pub(super) fn new(buffer: &'a [u8], count: usize) -> Self {
+        Decoder {
+            reader: BitReader::new(buffer),
+            remain: count,
+            decoded: 0,
+            prev_timestamp: 0,
+            prev_delta: 0,
+            prev_value: 0,
+            prev_leading: 0,
+            prev_trailing: 0,
+        }
+    }
+
+    fn read_timestamp(&mut self) -> Option<u64> {
+        let mut prefix_len = 0;
+        while prefix_len < 4 && self.reader.read_bit()? {
+            prefix_len += 1;
+        }
+        let dod = match prefix_len {
+            0 => 0,
+            4 => self.reader.read_bits(64)? as i64,
+            n => {
+                let nbits = DOD_BUCKETS[n - 1].2;
+                let raw = self.reader.read_bits(nbits)?;
+                // sign extension
+                ((raw << (64 - nbits)) as i64) >> (64 - nbits)
+            }
+        };
+        let delta = self.prev_delta.wrapping_add(dod);
+        self.prev_delta = delta;
+        Some(self.prev_timestamp.wrapping_add(delta as u64))
+    }
+
+    fn read_value(&mut self) -> Option<u64> {
+        if !self.reader.read_bit()? {
+            return Some(self.prev_value);
+        }
+        if self.reader.read_bit()? {
+            self.prev_leading = self.reader.read_bits(5)? as u8;
+            let mut meaningful = self.reader.read_bits(6)? as u8;
+            if meaningful == 0 {
+                meaningful = 64;
+            }
+            self.prev_trailing = 64 - self.prev_leading - meaningful;
+        }
+        let meaningful = 64 - self.prev_leading - self.prev_trailing;
+        let xor = self.reader.read_bits(meaningful)? << self.prev_trailing;
+        Some(self.prev_value ^ xor)
+    }
+}
+
+// Iterator[#TODO] (should add some comments)
+impl Iterator for Decoder<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remain == 0 {
+            return None;
+        }
+        let (timestamp, value) = if self.decoded == 0 {
+            (self.reader.read_bits(64)?, self.reader.read_bits(64)?)
+        } else {
+            (self.read_timestamp()?, self.read_value()?)
+        };
+        self.prev_timestamp = timestamp;
+        self.prev_value = value;
+        self.decoded += 1;
+        self.remain -= 1;
+        Some((timestamp, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(samples: &[(u64, u64)]) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        for &(ts, value) in samples {
+            encoder.append(ts, value);
+        }
+        let buffer = encoder.finish();
+        let decoded: Vec<(u64, u64)> = Decoder::new(&buffer, samples.len()).collect();
+        assert_eq!(decoded, samples);
+        buffer
+    }
+
+    #[test]
+    fn test_bit_writer_reader() {
+        let mut writer = BitWriter::new();
+        writer.write_bit(true);
+        writer.write_bits(0b1011, 4);
+        writer.write_bits(u64::MAX, 64);
+        let buffer = writer.finish();
+
+        let mut reader = BitReader::new(&buffer);
+        assert_eq!(reader.read_bit(), Some(true));
+        assert_eq!(reader.read_bits(4), Some(0b1011));
+        assert_eq!(reader.read_bits(64), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_regular_series() {
+        let samples: Vec<(u64, u64)> = (0..1000)
+            .map(|i| (1_700_000_000_000 + i * 1000, (20.0 + (i % 7) as f64 * 0.5).to_bits()))
+            .collect();
+        let buffer = roundtrip(&samples);
+        // 16B per sample without compression
+        assert!(buffer.len() < samples.len() * 4);
+    }
+
+    #[test]
+    fn test_irregular_series() {
+        let mut ts = 0u64;
+        let mut samples = vec![];
+        for i in 0..500u64 {
+            // jump through every delta-of-delta bucket
+            ts += match i % 5 {
+                0 => 1,
+                1 => 100,
+                2 => 1000,
+                3 => 5000,
+                _ => u32::MAX as u64,
+            };
+            samples.push((ts, (i as f64 * -1.25).to_bits()));
+        }
+        samples.push((u64::MAX, f64::NAN.to_bits()));
+        roundtrip(&samples);
+    }
+
+    #[test]
+    fn test_single_and_empty() {
+        roundtrip(&[(42, 7.5f64.to_bits())]);
+        assert_eq!(Decoder::new(&[], 0).count(), 0);
+    }
+}
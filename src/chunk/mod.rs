@@ -1,60 +1,110 @@
 mod encoder;
+mod gorilla;
 mod skiplist;
 
-use std::usize;
+use std::ops::{Range, RangeBounds};
 
-use crate::bytes::{self, VarintCodec};
+use crate::bytes::VarintCodec;
 use crate::errors::Error;
+use crate::tombstone::{self, KIND_PUT};
+
+type KeyValues = Vec<(Vec<u8>, Vec<u8>)>;
+
+// chunk value 的编码方式, 编码方式作为chunk第一个字节写入磁盘
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ValueEncoding {
+    // value 按原始字节保存
+    #[default]
+    Raw,
+    // key 作为时间戳, value 作为f64, 使用gorilla 压缩
+    // value 不是8字节或者chunk里面有tombstone, 带TTL的value时回退到Raw
+    Gorilla,
+}
+
+// chunk 的第一个字节是它的格式, 也是value格式的版本:
+// RAW 是entry出现之前写入的chunk, value 就是用户写入的值, 读出来的时候当作put entry;
+// ENTRIES 和 RAW 的布局一样, 但是每个value都是带kind前缀的entry, 见 tombstone.rs
+const CHUNK_FORMAT_RAW: u8 = 0;
+const CHUNK_FORMAT_GORILLA: u8 = 1;
+const CHUNK_FORMAT_ENTRIES: u8 = 2;
 
 pub struct Chunk {
     store: skiplist::SkipList,
     total_size: usize,
     pub used_size: usize,
-    key_nums: usize,
+    pub key_nums: usize,
     // TODO (add a key to record the first key insert into store)
     // should add first key, this is will used for checkpoints
     pub last_key: Vec<u8>,
+    // 由memtable在创建chunk的时候分配, chunk按照这个顺序写入block, 见 block/meta.rs
+    pub(crate) seq: u64,
+    // 写入这个chunk期间的delete_range, 它们隐藏了更旧的chunk和block里面落在范围内的key
+    pub(crate) range_tombstones: Vec<Range<u64>>,
 }
 
-const DEFAULT_MAX_CHUNK_SIZE: usize = 1024;
+pub(crate) const DEFAULT_MAX_CHUNK_SIZE: usize = 1024;
 
 // Chunk[#TODO] (should add some comments)
 impl Chunk {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Chunk::with_size(DEFAULT_MAX_CHUNK_SIZE)
+    }
+
+    pub fn with_size(total_size: usize) -> Self {
         Chunk {
             store: skiplist::SkipList::default(),
-            total_size: DEFAULT_MAX_CHUNK_SIZE,
+            total_size,
             used_size: 0,
             key_nums: 0,
             last_key: Vec::new(),
+            seq: 0,
+            range_tombstones: Vec::new(),
         }
     }
 }
 
 // Chunk[#TODO] (should add some comments)
 impl Chunk {
-    // chunk disk layout
-    // |----------------------------------------------------------------------------------|
-    // | key_num |  1st off | .| end off |k1_size|k1 | v1_size | v1 | ....................|
-    // |----------------------------------------------------------------------------------|
-    // |  8B     |  2B      | .|  2B     |  2B   |x  |  2B     | x  |  ......   ....      |
-    // |--------------------------------------------------------------|-------------------|
-    pub fn encode(self) -> (Vec<u8>, Vec<u8>) {
-        let mut buffer = vec![0u8; self.key_nums * (8 + 8 + 8) + 8 + self.used_size];
+    // chunk disk layout (raw and entries)
+    // |------------------------------------------------------------------------------------------|
+    // | format | key_num |  1st off | .| end off |k1_size|k1 | v1_size | v1 | ....................|
+    // |------------------------------------------------------------------------------------------|
+    // |  1B    |  8B     |  8B      | .|  8B     |  8B   |x  |  8B     | x  |  ......   ....      |
+    // |------------------------------------------------------------------------------------------|
+    //
+    // chunk disk layout (gorilla)
+    // |-----------------------------------------|
+    // | format | key_num(varint) | bit stream   |
+    // |-----------------------------------------|
+    //
+    // values are entries prefixed by their kind (see tombstone.rs), a gorilla chunk holds only
+    // put entries of 8 bytes values and the kind is not stored
+    pub fn encode(&self, encoding: ValueEncoding) -> (Vec<u8>, Vec<u8>) {
+        let key_nums = self.key_nums;
+        let used_size = self.used_size;
+        let items = self.scan(&..);
+        let first_key = items.first().unwrap().0.clone();
+
+        let is_series = items
+            .iter()
+            .all(|(_, entry)| entry.len() == 9 && entry[0] == KIND_PUT);
+        if encoding == ValueEncoding::Gorilla && is_series {
+            return (first_key, Self::encode_gorilla(&items));
+        }
+
+        let mut buffer = vec![0u8; Self::raw_size(key_nums, used_size)];
         let mut offset = 0;
-        buffer[offset..offset + 8].clone_from_slice(self.key_nums.to_le_bytes().as_ref());
+        buffer[offset] = CHUNK_FORMAT_ENTRIES;
+        offset += 1;
+
+        buffer[offset..offset + 8].clone_from_slice(key_nums.to_le_bytes().as_ref());
         offset += 8;
 
         let mut ptr_pos = offset;
-        offset += 8 * self.key_nums;
+        offset += 8 * key_nums;
 
-        let mut fist_key = None;
-
-        let store_iter = self.store.into_iter();
-        for (key, value) in store_iter {
-            if fist_key.is_none() {
-                fist_key = Some(key.clone());
-            }
+        for (key, value) in items {
             buffer[ptr_pos..ptr_pos + 8].clone_from_slice(offset.to_le_bytes().as_ref());
             ptr_pos += 8;
 
@@ -71,102 +121,80 @@ impl Chunk {
             offset += value.len();
         }
 
-        (fist_key.unwrap(), buffer)
+        (first_key, buffer)
     }
-    //  v2
-    pub fn encode_varint(self) -> (Vec<u8>, Vec<u8>) {
-        let mut buffer = Vec::new();
-        buffer.append(&mut (self.key_nums as u64).varint_encode());
-
-        let mut first_key = None;
-        let store_iter = self.store.into_iter();
-        for (mut key, mut value) in store_iter {
-            if first_key.is_none() {
-                first_key = Some(key.clone());
-            }
-            buffer.append(&mut key.len().varint_encode());
-            buffer.append(&mut key);
 
-            buffer.append(&mut value.len().varint_encode());
-            buffer.append(&mut value);
-        }
+    fn encode_gorilla(items: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut buffer = vec![CHUNK_FORMAT_GORILLA];
+        buffer.append(&mut items.len().varint_encode());
 
-        (first_key.unwrap(), buffer)
+        let mut encoder = gorilla::Encoder::new();
+        for (key, value) in items {
+            let timestamp = u64::varint_decode(key).1;
+            encoder.append(timestamp, u64::from_le_bytes(value[1..].try_into().unwrap()));
+        }
+        buffer.append(&mut encoder.finish());
+        buffer
     }
 
-    fn decode_varint(buffer: &[u8]) -> Result<Vec<(String, String)>, Error> {
-        let mut ordered_list = Vec::new();
-
-        let mut offset = 0;
-        let (r_byte_cnt, keys_num) = u64::varint_decode(buffer);
-        offset = r_byte_cnt;
-
-        for _ in 0..keys_num {
-            let (r_byte_cnt, key_size) = u64::varint_decode(&buffer[offset..]);
-            offset += r_byte_cnt;
-
-            let mut key = Vec::new();
-            key.clone_from_slice(buffer[offset..offset + key_size as usize].into());
-            offset += key_size as usize;
+    // size of the chunk in raw layout, used to account the compression ratio
+    #[inline]
+    pub fn raw_size(key_nums: usize, used_size: usize) -> usize {
+        key_nums * (8 + 8 + 8) + 1 + 8 + used_size
+    }
 
-            let (r_byte_cnt, value_size) = u64::varint_decode(&buffer[offset..]);
-            offset += r_byte_cnt;
+    // the smallest and the biggest key in this chunk
+    pub fn key_range(&self) -> Option<(u64, u64)> {
+        let mut iter = self.store.iter();
+        let first = u64::varint_decode(&iter.next()?.borrow().key).1;
+        let last = iter
+            .last()
+            .map(|node| u64::varint_decode(&node.borrow().key).1)
+            .unwrap_or(first);
+        Some((first, last))
+    }
 
-            let mut value = Vec::new();
-            value.clone_from_slice(buffer[offset..offset + value_size as usize].into());
-            offset += value_size as usize;
+    pub fn decode(buffer: &[u8]) -> Result<KeyValues, Error> {
+        match buffer.first() {
+            Some(&CHUNK_FORMAT_ENTRIES) => Self::decode_raw(&buffer[1..]),
+            // chunks written before entries are all puts
+            Some(&CHUNK_FORMAT_RAW) => Ok(Self::decode_raw(&buffer[1..])?
+                .into_iter()
+                .map(|(key, value)| (key, tombstone::put(&value)))
+                .collect()),
+            Some(&CHUNK_FORMAT_GORILLA) => Self::decode_gorilla(&buffer[1..]),
+            _ => Err(Error::Corrupted),
         }
-
-        Ok(ordered_list)
     }
-    pub fn decode_debug(buffer: &[u8]) -> Result<Vec<(String, String)>, Error> {
-        let mut offset = 0;
-        let key_num = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-        let mut ordered_list = Vec::new();
-
-        offset += 8 * key_num as usize;
-
-        for _ in 0..key_num {
-            let key_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
 
-            let key = buffer[offset..offset + key_size as usize].into();
-            offset += key_size as usize;
-
-            let value_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
-
-            let value: Vec<u8> = buffer[offset..offset + value_size as usize].into();
-            offset += value_size as usize;
-            ordered_list.push((
-                String::from_utf8(key).unwrap(),
-                String::from_utf8(value).unwrap(),
-            ));
+    fn decode_gorilla(buffer: &[u8]) -> Result<KeyValues, Error> {
+        let (r_byte_cnt, key_num) = usize::varint_decode(buffer);
+        let decoder = gorilla::Decoder::new(&buffer[r_byte_cnt..], key_num);
+        let ordered_list: KeyValues = decoder
+            .map(|(timestamp, value)| {
+                (timestamp.varint_encode(), tombstone::put(&value.to_le_bytes()))
+            })
+            .collect();
+        if ordered_list.len() != key_num {
+            return Err(Error::Corrupted);
         }
         Ok(ordered_list)
     }
-    /// for debug
-    pub fn decode(buffer: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
-        let mut offset = 0;
-        let key_num = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-        offset += 8;
-        let mut ordered_list = Vec::new();
 
-        offset += 8 * key_num as usize;
+    // sizes are read from the buffer, a chunk that doesn't hold them is corrupted
+    fn decode_raw(buffer: &[u8]) -> Result<KeyValues, Error> {
+        let mut offset = 0;
+        let key_num = read_size(buffer, &mut offset)?;
+        // offsets of the keys, entries are read in order without them
+        read_bytes(buffer, &mut offset, key_num.checked_mul(8).ok_or(Error::Corrupted)?)?;
 
+        let mut ordered_list = Vec::new();
         for _ in 0..key_num {
-            let key_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
-
-            let key = buffer[offset..offset + key_size as usize].into();
-            offset += key_size as usize;
-
-            let value_size = u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
-            offset += 8;
+            let key_size = read_size(buffer, &mut offset)?;
+            let key = read_bytes(buffer, &mut offset, key_size)?.into();
 
-            let value: Vec<u8> = buffer[offset..offset + value_size as usize].into();
-            offset += value_size as usize;
+            let value_size = read_size(buffer, &mut offset)?;
+            let value = read_bytes(buffer, &mut offset, value_size)?.into();
             ordered_list.push((key, value));
         }
         Ok(ordered_list)
@@ -189,10 +217,72 @@ impl Chunk {
             Err(_) => todo!(),
         }
     }
+    // remove every key in the range, returns how many were removed
+    pub(crate) fn delete_range(&mut self, range: &impl RangeBounds<u64>) -> usize {
+        let keys: Vec<Vec<u8>> = self.scan(range).into_iter().map(|(key, _)| key).collect();
+        for key in keys.iter() {
+            self.delete(key).unwrap();
+        }
+        keys.len()
+    }
+
+    // keys of the range written into this chunk so far are removed, the range is kept to hide
+    // the keys of older chunks
+    pub(crate) fn insert_range_tombstone(&mut self, range: Range<u64>) {
+        self.delete_range(&range);
+        self.range_tombstones.push(range);
+    }
+
+    // the key is deleted from older chunks by a range tombstone of this chunk
+    pub(crate) fn hides(&self, key: u64) -> bool {
+        self.range_tombstones.iter().any(|range| range.contains(&key))
+    }
+
+    // a key written again replaces its value in this chunk
     pub(crate) fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if let Some(replaced) = self.store.replace(key, value) {
+            self.used_size = self.used_size + value.len() - replaced.len();
+            return Ok(());
+        }
+        self.store.insert(key, value)?;
         self.used_size += key.len() + value.len();
         self.key_nums += 1;
-        self.store.insert(key, value)
+        Ok(())
+    }
+
+    // split into chunks whose keys (decoded as u64) fall into the same window of window_size keys,
+    // None if the keys are in one window or window_size is 0. Every piece keeps the seq, the
+    // range tombstones are left out
+    pub(crate) fn split(&self, window_size: u64) -> Option<Vec<Chunk>> {
+        let (first, last) = self.key_range()?;
+        if window_size == 0 || first / window_size == last / window_size {
+            return None;
+        }
+        let total_size = self.total_size;
+        let mut chunks: Vec<(u64, Chunk)> = Vec::new();
+        for (key, value) in self.scan(&..) {
+            let window = u64::varint_decode(&key).1 / window_size;
+            if chunks.last().map(|(last, _)| *last) != Some(window) {
+                let mut chunk = Chunk::with_size(total_size);
+                chunk.seq = self.seq;
+                chunks.push((window, chunk));
+            }
+            chunks.last_mut().unwrap().1.insert(&key, &value).unwrap();
+        }
+        Some(chunks.into_iter().map(|(_, chunk)| chunk).collect())
+    }
+
+    pub(crate) fn keys(&self) -> Vec<Vec<u8>> {
+        self.store.iter().map(|node| node.borrow().key.clone()).collect()
+    }
+
+    // all key-values whose key (decoded as u64) is in the range, ordered by key
+    pub(crate) fn scan(&self, range: &impl RangeBounds<u64>) -> KeyValues {
+        self.store
+            .iter()
+            .filter(|node| range.contains(&u64::varint_decode(&node.borrow().key).1))
+            .map(|node| (node.borrow().key.clone(), node.borrow().value.clone()))
+            .collect()
     }
 
     pub(crate) fn is_overflowed(&mut self, key: &[u8], value: &[u8]) -> bool {
@@ -206,6 +296,22 @@ impl Chunk {
     }
 }
 
+// the next size bytes of the buffer from the offset, the offset is moved past them
+fn read_bytes<'a>(buffer: &'a [u8], offset: &mut usize, size: u64) -> Result<&'a [u8], Error> {
+    let end = usize::try_from(size)
+        .ok()
+        .and_then(|size| offset.checked_add(size))
+        .ok_or(Error::Corrupted)?;
+    let bytes = buffer.get(*offset..end).ok_or(Error::Corrupted)?;
+    *offset = end;
+    Ok(bytes)
+}
+
+fn read_size(buffer: &[u8], offset: &mut usize) -> Result<u64, Error> {
+    let bytes = read_bytes(buffer, offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,9 +330,9 @@ mod tests {
     #[test]
     fn test_chunk_serialize() {
         let mut chunk = Chunk::new();
-        let key1 = b"key1".to_vec();
+        let key1 = 1u64.varint_encode();
         let value1 = b"value1".to_vec();
-        let key2 = b"key2".to_vec();
+        let key2 = 2u64.varint_encode();
         let value2 = b"value2".to_vec();
 
         chunk.insert(&key1, &value1).unwrap();
@@ -234,9 +340,9 @@ mod tests {
         let key_nums = chunk.key_nums;
         let used_size = chunk.used_size;
 
-        let (first_key, buffer) = chunk.encode();
+        let (first_key, buffer) = chunk.encode(ValueEncoding::Raw);
 
-        let expected_buffer_length = key_nums * (8 + 8 + 8) + 8 + used_size;
+        let expected_buffer_length = key_nums * (8 + 8 + 8) + 1 + 8 + used_size;
         // Add assertions here to validate the serialization result
         // For example:
         assert_eq!(first_key, key1);
@@ -246,32 +352,176 @@ mod tests {
     #[test]
     fn test_chunk_deserialize() {
         let mut chunk = Chunk::new();
-        let key1 = b"key1".to_vec();
+        let key1 = 1u64.varint_encode();
         let value1 = b"value1".to_vec();
-        let key2 = b"key2".to_vec();
+        let key2 = 2u64.varint_encode();
         let value2 = b"value2".to_vec();
         chunk.insert(&key1, &value1).unwrap();
         chunk.insert(&key2, &value2).unwrap();
 
         // Add code here to populate the buffer with serialized data
 
-        let (_, buffer) = chunk.encode();
+        let (_, buffer) = chunk.encode(ValueEncoding::Raw);
         let ordered_list = Chunk::decode(&buffer).unwrap();
 
         // Add assertions here to validate the deserialization result
         // For example:
 
         assert_eq!(ordered_list.len(), 2);
-        /* assert_eq!(ordered_list[0], ("key1".to_string(), "value1".to_string()));
-        assert_eq!(ordered_list[1], ("key2".to_string(), "value2".to_string())); */
+        assert_eq!(ordered_list[0], (key1, value1));
+        assert_eq!(ordered_list[1], (key2, value2));
+    }
+
+    // a truncated or malformed chunk fails the decode instead of panicking
+    #[test]
+    fn test_chunk_truncated() {
+        assert_eq!(Chunk::decode(&[2, 5, 0, 0, 0, 0, 0, 0, 0]), Err(Error::Corrupted));
+        assert_eq!(Chunk::decode(&[2, 1, 0, 0]), Err(Error::Corrupted));
+        // key num and key size that overflow the offset
+        let buffer = [2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Chunk::decode(&buffer), Err(Error::Corrupted));
+        let mut buffer = vec![2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        buffer.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Chunk::decode(&buffer), Err(Error::Corrupted));
+
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), b"value1").unwrap();
+        chunk.insert(&2u64.varint_encode(), b"value2").unwrap();
+        let (_, buffer) = chunk.encode(ValueEncoding::Raw);
+        assert!(Chunk::decode(&buffer).is_ok());
+        for len in 0..buffer.len() {
+            assert_eq!(Chunk::decode(&buffer[..len]), Err(Error::Corrupted), "{len}");
+        }
+    }
+
+    #[test]
+    fn test_chunk_legacy_format() {
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), b"value1").unwrap();
+        chunk.insert(&2u64.varint_encode(), &tombstone::tombstone()).unwrap();
+        let (_, mut buffer) = chunk.encode(ValueEncoding::Raw);
+        assert_eq!(buffer[0], CHUNK_FORMAT_ENTRIES);
+        assert_eq!(Chunk::decode(&buffer).unwrap()[1].1, tombstone::tombstone());
+
+        // values of a chunk written before entries are read as puts, whatever their first byte
+        buffer[0] = CHUNK_FORMAT_RAW;
+        let items = Chunk::decode(&buffer).unwrap();
+        assert_eq!(items[0].1, tombstone::put(b"value1"));
+        assert_eq!(items[1].1, tombstone::put(&tombstone::tombstone()));
+    }
+
+    #[test]
+    fn test_chunk_gorilla() {
+        let mut chunk = Chunk::new();
+        let mut expected = vec![];
+        for i in 0..40u64 {
+            let key = (1_700_000_000 + i * 15).varint_encode();
+            let value = tombstone::put(&(i as f64 * 0.25).to_le_bytes());
+            chunk.insert(&key, &value).unwrap();
+            expected.push((key, value));
+        }
+        assert_eq!(chunk.key_range(), Some((1_700_000_000, 1_700_000_000 + 39 * 15)));
+        let raw_size = Chunk::raw_size(chunk.key_nums, chunk.used_size);
+
+        let (first_key, buffer) = chunk.encode(ValueEncoding::Gorilla);
+        assert_eq!(first_key, expected[0].0);
+        assert_eq!(buffer[0], CHUNK_FORMAT_GORILLA);
+        assert!(buffer.len() < raw_size / 4);
+        assert_eq!(Chunk::decode(&buffer).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_chunk_gorilla_fallback() {
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), b"not-a-f64").unwrap();
+
+        let (_, buffer) = chunk.encode(ValueEncoding::Gorilla);
+        assert_eq!(buffer[0], CHUNK_FORMAT_ENTRIES);
+        assert_eq!(Chunk::decode(&buffer).unwrap()[0].1, b"not-a-f64");
+
+        // a tombstone can't be gorilla encoded
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), &tombstone::put(&1f64.to_le_bytes())).unwrap();
+        chunk.insert(&2u64.varint_encode(), &tombstone::tombstone()).unwrap();
+
+        let (_, buffer) = chunk.encode(ValueEncoding::Gorilla);
+        assert_eq!(buffer[0], CHUNK_FORMAT_ENTRIES);
+        assert_eq!(Chunk::decode(&buffer).unwrap()[1].1, tombstone::tombstone());
+    }
+
+    #[test]
+    fn test_chunk_overwrite() {
+        let mut chunk = Chunk::new();
+        let key = 1u64.varint_encode();
+        chunk.insert(&key, b"value1").unwrap();
+        chunk.insert(&key, b"v2").unwrap();
+
+        assert_eq!(chunk.get(&key), Ok(b"v2".to_vec()));
+        assert_eq!(chunk.key_nums, 1);
+        assert_eq!(chunk.used_size, key.len() + 2);
+    }
+
+    #[test]
+    fn test_chunk_split() {
+        let mut chunk = Chunk::new();
+        for key in [5u64, 99, 100, 250, 299, 1000] {
+            chunk.insert(&key.varint_encode(), b"v").unwrap();
+        }
+        let ranges: Vec<(u64, u64)> = chunk
+            .split(100)
+            .unwrap()
+            .iter()
+            .map(|chunk| chunk.key_range().unwrap())
+            .collect();
+        assert_eq!(ranges, vec![(5, 99), (100, 100), (250, 299), (1000, 1000)]);
+
+        let mut chunk = Chunk::new();
+        chunk.insert(&1u64.varint_encode(), b"v").unwrap();
+        chunk.insert(&1000u64.varint_encode(), b"v").unwrap();
+        assert!(chunk.split(0).is_none());
+        assert!(chunk.split(2000).is_none());
+
+        let mut chunk = Chunk::new();
+        chunk.seq = 7;
+        chunk.insert(&1u64.varint_encode(), b"v").unwrap();
+        chunk.insert(&1000u64.varint_encode(), b"v").unwrap();
+        assert!(chunk.split(100).unwrap().iter().all(|chunk| chunk.seq == 7));
+    }
+
+    #[test]
+    fn test_chunk_delete_range() {
+        let mut chunk = Chunk::new();
+        for key in 0..100u64 {
+            chunk.insert(&key.varint_encode(), b"value").unwrap();
+        }
+        assert_eq!(chunk.delete_range(&(10..20)), 10);
+        assert_eq!(chunk.delete_range(&(10..20)), 0);
+        assert_eq!(chunk.key_nums, 90);
+        assert_eq!(chunk.get(&15u64.varint_encode()), Err(Error::KeyNotFound));
+        assert_eq!(chunk.get(&20u64.varint_encode()), Ok(b"value".to_vec()));
+
+        // the chunk is still usable once it's emptied
+        assert_eq!(chunk.delete_range(&..), 90);
+        assert_eq!((chunk.key_nums, chunk.used_size), (0, 0));
+        assert!(chunk.key_range().is_none());
+        chunk.insert(&15u64.varint_encode(), b"again").unwrap();
+        assert_eq!(chunk.scan(&..), vec![(15u64.varint_encode(), b"again".to_vec())]);
+        // a range tombstone removes the keys written before it and hides them in older chunks,
+        // keys written after it are kept
+        chunk.insert(&16u64.varint_encode(), b"value").unwrap();
+        chunk.insert_range_tombstone(10..20);
+        chunk.insert(&12u64.varint_encode(), b"again").unwrap();
+        assert_eq!(chunk.keys(), vec![12u64.varint_encode()]);
+        assert!(chunk.hides(15) && chunk.hides(12) && !chunk.hides(20));
+        assert_eq!(chunk.range_tombstones, vec![10..20]);
     }
 
     #[test]
     fn test_chunk_get() {
         let mut chunk = Chunk::new();
-        let key1 = b"key1".to_vec();
+        let key1 = 1u64.varint_encode();
         let value1 = b"value1".to_vec();
-        let key2 = b"key2".to_vec();
+        let key2 = 2u64.varint_encode();
         let value2 = b"value2".to_vec();
 
         chunk.insert(&key1, &value1).unwrap();
@@ -289,7 +539,7 @@ mod tests {
     #[test]
     fn test_chunk_delete() {
         let mut chunk = Chunk::new();
-        let key1 = b"key1".to_vec();
+        let key1 = 1u64.varint_encode();
         let value1 = b"value1".to_vec();
 
         chunk.insert(&key1, &value1).unwrap();
@@ -1,6 +1,5 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::usize;
 
 use crate::bytes;
 use crate::errors::Error;
@@ -30,19 +29,21 @@ const EMPTY_NODE: Option<Rc<RefCell<Node>>> = None;
 
 #[derive(Debug, Default, PartialEq)]
 pub struct Node {
-    key: Vec<u8>,
-    value: Vec<u8>,
+    pub(super) key: Vec<u8>,
+    pub(super) value: Vec<u8>,
     next_nodes: [Option<Rc<RefCell<Node>>>; MAX_SKIP_HEIGH],
 }
 
+// every node is linked at level 0, and at each level above with probability 1/2
 fn get_random_height() -> i32 {
-    let mut height = 0;
-    for _ in 0..MAX_SKIP_HEIGH {
+    let mut height = 1;
+    while height < MAX_SKIP_HEIGH as i32 {
         let rand_dome = Random::u32().unwrap();
         let f_n = rand_dome as f64 / u32::MAX as f64;
-        if f_n < 0.5 {
-            height += 1;
+        if f_n >= 0.5 {
+            break;
         }
+        height += 1;
     }
     height
 }
@@ -80,6 +81,12 @@ impl SkipList {
         Ok(())
     }
 
+    // replace the value of an existing key, the old value is returned
+    pub(super) fn replace(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let (_, may_found) = self.search(key);
+        may_found.map(|node| std::mem::replace(&mut node.borrow_mut().value, value.to_vec()))
+    }
+
     pub(super) fn delete(&mut self, key: &[u8]) -> Result<Vec<u8>, Error> {
         let (mut travels, may_found) = self.search(key);
         if may_found.is_none() {
@@ -149,38 +156,6 @@ impl SkipList {
     }
 }
 
-pub(super) struct IntoIterator(SkipList);
-
-impl SkipList {
-    pub(super) fn into_iter(self) -> IntoIterator {
-        IntoIterator(self)
-    }
-}
-
-impl Iterator for IntoIterator {
-    type Item = (Vec<u8>, Vec<u8>);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let drop = self.0.head.borrow_mut().next_nodes[0].take();
-        drop.as_ref()?;
-        let node = drop.unwrap();
-        let next = node.borrow_mut().next_nodes[0].take();
-        self.0.head.borrow_mut().next_nodes[0] = next;
-        /* let node = Rc::try_unwrap(node).unwrap().into_inner(); */
-        let key = node.borrow().key.clone();
-        let value = node.borrow().value.clone();
-        Some((key, value))
-        /* if let Some(node) = self.0.head.borrow_mut().next_nodes[0].take() {
-            let next = node.borrow_mut().next_nodes[0].take();
-            self.0.head.borrow_mut().next_nodes[0] = next;
-            let node = Rc::try_unwrap(node).unwrap().into_inner();
-            Some((node.key, node.value))
-        } else {
-            None
-        } */
-    }
-}
-
 // Iter<'a>[#TODO] (shoule add some comments )
 pub(super) struct Iter {
     next: Option<SkipNode>,
@@ -199,9 +174,8 @@ impl Iterator for Iter {
     type Item = SkipNode;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next.take().map(|node| {
+        self.next.take().inspect(|node| {
             self.next = node.borrow().next_nodes[0].clone();
-            node
         })
     }
 }
@@ -237,6 +211,20 @@ mod tests {
         assert_eq!(list.get(&key), Err(Error::KeyNotFound));
     }
 
+    #[test]
+    fn test_replace() {
+        let mut list = SkipList::default();
+        let key = vec![1, 2, 3];
+
+        assert_eq!(list.replace(&key, &[1]), None);
+        list.insert(&key, &[4, 5, 6]).unwrap();
+        assert_eq!(list.insert(&key, &[7]), Err(Error::KeyExists));
+
+        assert_eq!(list.replace(&key, &[7]), Some(vec![4, 5, 6]));
+        assert_eq!(list.get(&key), Ok(vec![7]));
+        assert_eq!(list.iter().count(), 1);
+    }
+
     #[test]
     fn test_iterator() {
         let mut list = SkipList::default();
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Clock 决定TTL的过期时间, 单位是毫秒; 测试里面可以换成ManualClock, 不依赖真实时间
+pub trait Clock: Send + Sync {
+    // milliseconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+// wall time of the system
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+// Clock[#TODO] (should add some comments)
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
+
+// a clock that only moves when it's told to
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+// ManualClock[#TODO] (should add some comments)
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+// Clock[#TODO] (should add some comments)
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+// Debug[#TODO] (should add some comments)
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Clock({})", self.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let clock = ManualClock::new(100);
+        clock.advance(50);
+        assert_eq!(clock.now(), 150);
+        clock.set(10);
+        assert_eq!(clock.now(), 10);
+        // some time after 2020
+        assert!(SystemClock.now() > 1_577_836_800_000);
+    }
+}
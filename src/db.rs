@@ -1,22 +1,128 @@
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
-use std::{fs, usize};
+use std::fs;
+use std::ops::{Range, RangeBounds};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::block::Blocks;
-use crate::bytes::{self, VarintCodec};
-use crate::checkpoint::CheckPoint;
+use crate::btree::constant::{DEFAULT_MAX_THRESHOLD, DEFAULT_PAGE_SIZE};
+use crate::bytes::VarintCodec;
+use crate::clock::{Clock, SystemClock};
+use crate::chunk::DEFAULT_MAX_CHUNK_SIZE;
 use crate::errors::Error;
 use crate::memtable::MemTables;
+use crate::stats::Stats;
+use crate::tombstone;
 use crate::wal::WalManager;
 
+pub use crate::block::{Compression, Partition};
+pub use crate::chunk::ValueEncoding;
+pub use crate::snapshot::Snapshot;
+
 // Options[#TODO] (shoule add some comments )
+#[derive(Clone, Debug)]
 pub struct DBOptions {
     // chunk是memtable里面一块数据, chunk持久化到磁盘就是B树里面Leaf节点行一个value
-    chunk_size: usize,
+    pub chunk_size: usize,
     // block是一个B树,一个page定义一个B树节点, page_size定义树节点能存储多少数据
-    page_size: usize,
+    // 必须是 4KiB - 1MiB 之间的2的幂, 只对新建的block生效
+    pub page_size: usize,
     // 一个block最大可以占多少磁盘
-    block_size: usize,
+    pub block_size: usize,
+    // 整个数据库默认的value编码方式
+    pub value_encoding: ValueEncoding,
+    // 落在这些key(时间戳)范围内的chunk 使用Gorilla编码, 不管value_encoding是什么
+    pub series_ranges: Vec<Range<u64>>,
+    // 每个encoded chunk 写入block之前使用的压缩算法
+    pub compression: Compression,
+    // 每个key在bloom filter里面占用的bit数, 0表示不使用bloom filter
+    pub bloom_bits_per_key: usize,
+    // 最多同时缓存多少个已经打开的block reader
+    pub max_open_files: usize,
+    // 所有block共享的B树page cache大小(字节), 0表示不使用page cache
+    pub page_cache_size: usize,
+    // 只读的block使用mmap读取page, 不拷贝page也不经过page cache
+    pub mmap_blocks: bool,
+    // flush时block的B树自底向上批量构建, 每个节点填充到page_size的比例, 必须在 (0.25, 0.9] 之间
+    pub fill_factor: f64,
+    // 后台自动合并block: level 0 的block太多时合并进level 1, 某一层太大时把它的block合并进下一层,
+    // 合并时丢弃被覆盖的旧版本和tombstone
+    pub auto_compaction: bool,
+    // 后台合并每秒最多读写多少字节, 0表示不限速
+    pub compaction_bytes_per_sec: usize,
+    // level 0 有这么多个block时合并进level 1
+    pub level0_compaction_trigger: usize,
+    // level 1 的大小上限(字节), 超过之后它的block合并进level 2
+    pub level_base_size: usize,
+    // 每一层的大小上限是上一层的多少倍
+    pub level_size_ratio: usize,
+    // 按key(时间戳)把block划分成这么宽的窗口, 一个block只存一个窗口里面的key, 0表示不分区
+    pub partition_size: u64,
+    // 只保留最新的key往前这么多个key(时间)的数据, 更老的block整个删除, 0表示永久保留
+    pub retention: u64,
+    // 计算TTL过期时间的时钟, 默认是系统时间
+    pub clock: Arc<dyn Clock>,
+}
+
+// Default[#TODO] (should add some comments)
+impl Default for DBOptions {
+    fn default() -> Self {
+        DBOptions {
+            chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            page_size: DEFAULT_PAGE_SIZE,
+            block_size: 4096 * 10,
+            value_encoding: ValueEncoding::Raw,
+            series_ranges: Vec::new(),
+            compression: Compression::None,
+            bloom_bits_per_key: 10,
+            max_open_files: 64,
+            page_cache_size: 64 << 20,
+            mmap_blocks: false,
+            fill_factor: DEFAULT_MAX_THRESHOLD,
+            auto_compaction: true,
+            compaction_bytes_per_sec: 32 << 20,
+            level0_compaction_trigger: 4,
+            level_base_size: 4 << 20,
+            level_size_ratio: 10,
+            partition_size: 0,
+            retention: 0,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+// DBOptions[#TODO] (should add some comments)
+impl DBOptions {
+    // a chunk holding keys in [first, last] is gorilla encoded only if the whole chunk is
+    // covered by one series range
+    pub(crate) fn value_encoding_for(&self, first: u64, last: u64) -> ValueEncoding {
+        let in_series = self
+            .series_ranges
+            .iter()
+            .any(|range| range.contains(&first) && range.contains(&last));
+        if in_series {
+            ValueEncoding::Gorilla
+        } else {
+            self.value_encoding
+        }
+    }
+
+    // size limit of a level above 0, every level is level_size_ratio times bigger than the one
+    // above it
+    pub(crate) fn level_max_size(&self, level: usize) -> u64 {
+        let ratio = self.level_size_ratio.max(2) as u64;
+        (self.level_base_size as u64).saturating_mul(ratio.saturating_pow(level as u32 - 1))
+    }
+
+    // first key of the partition holding the key, 0 without partitioning
+    pub(crate) fn partition_of(&self, key: u64) -> u64 {
+        match self.partition_size {
+            0 => 0,
+            size => key - key % size,
+        }
+    }
 }
 
 // MintKv[#TODO] (shoule add some comments )
@@ -24,12 +130,16 @@ pub struct MintKv {
     memtables: MemTables,
     blocks: Blocks,
     wal_mg: WalManager,
-    check_point: CheckPoint,
+    clock: Arc<dyn Clock>,
 }
 
 // MintKv[#TODO] (should add some comments)
 impl MintKv {
     pub fn new(data_dir: &str) -> Self {
+        MintKv::open(data_dir, DBOptions::default())
+    }
+
+    pub fn open(data_dir: &str, options: DBOptions) -> Self {
         let mut is_initial = true;
         if fs::metadata(data_dir).is_err() {
             fs::create_dir(data_dir).expect("Fatal: Create Data Dir failed");
@@ -54,13 +164,13 @@ impl MintKv {
                 }
             }
         };
-        let check_point = CheckPoint::get_or_create(data_dir);
-
+        // chunks of the memtable are numbered after the ones in blocks
+        let blocks = Blocks::open_or_create(data_dir, &options);
         let mut db = MintKv {
-            check_point,
-            memtables: MemTables::default(),
-            blocks: Blocks::open_or_create(data_dir),
+            memtables: MemTables::new(options.chunk_size, blocks.next_seq()),
+            blocks,
             wal_mg: WalManager::new(data_dir.to_string(), wal_fp, is_initial),
+            clock: options.clock.clone(),
         };
 
         if !is_initial {
@@ -73,69 +183,901 @@ impl MintKv {
 
 /// Get / Delete / Get
 impl MintKv {
-    pub fn get(&self, key: u64) -> Result<String, Error> {
+    // the newest entry of the key wins, a tombstone or an expired entry hides the older ones
+    pub fn get(&self, key: u64) -> Result<Vec<u8>, Error> {
         let key = key.varint_encode();
-        if let Ok(result) = self.memtables.get(&key) {
-            return Ok(String::from_utf8(result).unwrap());
+        let now = self.clock.now();
+        if let Ok(entry) = self.memtables.get(&key) {
+            return tombstone::value(entry, now);
         }
-        if let Ok(result) = self.blocks.get(&key) {
-            return Ok(String::from_utf8(result).unwrap());
+        if let Ok(entry) = self.blocks.get(&key) {
+            return tombstone::value(entry, now);
         }
 
         Err(Error::KeyNotFound)
     }
 
+    // all key-values in the range ordered by key, newer values shadow older ones. A block that
+    // can't be decoded fails the scan with Error::Corrupted
+    pub fn scan(&self, range: impl RangeBounds<u64>) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut result = BTreeMap::new();
+        self.blocks.scan(&range, &mut result)?;
+        self.memtables.scan(&range, &mut result);
+        let now = self.clock.now();
+        Ok(result
+            .into_iter()
+            .filter_map(|(key, entry)| tombstone::value(entry, now).ok().map(|value| (key, value)))
+            .collect())
+    }
+
     pub fn insert(&mut self, key: u64, value: &[u8]) -> Result<(), Error> {
+        self.write(key, tombstone::put(value))
+    }
+
+    // the value is absent once ttl has passed on DBOptions::clock, compaction removes it after
+    // that
+    pub fn insert_with_ttl(&mut self, key: u64, value: &[u8], ttl: Duration) -> Result<(), Error> {
+        let expire_at = self.clock.now().saturating_add(ttl.as_millis() as u64);
+        self.write(key, tombstone::put_with_expiry(value, expire_at))
+    }
+
+    // a tombstone is written instead of removing the key, older values of the key may be in
+    // blocks already; they are dropped by compaction
+    pub fn delete(&mut self, key: u64) -> Result<Vec<u8>, Error> {
+        let value = self.get(key)?;
+        self.write(key, tombstone::tombstone())?;
+        Ok(value)
+    }
+
+    // every key in the range is deleted by one record in the wal and one range tombstone in the
+    // memtable, instead of a tombstone per key. The tombstone goes into the manifest when its
+    // chunk is written into blocks, it hides the keys there until compaction drops them
+    pub fn delete_range(&mut self, range: Range<u64>) -> Result<(), Error> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.memtables.delete_range(&range);
+        let entry = tombstone::range_tombstone(range.end);
+        self.wal_mg.record(&range.start.varint_encode(), &entry, self.memtables.seq());
+        Ok(())
+    }
+
+    // the wal record is tagged with the memtable chunk the entry went into, so it's written after
+    // the entry
+    fn write(&mut self, key: u64, entry: Vec<u8>) -> Result<(), Error> {
         let key = key.varint_encode();
-        self.wal_mg.record(&key, value);
         self.flush_memtable().unwrap();
-        self.memtables.insert(&key, value)
+        self.memtables.insert(&key, &entry)?;
+        self.wal_mg.record(&key, &entry, self.memtables.seq());
+        Ok(())
     }
+}
 
-    pub fn delete(&mut self, key: u64) -> Result<String, Error> {
-        // first delete from memtables
-        let key = key.varint_encode();
-        if let Ok(result) = self.memtables.delete(&key) {
-            return Ok(String::from_utf8(result).unwrap());
-        }
-        // if key not existed in memtables, then search from sstable ,remove it if existed in
-        // sstables;
-        if let Ok(result) = self.blocks.remove(&key) {
-            return Ok(String::from_utf8(result).unwrap());
-        }
+/// Typed time-series values, key is the timestamp and value is a f64 sample
+impl MintKv {
+    pub fn insert_f64(&mut self, timestamp: u64, value: f64) -> Result<(), Error> {
+        self.insert(timestamp, &value.to_le_bytes())
+    }
 
-        Err(Error::KeyNotFound)
+    pub fn get_f64(&self, timestamp: u64) -> Result<f64, Error> {
+        let value = self.get(timestamp)?;
+        let bytes = value.try_into().map_err(|_| Error::Corrupted)?;
+        Ok(f64::from_le_bytes(bytes))
     }
 
+    pub fn stats(&self) -> Stats {
+        self.blocks.stats()
+    }
 }
 
 // MintKv[#TODO] (should add some comments)
 impl MintKv {
     fn flush_memtable(&mut self) -> Result<(), Error> {
         while let Some(chunk) = self.memtables.expired_chunks() {
-            let key = u64::varint_decode(&chunk.last_key);
-            self.check_point.record(key.1);
-            self.blocks.write_block(chunk);
+            self.blocks.write_block(&chunk);
         }
+        self.truncate_wal();
         Ok(())
     }
 
     pub fn commit(&mut self) {
         self.blocks.flush();
+        self.truncate_wal();
     }
 
+    // the wal files holding only records of chunks in flushed blocks are dropped
+    fn truncate_wal(&mut self) {
+        if let Some(seq) = self.blocks.durable_seq() {
+            self.wal_mg.truncate(seq);
+        }
+    }
+
+    // merge level 0 and every level over its size limit now, instead of waiting for the
+    // background compaction
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.blocks.compact()
+    }
+
+    // partitions of the data in blocks and their key (time) bounds, see DBOptions::partition_size
+    pub fn partitions(&self) -> Vec<Partition> {
+        self.blocks.partitions()
+    }
+
+    // drop the blocks holding only keys below the cutoff by deleting their files, instead of
+    // deleting the keys one by one. Keys still in the memtable are not dropped. Returns the
+    // number of blocks dropped
+    pub fn drop_before(&mut self, cutoff: u64) -> usize {
+        self.blocks.drop_before(cutoff)
+    }
+
+    // a read-only view of the database as it is now, later writes, flushes and compactions don't
+    // change what it reads. It's pinned to the seq of the next memtable chunk: the chunks of the
+    // memtable are sealed and shared with it, and chunks written into blocks after it are
+    // skipped. Blocks removed while the snapshot lives keep their files until it's dropped
+    pub fn snapshot(&mut self) -> Snapshot {
+        let memtable = self.memtables.snapshot();
+        let blocks = self.blocks.snapshot(self.memtables.seq());
+        Snapshot::new(memtable, blocks, self.clock.now())
+    }
+
+    // every record of the wal is replayed in the order it was written, the ones already in blocks
+    // too: they are written again and shadow nothing newer. What's replayed is written into
+    // blocks and the wal starts over, a crash before that replays it again
     fn recover_wal(&mut self) {
-        while let Some(kv_item) = self.wal_mg.replay() {
+        let legacy = self.wal_mg.is_legacy();
+        while let Some(mut kv_item) = self.wal_mg.replay() {
             if kv_item.0.is_empty() {
                 continue;
             }
-            let key = u64::varint_decode(&kv_item.0).1;
-            if key < self.check_point.last_key {
+            // values of a legacy wal are all puts
+            if legacy {
+                kv_item.1 = tombstone::put(&kv_item.1);
+            }
+            // the range may cover keys replayed before it wherever they are
+            if let Some(end) = tombstone::range_end(&kv_item.1) {
+                let start = u64::varint_decode(&kv_item.0).1;
+                self.memtables.delete_range(&(start..end));
                 continue;
             }
             self.memtables
                 .insert(&kv_item.0, &kv_item.1)
                 .expect("Replay wal log failed");
         }
+        for chunk in self.memtables.drain() {
+            self.blocks.write_block(&chunk);
+        }
+        self.blocks.flush();
+        // a legacy wal isn't appended to, the new one is written in the current format
+        self.wal_mg.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::constant::{PAGE_TRAILER_SIZE, PAGE_TYPE_LEAF};
+    use crate::util::testing::{fill, open_db, Lcg};
+
+    #[test]
+    fn test_gorilla_series() {
+        let options = DBOptions {
+            series_ranges: std::iter::once(1_000_000..2_000_000).collect(),
+            ..DBOptions::default()
+        };
+        let (_tmp, mut db) = open_db("gorilla", options);
+        for i in 0..2000u64 {
+            db.insert_f64(1_000_000 + i * 10, i as f64 / 8.0).unwrap();
+        }
+        db.commit();
+
+        let stats = db.stats();
+        assert!(stats.chunk_encoded_bytes > 0);
+        assert!(stats.chunk_compression_ratio() < 0.25);
+
+        for i in (0..2000u64).step_by(7) {
+            assert_eq!(db.get_f64(1_000_000 + i * 10), Ok(i as f64 / 8.0));
+        }
+        let samples = db.scan(1_000_000..1_000_100).unwrap();
+        assert_eq!(samples.len(), 10);
+        assert_eq!(samples[3], (1_000_030, (3.0f64 / 8.0).to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_scan_mixed_encodings() {
+        let options = DBOptions {
+            series_ranges: std::iter::once(0..500).collect(),
+            ..DBOptions::default()
+        };
+        let (_tmp, mut db) = open_db("scan", options);
+        for i in 0..1000u64 {
+            if i < 500 {
+                db.insert_f64(i, i as f64).unwrap();
+            } else {
+                db.insert(i, format!("value-{}", i).as_bytes()).unwrap();
+            }
+        }
+        db.commit();
+
+        let result = db.scan(490..510).unwrap();
+        assert_eq!(result.len(), 20);
+        assert_eq!(result[0], (490, 490f64.to_le_bytes().to_vec()));
+        assert_eq!(result[19], (509, b"value-509".to_vec()));
+        assert_eq!(db.scan(..).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_block_compression() {
+        for compression in [Compression::Lz4, Compression::Zstd, Compression::Snappy] {
+            let options = DBOptions {
+                compression,
+                ..DBOptions::default()
+            };
+            let (_tmp, mut db) = open_db(&format!("compression-{:?}", compression), options);
+            fill(&mut db, 0..1000);
+            db.commit();
+
+            let stats = db.stats();
+            assert!(stats.chunk_compressed_bytes > 0);
+            assert!(stats.block_compression_ratio() < 0.8, "{:?}", stats);
+            for i in (0..1000u64).step_by(13) {
+                assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+            }
+            assert_eq!(db.scan(..).unwrap().len(), 1000);
+        }
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let options = DBOptions {
+            block_size: 4096,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("bloom", options);
+        for i in 0..2000u64 {
+            db.insert(i * 2, format!("value-{}", i).as_bytes()).unwrap();
+        }
+        db.commit();
+
+        for i in 0..2000u64 {
+            assert_eq!(db.get(i * 2), Ok(format!("value-{}", i).into_bytes()));
+            assert_eq!(db.get(i * 2 + 1), Err(Error::KeyNotFound));
+        }
+        let stats = db.stats();
+        assert!(stats.bloom_hits > 1500, "{:?}", stats);
+        assert!(stats.bloom_false_positives < 100, "{:?}", stats);
+
+        // filters are persisted in the block files
+        drop(db);
+        let db = MintKv::new(&tmp.dir);
+        assert_eq!(db.get(1001), Err(Error::KeyNotFound));
+        assert_eq!(db.stats().bloom_hits, 1);
+    }
+
+    // a value larger than a chunk, a wal file and a page gets a chunk and a wal file of its own
+    // and overflow pages in its block
+    #[test]
+    fn test_large_values() {
+        let options = DBOptions {
+            chunk_size: 256,
+            ..DBOptions::default()
+        };
+        let large: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let (tmp, mut db) = open_db("large-values", options);
+        db.insert(1, b"small").unwrap();
+        db.insert(2, &large).unwrap();
+        db.insert(3, b"small").unwrap();
+        assert_eq!(db.get(2), Ok(large.clone()));
+        drop(db);
+
+        // replayed from the wal
+        let mut db = tmp.reopen();
+        assert_eq!(db.get(2), Ok(large.clone()));
+        db.insert(4, &large).unwrap();
+        db.commit();
+        drop(db);
+
+        let db = tmp.reopen();
+        assert_eq!(db.get(4), Ok(large.clone()));
+        let values: Vec<usize> = db.scan(..).unwrap().iter().map(|(_, v)| v.len()).collect();
+        assert_eq!(values, vec![5, large.len(), 5, large.len()]);
+    }
+
+    #[test]
+    fn test_manifest() {
+        // every chunk gets its own block
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 1,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("manifest", options);
+        fill(&mut db, 0..3000);
+        db.commit();
+        drop(db);
+        let blocks = || {
+            fs::read_dir(format!("{}/blocks", tmp.dir))
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    name.to_str().unwrap().starts_with("block-")
+                })
+                .count()
+        };
+        let written = blocks();
+        assert!(written > 100, "{written}");
+
+        // blocks written after reopening get new ids
+        let mut db = tmp.reopen();
+        fill(&mut db, 3000..4000);
+        drop(db);
+        assert!(blocks() > written);
+
+        let db = tmp.reopen();
+        for i in (0..4000u64).step_by(7) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        assert_eq!(db.scan(..).unwrap().len(), 4000);
+    }
+
+    #[test]
+    fn test_delete() {
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("delete", options);
+        fill(&mut db, 0..2000);
+        db.commit();
+
+        // keys in blocks and in the memtable are hidden by tombstones
+        for i in (0..2000u64).step_by(2) {
+            assert_eq!(db.delete(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        assert_eq!(db.delete(0), Err(Error::KeyNotFound));
+        assert_eq!(db.delete(5000), Err(Error::KeyNotFound));
+        db.insert(10, b"again").unwrap();
+        for i in 0..2000u64 {
+            let expected = match i {
+                10 => Ok(b"again".to_vec()),
+                _ if i % 2 == 0 => Err(Error::KeyNotFound),
+                _ => Ok(format!("value-{}", i).into_bytes()),
+            };
+            assert_eq!(db.get(i), expected, "key {i}");
+        }
+        assert_eq!(db.scan(..).unwrap().len(), 1001);
+
+        // push the tombstones out of the memtable, compaction drops them with the values they hide
+        fill(&mut db, 2000..2200);
+        db.compact().unwrap();
+        let stats = db.stats();
+        assert!(stats.compactions > 0, "{:?}", stats);
+        assert!(stats.compaction_written_bytes < stats.compaction_read_bytes, "{:?}", stats);
+        assert_eq!(db.get(998), Err(Error::KeyNotFound));
+        assert_eq!(db.get(10), Ok(b"again".to_vec()));
+        assert_eq!(db.scan(0..2000).unwrap().len(), 1001);
+        drop(db);
+
+        let db = tmp.reopen();
+        for i in (0..2000u64).step_by(3) {
+            let expected = match i {
+                10 => Ok(b"again".to_vec()),
+                _ if i % 2 == 0 => Err(Error::KeyNotFound),
+                _ => Ok(format!("value-{}", i).into_bytes()),
+            };
+            assert_eq!(db.get(i), expected, "key {i}");
+        }
+        assert_eq!(db.scan(..).unwrap().len(), 1201);
+    }
+
+    #[test]
+    fn test_recover_wal() {
+        let options = DBOptions {
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("recover-wal", options);
+        fill(&mut db, 0..20);
+        db.delete_range(5..10).unwrap();
+        drop(db);
+
+        // the replayed records are written into blocks, the wal files are not read again
+        let db = tmp.reopen();
+        assert_eq!(db.scan(..).unwrap().len(), 15);
+        drop(db);
+        for entry in fs::read_dir(format!("{}/wal", tmp.dir)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() != "metadata" {
+                fs::remove_file(path).unwrap();
+            }
+        }
+        let db = tmp.reopen();
+        assert_eq!(db.get(4), Ok(b"value-4".to_vec()));
+        assert_eq!(db.get(5), Err(Error::KeyNotFound));
+        assert_eq!(db.scan(..).unwrap().len(), 15);
+    }
+
+    #[test]
+    fn test_legacy_wal() {
+        let options = DBOptions {
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("legacy-wal", options);
+        // records of a wal written before entries hold the values as they were inserted
+        for i in 2..50u64 {
+            db.wal_mg.record(&i.varint_encode(), format!("value-{}", i).as_bytes(), 0);
+        }
+        db.wal_mg.record(&50u64.varint_encode(), &tombstone::tombstone(), 0);
+        drop(db);
+        let metadata = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/wal/metadata", tmp.dir))
+            .unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&metadata, &[0], 2).unwrap();
+        drop(metadata);
+
+        // the legacy records are replayed as puts, written into blocks and the wal starts over in
+        // the current format
+        let db = tmp.reopen();
+        assert!(!db.wal_mg.is_legacy());
+        assert_eq!(db.get(2), Ok(b"value-2".to_vec()));
+        assert_eq!(db.get(50), Ok(tombstone::tombstone()));
+        assert_eq!(db.scan(..).unwrap().len(), 49);
+        drop(db);
+        let mut db = tmp.reopen();
+        assert_eq!(db.get(49), Ok(b"value-49".to_vec()));
+        db.insert(51, b"new").unwrap();
+        drop(db);
+        let db = MintKv::open(&tmp.dir, DBOptions::default());
+        assert_eq!(db.get(51), Ok(b"new".to_vec()));
+        assert_eq!(db.scan(..).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let check = |db: &MintKv, model: &BTreeMap<u64, Vec<u8>>| {
+            for i in 0..2200u64 {
+                assert_eq!(db.get(i).ok().as_ref(), model.get(&i), "key {i}");
+            }
+            let expected: Vec<(u64, Vec<u8>)> =
+                model.iter().map(|(key, value)| (*key, value.clone())).collect();
+            assert_eq!(db.scan(..).unwrap(), expected);
+        };
+        let mut model = BTreeMap::new();
+        let (tmp, mut db) = open_db("delete-range", options);
+        for i in 0..2000u64 {
+            db.insert(i, format!("value-{}", i).as_bytes()).unwrap();
+            model.insert(i, format!("value-{}", i).into_bytes());
+        }
+        db.commit();
+
+        // ranges in blocks, in the memtable and across both
+        for range in [100..300, 1990..2100, 500..500] {
+            db.delete_range(range.clone()).unwrap();
+            model.retain(|key, _| !range.contains(key));
+        }
+        // keys written again after a range delete are kept
+        for i in [150u64, 299, 1995] {
+            db.insert(i, b"again").unwrap();
+            model.insert(i, b"again".to_vec());
+        }
+        check(&db, &model);
+
+        // the range deletes and the writes after them are replayed from the wal in order
+        drop(db);
+        let mut db = tmp.reopen();
+        check(&db, &model);
+
+        db.delete_range(1000..1200).unwrap();
+        model.retain(|key, _| !(1000..1200).contains(key));
+        db.insert(1100, b"again").unwrap();
+        model.insert(1100, b"again".to_vec());
+        for i in 2000..2200u64 {
+            db.insert(i, format!("value-{}", i).as_bytes()).unwrap();
+            model.insert(i, format!("value-{}", i).into_bytes());
+        }
+        db.commit();
+        check(&db, &model);
+
+        // compaction drops the deleted keys for good
+        db.compact().unwrap();
+        check(&db, &model);
+        drop(db);
+        let db = tmp.reopen();
+        check(&db, &model);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let clock = Arc::new(crate::clock::ManualClock::new(1000));
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            auto_compaction: false,
+            clock: clock.clone(),
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("snapshot", options);
+        fill(&mut db, 0..1000);
+        db.insert_with_ttl(1000, b"ttl", Duration::from_millis(10)).unwrap();
+        db.delete(7).unwrap();
+        // keys in blocks and in the memtable
+        let snapshot = db.snapshot();
+        let expected = db.scan(..).unwrap();
+        assert_eq!(expected.len(), 1000);
+
+        // overwrites, deletes, flushes and compaction after it
+        for i in (0..1000u64).step_by(2) {
+            db.insert(i, b"new").unwrap();
+        }
+        db.delete(1).unwrap();
+        db.delete_range(500..600).unwrap();
+        fill(&mut db, 1000..2000);
+        db.compact().unwrap();
+        db.drop_before(300);
+        clock.advance(100);
+        assert_eq!(db.get(0), Err(Error::KeyNotFound));
+        assert_eq!(db.get(1000), Ok(b"value-1000".to_vec()));
+
+        assert_eq!(snapshot.scan(..).unwrap(), expected);
+        for i in 0..1001u64 {
+            let expected = match i {
+                7 => Err(Error::KeyNotFound),
+                1000 => Ok(b"ttl".to_vec()),
+                _ => Ok(format!("value-{}", i).into_bytes()),
+            };
+            assert_eq!(snapshot.get(i), expected, "key {i}");
+        }
+        assert_eq!(snapshot.scan(500..510).unwrap().len(), 10);
+        let stats = db.stats();
+        assert_eq!(stats.snapshots, 1);
+        assert!(stats.snapshot_kept_blocks > 0, "{:?}", stats);
+
+        // files are deleted once no snapshot reads them
+        let later = db.snapshot();
+        assert!(later.seq() > snapshot.seq());
+        drop(snapshot);
+        assert_eq!(db.stats().snapshot_kept_blocks, 0);
+        let blocks: usize = db.partitions().iter().map(|partition| partition.blocks).sum();
+        let files = fs::read_dir(format!("{}/blocks", tmp.dir))
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("block-"))
+            .count();
+        assert_eq!(files, blocks);
+        assert_eq!(later.scan(..).unwrap(), db.scan(..).unwrap());
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        // every round rewrites the same keys, its blocks overlap all the older ones
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            compaction_bytes_per_sec: 1 << 20,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("background-compaction", options);
+        for round in 0..8u64 {
+            for i in 0..300u64 {
+                db.insert(i, format!("{round}-{i}").as_bytes()).unwrap();
+            }
+            db.commit();
+        }
+        // the compaction is installed by a later write or commit once it's done
+        for _ in 0..100 {
+            if db.stats().compactions > 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            db.commit();
+        }
+        let stats = db.stats();
+        assert!(stats.compactions > 0, "{:?}", stats);
+        // shadowed versions are not written again
+        assert!(stats.compaction_written_bytes < stats.compaction_read_bytes, "{:?}", stats);
+        for i in 0..300u64 {
+            assert_eq!(db.get(i), Ok(format!("7-{i}").into_bytes()));
+        }
+        assert_eq!(db.scan(..).unwrap().len(), 300);
+        drop(db);
+
+        let db = tmp.reopen();
+        for i in (0..300u64).step_by(7) {
+            assert_eq!(db.get(i), Ok(format!("7-{i}").into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            compaction_bytes_per_sec: 0,
+            level_base_size: 64 << 10,
+            level_size_ratio: 4,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("leveled-compaction", options);
+        let mut model = BTreeMap::new();
+        let mut rng = Lcg(7);
+        for i in 0..20000u64 {
+            let key = rng.below(5000);
+            db.insert(key, format!("{i}").as_bytes()).unwrap();
+            model.insert(key, format!("{i}").into_bytes());
+        }
+        db.compact().unwrap();
+
+        // level 0 is merged away, the deeper levels stay within their limits
+        let stats = db.stats();
+        assert_eq!(stats.levels[0].blocks, 0, "{:?}", stats);
+        assert!(stats.levels[2..].iter().any(|level| level.blocks > 0), "{:?}", stats);
+        for level in 1..stats.levels.len() - 1 {
+            assert!(stats.levels[level].bytes < tmp.options.level_max_size(level), "{:?}", stats);
+        }
+        for key in (0..5000u64).step_by(3) {
+            match model.get(&key) {
+                Some(value) => assert_eq!(db.get(key).as_ref(), Ok(value), "key {key}"),
+                None => assert_eq!(db.get(key), Err(Error::KeyNotFound), "key {key}"),
+            }
+        }
+        assert_eq!(db.scan(..).unwrap(), model.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_retention() {
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            partition_size: 1000,
+            retention: 3000,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("retention", options);
+        fill(&mut db, 0..20000);
+        db.commit();
+
+        // partitions older than the retention are dropped whole
+        let partitions = db.partitions();
+        let newest = partitions.last().unwrap().max_key;
+        let oldest = tmp.options.partition_of(newest - 3000);
+        assert_eq!(partitions.first().unwrap().start, oldest, "{:?}", partitions);
+        for partition in partitions.iter() {
+            assert_eq!(partition.end - partition.start, 1000);
+            assert!(partition.start <= partition.min_key && partition.max_key < partition.end);
+        }
+        assert!(db.stats().expired_blocks > 0);
+        assert_eq!(db.get(oldest - 1), Err(Error::KeyNotFound));
+        assert_eq!(db.get(oldest), Ok(format!("value-{}", oldest).into_bytes()));
+        assert_eq!(db.scan(..).unwrap().len() as u64, 20000 - oldest);
+        drop(db);
+
+        // the files of dropped blocks are gone, the memtable replayed from the wal is written into
+        // the newest partition
+        let db = tmp.reopen();
+        let reopened = db.partitions();
+        let kept = partitions.len() - 1;
+        assert_eq!(reopened[..kept], partitions[..kept]);
+        assert_eq!(reopened[kept].max_key, 19999);
+        // a compaction may be writing its files in the background
+        let files = fs::read_dir(format!("{}/blocks", tmp.dir))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with("block-")
+            })
+            .count();
+        let blocks: usize = reopened.iter().map(|partition| partition.blocks).sum();
+        assert_eq!(files, blocks);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(crate::clock::ManualClock::new(1_000_000));
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 4096,
+            auto_compaction: false,
+            clock: clock.clone(),
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("ttl", options);
+        // even keys live for a second, odd keys forever; 10 was written without a ttl before
+        db.insert(10, b"old").unwrap();
+        fill(&mut db, 11..2000);
+        for i in (0..3000u64).step_by(2) {
+            let value = format!("ttl-{}", i);
+            db.insert_with_ttl(i, value.as_bytes(), Duration::from_secs(1)).unwrap();
+        }
+        db.commit();
+        assert_eq!(db.get(10), Ok(b"ttl-10".to_vec()));
+        assert_eq!(db.scan(..).unwrap().len(), 1500 + 995);
+
+        clock.advance(999);
+        assert_eq!(db.get(2998), Ok(b"ttl-2998".to_vec()));
+        clock.advance(1);
+        // expired entries hide the older values of their keys, in blocks and in the memtable
+        for i in (0..3000u64).step_by(5) {
+            let expected = match i {
+                _ if i % 2 == 0 => Err(Error::KeyNotFound),
+                11..=1999 => Ok(format!("value-{}", i).into_bytes()),
+                _ => Err(Error::KeyNotFound),
+            };
+            assert_eq!(db.get(i), expected, "key {i}");
+        }
+        assert_eq!(db.get(10), Err(Error::KeyNotFound));
+        assert_eq!(db.delete(2998), Err(Error::KeyNotFound));
+        assert_eq!(db.scan(..).unwrap().len(), 995);
+
+        // compaction removes them for good
+        db.compact().unwrap();
+        let stats = db.stats();
+        assert!(stats.expired_entries > 0, "{:?}", stats);
+        assert_eq!(db.scan(..).unwrap().len(), 995);
+        assert_eq!(db.get(10), Err(Error::KeyNotFound));
+        drop(db);
+
+        // the expiry is kept in the wal and in blocks
+        let db = tmp.reopen();
+        assert_eq!(db.get(10), Err(Error::KeyNotFound));
+        assert_eq!(db.get(11), Ok(b"value-11".to_vec()));
+        clock.set(0);
+        assert_eq!(db.get(2998), Ok(b"ttl-2998".to_vec()));
+    }
+
+    #[test]
+    fn test_table_cache() {
+        let options = DBOptions {
+            block_size: 4096,
+            max_open_files: 2,
+            ..DBOptions::default()
+        };
+        let (_tmp, mut db) = open_db("table-cache", options);
+        fill(&mut db, 0..1000);
+        db.commit();
+
+        // the same few blocks are read again and again
+        for _ in 0..10 {
+            for i in 0..100u64 {
+                assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+            }
+        }
+        let stats = db.stats();
+        assert!(stats.table_cache_misses <= 2, "{:?}", stats);
+        assert!(stats.table_cache_hits >= 990, "{:?}", stats);
+
+        // more blocks than max_open_files are still readable
+        for i in 0..900u64 {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        assert!(db.blocks.table_cache.borrow().len() <= 2);
+    }
+
+    #[test]
+    fn test_page_cache() {
+        for page_cache_size in [0, 64 << 20] {
+            // reopen a block reader on every switch, pages still come from the shared cache
+            let options = DBOptions {
+                block_size: 4096,
+                max_open_files: 1,
+                page_cache_size,
+                ..DBOptions::default()
+            };
+            let (_tmp, mut db) = open_db(&format!("page-cache-{}", page_cache_size), options);
+            fill(&mut db, 0..500);
+            db.commit();
+
+            for _ in 0..5 {
+                for i in (0..500u64).step_by(7) {
+                    assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+                }
+            }
+            let stats = db.stats();
+            if page_cache_size == 0 {
+                assert_eq!(stats.page_cache_hits + stats.page_cache_misses, 0);
+            } else {
+                assert!(stats.page_cache_hits > stats.page_cache_misses, "{:?}", stats);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmap_blocks() {
+        let options = DBOptions {
+            block_size: 4096,
+            max_open_files: 1,
+            mmap_blocks: true,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("mmap-blocks", options);
+        fill(&mut db, 0..500);
+        db.commit();
+        for i in (0..500u64).step_by(7) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        drop(db);
+
+        let db = tmp.reopen();
+        assert_eq!(db.scan(0..500).unwrap().len(), 500);
+        for i in (0..500u64).step_by(3) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_bulk_loaded_blocks() {
+        let options = DBOptions {
+            block_size: 1 << 20,
+            fill_factor: 0.5,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("bulk-load", options);
+        fill(&mut db, 0..1000);
+        // chunks written before the commit are readable before they are loaded into the tree
+        for i in (0..1000u64).step_by(11) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        db.commit();
+
+        // the loaded tree takes later chunks by insert
+        fill(&mut db, 1000..2000);
+        db.commit();
+        for i in (0..2000u64).step_by(11) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+
+        drop(db);
+        let db = MintKv::new(&tmp.dir);
+        for i in (0..2000u64).step_by(7) {
+            assert_eq!(db.get(i), Ok(format!("value-{}", i).into_bytes()));
+        }
+        assert_eq!(db.scan(..).unwrap().len(), 2000);
+    }
+
+    // a leaf page that fails its checksum fails scans and compactions instead of panicking
+    #[test]
+    fn test_damaged_leaf() {
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 16 << 10,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let (tmp, mut db) = open_db("damaged-leaf", options);
+        fill(&mut db, 0..2000);
+        db.commit();
+        drop(db);
+
+        let (files, _) = crate::block::manifest_files(&tmp.dir).unwrap();
+        assert!(files.len() > 1);
+        let mut file = fs::read(&files[0]).unwrap();
+        let page_size = tmp.options.page_size;
+        let leaf = (1..file.len() / page_size)
+            .map(|page| page * page_size)
+            .find(|&page| file[page + page_size - PAGE_TRAILER_SIZE] == PAGE_TYPE_LEAF)
+            .unwrap();
+        file[leaf + page_size / 2] ^= 0xff;
+        fs::write(&files[0], file).unwrap();
+
+        let mut db = tmp.reopen();
+        assert_eq!(db.scan(..), Err(Error::Corrupted));
+        assert_eq!(db.compact(), Err(Error::Corrupted));
     }
 }
@@ -3,4 +3,5 @@ pub enum Error {
     IOError,
     KeyNotFound,
     KeyExists,
+    Corrupted,
 }
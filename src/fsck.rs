@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use crate::block;
+use crate::btree::check::CheckReport;
+use crate::btree::error::Error;
+use crate::btree::{BTree, BTreeOptions};
+
+// fsck 检查一个关闭的数据库目录, 不会修改任何文件:
+// MANIFEST 里面的每个block文件都要存在, 并且B树是一致的;
+// 目录里面 MANIFEST 不知道的文件是crash留下的, 下次打开数据库时会被删除, 只报告不算错误
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    // every block of the manifest with a file, in the order of block ids
+    pub blocks: Vec<(String, Result<CheckReport, Error>)>,
+    // files of blocks in the manifest that don't exist
+    pub missing: Vec<String>,
+    // files of the block dir the manifest doesn't know
+    pub orphans: Vec<String>,
+}
+
+// FsckReport[#TODO] (should add some comments)
+impl FsckReport {
+    // blocks that can't be opened or whose tree is not consistent
+    pub fn damaged(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|(_, report)| !report.as_ref().is_ok_and(CheckReport::is_ok))
+            .count()
+    }
+
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.damaged() == 0
+    }
+}
+
+// the manifest can't be read when it's missing or corrupted, nothing else is checked then
+pub fn fsck(data_dir: &str) -> Result<FsckReport, String> {
+    let (files, orphans) = block::manifest_files(data_dir)?;
+    let mut report = FsckReport {
+        orphans,
+        ..FsckReport::default()
+    };
+    for path in files {
+        if !Path::new(&path).exists() {
+            report.missing.push(path);
+            continue;
+        }
+        let check = BTree::try_reader_with_options(&path, &BTreeOptions::default())
+            .map(|tree| tree.check());
+        report.blocks.push((path, check));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::db::{DBOptions, MintKv};
+    use crate::util::testing::temp_dir;
+
+    #[test]
+    fn test_fsck() {
+        let root_dir = &temp_dir("fsck");
+        assert!(fsck(root_dir).is_err());
+
+        // every chunk gets its own block
+        let options = DBOptions {
+            chunk_size: 256,
+            page_size: 4096,
+            block_size: 1,
+            auto_compaction: false,
+            ..DBOptions::default()
+        };
+        let mut db = MintKv::open(root_dir, options);
+        for i in 0..1000u64 {
+            db.insert(i, format!("value-{i}").as_bytes()).unwrap();
+        }
+        db.commit();
+        drop(db);
+        let report = fsck(root_dir).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert!(report.blocks.len() > 1);
+        assert!(report.orphans.is_empty());
+
+        // a block removed from the dir, a file the manifest doesn't know and a damaged block
+        let first = report.blocks[0].0.clone();
+        let last = report.blocks[report.blocks.len() - 1].0.clone();
+        let orphan = format!("{root_dir}/blocks/block-999");
+        fs::rename(&first, &orphan).unwrap();
+        fs::write(&last, b"damaged").unwrap();
+        let report = fsck(root_dir).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.missing, vec![first]);
+        assert_eq!(report.orphans, vec![orphan]);
+        assert_eq!(report.damaged(), 1);
+        assert!(report.blocks.iter().any(|(path, check)| *path == last && check.is_err()));
+    }
+}
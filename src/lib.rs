@@ -1,3 +1,4 @@
+
 mod tombstone;
 mod block;
 mod btree;
@@ -5,11 +6,23 @@ mod memtable;
 mod wal;
 mod chunk;
 mod util;
-mod checkpoint;
 mod bytes;
 
 pub mod errors;
 pub mod db;
+pub mod stats;
+pub mod clock;
+pub mod snapshot;
+pub mod fsck;
+
+// the B+tree of a block file, for fsck, export, the benchmarks and readers on other threads; the
+// rest of the module is internal to the block layer
+pub use btree::buffer::BufferPool;
+pub use btree::builder::BTreeBuilder;
+pub use btree::check::CheckReport;
+pub use btree::concurrent::{BTreeReader, SharedBTree};
+pub use btree::error::Error as BTreeError;
+pub use btree::{BTree, BTreeOptions};
 
 
 #[cfg(test)]
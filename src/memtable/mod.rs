@@ -1,69 +1,254 @@
-mod constant;
-mod encoder;
+use std::collections::BTreeMap;
+use std::ops::{Range, RangeBounds};
+use std::rc::Rc;
 
-use crate::chunk::Chunk;
+use crate::bytes::VarintCodec;
+use crate::chunk::{Chunk, DEFAULT_MAX_CHUNK_SIZE};
 use crate::errors::Error;
+use crate::tombstone;
 
 pub struct MemTables {
     // in memory ,read && writable
-    mutable: *mut Chunk,
-    // in memory ,but not modified
-    warm_chunks: Vec<Chunk>,
+    mutable: Chunk,
+    // in memory ,but not modified, the newest first. Snapshots share them
+    warm_chunks: Vec<Rc<Chunk>>,
     // in memory, but need to persistend to disk
-    cold_chunks: Vec<Chunk>,
-    /* _marker: PhantomPinned, */
+    cold_chunks: Vec<Rc<Chunk>>,
     warm_num: usize,
+    chunk_size: usize,
+    // seq of the next chunk created, it follows the chunks already in blocks
+    next_seq: u64,
 }
 
 const DEFAULT_WARM_CHUNKS_NUM: usize = 4;
 // Default[#TODO] (should add some comments)
 impl Default for MemTables {
     fn default() -> Self {
+        MemTables::new(DEFAULT_MAX_CHUNK_SIZE, 0)
+    }
+}
+
+// MemTables[#TODO] (should add some comments)
+impl MemTables {
+    pub fn new(chunk_size: usize, next_seq: u64) -> Self {
         let mut memtables = MemTables {
-            mutable: std::ptr::null_mut(),
-            warm_chunks: vec![Chunk::new()],
+            mutable: Chunk::with_size(chunk_size),
+            warm_chunks: vec![],
             cold_chunks: vec![],
             warm_num: DEFAULT_WARM_CHUNKS_NUM,
+            chunk_size,
+            next_seq,
         };
-        memtables.mutable = &mut memtables.warm_chunks[0];
+        memtables.mutable = memtables.new_chunk();
         memtables
     }
+
+    fn new_chunk(&mut self) -> Chunk {
+        let mut chunk = Chunk::with_size(self.chunk_size);
+        chunk.seq = self.next_seq;
+        self.next_seq += 1;
+        chunk
+    }
 }
 
 // MemTables[#TODO] (should add some comments)
 impl MemTables {
     pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
-        for memtable in self.warm_chunks.iter() {
-            if let Ok(result) = memtable.get(key) {
-                return Ok(result);
-            }
-        }
+        get(self.chunks(), key)
+    }
 
-        Err(Error::KeyNotFound)
+    pub fn scan(&self, range: &impl RangeBounds<u64>, result: &mut BTreeMap<u64, Vec<u8>>) {
+        scan(self.chunks(), range, result)
     }
-    pub fn delete(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
-        unsafe { (*self.mutable).delete(key) }
+
+    // every chunk, the newest first
+    fn chunks(&self) -> impl DoubleEndedIterator<Item = &Chunk> {
+        std::iter::once(&self.mutable)
+            .chain(self.warm_chunks.iter().map(|chunk| chunk.as_ref()))
+            .chain(self.cold_chunks.iter().rev().map(|chunk| chunk.as_ref()))
     }
 
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
-        unsafe {
-            if (*self.mutable).is_overflowed(key, value) {
-                let _ = self.rotate();
-            }
-            (*self.mutable).insert(key, value)
+        if self.mutable.is_overflowed(key, value) {
+            self.rotate();
         }
+        self.mutable.insert(key, value)
     }
 
-    fn rotate(&mut self) -> Result<(), Error> {
+    // the mutable chunk is sealed and a new one takes its place
+    fn rotate(&mut self) {
+        let chunk = self.new_chunk();
+        let sealed = std::mem::replace(&mut self.mutable, chunk);
+        self.warm_chunks.insert(0, Rc::new(sealed));
         if self.warm_chunks.len() == self.warm_num {
             self.cold_chunks.push(self.warm_chunks.pop().unwrap());
         }
-        self.warm_chunks.insert(0, Chunk::new());
-        self.mutable = &mut self.warm_chunks[0];
-        Ok(())
     }
 
-    pub(crate) fn expired_chunks(&mut self) -> Option<Chunk> {
-        self.cold_chunks.pop()
+    // the oldest cold chunk, chunks are written into blocks in the order they were filled
+    pub(crate) fn expired_chunks(&mut self) -> Option<Rc<Chunk>> {
+        if self.cold_chunks.is_empty() {
+            return None;
+        }
+        Some(self.cold_chunks.remove(0))
+    }
+
+    // every chunk oldest first, the memtable is left empty
+    pub(crate) fn drain(&mut self) -> Vec<Rc<Chunk>> {
+        let chunk = self.new_chunk();
+        let mutable = std::mem::replace(&mut self.mutable, chunk);
+        let mut chunks = std::mem::take(&mut self.cold_chunks);
+        chunks.extend(std::mem::take(&mut self.warm_chunks).into_iter().rev());
+        chunks.push(Rc::new(mutable));
+        chunks
+    }
+
+    // the chunks as they are now, the newest first. The mutable chunk is sealed first so that
+    // none of them changes anymore, they are shared and not copied
+    pub(crate) fn snapshot(&mut self) -> Vec<Rc<Chunk>> {
+        if self.mutable.key_nums > 0 || !self.mutable.range_tombstones.is_empty() {
+            self.rotate();
+        }
+        self.warm_chunks
+            .iter()
+            .chain(self.cold_chunks.iter().rev())
+            .cloned()
+            .collect()
+    }
+
+    // seq of the chunk being written, the wal records of a write are tagged with it
+    pub(crate) fn seq(&self) -> u64 {
+        self.mutable.seq
+    }
+
+    // the range tombstone is written into the mutable chunk, it hides the keys of older chunks
+    // and is written into blocks with the chunk, see Blocks::write_block
+    pub fn delete_range(&mut self, range: &Range<u64>) {
+        self.mutable.insert_range_tombstone(range.clone());
+    }
+}
+
+// the entry of the key in the newest chunk holding it, see tombstone.rs. A range tombstone of a
+// newer chunk hides it, and the older entries in blocks: a tombstone is returned then
+pub(crate) fn get<'a>(
+    chunks: impl Iterator<Item = &'a Chunk>,
+    key: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let decoded = u64::varint_decode(key).1;
+    for chunk in chunks {
+        if let Ok(result) = chunk.get(key) {
+            return Ok(result);
+        }
+        if chunk.hides(decoded) {
+            return Ok(tombstone::tombstone());
+        }
+    }
+
+    Err(Error::KeyNotFound)
+}
+
+// merge all key-values of the chunks (the newest first) in the range into result, newer chunks
+// overwrite older ones. The range tombstones of a chunk remove the keys merged before it, from
+// blocks too
+pub(crate) fn scan<'a>(
+    chunks: impl DoubleEndedIterator<Item = &'a Chunk>,
+    range: &impl RangeBounds<u64>,
+    result: &mut BTreeMap<u64, Vec<u8>>,
+) {
+    for chunk in chunks.rev() {
+        for deleted in chunk.range_tombstones.iter() {
+            let keys: Vec<u64> = result.range(deleted.clone()).map(|(key, _)| *key).collect();
+            for key in keys {
+                result.remove(&key);
+            }
+        }
+        for (key, value) in chunk.scan(range) {
+            result.insert(u64::varint_decode(&key).1, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_chunks_order() {
+        let mut memtables = MemTables::new(64, 0);
+        let key = 7u64.varint_encode();
+        memtables.insert(&key, b"old").unwrap();
+        for i in 100..300u64 {
+            memtables.insert(&i.varint_encode(), b"value").unwrap();
+        }
+        memtables.insert(&key, b"new").unwrap();
+        for i in 300..500u64 {
+            memtables.insert(&i.varint_encode(), b"value").unwrap();
+        }
+
+        // chunks come out in the order they were filled, so the blocks written from them tell
+        // the newest entry of a key by their order too
+        let mut lasts = Vec::new();
+        let mut flushed = BTreeMap::new();
+        while let Some(chunk) = memtables.expired_chunks() {
+            lasts.push(chunk.key_range().unwrap().1);
+            for (key, value) in chunk.scan(&..) {
+                flushed.insert(u64::varint_decode(&key).1, value);
+            }
+        }
+        assert!(lasts.len() > 2);
+        assert!(lasts.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", lasts);
+        assert_eq!(flushed.get(&7), Some(&b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_range_tombstone() {
+        let mut memtables = MemTables::new(64, 10);
+        for i in 0..60u64 {
+            memtables.insert(&i.varint_encode(), b"value").unwrap();
+        }
+        memtables.delete_range(&(5..15));
+        memtables.insert(&8u64.varint_encode(), b"again").unwrap();
+        assert!(!memtables.cold_chunks.is_empty());
+
+        // keys of older chunks are hidden by a tombstone, keys in blocks too
+        let get = |key: u64| memtables.get(&key.varint_encode());
+        assert_eq!(get(4), Ok(b"value".to_vec()));
+        assert_eq!(get(5), Ok(tombstone::tombstone()));
+        assert_eq!(get(8), Ok(b"again".to_vec()));
+        assert_eq!(get(100), Err(Error::KeyNotFound));
+        let mut result: BTreeMap<u64, Vec<u8>> = (0..200).map(|key| (key, vec![])).collect();
+        memtables.scan(&.., &mut result);
+        let keys: Vec<u64> = result.keys().cloned().collect();
+        let expected: Vec<u64> = (0..5).chain([8]).chain(15..200).collect();
+        assert_eq!(keys, expected);
+
+        // chunks are numbered in the order they are created
+        let seqs: Vec<u64> = memtables.drain().iter().map(|chunk| chunk.seq).collect();
+        assert_eq!(seqs[0], 10);
+        assert!(seqs.windows(2).all(|pair| pair[0] + 1 == pair[1]));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut memtables = MemTables::new(1 << 20, 0);
+        for i in 0..10u64 {
+            memtables.insert(&i.varint_encode(), b"old").unwrap();
+        }
+        let chunks = memtables.snapshot();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(memtables.seq(), 1);
+
+        // the sealed chunks are shared, later writes go into a new chunk
+        memtables.insert(&3u64.varint_encode(), b"new").unwrap();
+        memtables.delete_range(&(5..8));
+        let get = |key: u64| get(chunks.iter().map(|chunk| chunk.as_ref()), &key.varint_encode());
+        assert_eq!(get(3), Ok(b"old".to_vec()));
+        assert_eq!(get(6), Ok(b"old".to_vec()));
+        assert_eq!(memtables.get(&6u64.varint_encode()), Ok(tombstone::tombstone()));
+
+        // a snapshot of an empty memtable doesn't seal another chunk
+        let mut memtables = MemTables::new(1 << 20, 0);
+        assert!(memtables.snapshot().is_empty());
+        assert_eq!(memtables.seq(), 0);
     }
 }
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::rc::Rc;
+
+use crate::block::BlockSnapshot;
+use crate::bytes::VarintCodec;
+use crate::chunk::Chunk;
+use crate::errors::Error;
+use crate::memtable;
+use crate::tombstone;
+
+// Snapshot 是数据库在某一个时刻的只读视图, 由 MintKv::snapshot() 创建.
+// 之后的写入, 删除, flush 和 compaction 都不会改变它读到的内容:
+// 它固定在一个seq上, memtable 的chunk被封住之后和它共享, 不会复制;
+// block 由 BlockSnapshot 固定下来, 之后写入的chunk按照seq跳过, 见 block/snapshot.rs
+pub struct Snapshot {
+    // chunks of the memtable when the snapshot was taken, the newest first
+    memtable: Vec<Rc<Chunk>>,
+    blocks: BlockSnapshot,
+    // time of the clock when the snapshot was taken, ttl is checked against it
+    now: u64,
+}
+
+// Snapshot[#TODO] (should add some comments)
+impl Snapshot {
+    pub(crate) fn new(memtable: Vec<Rc<Chunk>>, blocks: BlockSnapshot, now: u64) -> Self {
+        Snapshot {
+            memtable,
+            blocks,
+            now,
+        }
+    }
+
+    // the snapshot is pinned to this sequence number: it reads the chunks created before it, in
+    // the memtable and in blocks
+    pub fn seq(&self) -> u64 {
+        self.blocks.seq()
+    }
+
+    pub fn get(&self, key: u64) -> Result<Vec<u8>, Error> {
+        let key = key.varint_encode();
+        if let Ok(entry) = memtable::get(self.chunks(), &key) {
+            return tombstone::value(entry, self.now);
+        }
+        if let Ok(entry) = self.blocks.get(&key) {
+            return tombstone::value(entry, self.now);
+        }
+        Err(Error::KeyNotFound)
+    }
+
+    pub fn scan(&self, range: impl RangeBounds<u64>) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut result = BTreeMap::new();
+        self.blocks.scan(&range, &mut result)?;
+        memtable::scan(self.chunks(), &range, &mut result);
+        Ok(result
+            .into_iter()
+            .filter_map(|(key, entry)| {
+                tombstone::value(entry, self.now)
+                    .ok()
+                    .map(|value| (key, value))
+            })
+            .collect())
+    }
+
+    fn chunks(&self) -> impl DoubleEndedIterator<Item = &Chunk> {
+        self.memtable.iter().map(|chunk| chunk.as_ref())
+    }
+}
@@ -0,0 +1,76 @@
+use crate::block::NUM_LEVELS;
+
+// Stats 记录数据库自打开以来的运行统计, 不会持久化到磁盘
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    // chunk 如果按Raw格式编码需要占用的字节数
+    pub chunk_raw_bytes: u64,
+    // chunk 编码后, 压缩之前的字节数
+    pub chunk_encoded_bytes: u64,
+    // chunk 压缩之后实际写入block的字节数
+    pub chunk_compressed_bytes: u64,
+    // bloom filter 判断key不存在, 跳过了一次block读取
+    pub bloom_hits: u64,
+    // bloom filter 判断key可能存在, 但是block里面没有找到
+    pub bloom_false_positives: u64,
+    // 查询block时reader已经在table cache里面
+    pub table_cache_hits: u64,
+    // 查询block时需要重新打开文件
+    pub table_cache_misses: u64,
+    // B树页面在共享page cache里面命中
+    pub page_cache_hits: u64,
+    // B树页面需要从文件读取
+    pub page_cache_misses: u64,
+    // dirty页面被淘汰或者flush时写回文件的次数
+    pub page_cache_write_backs: u64,
+    // 完成的compaction次数
+    pub compactions: u64,
+    // compaction 读取和写入block的字节数
+    pub compaction_read_bytes: u64,
+    pub compaction_written_bytes: u64,
+    // compaction 删除的TTL过期entry数量
+    pub expired_entries: u64,
+    // 因为读到损坏的block而失败的compaction次数, 后台compaction失败之后就不再自动运行
+    pub compaction_errors: u64,
+    // 没有重写文件, 直接移动到下一层的block数量
+    pub block_moves: u64,
+    // 超出retention被整个删除的block数量
+    pub expired_blocks: u64,
+    // 还没有drop的snapshot数量
+    pub snapshots: u64,
+    // 已经被compaction或者retention删除, 但是因为snapshot还在读而保留文件的block数量
+    pub snapshot_kept_blocks: u64,
+    // 每一层的统计, levels[0] 是 level 0
+    pub levels: [LevelStats; NUM_LEVELS],
+}
+
+// LevelStats 记录一层block的统计
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelStats {
+    // 这一层现在的block数量和文件大小
+    pub blocks: u64,
+    pub bytes: u64,
+    // 输出到这一层的compaction次数, 以及它们读取和写入的字节数
+    pub compactions: u64,
+    pub compaction_read_bytes: u64,
+    pub compaction_written_bytes: u64,
+}
+
+// Stats[#TODO] (should add some comments)
+impl Stats {
+    // encoded / raw, 1.0 means no compression at all
+    pub fn chunk_compression_ratio(&self) -> f64 {
+        if self.chunk_raw_bytes == 0 {
+            return 1.0;
+        }
+        self.chunk_encoded_bytes as f64 / self.chunk_raw_bytes as f64
+    }
+
+    // compressed / encoded, 1.0 means the codec saved nothing
+    pub fn block_compression_ratio(&self) -> f64 {
+        if self.chunk_encoded_bytes == 0 {
+            return 1.0;
+        }
+        self.chunk_compressed_bytes as f64 / self.chunk_encoded_bytes as f64
+    }
+}
@@ -1,4 +1,133 @@
+use crate::errors::Error;
 
-// Tombstone[#TODO] (shoule add some comments )
-struct Tombstone {
+// 写入memtable, wal 和 chunk 的每一个value前面都有一个字节记录它的类型
+// 删除一个key时写入的是一个没有value的tombstone: 这个key可能还有更老的版本在别的chunk或者block里面,
+// tombstone会遮住它们, 直到compaction把这个key所有更老的版本都合并掉之后才会被丢弃
+// |--------------|
+// | kind | value |
+// |--------------|
+// |  1B  |  xB   |
+// |--------------|
+//
+// 带TTL的value在前面多记录一个过期时间(毫秒, 见clock.rs), 过期之后和tombstone一样遮住更老的版本
+// |------------------------------|
+// | kind | expire_at (LE) | value |
+// |------------------------------|
+// |  1B  |       8B       |  xB   |
+// |------------------------------|
+pub(crate) const KIND_DELETE: u8 = 0;
+pub(crate) const KIND_PUT: u8 = 1;
+pub(crate) const KIND_PUT_TTL: u8 = 2;
+// delete_range 在wal里面只记录一条: key是范围的start, value是 kind | end (LE, 不包含),
+// 它不会写入chunk, 见 block/meta.rs 的 RangeTombstone
+pub(crate) const KIND_DELETE_RANGE: u8 = 3;
+
+// entry of a value written by insert
+pub(crate) fn put(value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(value.len() + 1);
+    entry.push(KIND_PUT);
+    entry.extend_from_slice(value);
+    entry
+}
+
+// entry of a value written by insert_with_ttl, it's absent from expire_at on
+pub(crate) fn put_with_expiry(value: &[u8], expire_at: u64) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(value.len() + 9);
+    entry.push(KIND_PUT_TTL);
+    entry.extend_from_slice(&expire_at.to_le_bytes());
+    entry.extend_from_slice(value);
+    entry
+}
+
+// entry written by delete
+pub(crate) fn tombstone() -> Vec<u8> {
+    vec![KIND_DELETE]
+}
+
+// wal record of delete_range, its key is the start of the range
+pub(crate) fn range_tombstone(end: u64) -> Vec<u8> {
+    let mut entry = vec![KIND_DELETE_RANGE];
+    entry.extend_from_slice(&end.to_le_bytes());
+    entry
+}
+
+// the end of the range if the entry is a wal record of delete_range
+pub(crate) fn range_end(entry: &[u8]) -> Option<u64> {
+    match entry.first() {
+        Some(&KIND_DELETE_RANGE) if entry.len() == 9 => {
+            Some(u64::from_le_bytes(entry[1..9].try_into().unwrap()))
+        }
+        _ => None,
+    }
+}
+
+#[inline]
+pub(crate) fn is_tombstone(entry: &[u8]) -> bool {
+    entry.first() == Some(&KIND_DELETE)
+}
+
+// an entry with a ttl whose expiry time has come, it hides older entries like a tombstone
+#[inline]
+pub(crate) fn is_expired(entry: &[u8], now: u64) -> bool {
+    entry.first() == Some(&KIND_PUT_TTL)
+        && entry.len() >= 9
+        && u64::from_le_bytes(entry[1..9].try_into().unwrap()) <= now
+}
+
+// the value of an entry at time now, a tombstone or an expired entry means the key is not found
+pub(crate) fn value(mut entry: Vec<u8>, now: u64) -> Result<Vec<u8>, Error> {
+    match entry.first() {
+        Some(&KIND_PUT) => {
+            entry.remove(0);
+            Ok(entry)
+        }
+        Some(&KIND_PUT_TTL) if entry.len() >= 9 => {
+            if is_expired(&entry, now) {
+                return Err(Error::KeyNotFound);
+            }
+            Ok(entry.split_off(9))
+        }
+        Some(&KIND_DELETE) => Err(Error::KeyNotFound),
+        _ => Err(Error::Corrupted),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(value(put(b"value"), 0), Ok(b"value".to_vec()));
+        assert_eq!(value(put(b""), u64::MAX), Ok(vec![]));
+        assert!(!is_tombstone(&put(b"")));
+        assert!(is_tombstone(&tombstone()));
+        assert_eq!(value(tombstone(), 0), Err(Error::KeyNotFound));
+        assert_eq!(value(vec![], 0), Err(Error::Corrupted));
+        assert_eq!(value(vec![7, 1], 0), Err(Error::Corrupted));
+    }
+
+    #[test]
+    fn test_entry_with_expiry() {
+        let entry = put_with_expiry(b"value", 1000);
+        assert_eq!(entry.len(), 14);
+        assert!(!is_tombstone(&entry));
+        assert!(!is_expired(&entry, 999));
+        assert!(is_expired(&entry, 1000));
+        assert!(!is_expired(&put(b"value"), u64::MAX));
+        assert_eq!(value(entry.clone(), 999), Ok(b"value".to_vec()));
+        assert_eq!(value(entry, 1000), Err(Error::KeyNotFound));
+        assert_eq!(value(put_with_expiry(b"", 5), 0), Ok(vec![]));
+        // the expiry is cut short
+        assert_eq!(value(vec![KIND_PUT_TTL, 1, 2], 0), Err(Error::Corrupted));
+    }
+
+    #[test]
+    fn test_range_tombstone() {
+        assert_eq!(range_end(&range_tombstone(1 << 40)), Some(1 << 40));
+        assert_eq!(range_end(&tombstone()), None);
+        assert_eq!(range_end(&put(b"12345678")), None);
+        assert!(!is_tombstone(&range_tombstone(7)));
+        assert_eq!(value(range_tombstone(7), 0), Err(Error::Corrupted));
+    }
 }
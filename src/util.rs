@@ -6,14 +6,6 @@ pub(crate) struct Random;
 
 // Random[#TODO] (should add some comments)
 impl Random {
-    pub(crate) fn int32() -> Result<i32, std::io::Error> {
-        let mut file = File::open("/dev/random")?;
-        let mut random_bytes = [0u8; 4];
-        file.read_exact(&mut random_bytes)?;
-        let random_integer = i32::from_be_bytes(random_bytes);
-        Ok(random_integer)
-    }
-
     pub(crate) fn u32() -> Result<u32, std::io::Error> {
         let mut file = File::open("/dev/random")?;
         let mut random_bytes = [0u8; 4];
@@ -21,20 +13,151 @@ impl Random {
         let random_integer = u32::from_be_bytes(random_bytes);
         Ok(random_integer)
     }
+}
 
-    pub(crate) fn f64() -> Result<f64, std::io::Error> {
-        let mut file = File::open("/dev/random")?;
-        let mut random_bytes = [0u8; 8];
-        file.read_exact(&mut random_bytes)?;
-        let random_integer = f64::from_be_bytes(random_bytes);
-        Ok(random_integer)
+// crc32 (IEEE 802.3), used to check meta and pages read back from disk
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
     }
+    table
+};
 
-    pub(crate) fn u64() -> Result<u64, std::io::Error> {
-        let mut file = File::open("/dev/random")?;
-        let mut random_bytes = [0u8; 8];
-        file.read_exact(&mut random_bytes)?;
-        let random_integer = u64::from_be_bytes(random_bytes);
-        Ok(random_integer)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// helpers shared by the tests of every module
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::fmt;
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::Path;
+
+    use crate::db::{DBOptions, MintKv};
+
+    // a file under the temp dir, the pid keeps test binaries running at the same time apart;
+    // names must be unique in the crate since the tests of one binary run in parallel
+    pub(crate) fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mintkv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    // same as temp_path for a dir, what an earlier run left is removed but the dir isn't created;
+    // the dir is removed again when the guard is dropped, also when an assertion fails
+    pub(crate) fn temp_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("mintkv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir.to_str().unwrap().to_string())
+    }
+
+    // TempDir[#TODO] (shoule add some comments )
+    pub(crate) struct TempDir(String);
+
+    // Deref[#TODO] (should add some comments)
+    impl Deref for TempDir {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    // AsRef[#TODO] (should add some comments)
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            Path::new(&self.0)
+        }
+    }
+
+    // Display[#TODO] (should add some comments)
+    impl fmt::Display for TempDir {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    // Drop[#TODO] (should add some comments)
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // a database opened in an empty temp dir; bind the guard before the db,
+    // `let (tmp, mut db) = open_db(..)`, so the db is dropped before its dir is removed
+    pub(crate) fn open_db(name: &str, options: DBOptions) -> (TempDb, MintKv) {
+        let dir = temp_dir(name);
+        let db = MintKv::open(&dir, options.clone());
+        (TempDb { dir, options }, db)
+    }
+
+    // TempDb[#TODO] (shoule add some comments )
+    pub(crate) struct TempDb {
+        pub(crate) dir: TempDir,
+        pub(crate) options: DBOptions,
+    }
+
+    // TempDb[#TODO] (should add some comments)
+    impl TempDb {
+        // open the database again with the options it was created with
+        pub(crate) fn reopen(&self) -> MintKv {
+            MintKv::open(&self.dir, self.options.clone())
+        }
+    }
+
+    // insert "value-{key}" for every key, the values most tests check against
+    pub(crate) fn fill(db: &mut MintKv, keys: impl IntoIterator<Item = u64>) {
+        for key in keys {
+            db.insert(key, format!("value-{}", key).as_bytes()).unwrap();
+        }
+    }
+
+    // Knuth's MMIX LCG, seeded so that a failing run can be repeated
+    pub(crate) struct Lcg(pub(crate) u64);
+
+    // Lcg[#TODO] (should add some comments)
+    impl Lcg {
+        pub(crate) fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        pub(crate) fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
     }
 }
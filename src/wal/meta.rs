@@ -1,9 +1,14 @@
 use memmap2::MmapMut;
 
+use super::WAL_FORMAT_VERSION;
+
 const DEFAULT_WAL_LEN: u8 = 10;
 pub struct WalMeta {
     pub start_index: u8,
     pub wal_len: u8,
+    // format of the records, see WAL_FORMAT_VERSION. Metadata written before the format was
+    // versioned holds 0 here
+    pub format: u8,
     pub cache: Vec<u8>,
     mmap: MmapMut,
 
@@ -20,11 +25,13 @@ impl WalMeta {
             cache,
             mmap,
             wal_len: 0,
+            format: WAL_FORMAT_VERSION,
             cur_index: 0,
         };
         if is_initial {
             wal.mmap[0] = wal.wal_len;
             wal.mmap[1] = wal.start_index;
+            wal.mmap[2] = wal.format;
         }
         wal
     }
@@ -32,12 +39,15 @@ impl WalMeta {
 
 // WalMeta[#TODO] (should add some comments)
 impl WalMeta {
+    // drop every file and write the records in the current format from now on
     pub fn reset(&mut self) {
         self.cache.clear();
         self.wal_len = 0;
         self.start_index = 0;
+        self.format = WAL_FORMAT_VERSION;
         self.mmap[0] = self.wal_len;
         self.mmap[1] = self.start_index;
+        self.mmap[2] = self.format;
     }
     pub fn reinitial(&mut self) {
         let mut offset = 0;
@@ -46,17 +56,12 @@ impl WalMeta {
         self.start_index = self.mmap[offset];
         self.cur_index = self.start_index;
         offset += 1;
-        if self.wal_len < DEFAULT_WAL_LEN {
-            for i in 0..self.wal_len {
-                self.cache.insert(0, i);
-            }
-        } else {
-            for i in self.cur_index..DEFAULT_WAL_LEN {
-                self.cache.push(i);
-            }
-            for i in 0..self.cur_index {
-                self.cache.push(i);
-            }
+        self.format = self.mmap[offset];
+        offset += 1;
+        // the last wal_len files before start_index, replayed in the order they were written
+        for i in 0..self.wal_len {
+            self.cache
+                .push((self.cur_index + DEFAULT_WAL_LEN - self.wal_len + i) % DEFAULT_WAL_LEN);
         }
         _ = offset;
     }
@@ -65,15 +70,22 @@ impl WalMeta {
         if self.cache.is_empty() {
             return None;
         }
-        self.wal_len -= 1;
+        // replayed files are kept until the wal is reset, a crash before replays them again
         Some(self.cache.remove(0))
     }
 
+    // the n oldest files are dropped, their records are in blocks. Files are not reused before
+    // the ring comes around to them
+    pub fn truncate(&mut self, n: u8) {
+        self.wal_len -= n.min(self.wal_len);
+        self.mmap[0] = self.wal_len;
+    }
+
     pub fn rotate(&mut self) -> u8 {
         if self.cache.len() < DEFAULT_WAL_LEN as usize {
             self.cache.push(self.start_index);
-            self.wal_len += 1;
         }
+        self.wal_len = (self.wal_len + 1).min(DEFAULT_WAL_LEN);
         let allocated = self.start_index;
         self.start_index = (self.start_index + 1) % DEFAULT_WAL_LEN;
 
@@ -82,3 +94,74 @@ impl WalMeta {
         allocated
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reopen(wal: &WalMeta) -> WalMeta {
+        let mut mmap = MmapMut::map_anon(3).unwrap();
+        mmap.copy_from_slice(&wal.mmap[..3]);
+        let mut reopened = WalMeta::new(mmap, false);
+        reopened.reinitial();
+        reopened
+    }
+
+    fn replay(wal: &mut WalMeta) -> Vec<u8> {
+        std::iter::from_fn(|| wal.itertor()).collect()
+    }
+
+    #[test]
+    fn test_replay_order() {
+        let mut wal = WalMeta::new(MmapMut::map_anon(3).unwrap(), true);
+        for _ in 0..3 {
+            wal.rotate();
+        }
+        let mut wal = reopen(&wal);
+        assert_eq!(replay(&mut wal), vec![0, 1, 2]);
+
+        // files written before a restart are still replayed after the next one
+        wal.rotate();
+        let mut wal = reopen(&wal);
+        assert_eq!(replay(&mut wal), vec![0, 1, 2, 3]);
+
+        // once the ring wraps, the oldest file is the one after the newest
+        for _ in 0..8 {
+            wal.rotate();
+        }
+        let mut wal = reopen(&wal);
+        assert_eq!(replay(&mut wal), vec![2, 3, 4, 5, 6, 7, 8, 9, 0, 1]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut wal = WalMeta::new(MmapMut::map_anon(3).unwrap(), true);
+        for _ in 0..5 {
+            wal.rotate();
+        }
+        wal.truncate(3);
+        let mut wal = reopen(&wal);
+        assert_eq!(replay(&mut wal), vec![3, 4]);
+
+        // new files follow the newest one
+        wal.rotate();
+        let mut wal = reopen(&wal);
+        assert_eq!(replay(&mut wal), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_format() {
+        let mut wal = WalMeta::new(MmapMut::map_anon(3).unwrap(), true);
+        wal.rotate();
+        assert_eq!(reopen(&wal).format, WAL_FORMAT_VERSION);
+
+        // metadata of a wal written before the format was versioned
+        wal.mmap[2] = 0;
+        let mut wal = reopen(&wal);
+        assert_eq!(wal.format, 0);
+        wal.reset();
+        let mut wal = reopen(&wal);
+        assert_eq!(wal.format, WAL_FORMAT_VERSION);
+        assert!(replay(&mut wal).is_empty());
+    }
+}
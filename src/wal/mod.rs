@@ -1,26 +1,32 @@
 mod meta;
 
 use memmap2::{Mmap, MmapMut};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
-use std::{fs, usize};
-
-use crate::bytes;
+use std::fs;
 
 use self::meta::WalMeta;
 
-const DEFAULT_WAL_FILE_NUMBER: usize = 8;
 // default wal page size is 10M
 const DEFAULT_WAL_PAGE_SIZE: u64 = 1024;
 
 const WAL_END_MAGIC_NUMBER: u64 = 0xABCDABEF;
 
+// format of the records, stored in the metadata file
+// 0: values as they were inserted, written before deletes were logged
+// 1: entries prefixed by their kind, see tombstone.rs
+pub(crate) const WAL_FORMAT_VERSION: u8 = 1;
+
 pub struct WalManager {
     wal: Option<Wal>,
     metadata: WalMeta,
     root_dir: String,
     page_size: u64,
     reader: Option<WalReader>,
+    // seq of the newest memtable chunk with records in every file written since the wal was
+    // reset, oldest first. Files up to a flushed chunk are dropped by truncate
+    tags: VecDeque<u64>,
 }
 
 // WalManager[#TODO] (should add some comments)
@@ -43,25 +49,67 @@ impl WalManager {
             root_dir,
             page_size: DEFAULT_WAL_PAGE_SIZE,
             reader: None,
+            tags: VecDeque::new(),
         }
     }
 
-    pub fn record(&mut self, key: &[u8], val: &[u8]) {
+    // seq is the memtable chunk the record is written into. A record larger than a file gets a
+    // file of its own, big enough to hold it
+    pub fn record(&mut self, key: &[u8], val: &[u8], seq: u64) {
         if self.wal.is_none() || self.wal.as_mut().unwrap().is_overflow(key, val) {
-            self.rotate();
+            let record_size = 8 + key.len() + 8 + val.len() + 8 + 1;
+            self.rotate(self.page_size.max(record_size as u64));
         }
         if let Some(ref mut wal) = self.wal {
             wal.write(key, val);
         }
+        if let Some(tag) = self.tags.back_mut() {
+            *tag = (*tag).max(seq);
+        }
+    }
+
+    // drop the oldest files whose records are all in chunks up to the durable seq, the file being
+    // written is kept
+    pub fn truncate(&mut self, durable_seq: u64) {
+        let files = self.tags.len().saturating_sub(1);
+        let dropped = self
+            .tags
+            .iter()
+            .take(files)
+            .take_while(|tag| **tag <= durable_seq)
+            .count();
+        if dropped > 0 {
+            self.tags.drain(..dropped);
+            self.metadata.truncate(dropped as u8);
+        }
+    }
+
+    // the records were written in an older format, see WAL_FORMAT_VERSION
+    pub fn is_legacy(&self) -> bool {
+        self.metadata.format < WAL_FORMAT_VERSION
+    }
+
+    // drop every record, they must be persisted somewhere else first. Records are written in the
+    // current format from then on
+    pub fn reset(&mut self) {
+        self.wal.take();
+        self.reader.take();
+        self.tags.clear();
+        self.metadata.reset();
     }
 
-    pub fn rotate(&mut self) {
+    pub fn rotate(&mut self, size: u64) {
         self.wal.take();
         /* if let Some(wal) = self.wal.take() {
         }; */
         let id = self.metadata.rotate();
+        // the oldest file is overwritten once the ring is full
+        self.tags.push_back(0);
+        while self.tags.len() > self.metadata.wal_len as usize {
+            self.tags.pop_front();
+        }
         let wal_fname = format!("{}/wal/wal-{}", self.root_dir, id,);
-        let wal = Wal::new_writer(&wal_fname, self.page_size);
+        let wal = Wal::new_writer(&wal_fname, size);
         self.wal = Some(wal);
     }
 
@@ -203,3 +251,78 @@ impl WalReader {
         Some((key, value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp_dir;
+
+    #[test]
+    fn test_truncate() {
+        let root_dir = temp_dir("wal-truncate");
+        fs::create_dir_all(format!("{root_dir}/wal")).unwrap();
+        let metadata = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(format!("{root_dir}/wal/metadata"))
+                .unwrap()
+        };
+        let mut wal_mg = WalManager::new(root_dir.to_string(), metadata(), true);
+        // a file holds two records, of the chunks 0 and 1, 2 and 3, 4 and 5
+        for seq in 0..6u64 {
+            wal_mg.record(&[seq as u8], &[0; 400], seq);
+        }
+        assert_eq!(wal_mg.tags, vec![1, 3, 5]);
+        wal_mg.truncate(2);
+        assert_eq!(wal_mg.tags, vec![3, 5]);
+        // the file being written is kept
+        wal_mg.truncate(5);
+        assert_eq!(wal_mg.tags, vec![5]);
+        drop(wal_mg);
+
+        let mut wal_mg = WalManager::new(root_dir.to_string(), metadata(), false);
+        let keys: Vec<Vec<u8>> = std::iter::from_fn(|| wal_mg.replay())
+            .filter(|(key, _)| !key.is_empty())
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![vec![4], vec![5]]);
+    }
+
+    #[test]
+    fn test_large_record() {
+        let root_dir = temp_dir("wal-large");
+        fs::create_dir_all(format!("{root_dir}/wal")).unwrap();
+        let metadata = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(format!("{root_dir}/wal/metadata"))
+                .unwrap()
+        };
+        // records larger than a file, between small ones
+        let large: Vec<u8> = (0..10 * DEFAULT_WAL_PAGE_SIZE).map(|i| i as u8).collect();
+        let mut wal_mg = WalManager::new(root_dir.to_string(), metadata(), true);
+        wal_mg.record(&[1], b"small", 0);
+        wal_mg.record(&[2], &large, 0);
+        wal_mg.record(&[3], &large[1..], 0);
+        wal_mg.record(&[4], b"small", 0);
+        drop(wal_mg);
+
+        let mut wal_mg = WalManager::new(root_dir.to_string(), metadata(), false);
+        let records: Vec<(Vec<u8>, Vec<u8>)> = std::iter::from_fn(|| wal_mg.replay())
+            .filter(|(key, _)| !key.is_empty())
+            .collect();
+        let expected = vec![
+            (vec![1], b"small".to_vec()),
+            (vec![2], large.clone()),
+            (vec![3], large[1..].to_vec()),
+            (vec![4], b"small".to_vec()),
+        ];
+        assert_eq!(records, expected);
+    }
+}